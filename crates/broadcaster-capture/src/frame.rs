@@ -1,7 +1,7 @@
 //! Captured frame types.
 
 use bytes::Bytes;
-use std::time::Instant;
+use std::time::{Instant, SystemTime};
 
 /// Timestamp for a captured frame.
 #[derive(Debug, Clone, Copy)]
@@ -11,6 +11,9 @@ pub struct CaptureTimestamp {
 
     /// Frame presentation timestamp in 100ns units (for AV sync).
     pub pts_100ns: u64,
+
+    /// Wall-clock time when the frame was captured (for latency measurement).
+    pub wall_clock: SystemTime,
 }
 
 impl CaptureTimestamp {
@@ -23,6 +26,7 @@ impl CaptureTimestamp {
         Self {
             capture_time,
             pts_100ns,
+            wall_clock: SystemTime::now(),
         }
     }
 
@@ -1,18 +1,42 @@
 //! AAC audio encoder.
 
 use bytes::Bytes;
-use tracing::{debug, instrument, trace};
+use tracing::{debug, instrument, trace, warn};
 
 use crate::error::EncoderError;
-use crate::{AudioEncoder, AudioEncoderConfig, EncodedAudioPacket, EncoderResult};
+use crate::{
+    AacProfile, AacTransport, AudioCodec, AudioCodecConfig, AudioEncoder, AudioEncoderConfig,
+    EncodedAudioPacket, EncoderResult,
+};
 
-/// AAC-LC audio encoder using fdk-aac.
+/// Input timestamp deviation from the sample count tolerated as jitter.
+const MAX_TIMESTAMP_JITTER_100NS: i64 = 1_000_000; // 100 ms
+
+/// Longest forward gap in the input that is filled with silence. Longer gaps
+/// and backward jumps re-anchor the timeline instead.
+const MAX_SILENCE_FILL_100NS: i64 = 10_000_000; // 1 s
+
+/// AAC audio encoder using fdk-aac.
 pub struct AacEncoder {
     encoder: fdk_aac::enc::Encoder,
     config: AudioEncoderConfig,
+    /// Samples per channel in one AAC frame (1024, or 2048 with SBR).
+    frame_length: u32,
     samples_per_frame: usize,
+    /// AudioSpecificConfig reported by the encoder.
+    audio_specific_config: Bytes,
+    /// Samples per channel the encoder delays its output by.
+    priming_samples: u32,
     sample_buffer: Vec<f32>,
     frame_count: u64,
+    /// Input PTS the sample counter is anchored to, set by the first input.
+    anchor_pts_100ns: Option<u64>,
+    /// Input sample index (per channel) at the anchor.
+    anchor_sample: u64,
+    /// Samples per channel received so far, including inserted silence.
+    samples_in: u64,
+    /// Packets produced so far.
+    packets_out: u64,
     /// Output buffer for encoded data.
     output_buffer: Vec<u8>,
 }
@@ -25,12 +49,11 @@ impl AacEncoder {
             sample_rate = config.sample_rate,
             channels = config.channels,
             bitrate_kbps = config.bitrate_kbps,
+            profile = ?config.aac.profile,
+            transport = ?config.aac.transport,
             "Initializing AAC encoder"
         );
 
-        // AAC frame size is 1024 samples per channel
-        let samples_per_frame = 1024 * config.channels as usize;
-
         // Configure channel mode
         let channel_mode = if config.channels == 1 {
             fdk_aac::enc::ChannelMode::Mono
@@ -38,12 +61,32 @@ impl AacEncoder {
             fdk_aac::enc::ChannelMode::Stereo
         };
 
+        // Parametric stereo codes a mono core plus stereo side information
+        if config.aac.profile == AacProfile::HeV2 && config.channels != 2 {
+            return Err(EncoderError::NotSupported(format!(
+                "HE-AAC v2 requires stereo input, got {} channels",
+                config.channels
+            )));
+        }
+
+        let audio_object_type = match config.aac.profile {
+            AacProfile::Lc => fdk_aac::enc::AudioObjectType::Mpeg4LowComplexity,
+            AacProfile::HeV1 => fdk_aac::enc::AudioObjectType::Mpeg4HeAac,
+            AacProfile::HeV2 => fdk_aac::enc::AudioObjectType::Mpeg4HeAacV2,
+        };
+
+        let transport = match config.aac.transport {
+            AacTransport::Raw => fdk_aac::enc::Transport::Raw,
+            AacTransport::Adts => fdk_aac::enc::Transport::Adts,
+        };
+
         // Create encoder parameters
         let params = fdk_aac::enc::EncoderParams {
             bit_rate: fdk_aac::enc::BitRate::Cbr(config.bitrate_kbps * 1000),
             sample_rate: config.sample_rate,
-            transport: fdk_aac::enc::Transport::Raw, // Raw AAC for RTMP
+            transport,
             channels: channel_mode,
+            audio_object_type,
         };
 
         // Create the encoder
@@ -58,22 +101,106 @@ impl AacEncoder {
         debug!(
             max_out_buf_bytes = info.maxOutBufBytes,
             frame_length = info.frameLength,
+            delay = info.nDelay,
+            conf_size = info.confSize,
             "AAC encoder initialized"
         );
 
+        if info.confSize == 0 {
+            return Err(EncoderError::Initialization(
+                "fdk-aac reported no AudioSpecificConfig".into(),
+            ));
+        }
+        let audio_specific_config = Bytes::copy_from_slice(&info.confBuf[..info.confSize as usize]);
+
+        // One AAC frame per channel: 1024 samples for LC, 2048 with SBR
+        let frame_length = info.frameLength;
+        let samples_per_frame = frame_length as usize * config.channels as usize;
+
         // Allocate output buffer based on encoder info
         let output_buffer = vec![0u8; info.maxOutBufBytes as usize];
 
         Ok(Self {
             encoder,
             config,
+            frame_length,
             samples_per_frame,
+            audio_specific_config,
+            priming_samples: info.nDelay,
             sample_buffer: Vec::with_capacity(samples_per_frame * 2),
             frame_count: 0,
+            anchor_pts_100ns: None,
+            anchor_sample: 0,
+            samples_in: 0,
+            packets_out: 0,
             output_buffer,
         })
     }
 
+    /// Input PTS of the given input sample index on the current timeline.
+    fn sample_pts(&self, sample: i64) -> i64 {
+        let anchor_pts = self.anchor_pts_100ns.unwrap_or(0) as i64;
+        let offset = (sample - self.anchor_sample as i64) * 10_000_000;
+        anchor_pts + offset.div_euclid(self.config.sample_rate as i64)
+    }
+
+    /// Check an input timestamp against the sample count.
+    ///
+    /// Small forward gaps (dropped capture buffers) are filled with silence
+    /// so the timeline stays continuous; anything else re-anchors it.
+    fn check_input_timestamp(&mut self, pts_100ns: u64) {
+        if self.anchor_pts_100ns.is_none() {
+            self.anchor_pts_100ns = Some(pts_100ns);
+            self.anchor_sample = self.samples_in;
+            return;
+        }
+
+        let gap = pts_100ns as i64 - self.sample_pts(self.samples_in as i64);
+        if gap.abs() <= MAX_TIMESTAMP_JITTER_100NS {
+            return;
+        }
+
+        if gap > 0 && gap <= MAX_SILENCE_FILL_100NS {
+            let missing = (gap * self.config.sample_rate as i64 / 10_000_000) as u64;
+            warn!(
+                gap_ms = gap / 10_000,
+                missing, "Audio input gap, inserting silence"
+            );
+            self.sample_buffer.extend(std::iter::repeat_n(
+                0.0f32,
+                missing as usize * self.config.channels as usize,
+            ));
+            self.samples_in += missing;
+        } else {
+            warn!(
+                gap_ms = gap / 10_000,
+                "Audio timestamp discontinuity, resynchronizing"
+            );
+            self.anchor_pts_100ns = Some(pts_100ns);
+            self.anchor_sample = self.samples_in;
+        }
+    }
+
+    /// PTS of the next output packet.
+    ///
+    /// Packet `n` holds the frame starting at input sample `n * frame_length`,
+    /// shifted back by the encoder's priming delay.
+    fn next_output_pts(&self) -> u64 {
+        let first_sample =
+            (self.packets_out * self.frame_length as u64) as i64 - self.priming_samples as i64;
+        self.sample_pts(first_sample).max(0) as u64
+    }
+
+    /// Wrap encoder output in a packet stamped with the next output PTS.
+    fn output_packet(&mut self, size: usize) -> EncodedAudioPacket {
+        let packet = EncodedAudioPacket {
+            data: Bytes::copy_from_slice(&self.output_buffer[..size]),
+            pts_100ns: self.next_output_pts(),
+        };
+        self.packets_out += 1;
+        packet
+    }
+
     /// Convert f32 samples to i16 for encoding.
     fn f32_to_i16(samples: &[f32]) -> Vec<i16> {
         samples
@@ -90,8 +217,11 @@ impl AudioEncoder for AacEncoder {
         samples: &[f32],
         pts_100ns: u64,
     ) -> EncoderResult<Option<EncodedAudioPacket>> {
+        self.check_input_timestamp(pts_100ns);
+
         // Add samples to buffer
         self.sample_buffer.extend_from_slice(samples);
+        self.samples_in += (samples.len() / self.config.channels as usize) as u64;
 
         // Check if we have enough samples for a frame
         if self.sample_buffer.len() < self.samples_per_frame {
@@ -122,13 +252,7 @@ impl AudioEncoder for AacEncoder {
             return Ok(None);
         }
 
-        // Extract the encoded data
-        let aac_data = self.output_buffer[..encode_info.output_size].to_vec();
-
-        let packet = EncodedAudioPacket {
-            data: Bytes::from(aac_data),
-            pts_100ns,
-        };
+        let packet = self.output_packet(encode_info.output_size);
 
         self.frame_count += 1;
 
@@ -151,14 +275,7 @@ impl AudioEncoder for AacEncoder {
 
             if let Ok(encode_info) = self.encoder.encode(&pcm_i16, &mut self.output_buffer) {
                 if encode_info.output_size > 0 {
-                    let aac_data = self.output_buffer[..encode_info.output_size].to_vec();
-                    // Use frame count for final PTS calculation
-                    let pts_100ns =
-                        (self.frame_count * 1024 * 10_000_000) / self.config.sample_rate as u64;
-                    packets.push(EncodedAudioPacket {
-                        data: Bytes::from(aac_data),
-                        pts_100ns,
-                    });
+                    packets.push(self.output_packet(encode_info.output_size));
                 }
             }
 
@@ -168,8 +285,23 @@ impl AudioEncoder for AacEncoder {
         Ok(packets)
     }
 
+    fn codec_config(&self) -> AudioCodecConfig {
+        AudioCodecConfig {
+            codec: AudioCodec::Aac,
+            sample_rate: self.config.sample_rate,
+            channels: self.config.channels,
+            frame_samples: self.frame_length,
+            priming_samples: self.priming_samples,
+            data: self.audio_specific_config.clone(),
+        }
+    }
+
     fn name(&self) -> &'static str {
-        "AAC-LC"
+        match self.config.aac.profile {
+            AacProfile::Lc => "AAC-LC",
+            AacProfile::HeV1 => "HE-AAC",
+            AacProfile::HeV2 => "HE-AACv2",
+        }
     }
 }
 
@@ -178,3 +310,118 @@ impl Drop for AacEncoder {
         debug!("Closing AAC encoder");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AacConfig;
+
+    fn config(profile: AacProfile, channels: u16) -> AudioEncoderConfig {
+        AudioEncoderConfig {
+            channels,
+            bitrate_kbps: 48,
+            aac: AacConfig {
+                profile,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Feed 10 ms stereo chunks at 48 kHz with the given input PTS and
+    /// return the output PTS.
+    fn encode_chunks(encoder: &mut AacEncoder, pts_100ns: impl Iterator<Item = u64>) -> Vec<u64> {
+        let chunk = vec![0.1f32; 480 * 2];
+        pts_100ns
+            .filter_map(|pts| encoder.encode(&chunk, pts).unwrap())
+            .map(|packet| packet.pts_100ns)
+            .collect()
+    }
+
+    /// Assert output PTS advance by exactly 1024 samples at 48 kHz, to within
+    /// the 100 ns rounding of each timestamp.
+    fn assert_frame_spaced(pts: &[u64], first: u64) {
+        for (n, &pts) in pts.iter().enumerate() {
+            let expected = first as f64 + n as f64 * 1024.0 / 48000.0 * 1e7;
+            assert!(
+                (pts as f64 - expected).abs() < 1.0,
+                "packet {}: pts {} expected {}",
+                n,
+                pts,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_timestamps_follow_sample_count() {
+        let mut encoder = AacEncoder::new(config(AacProfile::Lc, 2)).unwrap();
+        let priming_100ns = encoder.codec_config().priming_samples as f64 / 48000.0 * 1e7;
+
+        // One second of input with a few milliseconds of capture jitter
+        let start = 100_000_000;
+        let pts = encode_chunks(
+            &mut encoder,
+            (0..100).map(|i| start + i * 100_000 + (i * 7919 % 5) * 10_000),
+        );
+
+        assert_eq!(pts.len(), 100 * 480 / 1024);
+        assert_frame_spaced(&pts, (start as f64 - priming_100ns).floor() as u64);
+    }
+
+    #[test]
+    fn test_short_gap_filled_with_silence() {
+        let mut encoder = AacEncoder::new(config(AacProfile::Lc, 2)).unwrap();
+
+        // 300 ms of input missing after the first 200 ms
+        let start = 100_000_000;
+        let chunks = (0..20).chain(50..100);
+        let pts = encode_chunks(&mut encoder, chunks.map(|i| start + i * 100_000));
+
+        // The silence keeps the output continuous
+        assert_eq!(pts.len(), 100 * 480 / 1024);
+        let first = pts[0];
+        assert_frame_spaced(&pts, first);
+    }
+
+    #[test]
+    fn test_long_gap_resynchronizes() {
+        let mut encoder = AacEncoder::new(config(AacProfile::Lc, 2)).unwrap();
+
+        // Input resumes 5 s later
+        let start = 100_000_000;
+        let chunks = (0..50).chain(550..600);
+        let pts = encode_chunks(&mut encoder, chunks.map(|i| start + i * 100_000));
+
+        let jumps: Vec<u64> = pts.windows(2).map(|w| w[1] - w[0]).collect();
+        let resync = jumps.iter().position(|&d| d > 1_000_000).unwrap();
+        assert!(jumps[resync] > 50_000_000);
+        assert_frame_spaced(&pts[resync + 1..], pts[resync + 1]);
+    }
+
+    #[test]
+    fn test_lc_codec_config() {
+        let encoder = AacEncoder::new(config(AacProfile::Lc, 2)).unwrap();
+        let codec_config = encoder.codec_config();
+
+        // AAC-LC (object type 2), 48 kHz (index 3), 2 channels
+        assert_eq!(&codec_config.data[..], [0x11, 0x90]);
+        assert_eq!(codec_config.frame_samples, 1024);
+        assert_eq!(codec_config.sample_rate, 48000);
+    }
+
+    #[test]
+    fn test_he_aac_frames_include_sbr() {
+        let encoder = AacEncoder::new(config(AacProfile::HeV1, 2)).unwrap();
+        assert_eq!(encoder.codec_config().frame_samples, 2048);
+        assert_eq!(encoder.name(), "HE-AAC");
+    }
+
+    #[test]
+    fn test_he_aac_v2_requires_stereo() {
+        assert!(matches!(
+            AacEncoder::new(config(AacProfile::HeV2, 1)),
+            Err(EncoderError::NotSupported(_))
+        ));
+    }
+}
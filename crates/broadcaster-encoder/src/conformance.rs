@@ -0,0 +1,199 @@
+//! Behaviour every software video encoder backend must share.
+//!
+//! Each check runs against every backend compiled in, so swapping encoders
+//! cannot change what the pipeline and muxer see. The fake H.264 encoder
+//! from the `testing` feature is held to the same contract, so tests built
+//! on it hold for the real encoders.
+
+use crate::{
+    EncodedVideoPacket, EncoderError, EncoderResult, H264Profile, PixelFormat, VideoEncoder,
+    VideoEncoderConfig, VideoEncoderParams,
+};
+
+/// Opens the encoder under test.
+type Open = fn(VideoEncoderConfig) -> EncoderResult<Box<dyn VideoEncoder>>;
+
+const WIDTH: u32 = 320;
+const HEIGHT: u32 = 240;
+const FPS: u32 = 30;
+
+const NAL_TYPE_IDR: u8 = 5;
+const NAL_TYPE_SPS: u8 = 7;
+const NAL_TYPE_PPS: u8 = 8;
+
+fn config(input_format: PixelFormat) -> VideoEncoderConfig {
+    VideoEncoderConfig {
+        width: WIDTH,
+        height: HEIGHT,
+        fps: FPS,
+        bitrate_kbps: 500,
+        profile: H264Profile::Baseline,
+        input_format,
+        ..Default::default()
+    }
+}
+
+fn open(backend: Open, input_format: PixelFormat) -> Box<dyn VideoEncoder> {
+    backend(config(input_format)).unwrap()
+}
+
+/// A moving diagonal gradient; the layout of the grey chroma does not matter.
+fn frame(index: u32) -> Vec<u8> {
+    let (width, height) = (WIDTH as usize, HEIGHT as usize);
+    let mut frame = vec![128u8; PixelFormat::Nv12.frame_size(WIDTH, HEIGHT)];
+    for (y, row) in frame[..width * height].chunks_exact_mut(width).enumerate() {
+        for (x, pixel) in row.iter_mut().enumerate() {
+            *pixel = (x + y + index as usize * 3) as u8;
+        }
+    }
+    frame
+}
+
+fn pts(index: u32) -> u64 {
+    index as u64 * 10_000_000 / FPS as u64
+}
+
+/// Encode `count` frames, requesting a keyframe before `keyframe_at`.
+fn encode(
+    encoder: &mut dyn VideoEncoder,
+    count: u32,
+    keyframe_at: Option<u32>,
+) -> Vec<EncodedVideoPacket> {
+    let mut packets = Vec::new();
+    for i in 0..count {
+        if keyframe_at == Some(i) {
+            encoder.request_keyframe();
+        }
+        packets.extend(encoder.encode(&frame(i), pts(i)).unwrap());
+    }
+    packets.extend(encoder.flush().unwrap());
+    packets
+}
+
+/// NAL unit types in an Annex B byte stream.
+fn nal_types(data: &[u8]) -> Vec<u8> {
+    data.windows(4)
+        .filter(|w| w[..3] == [0, 0, 1])
+        .map(|w| w[3] & 0x1F)
+        .collect()
+}
+
+fn headers_have_sps_and_pps(backend: Open) {
+    let encoder = open(backend, PixelFormat::Nv12);
+    let types = nal_types(&encoder.get_headers().unwrap());
+    assert!(types.contains(&NAL_TYPE_SPS), "{:?}", types);
+    assert!(types.contains(&NAL_TYPE_PPS), "{:?}", types);
+}
+
+fn every_frame_comes_out_once(backend: Open) {
+    let mut encoder = open(backend, PixelFormat::Nv12);
+    let packets = encode(encoder.as_mut(), 20, None);
+
+    // Baseline has no B-frames, so decode order is presentation order
+    let output_pts: Vec<u64> = packets.iter().map(|p| p.pts_100ns).collect();
+    assert_eq!(output_pts, (0..20).map(pts).collect::<Vec<_>>());
+
+    assert!(packets[0].is_keyframe);
+    assert!(nal_types(&packets[0].data).contains(&NAL_TYPE_IDR));
+    assert!(packets.iter().all(|p| p.dts_100ns <= p.pts_100ns as i64));
+}
+
+fn accepts_i420(backend: Open) {
+    let mut encoder = open(backend, PixelFormat::I420);
+    let packets = encode(encoder.as_mut(), 5, None);
+    assert_eq!(packets.len(), 5);
+    assert!(packets[0].is_keyframe);
+}
+
+fn rejects_wrong_frame_size(backend: Open) {
+    let mut encoder = open(backend, PixelFormat::Nv12);
+    let short = vec![0u8; (WIDTH * HEIGHT) as usize];
+    assert!(matches!(
+        encoder.encode(&short, 0),
+        Err(EncoderError::InvalidInput(_))
+    ));
+}
+
+fn requested_keyframe_is_idr(backend: Open) {
+    let mut encoder = open(backend, PixelFormat::Nv12);
+    let packets = encode(encoder.as_mut(), 15, Some(10));
+
+    let requested = packets.iter().find(|p| p.pts_100ns == pts(10)).unwrap();
+    assert!(requested.is_keyframe);
+    assert!(nal_types(&requested.data).contains(&NAL_TYPE_IDR));
+    assert!(packets
+        .iter()
+        .filter(|p| p.pts_100ns != 0 && p.pts_100ns != pts(10))
+        .all(|p| !p.is_keyframe));
+}
+
+fn reconfigures_bitrate(backend: Open) {
+    let mut encoder = open(backend, PixelFormat::Nv12);
+    let mut packets = encode_without_flush(encoder.as_mut(), 0..5);
+
+    encoder
+        .reconfigure(&VideoEncoderParams {
+            bitrate_kbps: Some(250),
+            ..Default::default()
+        })
+        .unwrap();
+    packets.extend(encode_without_flush(encoder.as_mut(), 5..10));
+    packets.extend(encoder.flush().unwrap());
+
+    assert_eq!(packets.len(), 10);
+}
+
+fn encode_without_flush(
+    encoder: &mut dyn VideoEncoder,
+    frames: std::ops::Range<u32>,
+) -> Vec<EncodedVideoPacket> {
+    frames
+        .flat_map(|i| encoder.encode(&frame(i), pts(i)).unwrap())
+        .collect()
+}
+
+/// Generate the conformance tests for each backend behind its feature.
+macro_rules! conformance_tests {
+    ($($module:ident: $open:expr, $feature:literal;)*) => {$(
+        #[cfg(feature = $feature)]
+        mod $module {
+            use super::*;
+
+            #[test]
+            fn test_headers_have_sps_and_pps() {
+                headers_have_sps_and_pps($open);
+            }
+
+            #[test]
+            fn test_every_frame_comes_out_once() {
+                every_frame_comes_out_once($open);
+            }
+
+            #[test]
+            fn test_accepts_i420() {
+                accepts_i420($open);
+            }
+
+            #[test]
+            fn test_rejects_wrong_frame_size() {
+                rejects_wrong_frame_size($open);
+            }
+
+            #[test]
+            fn test_requested_keyframe_is_idr() {
+                requested_keyframe_is_idr($open);
+            }
+
+            #[test]
+            fn test_reconfigures_bitrate() {
+                reconfigures_bitrate($open);
+            }
+        }
+    )*};
+}
+
+conformance_tests! {
+    x264: |config| crate::VideoBackend::X264.create(config), "x264";
+    openh264: |config| crate::VideoBackend::OpenH264.create(config), "openh264";
+    fake_h264: |config| Ok(Box::new(crate::testing::FakeH264Encoder::new(config)?)), "testing";
+}
@@ -1,25 +1,53 @@
-//! Video (NVENC/x264) and audio (AAC) encoding.
+//! Video (NVENC/x264/OpenH264/rav1e) and audio (AAC/Opus) encoding.
 //!
-//! This crate provides hardware-accelerated video encoding via NVENC
-//! with x264 software fallback, plus AAC audio encoding.
+//! This crate provides hardware-accelerated H.264 encoding via NVENC
+//! with x264 software fallback, experimental AV1 encoding via rav1e, plus
+//! AAC and Opus audio encoding.
+//!
+//! The software encoders are portable and sit behind the `x264` and
+//! `fdk-aac` features (both on by default) and the opt-in `openh264`,
+//! `rav1e` and `opus` features. NVENC is only built on Windows. The
+//! `testing` feature adds stand-in encoders for tests (see [`testing`]).
 
-#[cfg(windows)]
+#[cfg(feature = "fdk-aac")]
 mod aac;
+#[cfg(all(test, any(feature = "x264", feature = "openh264", feature = "testing")))]
+mod conformance;
 mod error;
 #[cfg(windows)]
 mod nvenc;
-#[cfg(windows)]
+#[cfg(feature = "openh264")]
+mod openh264;
+#[cfg(feature = "opus")]
+mod opus;
+#[cfg(feature = "rav1e")]
+mod rav1e;
+mod registry;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(feature = "x264")]
 mod x264;
 
-#[cfg(windows)]
+#[cfg(feature = "fdk-aac")]
 pub use aac::AacEncoder;
 pub use error::EncoderError;
 #[cfg(windows)]
 pub use nvenc::NvencEncoder;
-#[cfg(windows)]
+#[cfg(feature = "openh264")]
+pub use openh264::OpenH264Encoder;
+#[cfg(feature = "opus")]
+pub use opus::OpusEncoder;
+#[cfg(feature = "rav1e")]
+pub use rav1e::Rav1eEncoder;
+pub use registry::{
+    benchmark, benchmark_backend, choose, fall_back_to_baseline, select_video_encoder,
+    BenchmarkResult, EncoderCapabilities, VideoBackend, BENCHMARK_FRAMES,
+};
+#[cfg(feature = "x264")]
 pub use x264::X264Encoder;
 
 use bytes::Bytes;
+use tracing::warn;
 
 /// Channel capacity for encoded packets.
 pub const ENCODED_CHANNEL_CAPACITY: usize = 8;
@@ -40,13 +68,121 @@ pub struct VideoEncoderConfig {
     pub fps: u32,
 
     /// Target bitrate in kbps.
+    ///
+    /// Used by the bitrate-based [`RateControl`] modes.
     pub bitrate_kbps: u32,
 
+    /// Rate-control mode.
+    pub rate_control: RateControl,
+
     /// Keyframe interval in seconds.
     pub keyframe_interval_secs: u32,
 
     /// H.264 profile.
     pub profile: H264Profile,
+
+    /// H.264 `level_idc` (None lets the encoder pick).
+    pub level_idc: Option<u8>,
+
+    /// Speed/quality tradeoff.
+    pub preset: EncoderPreset,
+
+    /// Content tuning.
+    pub tune: EncoderTune,
+
+    /// Encoder threads (0 for automatic).
+    pub threads: u32,
+
+    /// Encode slices of one frame in parallel rather than several frames.
+    pub slice_threads: bool,
+
+    /// Maximum consecutive B-frames (0 disables B-frames).
+    ///
+    /// B-frames delay output by the reorder depth and are not allowed in
+    /// the Baseline profile.
+    pub bframes: u32,
+
+    /// Rate-control lookahead in frames (0 disables lookahead).
+    pub lookahead_frames: u32,
+
+    /// Layout of the frames passed to [`VideoEncoder::encode`].
+    pub input_format: PixelFormat,
+
+    /// Codec to produce.
+    pub codec: VideoCodec,
+
+    /// AV1-specific settings, ignored by other codecs.
+    pub av1: Av1Config,
+
+    /// Backend to use, or `None` to pick one automatically.
+    pub backend: Option<VideoBackend>,
+
+    /// Regions encoded at a different quality than the rest of the frame.
+    ///
+    /// Only backends with
+    /// [`regions_of_interest`](EncoderCapabilities::regions_of_interest)
+    /// support them, and not with [`RateControl::Cqp`].
+    pub regions_of_interest: Vec<RegionOfInterest>,
+}
+
+impl VideoEncoderConfig {
+    /// Whether the encoder may output frames later than they are submitted.
+    pub fn is_low_latency(&self) -> bool {
+        self.bframes == 0 && self.lookahead_frames == 0
+    }
+
+    /// Keyframe interval in frames.
+    pub fn keyframe_interval_frames(&self) -> u32 {
+        self.fps * self.keyframe_interval_secs
+    }
+
+    /// VBV maximum bitrate and buffer size in kbits, if the rate control
+    /// mode constrains the bitrate. The buffer size saturates at `u32::MAX`.
+    pub fn vbv(&self) -> Option<(u32, u32)> {
+        let buffer_kbits = |kbps: u32, ms: u32| {
+            u32::try_from(u64::from(kbps) * u64::from(ms) / 1000).unwrap_or(u32::MAX)
+        };
+        match self.rate_control {
+            RateControl::Cbr { vbv_buffer_ms } => Some((
+                self.bitrate_kbps,
+                buffer_kbits(self.bitrate_kbps, vbv_buffer_ms),
+            )),
+            RateControl::Vbr {
+                max_bitrate_kbps,
+                vbv_buffer_ms,
+            } => Some((
+                max_bitrate_kbps,
+                buffer_kbits(max_bitrate_kbps, vbv_buffer_ms),
+            )),
+            RateControl::Crf { .. } | RateControl::Cqp { .. } => None,
+        }
+    }
+
+    /// Apply runtime parameter changes. Returns whether anything changed.
+    pub fn apply(&mut self, params: &VideoEncoderParams) -> bool {
+        let before = (
+            self.bitrate_kbps,
+            self.keyframe_interval_secs,
+            self.rate_control,
+        );
+
+        if let Some(bitrate_kbps) = params.bitrate_kbps {
+            self.bitrate_kbps = bitrate_kbps;
+        }
+        if let Some(keyframe_interval_secs) = params.keyframe_interval_secs {
+            self.keyframe_interval_secs = keyframe_interval_secs;
+        }
+        if let Some(rate_control) = params.rate_control {
+            self.rate_control = rate_control;
+        }
+
+        before
+            != (
+                self.bitrate_kbps,
+                self.keyframe_interval_secs,
+                self.rate_control,
+            )
+    }
 }
 
 impl Default for VideoEncoderConfig {
@@ -56,12 +192,212 @@ impl Default for VideoEncoderConfig {
             height: 1080,
             fps: 60,
             bitrate_kbps: 6000,
+            rate_control: RateControl::default(),
             keyframe_interval_secs: 2,
             profile: H264Profile::High,
+            level_idc: None,
+            preset: EncoderPreset::Veryfast,
+            tune: EncoderTune::None,
+            threads: 0,
+            slice_threads: false,
+            bframes: 0,
+            lookahead_frames: 0,
+            input_format: PixelFormat::default(),
+            codec: VideoCodec::default(),
+            av1: Av1Config::default(),
+            backend: None,
+            regions_of_interest: Vec::new(),
+        }
+    }
+}
+
+/// A rectangle of the frame encoded at a different quality, e.g. a text
+/// panel in a screen share.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegionOfInterest {
+    /// Left edge in pixels.
+    pub x: u32,
+
+    /// Top edge in pixels.
+    pub y: u32,
+
+    /// Width in pixels.
+    pub width: u32,
+
+    /// Height in pixels.
+    pub height: u32,
+
+    /// Quality change in quantizer steps. Positive values lower the
+    /// quantizer for a sharper region, negative values raise it to spend
+    /// fewer bits there.
+    pub quality_offset: i8,
+}
+
+/// Raw frame layout, both 8-bit 4:2:0.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// Y plane followed by one plane of interleaved U/V samples.
+    #[default]
+    Nv12,
+    /// Y, U and V planes one after another.
+    I420,
+}
+
+impl PixelFormat {
+    /// Bytes in one frame of `width` x `height`.
+    pub fn frame_size(self, width: u32, height: u32) -> usize {
+        (width * height * 3 / 2) as usize
+    }
+}
+
+/// Convert an NV12 frame to I420, reusing `i420`'s allocation.
+#[cfg(any(feature = "openh264", feature = "rav1e", feature = "testing"))]
+pub(crate) fn nv12_to_i420(frame: &[u8], width: u32, height: u32, i420: &mut Vec<u8>) {
+    let y_size = (width * height) as usize;
+    let chroma_size = y_size / 4;
+
+    i420.clear();
+    i420.extend_from_slice(&frame[..y_size]);
+    i420.resize(y_size + 2 * chroma_size, 0);
+
+    let (u, v) = i420[y_size..].split_at_mut(chroma_size);
+    let (uv, _) = frame[y_size..].as_chunks::<2>();
+    for (&[u_sample, v_sample], (u, v)) in uv.iter().zip(u.iter_mut().zip(v)) {
+        *u = u_sample;
+        *v = v_sample;
+    }
+}
+
+/// Compressed video format.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum VideoCodec {
+    /// H.264/AVC.
+    #[default]
+    H264,
+    /// AV1.
+    Av1,
+}
+
+/// AV1 encoder settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Av1Config {
+    /// Encoder speed from 0 (slowest) to 10 (fastest), or `None` to derive
+    /// it from the [`EncoderPreset`].
+    pub speed: Option<u8>,
+
+    /// Base quantizer index (0-255) for the constant-quality rate-control
+    /// modes. H.264 CRF/QP values do not carry over to AV1's scale.
+    pub quantizer: u8,
+
+    /// Lowest quantizer index the bitrate modes may use.
+    pub min_quantizer: u8,
+}
+
+impl Default for Av1Config {
+    fn default() -> Self {
+        Self {
+            speed: None,
+            quantizer: 100,
+            min_quantizer: 0,
+        }
+    }
+}
+
+/// Encoder speed preset, fastest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncoderPreset {
+    Ultrafast,
+    Superfast,
+    Veryfast,
+    Faster,
+    Fast,
+    Medium,
+    Slow,
+    Slower,
+    Veryslow,
+    Placebo,
+}
+
+impl EncoderPreset {
+    /// The next faster preset, or `None` for [`Ultrafast`](Self::Ultrafast).
+    pub fn faster(self) -> Option<Self> {
+        use EncoderPreset::*;
+        match self {
+            Ultrafast => None,
+            Superfast => Some(Ultrafast),
+            Veryfast => Some(Superfast),
+            Faster => Some(Veryfast),
+            Fast => Some(Faster),
+            Medium => Some(Fast),
+            Slow => Some(Medium),
+            Slower => Some(Slow),
+            Veryslow => Some(Slower),
+            Placebo => Some(Veryslow),
+        }
+    }
+}
+
+/// Encoder tuning for the content or use case.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncoderTune {
+    None,
+    Film,
+    Animation,
+    Grain,
+    StillImage,
+    Psnr,
+    Ssim,
+    /// Disable tools that are expensive to decode.
+    FastDecode,
+    /// No frame delay: disables B-frames and lookahead.
+    ZeroLatency,
+}
+
+/// Video rate-control mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateControl {
+    /// Constant bitrate at `bitrate_kbps`, enforced by a VBV buffer holding
+    /// `vbv_buffer_ms` of video. What most live ingests expect.
+    Cbr { vbv_buffer_ms: u32 },
+
+    /// Variable bitrate averaging `bitrate_kbps` and never exceeding
+    /// `max_bitrate_kbps` over a VBV buffer of `vbv_buffer_ms`.
+    Vbr {
+        max_bitrate_kbps: u32,
+        vbv_buffer_ms: u32,
+    },
+
+    /// Constant quality (x264 CRF, NVENC target quality), ignoring the
+    /// bitrate. Lower is better; for recording.
+    Crf { crf: u8 },
+
+    /// Constant quantizer, ignoring the bitrate. For recording.
+    Cqp { qp: u8 },
+}
+
+impl Default for RateControl {
+    fn default() -> Self {
+        Self::Cbr {
+            vbv_buffer_ms: 1000,
         }
     }
 }
 
+/// Video encoder settings that can be changed while encoding.
+///
+/// Fields left at `None` keep their current value.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VideoEncoderParams {
+    /// Target bitrate in kbps.
+    pub bitrate_kbps: Option<u32>,
+
+    /// Keyframe interval in seconds.
+    pub keyframe_interval_secs: Option<u32>,
+
+    /// Rate-control mode.
+    pub rate_control: Option<RateControl>,
+}
+
 /// H.264 profile levels.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum H264Profile {
@@ -70,9 +406,33 @@ pub enum H264Profile {
     High,
 }
 
+impl H264Profile {
+    /// The `profile_idc` value signalled in the SPS for this profile.
+    pub fn profile_idc(self) -> u8 {
+        match self {
+            Self::Baseline => 66,
+            Self::Main => 77,
+            Self::High => 100,
+        }
+    }
+}
+
+/// Audio codecs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AudioCodec {
+    /// AAC via fdk-aac, the only audio codec in classic RTMP.
+    #[default]
+    Aac,
+    /// Opus, for WebRTC, Enhanced RTMP and recordings.
+    Opus,
+}
+
 /// Audio encoding configuration.
 #[derive(Debug, Clone)]
 pub struct AudioEncoderConfig {
+    /// Codec to encode with.
+    pub codec: AudioCodec,
+
     /// Sample rate in Hz.
     pub sample_rate: u32,
 
@@ -81,29 +441,146 @@ pub struct AudioEncoderConfig {
 
     /// Target bitrate in kbps.
     pub bitrate_kbps: u32,
+
+    /// AAC-specific settings, ignored by other codecs.
+    pub aac: AacConfig,
+
+    /// Opus-specific settings, ignored by other codecs.
+    pub opus: OpusConfig,
 }
 
 impl Default for AudioEncoderConfig {
     fn default() -> Self {
         Self {
+            codec: AudioCodec::Aac,
             sample_rate: 48000,
             channels: 2,
             bitrate_kbps: 128,
+            aac: AacConfig::default(),
+            opus: OpusConfig::default(),
+        }
+    }
+}
+
+/// AAC encoder settings.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AacConfig {
+    /// Audio object type to encode.
+    pub profile: AacProfile,
+
+    /// Framing of the encoded packets.
+    pub transport: AacTransport,
+}
+
+/// AAC profiles.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AacProfile {
+    /// AAC-LC, for typical streaming bitrates.
+    #[default]
+    Lc,
+    /// HE-AAC v1 (AAC-LC + SBR), for low bitrates.
+    HeV1,
+    /// HE-AAC v2 (HE-AAC + parametric stereo), for very low stereo bitrates.
+    HeV2,
+}
+
+impl AacProfile {
+    /// The MPEG-4 audio object type signalled in the AudioSpecificConfig.
+    pub fn audio_object_type(self) -> u8 {
+        match self {
+            Self::Lc => 2,
+            Self::HeV1 => 5,
+            Self::HeV2 => 29,
+        }
+    }
+}
+
+/// AAC packet framing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AacTransport {
+    /// Raw access units, with the AudioSpecificConfig sent out of band
+    /// (FLV/RTMP, MP4).
+    #[default]
+    Raw,
+    /// Each access unit prefixed with an ADTS header (MPEG-TS, HLS).
+    Adts,
+}
+
+/// Opus encoder settings.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpusConfig {
+    /// Duration of audio in each packet, at least as long as the 10 ms
+    /// chunks audio is encoded in.
+    pub frame_duration: OpusFrameDuration,
+
+    /// What the encoder optimizes for.
+    pub application: OpusApplication,
+
+    /// Embed forward error correction data for the previous frame.
+    pub fec: bool,
+
+    /// Packet loss the FEC data is sized for, in percent (0-100).
+    pub expected_packet_loss_percent: u8,
+}
+
+/// Opus frame durations.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OpusFrameDuration {
+    Ms2_5,
+    Ms5,
+    Ms10,
+    #[default]
+    Ms20,
+    Ms40,
+    Ms60,
+}
+
+impl OpusFrameDuration {
+    /// Duration in microseconds.
+    pub fn as_micros(self) -> u32 {
+        match self {
+            Self::Ms2_5 => 2_500,
+            Self::Ms5 => 5_000,
+            Self::Ms10 => 10_000,
+            Self::Ms20 => 20_000,
+            Self::Ms40 => 40_000,
+            Self::Ms60 => 60_000,
         }
     }
+
+    /// Samples per channel in one frame at `sample_rate`.
+    pub fn samples(self, sample_rate: u32) -> usize {
+        (sample_rate as u64 * self.as_micros() as u64 / 1_000_000) as usize
+    }
+}
+
+/// Opus application modes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OpusApplication {
+    /// Speech intelligibility, for voice chat.
+    Voip,
+    /// Fidelity for music and mixed content.
+    #[default]
+    Audio,
+    /// Lowest delay, disabling the speech-optimized modes.
+    RestrictedLowDelay,
 }
 
 /// An encoded video packet.
 #[derive(Debug, Clone)]
 pub struct EncodedVideoPacket {
-    /// Encoded NAL data.
+    /// One encoded frame in the encoder's [`VideoCodec`]: Annex B NAL units
+    /// for H.264, a temporal unit of low-overhead OBUs for AV1.
     pub data: Bytes,
 
-    /// Presentation timestamp in 100ns units.
+    /// Presentation timestamp in 100ns units, as passed to `encode`.
     pub pts_100ns: u64,
 
     /// Decode timestamp in 100ns units.
-    pub dts_100ns: u64,
+    ///
+    /// Equals the PTS without B-frames. With B-frames it trails the PTS and
+    /// is negative for the first frames.
+    pub dts_100ns: i64,
 
     /// Whether this is a keyframe.
     pub is_keyframe: bool,
@@ -128,7 +605,7 @@ pub enum FrameType {
 /// An encoded audio packet.
 #[derive(Debug, Clone)]
 pub struct EncodedAudioPacket {
-    /// Encoded AAC data.
+    /// Encoded audio data.
     pub data: Bytes,
 
     /// Presentation timestamp in 100ns units.
@@ -137,26 +614,87 @@ pub struct EncodedAudioPacket {
 
 /// Trait for video encoders.
 pub trait VideoEncoder: Send {
-    /// Encode a frame in NV12 format.
-    fn encode(&mut self, frame: &[u8], pts_100ns: u64)
-        -> EncoderResult<Option<EncodedVideoPacket>>;
+    /// Encode a frame laid out as the configured
+    /// [`input_format`](VideoEncoderConfig::input_format).
+    ///
+    /// Returns every packet ready, in decode order: none while the encoder
+    /// buffers frames, and more than one when frames of a rebuilt encoder
+    /// are handed out too.
+    fn encode(&mut self, frame: &[u8], pts_100ns: u64) -> EncoderResult<Vec<EncodedVideoPacket>>;
 
     /// Flush any remaining frames.
+    ///
+    /// Returns the frames still delayed for reordering or lookahead, in
+    /// decode order. The encoder cannot be used afterwards.
     fn flush(&mut self) -> EncoderResult<Vec<EncodedVideoPacket>>;
 
+    /// Change encoder settings while encoding.
+    ///
+    /// Takes effect from the next frame submitted. Encoders that cannot
+    /// change a setting in place rebuild themselves, which starts a new GOP.
+    fn reconfigure(&mut self, params: &VideoEncoderParams) -> EncoderResult<()>;
+
+    /// Make the next frame submitted an IDR keyframe.
+    fn request_keyframe(&mut self);
+
+    /// Replace the [`regions_of_interest`](VideoEncoderConfig::regions_of_interest).
+    ///
+    /// Takes effect from the next frame submitted, without starting a new
+    /// GOP. Backends without per-block quality control only accept an empty
+    /// list.
+    fn set_regions_of_interest(&mut self, regions: &[RegionOfInterest]) -> EncoderResult<()> {
+        if regions.is_empty() {
+            Ok(())
+        } else {
+            Err(EncoderError::NotSupported(format!(
+                "{} cannot encode regions of interest",
+                self.name()
+            )))
+        }
+    }
+
     /// Check if the encoder supports hardware acceleration.
     fn is_hardware_accelerated(&self) -> bool;
 
     /// Get encoder name for diagnostics.
     fn name(&self) -> &'static str;
 
-    /// Get the SPS/PPS header data in Annex B format.
+    /// Codec of the packets produced.
+    fn codec(&self) -> VideoCodec;
+
+    /// Get the decoder configuration for container headers.
     ///
-    /// This is needed to build the AVC decoder configuration record
-    /// (sequence header) for RTMP streaming.
+    /// For H.264 this is the SPS/PPS in Annex B format, from which the AVC
+    /// decoder configuration record (sequence header) is built for RTMP.
+    /// For AV1 it is the complete `av1C` record, holding the sequence header
+    /// OBU.
     fn get_headers(&self) -> Option<Bytes>;
 }
 
+/// Decoder configuration of an encoded audio stream, for container headers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AudioCodecConfig {
+    /// Codec of the stream.
+    pub codec: AudioCodec,
+
+    /// Output sample rate in Hz.
+    pub sample_rate: u32,
+
+    /// Number of channels.
+    pub channels: u16,
+
+    /// Samples per channel in each packet.
+    pub frame_samples: u32,
+
+    /// Samples per channel of encoder delay at the start of the stream,
+    /// which players trim (MP4 edit list, Opus pre-skip).
+    pub priming_samples: u32,
+
+    /// Codec-specific decoder configuration: the AudioSpecificConfig for
+    /// AAC, the `OpusHead` header for Opus.
+    pub data: Bytes,
+}
+
 /// Trait for audio encoders.
 pub trait AudioEncoder: Send {
     /// Encode audio samples.
@@ -169,47 +707,64 @@ pub trait AudioEncoder: Send {
     /// Flush any remaining samples.
     fn flush(&mut self) -> EncoderResult<Vec<EncodedAudioPacket>>;
 
+    /// Decoder configuration for the stream being produced.
+    fn codec_config(&self) -> AudioCodecConfig;
+
     /// Get encoder name for diagnostics.
     fn name(&self) -> &'static str;
 }
 
-/// Create a video encoder, preferring NVENC with x264 fallback.
-#[cfg(windows)]
+/// Create a video encoder from the backends compiled in.
+///
+/// Unless `config.backend` names one, the most preferred backend that can
+/// handle `config` and opens is used, NVENC first where available. The
+/// preset is used as given; [`select_video_encoder`] benchmarks for a
+/// backend and preset that sustain `config.fps`.
 pub fn create_video_encoder(config: VideoEncoderConfig) -> EncoderResult<Box<dyn VideoEncoder>> {
-    // Try NVENC first
-    match NvencEncoder::new(config.clone()) {
-        Ok(encoder) => {
-            tracing::info!("Using NVENC hardware encoder");
-            Ok(Box::new(encoder))
-        }
-        Err(e) => {
-            tracing::warn!("NVENC not available: {}, falling back to x264", e);
-            let encoder = X264Encoder::new(config)?;
-            tracing::info!("Using x264 software encoder");
-            Ok(Box::new(encoder))
+    for backend in registry::candidates(&config)? {
+        match backend.create(config.clone()) {
+            Ok(encoder) => return Ok(encoder),
+            Err(e) if config.backend.is_some() => return Err(e),
+            Err(e) => warn!(backend = backend.name(), "Encoder unavailable: {}", e),
         }
     }
+    Err(registry::no_encoder_available(&config))
+}
+
+/// Create an audio encoder for `config.codec`.
+pub fn create_audio_encoder(config: AudioEncoderConfig) -> EncoderResult<Box<dyn AudioEncoder>> {
+    match config.codec {
+        AudioCodec::Aac => create_aac_encoder(config),
+        AudioCodec::Opus => create_opus_encoder(config),
+    }
+}
+
+/// Create the fdk-aac encoder.
+#[cfg(feature = "fdk-aac")]
+fn create_aac_encoder(config: AudioEncoderConfig) -> EncoderResult<Box<dyn AudioEncoder>> {
+    let encoder = AacEncoder::new(config)?;
+    Ok(Box::new(encoder))
 }
 
-/// Create a video encoder (stub for non-Windows platforms).
-#[cfg(not(windows))]
-pub fn create_video_encoder(_config: VideoEncoderConfig) -> EncoderResult<Box<dyn VideoEncoder>> {
+/// Stub when fdk-aac is not compiled in.
+#[cfg(not(feature = "fdk-aac"))]
+fn create_aac_encoder(_config: AudioEncoderConfig) -> EncoderResult<Box<dyn AudioEncoder>> {
     Err(EncoderError::NotSupported(
-        "Video encoding is only supported on Windows".into(),
+        "No AAC encoder available (build with the `fdk-aac` feature)".into(),
     ))
 }
 
-/// Create an audio encoder.
-#[cfg(windows)]
-pub fn create_audio_encoder(config: AudioEncoderConfig) -> EncoderResult<Box<dyn AudioEncoder>> {
-    let encoder = AacEncoder::new(config)?;
+/// Create the libopus encoder.
+#[cfg(feature = "opus")]
+fn create_opus_encoder(config: AudioEncoderConfig) -> EncoderResult<Box<dyn AudioEncoder>> {
+    let encoder = OpusEncoder::new(config)?;
     Ok(Box::new(encoder))
 }
 
-/// Create an audio encoder (stub for non-Windows platforms).
-#[cfg(not(windows))]
-pub fn create_audio_encoder(_config: AudioEncoderConfig) -> EncoderResult<Box<dyn AudioEncoder>> {
+/// Stub when libopus is not compiled in.
+#[cfg(not(feature = "opus"))]
+fn create_opus_encoder(_config: AudioEncoderConfig) -> EncoderResult<Box<dyn AudioEncoder>> {
     Err(EncoderError::NotSupported(
-        "Audio encoding is only supported on Windows".into(),
+        "No Opus encoder available (build with the `opus` feature)".into(),
     ))
 }
@@ -4,7 +4,10 @@ use bytes::Bytes;
 use tracing::{debug, instrument};
 
 use crate::error::EncoderError;
-use crate::{EncodedVideoPacket, EncoderResult, FrameType, VideoEncoder, VideoEncoderConfig};
+use crate::{
+    EncodedVideoPacket, EncoderResult, FrameType, PixelFormat, RateControl, VideoCodec,
+    VideoEncoder, VideoEncoderConfig, VideoEncoderParams,
+};
 
 // Conditional compilation for NVENC support
 #[cfg(all(windows, feature = "nvenc"))]
@@ -45,6 +48,50 @@ mod nvenc_impl {
     }
 }
 
+/// NVENC rate-control settings for a config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct NvencRateControl {
+    /// `NV_ENC_PARAMS_RC_MODE` name.
+    mode: &'static str,
+    /// Average bitrate in bits/s (0 when unused).
+    average_bitrate: u32,
+    /// Maximum bitrate in bits/s (0 when unused).
+    max_bitrate: u32,
+    /// VBV buffer size in bits (0 for the driver default).
+    vbv_buffer_size: u32,
+    /// Constant QP or target quality (0 when unused).
+    quality: u8,
+}
+
+/// Map a config's rate control onto NVENC's `NV_ENC_RC_PARAMS`.
+fn rate_control_params(config: &VideoEncoderConfig) -> NvencRateControl {
+    let (max_bitrate_kbps, vbv_buffer_kbits) = config.vbv().unwrap_or((0, 0));
+    let mut params = NvencRateControl {
+        mode: "NV_ENC_PARAMS_RC_CBR",
+        average_bitrate: config.bitrate_kbps.saturating_mul(1000),
+        max_bitrate: max_bitrate_kbps.saturating_mul(1000),
+        vbv_buffer_size: vbv_buffer_kbits.saturating_mul(1000),
+        quality: 0,
+    };
+
+    match config.rate_control {
+        RateControl::Cbr { .. } => {}
+        RateControl::Vbr { .. } => params.mode = "NV_ENC_PARAMS_RC_VBR",
+        // VBR with no bitrate target and a target quality
+        RateControl::Crf { crf } => {
+            params.mode = "NV_ENC_PARAMS_RC_VBR";
+            params.average_bitrate = 0;
+            params.quality = crf;
+        }
+        RateControl::Cqp { qp } => {
+            params.mode = "NV_ENC_PARAMS_RC_CONSTQP";
+            params.average_bitrate = 0;
+            params.quality = qp;
+        }
+    }
+    params
+}
+
 /// NVENC hardware encoder wrapper.
 ///
 /// This encoder uses NVIDIA's hardware video encoding capabilities when available.
@@ -54,6 +101,8 @@ pub struct NvencEncoder {
     initialized: bool,
     frame_count: u64,
     keyframe_interval: u64,
+    /// Encode the next frame as an IDR.
+    force_keyframe: bool,
     // In the future with full NVENC support:
     // #[cfg(all(windows, feature = "nvenc"))]
     // session: Option<Session>,
@@ -72,20 +121,29 @@ impl NvencEncoder {
             ));
         }
 
-        let keyframe_interval = (config.fps * config.keyframe_interval_secs) as u64;
+        if config.input_format != PixelFormat::Nv12 {
+            return Err(EncoderError::NotSupported(format!(
+                "NVENC input must be NV12, not {:?}",
+                config.input_format
+            )));
+        }
+
+        let keyframe_interval = config.keyframe_interval_frames() as u64;
 
         debug!(
             width = config.width,
             height = config.height,
             fps = config.fps,
             bitrate_kbps = config.bitrate_kbps,
+            rate_control = ?rate_control_params(&config),
             "Initializing NVENC encoder"
         );
 
         // Full NVENC implementation would:
         // 1. Create CUDA context
         // 2. Initialize Encoder with the CUDA device
-        // 3. Configure encoder settings (profile, bitrate, etc.)
+        // 3. Configure encoder settings (profile, rate control from
+        //    `rate_control_params`, etc.)
         // 4. Start a Session
         // 5. Allocate input Buffers and output Bitstreams
 
@@ -94,6 +152,7 @@ impl NvencEncoder {
             initialized: true,
             frame_count: 0,
             keyframe_interval,
+            force_keyframe: false,
         })
     }
 
@@ -110,11 +169,7 @@ impl NvencEncoder {
 
 impl VideoEncoder for NvencEncoder {
     #[instrument(name = "nvenc_encode", skip(self, frame))]
-    fn encode(
-        &mut self,
-        frame: &[u8],
-        pts_100ns: u64,
-    ) -> EncoderResult<Option<EncodedVideoPacket>> {
+    fn encode(&mut self, frame: &[u8], pts_100ns: u64) -> EncoderResult<Vec<EncodedVideoPacket>> {
         if !self.initialized {
             return Err(EncoderError::NotInitialized);
         }
@@ -128,7 +183,13 @@ impl VideoEncoder for NvencEncoder {
             )));
         }
 
-        let is_keyframe = self.frame_count.is_multiple_of(self.keyframe_interval);
+        let is_keyframe =
+            self.force_keyframe || self.frame_count.is_multiple_of(self.keyframe_interval);
+        if is_keyframe {
+            // Restart the GOP so the regular interval follows the forced keyframe
+            self.frame_count = 0;
+            self.force_keyframe = false;
+        }
         let frame_type = if is_keyframe {
             FrameType::I
         } else {
@@ -145,14 +206,14 @@ impl VideoEncoder for NvencEncoder {
         let packet = EncodedVideoPacket {
             data: Bytes::from(vec![0u8; 1024]), // Placeholder
             pts_100ns,
-            dts_100ns: pts_100ns,
+            dts_100ns: pts_100ns as i64,
             is_keyframe,
             frame_type,
         };
 
         self.frame_count += 1;
 
-        Ok(Some(packet))
+        Ok(vec![packet])
     }
 
     fn flush(&mut self) -> EncoderResult<Vec<EncodedVideoPacket>> {
@@ -160,6 +221,29 @@ impl VideoEncoder for NvencEncoder {
         Ok(Vec::new())
     }
 
+    fn reconfigure(&mut self, params: &VideoEncoderParams) -> EncoderResult<()> {
+        if !self.config.apply(params) {
+            return Ok(());
+        }
+
+        debug!(
+            bitrate_kbps = self.config.bitrate_kbps,
+            rate_control = ?rate_control_params(&self.config),
+            keyframe_interval_secs = self.config.keyframe_interval_secs,
+            "Reconfiguring NVENC encoder"
+        );
+
+        // A full implementation would call nvEncReconfigureEncoder with the
+        // new `rate_control_params`, which changes them in place without a new IDR
+        self.keyframe_interval = self.config.keyframe_interval_frames() as u64;
+        Ok(())
+    }
+
+    fn request_keyframe(&mut self) {
+        // A full implementation sets NV_ENC_PIC_FLAG_FORCEIDR on the next picture
+        self.force_keyframe = true;
+    }
+
     fn is_hardware_accelerated(&self) -> bool {
         true
     }
@@ -168,6 +252,10 @@ impl VideoEncoder for NvencEncoder {
         "NVENC"
     }
 
+    fn codec(&self) -> VideoCodec {
+        VideoCodec::H264
+    }
+
     fn get_headers(&self) -> Option<bytes::Bytes> {
         // TODO: When full NVENC support is implemented, extract headers from encoder
         None
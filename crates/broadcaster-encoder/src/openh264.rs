@@ -0,0 +1,373 @@
+//! OpenH264 software video encoder.
+//!
+//! A BSD-licensed alternative to x264. OpenH264 only produces the
+//! Constrained Baseline profile, without B-frames, so every frame comes out
+//! of `encode` as soon as it goes in.
+//!
+//! The bindings only set the rate, threading and usage of the encoder, so
+//! OpenH264 picks the level and encoder complexity itself, and keyframes
+//! are forced here at the configured interval.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use openh264::encoder::{
+    EncodedBitStream, Encoder, EncoderConfig, FrameType as OpenH264FrameType, RateControlMode,
+    UsageType,
+};
+use openh264::formats::YUVSlices;
+use openh264::OpenH264API;
+use tracing::{debug, info, instrument, trace};
+
+use crate::error::EncoderError;
+use crate::{
+    nv12_to_i420, EncodedVideoPacket, EncoderResult, EncoderTune, FrameType, H264Profile,
+    PixelFormat, RateControl, VideoCodec, VideoEncoder, VideoEncoderConfig, VideoEncoderParams,
+};
+
+/// NAL unit types of the parameter sets.
+const NAL_TYPE_SPS: u8 = 7;
+const NAL_TYPE_PPS: u8 = 8;
+
+/// Type of the NAL unit starting after the Annex B start code in `nal`.
+fn nal_type(nal: &[u8]) -> Option<u8> {
+    let start = if nal.starts_with(&[0, 0, 0, 1]) {
+        4
+    } else if nal.starts_with(&[0, 0, 1]) {
+        3
+    } else {
+        return None;
+    };
+    nal.get(start).map(|header| header & 0x1F)
+}
+
+/// OpenH264 software encoder wrapper.
+pub struct OpenH264Encoder {
+    encoder: Encoder,
+    config: VideoEncoderConfig,
+    frame_count: u64,
+    /// Frames encoded since the last IDR frame.
+    since_keyframe: u32,
+    /// SPS/PPS in Annex B format, refreshed from every IDR frame.
+    headers: Bytes,
+    /// I420 copy of the current frame when the input is NV12.
+    i420: Vec<u8>,
+}
+
+impl OpenH264Encoder {
+    /// Create a new OpenH264 encoder.
+    #[instrument(name = "openh264_new", skip_all)]
+    pub fn new(config: VideoEncoderConfig) -> EncoderResult<Self> {
+        let encoder = Self::open(&config)?;
+        let headers = Self::probe_headers(&config)?;
+
+        Ok(Self {
+            encoder,
+            config,
+            frame_count: 0,
+            since_keyframe: 0,
+            headers,
+            i420: Vec::new(),
+        })
+    }
+
+    /// Open an OpenH264 encoder for `config`.
+    fn open(config: &VideoEncoderConfig) -> EncoderResult<Encoder> {
+        debug!(
+            width = config.width,
+            height = config.height,
+            fps = config.fps,
+            bitrate_kbps = config.bitrate_kbps,
+            rate_control = ?config.rate_control,
+            preset = ?config.preset,
+            threads = config.threads,
+            "Initializing OpenH264 encoder"
+        );
+
+        if config.profile != H264Profile::Baseline {
+            return Err(EncoderError::NotSupported(format!(
+                "OpenH264 only supports the Baseline profile, not {:?}",
+                config.profile
+            )));
+        }
+        if config.bframes > 0 {
+            return Err(EncoderError::NotSupported(
+                "OpenH264 does not support B-frames".into(),
+            ));
+        }
+        // Without a QP range the quantizer cannot be pinned
+        if matches!(config.rate_control, RateControl::Cqp { .. }) {
+            return Err(EncoderError::NotSupported(
+                "OpenH264 does not support CQP".into(),
+            ));
+        }
+        if let Some(level_idc) = config.level_idc {
+            // Validation has checked the stream fits the requested level, so
+            // the lowest level OpenH264 finds for it is no higher
+            debug!(level_idc, "OpenH264 picks its own level");
+        }
+
+        let usage = match config.tune {
+            EncoderTune::StillImage => UsageType::ScreenContentRealTime,
+            _ => UsageType::CameraVideoRealTime,
+        };
+
+        let rate_control_mode = match config.rate_control {
+            RateControl::Cbr { .. } | RateControl::Vbr { .. } => RateControlMode::Bitrate,
+            // Quality first, around the target bitrate
+            RateControl::Crf { .. } => RateControlMode::Quality,
+            RateControl::Cqp { .. } => unreachable!("rejected above"),
+        };
+        let encoder_config = EncoderConfig::new()
+            .usage_type(usage)
+            .max_frame_rate(config.fps as f32)
+            .set_multiple_thread_idc(config.threads as u16)
+            // Dropping frames would leave gaps the muxer cannot account for
+            .enable_skip_frame(false)
+            .rate_control_mode(rate_control_mode)
+            .set_bitrate_bps(config.bitrate_kbps.saturating_mul(1000));
+
+        Encoder::with_api_config(OpenH264API::from_source(), encoder_config)
+            .map_err(|e| EncoderError::Initialization(format!("OpenH264 setup failed: {}", e)))
+    }
+
+    /// Get the SPS/PPS for `config` before the first frame.
+    ///
+    /// OpenH264 writes its parameter sets in front of each IDR frame rather
+    /// than handing them out up front, so encode one black frame on a
+    /// throwaway encoder with the same settings.
+    fn probe_headers(config: &VideoEncoderConfig) -> EncoderResult<Bytes> {
+        let mut encoder = Self::open(config)?;
+
+        let (width, height) = (config.width as usize, config.height as usize);
+        let mut black = vec![16u8; width * height];
+        black.resize(
+            PixelFormat::I420.frame_size(config.width, config.height),
+            128,
+        );
+
+        let yuv = Self::slices(&black, width, height);
+        let bitstream = encoder
+            .encode(&yuv)
+            .map_err(|e| EncoderError::Initialization(format!("OpenH264 probe failed: {}", e)))?;
+        let headers = parameter_sets(&bitstream);
+
+        if headers.is_empty() {
+            return Err(EncoderError::Initialization(
+                "OpenH264 produced no SPS/PPS".into(),
+            ));
+        }
+        debug!(header_size = headers.len(), "OpenH264 encoder initialized");
+        Ok(headers)
+    }
+
+    /// Borrow an I420 frame as OpenH264 input planes.
+    fn slices(frame: &[u8], width: usize, height: usize) -> YUVSlices<'_> {
+        let y_size = width * height;
+        let chroma_size = y_size / 4;
+        let (y, chroma) = frame.split_at(y_size);
+        let (u, v) = chroma.split_at(chroma_size);
+        YUVSlices::new(
+            (y, &u[..chroma_size], &v[..chroma_size]),
+            (width, height),
+            (width, width / 2, width / 2),
+        )
+    }
+}
+
+/// Collect the SPS and PPS NAL units of an encoded frame.
+fn parameter_sets(bitstream: &EncodedBitStream<'_>) -> Bytes {
+    let mut headers = BytesMut::new();
+    for layer in (0..bitstream.num_layers()).filter_map(|i| bitstream.layer(i)) {
+        for nal in (0..layer.nal_count()).filter_map(|i| layer.nal_unit(i)) {
+            if matches!(nal_type(nal), Some(NAL_TYPE_SPS | NAL_TYPE_PPS)) {
+                headers.put_slice(nal);
+            }
+        }
+    }
+    headers.freeze()
+}
+
+impl VideoEncoder for OpenH264Encoder {
+    #[instrument(name = "openh264_encode", skip(self, frame))]
+    fn encode(&mut self, frame: &[u8], pts_100ns: u64) -> EncoderResult<Vec<EncodedVideoPacket>> {
+        let format = self.config.input_format;
+        let expected_size = format.frame_size(self.config.width, self.config.height);
+        if frame.len() != expected_size {
+            return Err(EncoderError::InvalidInput(format!(
+                "Expected {} bytes ({}x{} {:?}), got {}",
+                expected_size,
+                self.config.width,
+                self.config.height,
+                format,
+                frame.len()
+            )));
+        }
+
+        trace!(frame = self.frame_count, pts = pts_100ns, "Encoding frame");
+
+        // OpenH264 only takes planar input
+        if format == PixelFormat::Nv12 {
+            nv12_to_i420(frame, self.config.width, self.config.height, &mut self.i420);
+        }
+        let planar = match format {
+            PixelFormat::I420 => frame,
+            PixelFormat::Nv12 => &self.i420,
+        };
+
+        let yuv = Self::slices(
+            planar,
+            self.config.width as usize,
+            self.config.height as usize,
+        );
+        // OpenH264 only starts with an IDR frame unless told otherwise
+        if self.since_keyframe >= self.config.keyframe_interval_frames() {
+            self.encoder.force_intra_frame();
+        }
+        let bitstream = self
+            .encoder
+            .encode(&yuv)
+            .map_err(|e| EncoderError::Encoding(format!("OpenH264 encode failed: {}", e)))?;
+        self.frame_count += 1;
+
+        let is_keyframe = bitstream.frame_type() == OpenH264FrameType::IDR;
+        let frame_type = match bitstream.frame_type() {
+            OpenH264FrameType::IDR | OpenH264FrameType::I => FrameType::I,
+            OpenH264FrameType::P | OpenH264FrameType::IPMixed => FrameType::P,
+            OpenH264FrameType::Skip | OpenH264FrameType::Invalid => return Ok(Vec::new()),
+        };
+        if is_keyframe {
+            self.since_keyframe = 0;
+            let headers = parameter_sets(&bitstream);
+            if !headers.is_empty() {
+                self.headers = headers;
+            }
+        }
+        self.since_keyframe += 1;
+        let data = Bytes::from(bitstream.to_vec());
+
+        // No reordering: frames come out in presentation order
+        Ok(vec![EncodedVideoPacket {
+            data,
+            pts_100ns,
+            dts_100ns: pts_100ns as i64,
+            is_keyframe,
+            frame_type,
+        }])
+    }
+
+    fn flush(&mut self) -> EncoderResult<Vec<EncodedVideoPacket>> {
+        // Nothing is ever held back
+        debug!("Flushing OpenH264 encoder");
+        Ok(Vec::new())
+    }
+
+    fn reconfigure(&mut self, params: &VideoEncoderParams) -> EncoderResult<()> {
+        let mut config = self.config.clone();
+        if !config.apply(params) {
+            return Ok(());
+        }
+
+        info!(
+            bitrate_kbps = config.bitrate_kbps,
+            rate_control = ?config.rate_control,
+            keyframe_interval_secs = config.keyframe_interval_secs,
+            "Reconfiguring OpenH264 encoder"
+        );
+
+        // The safe bindings have no runtime option setters, so start a new
+        // encoder, which begins with an IDR. The old one keeps running if
+        // the new settings are rejected.
+        self.encoder = Self::open(&config)?;
+        self.config = config;
+        Ok(())
+    }
+
+    fn request_keyframe(&mut self) {
+        debug!("Keyframe requested");
+        self.encoder.force_intra_frame();
+    }
+
+    fn is_hardware_accelerated(&self) -> bool {
+        false
+    }
+
+    fn name(&self) -> &'static str {
+        "OpenH264"
+    }
+
+    fn codec(&self) -> VideoCodec {
+        VideoCodec::H264
+    }
+
+    fn get_headers(&self) -> Option<Bytes> {
+        Some(self.headers.clone())
+    }
+}
+
+impl Drop for OpenH264Encoder {
+    fn drop(&mut self) {
+        debug!("Closing OpenH264 encoder");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nal_type_after_start_code() {
+        assert_eq!(nal_type(&[0, 0, 0, 1, 0x67, 0x42]), Some(NAL_TYPE_SPS));
+        assert_eq!(nal_type(&[0, 0, 1, 0x68, 0xCE]), Some(NAL_TYPE_PPS));
+        assert_eq!(nal_type(&[0x65, 0x88]), None);
+    }
+
+    #[test]
+    fn test_rejects_main_profile() {
+        let config = VideoEncoderConfig {
+            width: 320,
+            height: 240,
+            profile: H264Profile::Main,
+            ..Default::default()
+        };
+        assert!(matches!(
+            OpenH264Encoder::new(config),
+            Err(EncoderError::NotSupported(_))
+        ));
+    }
+    #[test]
+    fn test_rejects_cqp() {
+        let config = VideoEncoderConfig {
+            width: 320,
+            height: 240,
+            profile: H264Profile::Baseline,
+            rate_control: RateControl::Cqp { qp: 23 },
+            ..Default::default()
+        };
+        assert!(matches!(
+            OpenH264Encoder::new(config),
+            Err(EncoderError::NotSupported(_))
+        ));
+    }
+
+    #[test]
+    fn test_keyframes_at_interval() {
+        let config = VideoEncoderConfig {
+            width: 320,
+            height: 240,
+            fps: 5,
+            keyframe_interval_secs: 1,
+            profile: H264Profile::Baseline,
+            input_format: PixelFormat::I420,
+            ..Default::default()
+        };
+        let frame = vec![128u8; PixelFormat::I420.frame_size(320, 240)];
+        let mut encoder = OpenH264Encoder::new(config).unwrap();
+
+        let keyframes: Vec<u64> = (0..12u64)
+            .filter(|&i| {
+                let packets = encoder.encode(&frame, i * 2_000_000).unwrap();
+                packets[0].is_keyframe
+            })
+            .collect();
+        assert_eq!(keyframes, [0, 5, 10]);
+    }
+}
@@ -0,0 +1,345 @@
+//! Opus audio encoder.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use tracing::{debug, instrument, trace};
+
+use crate::error::EncoderError;
+use crate::{
+    AudioCodec, AudioCodecConfig, AudioEncoder, AudioEncoderConfig, EncodedAudioPacket,
+    EncoderResult, OpusApplication, OpusFrameDuration,
+};
+
+/// Sample rates libopus accepts.
+const SUPPORTED_SAMPLE_RATES: [u32; 5] = [8000, 12000, 16000, 24000, 48000];
+
+/// Largest packet libopus produces for one frame (RFC 6716 recommendation).
+const MAX_PACKET_BYTES: usize = 4000;
+
+/// Rate that Ogg/Matroska pre-skip is expressed in, whatever the input rate.
+const PRE_SKIP_RATE: u64 = 48000;
+
+/// Shortest frame accepted. `encode` returns at most one packet per call, so
+/// frames shorter than the mixer's 10 ms chunks would pile up unencoded.
+const MIN_FRAME_DURATION: OpusFrameDuration = OpusFrameDuration::Ms10;
+
+/// Opus audio encoder using libopus.
+pub struct OpusEncoder {
+    encoder: opus::Encoder,
+    config: AudioEncoderConfig,
+    /// Interleaved samples per frame across all channels.
+    samples_per_frame: usize,
+    sample_buffer: Vec<f32>,
+    /// PTS of the first sample passed to `encode`.
+    start_pts_100ns: Option<u64>,
+    /// Samples per channel encoded so far.
+    samples_encoded: u64,
+    /// Encoder lookahead in input samples.
+    lookahead: u32,
+    /// Encoder lookahead in 48 kHz samples.
+    pre_skip: u16,
+    output_buffer: Vec<u8>,
+}
+
+impl OpusEncoder {
+    /// Create a new Opus encoder.
+    #[instrument(name = "opus_new", skip_all)]
+    pub fn new(config: AudioEncoderConfig) -> EncoderResult<Self> {
+        let opus_config = config.opus;
+        debug!(
+            sample_rate = config.sample_rate,
+            channels = config.channels,
+            bitrate_kbps = config.bitrate_kbps,
+            frame_us = opus_config.frame_duration.as_micros(),
+            fec = opus_config.fec,
+            "Initializing Opus encoder"
+        );
+
+        if !SUPPORTED_SAMPLE_RATES.contains(&config.sample_rate) {
+            return Err(EncoderError::NotSupported(format!(
+                "Opus does not support {} Hz (use one of {:?})",
+                config.sample_rate, SUPPORTED_SAMPLE_RATES
+            )));
+        }
+
+        let channels = match config.channels {
+            1 => opus::Channels::Mono,
+            2 => opus::Channels::Stereo,
+            n => {
+                return Err(EncoderError::NotSupported(format!(
+                    "Opus supports mono or stereo, not {} channels",
+                    n
+                )))
+            }
+        };
+
+        if opus_config.frame_duration.as_micros() < MIN_FRAME_DURATION.as_micros() {
+            return Err(EncoderError::NotSupported(format!(
+                "Opus frames shorter than the {} us input chunks are not supported, got {} us",
+                MIN_FRAME_DURATION.as_micros(),
+                opus_config.frame_duration.as_micros()
+            )));
+        }
+
+        if opus_config.expected_packet_loss_percent > 100 {
+            return Err(EncoderError::NotSupported(format!(
+                "Expected packet loss {}% is out of range",
+                opus_config.expected_packet_loss_percent
+            )));
+        }
+
+        let application = match opus_config.application {
+            OpusApplication::Voip => opus::Application::Voip,
+            OpusApplication::Audio => opus::Application::Audio,
+            OpusApplication::RestrictedLowDelay => opus::Application::LowDelay,
+        };
+
+        let init_error =
+            |e: opus::Error| EncoderError::Initialization(format!("libopus init failed: {}", e));
+
+        let mut encoder =
+            opus::Encoder::new(config.sample_rate, channels, application).map_err(init_error)?;
+        encoder
+            .set_bitrate(opus::Bitrate::Bits(config.bitrate_kbps as i32 * 1000))
+            .map_err(init_error)?;
+        encoder
+            .set_inband_fec(opus_config.fec)
+            .map_err(init_error)?;
+        encoder
+            .set_packet_loss_perc(opus_config.expected_packet_loss_percent as i32)
+            .map_err(init_error)?;
+
+        // Lookahead is reported at the input rate; pre-skip is always 48 kHz
+        let lookahead = encoder.get_lookahead().map_err(init_error)?;
+        let pre_skip = (lookahead as u64 * PRE_SKIP_RATE / config.sample_rate as u64) as u16;
+
+        let samples_per_frame =
+            opus_config.frame_duration.samples(config.sample_rate) * config.channels as usize;
+
+        debug!(lookahead, pre_skip, "Opus encoder initialized");
+
+        Ok(Self {
+            encoder,
+            config,
+            samples_per_frame,
+            sample_buffer: Vec::with_capacity(samples_per_frame * 2),
+            start_pts_100ns: None,
+            samples_encoded: 0,
+            lookahead: lookahead as u32,
+            pre_skip,
+            output_buffer: vec![0u8; MAX_PACKET_BYTES],
+        })
+    }
+
+    /// Samples at the start of the stream a decoder must discard, at 48 kHz.
+    pub fn pre_skip(&self) -> u16 {
+        self.pre_skip
+    }
+
+    /// The `OpusHead` identification header (RFC 7845 section 5.1).
+    ///
+    /// This is the codec-private data for Matroska/WebM and the `dOps` box
+    /// payload source for MP4, and the first Ogg page of a recording.
+    pub fn codec_private(&self) -> Bytes {
+        let mut head = BytesMut::with_capacity(19);
+        head.put_slice(b"OpusHead");
+        head.put_u8(1); // version
+        head.put_u8(self.config.channels as u8);
+        head.put_u16_le(self.pre_skip);
+        head.put_u32_le(self.config.sample_rate);
+        head.put_i16_le(0); // output gain
+        head.put_u8(0); // channel mapping family: mono/stereo
+        head.freeze()
+    }
+
+    /// Encode one full frame of interleaved samples.
+    fn encode_frame(&mut self, frame: &[f32]) -> EncoderResult<EncodedAudioPacket> {
+        let size = self
+            .encoder
+            .encode_float(frame, &mut self.output_buffer)
+            .map_err(|e| EncoderError::Encoding(format!("Opus encode failed: {}", e)))?;
+
+        let pts_100ns = self.start_pts_100ns.unwrap_or(0)
+            + self.samples_encoded * 10_000_000 / self.config.sample_rate as u64;
+        self.samples_encoded += (frame.len() / self.config.channels as usize) as u64;
+
+        Ok(EncodedAudioPacket {
+            data: Bytes::copy_from_slice(&self.output_buffer[..size]),
+            pts_100ns,
+        })
+    }
+}
+
+impl AudioEncoder for OpusEncoder {
+    #[instrument(name = "opus_encode", skip(self, samples))]
+    fn encode(
+        &mut self,
+        samples: &[f32],
+        pts_100ns: u64,
+    ) -> EncoderResult<Option<EncodedAudioPacket>> {
+        self.start_pts_100ns.get_or_insert(pts_100ns);
+        self.sample_buffer.extend_from_slice(samples);
+
+        if self.sample_buffer.len() < self.samples_per_frame {
+            return Ok(None);
+        }
+
+        trace!(
+            buffer_size = self.sample_buffer.len(),
+            samples_encoded = self.samples_encoded,
+            "Encoding Opus frame"
+        );
+
+        let frame: Vec<f32> = self.sample_buffer.drain(..self.samples_per_frame).collect();
+        self.encode_frame(&frame).map(Some)
+    }
+
+    fn flush(&mut self) -> EncoderResult<Vec<EncodedAudioPacket>> {
+        debug!("Flushing Opus encoder");
+
+        let mut packets = Vec::new();
+
+        while !self.sample_buffer.is_empty() {
+            // Pad the last frame with silence
+            if self.sample_buffer.len() < self.samples_per_frame {
+                self.sample_buffer.resize(self.samples_per_frame, 0.0);
+            }
+            let frame: Vec<f32> = self.sample_buffer.drain(..self.samples_per_frame).collect();
+            packets.push(self.encode_frame(&frame)?);
+        }
+
+        Ok(packets)
+    }
+
+    fn codec_config(&self) -> AudioCodecConfig {
+        AudioCodecConfig {
+            codec: AudioCodec::Opus,
+            sample_rate: self.config.sample_rate,
+            channels: self.config.channels,
+            frame_samples: self
+                .config
+                .opus
+                .frame_duration
+                .samples(self.config.sample_rate) as u32,
+            priming_samples: self.lookahead,
+            data: self.codec_private(),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "Opus"
+    }
+}
+
+impl Drop for OpusEncoder {
+    fn drop(&mut self) {
+        debug!("Closing Opus encoder");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::OpusConfig;
+
+    fn config(frame_duration: OpusFrameDuration) -> AudioEncoderConfig {
+        AudioEncoderConfig {
+            codec: crate::AudioCodec::Opus,
+            bitrate_kbps: 96,
+            opus: OpusConfig {
+                frame_duration,
+                fec: true,
+                expected_packet_loss_percent: 10,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// 10 ms chunks of a 440 Hz stereo tone at 48 kHz.
+    fn tone_chunks(count: usize) -> Vec<Vec<f32>> {
+        (0..count)
+            .map(|chunk| {
+                (0..480)
+                    .flat_map(|i| {
+                        let t = (chunk * 480 + i) as f32 / 48000.0;
+                        let s = (t * 440.0 * std::f32::consts::TAU).sin() * 0.5;
+                        [s, s]
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_one_packet_per_frame_with_sample_timestamps() {
+        let mut encoder = OpusEncoder::new(config(OpusFrameDuration::Ms20)).unwrap();
+
+        let mut packets = Vec::new();
+        for (i, chunk) in tone_chunks(10).iter().enumerate() {
+            // Jittered input timestamps must not leak into the output
+            let pts = 1_000_000 + i as u64 * 100_000 + (i as u64 % 3) * 7_000;
+            packets.extend(encoder.encode(chunk, pts).unwrap());
+        }
+
+        let pts: Vec<u64> = packets.iter().map(|p| p.pts_100ns).collect();
+        assert_eq!(pts, [1_000_000, 1_200_000, 1_400_000, 1_600_000, 1_800_000]);
+        assert!(packets.iter().all(|p| !p.data.is_empty()));
+    }
+
+    #[test]
+    fn test_flush_pads_partial_frame() {
+        let mut encoder = OpusEncoder::new(config(OpusFrameDuration::Ms40)).unwrap();
+        for chunk in tone_chunks(3) {
+            assert!(encoder.encode(&chunk, 0).unwrap().is_none());
+        }
+
+        let packets = encoder.flush().unwrap();
+        assert_eq!(packets.len(), 1);
+    }
+
+    #[test]
+    fn test_codec_private_is_opus_head() {
+        let encoder = OpusEncoder::new(config(OpusFrameDuration::Ms20)).unwrap();
+        let head = encoder.codec_private();
+
+        assert_eq!(head.len(), 19);
+        assert_eq!(&head[..8], b"OpusHead");
+        assert_eq!(head[8], 1);
+        assert_eq!(head[9], 2);
+        assert_eq!(u16::from_le_bytes([head[10], head[11]]), encoder.pre_skip());
+        assert_eq!(
+            u32::from_le_bytes([head[12], head[13], head[14], head[15]]),
+            48000
+        );
+        assert!(encoder.pre_skip() > 0);
+    }
+
+    #[test]
+    fn test_frames_keep_up_with_input_chunks() {
+        // Shorter frames than the 10 ms chunks would fall behind the input
+        for frame_duration in [OpusFrameDuration::Ms2_5, OpusFrameDuration::Ms5] {
+            assert!(matches!(
+                OpusEncoder::new(config(frame_duration)),
+                Err(EncoderError::NotSupported(_))
+            ));
+        }
+
+        let mut encoder = OpusEncoder::new(config(OpusFrameDuration::Ms10)).unwrap();
+        for chunk in tone_chunks(50) {
+            assert!(encoder.encode(&chunk, 0).unwrap().is_some());
+            assert!(encoder.sample_buffer.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_rejects_unsupported_sample_rate() {
+        let config = AudioEncoderConfig {
+            sample_rate: 44100,
+            ..config(OpusFrameDuration::Ms20)
+        };
+        assert!(matches!(
+            OpusEncoder::new(config),
+            Err(EncoderError::NotSupported(_))
+        ));
+    }
+}
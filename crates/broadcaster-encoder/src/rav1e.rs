@@ -0,0 +1,432 @@
+//! rav1e AV1 software video encoder.
+//!
+//! Experimental: AV1 suits recordings and Enhanced RTMP, which the FLV
+//! muxer does not speak yet. rav1e reorders frames internally but emits one
+//! temporal unit per input frame, in presentation order.
+
+use std::collections::HashMap;
+
+use bytes::Bytes;
+use rav1e::prelude::{
+    ChromaSampling, Config, Context, EncoderConfig, EncoderStatus, FrameParameters,
+    FrameType as Rav1eFrameType, FrameTypeOverride, Packet, Rational, SceneDetectionSpeed,
+    SpeedSettings,
+};
+use tracing::{debug, info, instrument, trace};
+
+use crate::error::EncoderError;
+use crate::{
+    nv12_to_i420, EncodedVideoPacket, EncoderPreset, EncoderResult, FrameType, PixelFormat,
+    RateControl, VideoCodec, VideoEncoder, VideoEncoderConfig, VideoEncoderParams,
+};
+
+/// Map a preset onto rav1e's speed levels (0 slowest, 10 fastest).
+fn speed(preset: EncoderPreset) -> u8 {
+    match preset {
+        EncoderPreset::Ultrafast => 10,
+        EncoderPreset::Superfast => 9,
+        EncoderPreset::Veryfast => 8,
+        EncoderPreset::Faster => 7,
+        EncoderPreset::Fast => 6,
+        EncoderPreset::Medium => 5,
+        EncoderPreset::Slow => 4,
+        EncoderPreset::Slower => 3,
+        EncoderPreset::Veryslow => 2,
+        EncoderPreset::Placebo => 0,
+    }
+}
+
+/// rav1e AV1 encoder wrapper.
+pub struct Rav1eEncoder {
+    context: Option<Context<u8>>,
+    config: VideoEncoderConfig,
+    /// `av1C` record for the current context.
+    headers: Bytes,
+    /// Frames sent to the current context.
+    frames_in: u64,
+    /// Caller PTS of frames still inside the encoder, by rav1e frame number.
+    pending_pts: HashMap<u64, u64>,
+    /// Encoded frames waiting to be returned.
+    queued: Vec<EncodedVideoPacket>,
+    /// Force the next frame to be a key frame.
+    keyframe_pending: bool,
+    /// I420 copy of the current frame when the input is NV12.
+    i420: Vec<u8>,
+}
+
+impl Rav1eEncoder {
+    /// Create a new rav1e encoder.
+    #[instrument(name = "rav1e_new", skip_all)]
+    pub fn new(config: VideoEncoderConfig) -> EncoderResult<Self> {
+        let (context, headers) = Self::open(&config)?;
+
+        Ok(Self {
+            context: Some(context),
+            config,
+            headers,
+            frames_in: 0,
+            pending_pts: HashMap::new(),
+            queued: Vec::new(),
+            keyframe_pending: false,
+            i420: Vec::new(),
+        })
+    }
+
+    /// Open a rav1e context for `config`, returning it with its `av1C`.
+    fn open(config: &VideoEncoderConfig) -> EncoderResult<(Context<u8>, Bytes)> {
+        let speed = config.av1.speed.unwrap_or_else(|| speed(config.preset));
+        debug!(
+            width = config.width,
+            height = config.height,
+            fps = config.fps,
+            bitrate_kbps = config.bitrate_kbps,
+            rate_control = ?config.rate_control,
+            speed,
+            threads = config.threads,
+            "Initializing rav1e encoder"
+        );
+
+        if speed > 10 {
+            return Err(EncoderError::NotSupported(format!(
+                "rav1e speed must be 0-10, got {}",
+                speed
+            )));
+        }
+
+        let mut speed_settings = SpeedSettings::from_preset(speed);
+        // Keyframes only at the configured interval
+        speed_settings.scene_detection_mode = SceneDetectionSpeed::None;
+
+        let keyframe_interval = config.keyframe_interval_frames() as u64;
+        let mut encoder_config = EncoderConfig {
+            width: config.width as usize,
+            height: config.height as usize,
+            bit_depth: 8,
+            chroma_sampling: ChromaSampling::Cs420,
+            time_base: Rational::new(1, config.fps as u64),
+            min_key_frame_interval: keyframe_interval,
+            max_key_frame_interval: keyframe_interval,
+            low_latency: config.is_low_latency(),
+            speed_settings,
+            ..Default::default()
+        };
+
+        // A zero bitrate selects rav1e's constant-quantizer mode
+        match config.rate_control {
+            RateControl::Cbr { .. } | RateControl::Vbr { .. } => {
+                encoder_config.bitrate = (config.bitrate_kbps * 1000) as i32;
+                encoder_config.min_quantizer = config.av1.min_quantizer;
+            }
+            RateControl::Crf { .. } | RateControl::Cqp { .. } => {
+                encoder_config.bitrate = 0;
+                encoder_config.quantizer = config.av1.quantizer as usize;
+            }
+        }
+
+        let context: Context<u8> = Config::new()
+            .with_encoder_config(encoder_config)
+            .with_threads(config.threads as usize)
+            .new_context()
+            .map_err(|e| EncoderError::Initialization(format!("rav1e setup failed: {}", e)))?;
+
+        let headers = Bytes::from(context.container_sequence_header());
+        debug!(header_size = headers.len(), "rav1e encoder initialized");
+
+        Ok((context, headers))
+    }
+
+    /// Collect every packet the context has ready.
+    fn receive(&mut self) -> EncoderResult<()> {
+        let Some(mut context) = self.context.take() else {
+            return Ok(());
+        };
+        let result = loop {
+            match context.receive_packet() {
+                Ok(packet) => {
+                    let packet = self.output_packet(packet);
+                    self.queued.push(packet);
+                }
+                // A frame was encoded but not shown yet
+                Err(EncoderStatus::Encoded) => {}
+                Err(EncoderStatus::NeedMoreData | EncoderStatus::LimitReached) => break Ok(()),
+                Err(e) => {
+                    break Err(EncoderError::Encoding(format!(
+                        "rav1e encode failed: {:?}",
+                        e
+                    )))
+                }
+            }
+        };
+        self.context = Some(context);
+        result
+    }
+
+    /// Take the context and collect the frames it still holds.
+    fn drain_context(&mut self) -> EncoderResult<()> {
+        let Some(context) = self.context.as_mut() else {
+            return Ok(());
+        };
+        context.flush();
+        self.receive()?;
+        self.context = None;
+        self.pending_pts.clear();
+        Ok(())
+    }
+
+    /// Build a packet from encoder output.
+    fn output_packet(&mut self, packet: Packet<u8>) -> EncodedVideoPacket {
+        let pts_100ns = self
+            .pending_pts
+            .remove(&packet.input_frameno)
+            .unwrap_or_else(|| packet.input_frameno * 10_000_000 / self.config.fps as u64);
+
+        let frame_type = match packet.frame_type {
+            Rav1eFrameType::KEY | Rav1eFrameType::INTRA_ONLY => FrameType::I,
+            Rav1eFrameType::INTER | Rav1eFrameType::SWITCH => FrameType::P,
+        };
+
+        // Each temporal unit shows one frame, in presentation order
+        EncodedVideoPacket {
+            data: Bytes::from(packet.data),
+            pts_100ns,
+            dts_100ns: pts_100ns as i64,
+            is_keyframe: packet.frame_type == Rav1eFrameType::KEY,
+            frame_type,
+        }
+    }
+}
+
+impl VideoEncoder for Rav1eEncoder {
+    #[instrument(name = "rav1e_encode", skip(self, frame))]
+    fn encode(&mut self, frame: &[u8], pts_100ns: u64) -> EncoderResult<Vec<EncodedVideoPacket>> {
+        let format = self.config.input_format;
+        let expected_size = format.frame_size(self.config.width, self.config.height);
+        if frame.len() != expected_size {
+            return Err(EncoderError::InvalidInput(format!(
+                "Expected {} bytes ({}x{} {:?}), got {}",
+                expected_size,
+                self.config.width,
+                self.config.height,
+                format,
+                frame.len()
+            )));
+        }
+
+        trace!(frame = self.frames_in, pts = pts_100ns, "Encoding frame");
+
+        // rav1e takes separate U and V planes
+        if format == PixelFormat::Nv12 {
+            nv12_to_i420(frame, self.config.width, self.config.height, &mut self.i420);
+        }
+        let planar = match format {
+            PixelFormat::I420 => frame,
+            PixelFormat::Nv12 => &self.i420,
+        };
+
+        let context = self
+            .context
+            .as_mut()
+            .ok_or_else(|| EncoderError::Encoding("Encoder has been flushed".to_string()))?;
+
+        let width = self.config.width as usize;
+        let y_size = width * self.config.height as usize;
+        let chroma_size = y_size / 4;
+        let mut input = context.new_frame();
+        input.planes[0].copy_from_raw_u8(&planar[..y_size], width, 1);
+        input.planes[1].copy_from_raw_u8(&planar[y_size..y_size + chroma_size], width / 2, 1);
+        input.planes[2].copy_from_raw_u8(&planar[y_size + chroma_size..], width / 2, 1);
+
+        let params = FrameParameters {
+            frame_type_override: if self.keyframe_pending {
+                FrameTypeOverride::Key
+            } else {
+                FrameTypeOverride::No
+            },
+            ..Default::default()
+        };
+        context
+            .send_frame((input, params))
+            .map_err(|e| EncoderError::Encoding(format!("rav1e send failed: {:?}", e)))?;
+
+        self.keyframe_pending = false;
+        self.pending_pts.insert(self.frames_in, pts_100ns);
+        self.frames_in += 1;
+
+        self.receive()?;
+        Ok(std::mem::take(&mut self.queued))
+    }
+
+    fn flush(&mut self) -> EncoderResult<Vec<EncodedVideoPacket>> {
+        debug!("Flushing rav1e encoder");
+
+        self.drain_context()?;
+        let packets = std::mem::take(&mut self.queued);
+
+        debug!(delayed_frames = packets.len(), "rav1e encoder flushed");
+        Ok(packets)
+    }
+
+    fn reconfigure(&mut self, params: &VideoEncoderParams) -> EncoderResult<()> {
+        let mut config = self.config.clone();
+        if !config.apply(params) {
+            return Ok(());
+        }
+
+        info!(
+            bitrate_kbps = config.bitrate_kbps,
+            rate_control = ?config.rate_control,
+            keyframe_interval_secs = config.keyframe_interval_secs,
+            "Reconfiguring rav1e encoder"
+        );
+
+        // rav1e cannot change settings in place. Open the new context first
+        // so the old one keeps running if the settings are rejected, then
+        // drain the old one; its frames go out before the new context's.
+        let (context, headers) = Self::open(&config)?;
+        self.drain_context()?;
+
+        self.context = Some(context);
+        self.headers = headers;
+        self.config = config;
+        self.frames_in = 0;
+        Ok(())
+    }
+
+    fn request_keyframe(&mut self) {
+        debug!("Keyframe requested");
+        self.keyframe_pending = true;
+    }
+
+    fn is_hardware_accelerated(&self) -> bool {
+        false
+    }
+
+    fn name(&self) -> &'static str {
+        "rav1e"
+    }
+
+    fn codec(&self) -> VideoCodec {
+        VideoCodec::Av1
+    }
+
+    fn get_headers(&self) -> Option<Bytes> {
+        Some(self.headers.clone())
+    }
+}
+
+impl Drop for Rav1eEncoder {
+    fn drop(&mut self) {
+        debug!("Closing rav1e encoder");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: u32 = 64;
+    const HEIGHT: u32 = 48;
+    const FPS: u32 = 30;
+
+    fn encoder(input_format: PixelFormat) -> Rav1eEncoder {
+        Rav1eEncoder::new(VideoEncoderConfig {
+            width: WIDTH,
+            height: HEIGHT,
+            fps: FPS,
+            bitrate_kbps: 200,
+            codec: VideoCodec::Av1,
+            preset: EncoderPreset::Ultrafast,
+            input_format,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    /// I420 frame with a moving gradient and distinct U and V planes.
+    fn i420_frame(index: u32) -> Vec<u8> {
+        let y_size = (WIDTH * HEIGHT) as usize;
+        let mut frame: Vec<u8> = (0..y_size).map(|i| (i as u32 + index * 5) as u8).collect();
+        frame.extend((0..y_size / 4).map(|i| (i % 64) as u8 + 64));
+        frame.extend((0..y_size / 4).map(|i| 192 - (i % 64) as u8));
+        frame
+    }
+
+    /// The same frame as NV12.
+    fn nv12_frame(index: u32) -> Vec<u8> {
+        let i420 = i420_frame(index);
+        let y_size = (WIDTH * HEIGHT) as usize;
+        let (u, v) = i420[y_size..].split_at(y_size / 4);
+        let mut frame = i420[..y_size].to_vec();
+        frame.extend(u.iter().zip(v).flat_map(|(&u, &v)| [u, v]));
+        frame
+    }
+
+    fn pts(index: u32) -> u64 {
+        index as u64 * 10_000_000 / FPS as u64
+    }
+
+    fn encode_all(
+        encoder: &mut Rav1eEncoder,
+        frames: impl Iterator<Item = (u32, Vec<u8>)>,
+    ) -> Vec<EncodedVideoPacket> {
+        let mut packets = Vec::new();
+        for (i, frame) in frames {
+            packets.extend(encoder.encode(&frame, pts(i)).unwrap());
+        }
+        packets.extend(encoder.flush().unwrap());
+        packets
+    }
+
+    #[test]
+    fn test_headers_are_av1c_record() {
+        let headers = encoder(PixelFormat::Nv12).get_headers().unwrap();
+        // marker bit set, version 1
+        assert_eq!(headers[0], 0x81);
+        assert!(headers.len() > 4, "av1C carries the sequence header OBU");
+    }
+
+    #[test]
+    fn test_every_frame_comes_out_in_order() {
+        let mut encoder = encoder(PixelFormat::Nv12);
+        let packets = encode_all(&mut encoder, (0..12).map(|i| (i, nv12_frame(i))));
+
+        let output_pts: Vec<u64> = packets.iter().map(|p| p.pts_100ns).collect();
+        assert_eq!(output_pts, (0..12).map(pts).collect::<Vec<_>>());
+        assert!(packets[0].is_keyframe);
+        assert!(packets.iter().all(|p| p.dts_100ns == p.pts_100ns as i64));
+    }
+
+    #[test]
+    fn test_nv12_matches_i420() {
+        let mut nv12 = encoder(PixelFormat::Nv12);
+        let mut i420 = encoder(PixelFormat::I420);
+
+        let from_nv12 = encode_all(&mut nv12, (0..4).map(|i| (i, nv12_frame(i))));
+        let from_i420 = encode_all(&mut i420, (0..4).map(|i| (i, i420_frame(i))));
+
+        let data = |packets: &[EncodedVideoPacket]| -> Vec<Bytes> {
+            packets.iter().map(|p| p.data.clone()).collect()
+        };
+        assert_eq!(data(&from_nv12), data(&from_i420));
+    }
+
+    #[test]
+    fn test_requested_keyframe() {
+        let mut encoder = encoder(PixelFormat::Nv12);
+        let mut packets = Vec::new();
+        for i in 0..8 {
+            if i == 5 {
+                encoder.request_keyframe();
+            }
+            packets.extend(encoder.encode(&nv12_frame(i), pts(i)).unwrap());
+        }
+        packets.extend(encoder.flush().unwrap());
+
+        let keyframes: Vec<u64> = packets
+            .iter()
+            .filter(|p| p.is_keyframe)
+            .map(|p| p.pts_100ns)
+            .collect();
+        assert_eq!(keyframes, [pts(0), pts(5)]);
+    }
+}
@@ -0,0 +1,697 @@
+//! Video encoder backends and automatic selection.
+//!
+//! Each backend reports static capabilities. Selection filters the backends
+//! compiled in by those capabilities, then encodes a short run of synthetic
+//! frames to find one that sustains the target frame rate, stepping software
+//! encoders down to faster presets as needed.
+
+use std::time::{Duration, Instant};
+
+use tracing::{debug, info, warn};
+
+use crate::error::EncoderError;
+use crate::{
+    EncoderPreset, EncoderResult, H264Profile, PixelFormat, RateControl, VideoCodec, VideoEncoder,
+    VideoEncoderConfig,
+};
+
+/// Frames encoded per benchmark run.
+pub const BENCHMARK_FRAMES: u32 = 30;
+
+/// Throughput a backend must reach relative to the target frame rate, to
+/// leave room for capture, audio and scheduling jitter.
+const BENCHMARK_HEADROOM: f32 = 1.2;
+
+/// Distinct synthetic frames cycled through during a benchmark.
+const SYNTHETIC_FRAMES: u32 = 8;
+
+/// Video encoder backends, in order of preference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum VideoBackend {
+    /// NVIDIA NVENC hardware encoder.
+    Nvenc,
+    /// x264 software encoder.
+    X264,
+    /// Cisco OpenH264 software encoder.
+    OpenH264,
+    /// rav1e AV1 software encoder.
+    Rav1e,
+}
+
+impl VideoBackend {
+    /// All backends, most preferred first.
+    pub const ALL: [VideoBackend; 4] = [Self::Nvenc, Self::X264, Self::OpenH264, Self::Rav1e];
+
+    /// Name used in settings and diagnostics.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Nvenc => "nvenc",
+            Self::X264 => "x264",
+            Self::OpenH264 => "openh264",
+            Self::Rav1e => "rav1e",
+        }
+    }
+
+    /// Look up a backend by its [`name`](Self::name).
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|backend| backend.name().eq_ignore_ascii_case(name))
+    }
+
+    /// Whether this build includes the backend.
+    pub fn is_compiled(self) -> bool {
+        match self {
+            Self::Nvenc => cfg!(windows),
+            Self::X264 => cfg!(feature = "x264"),
+            Self::OpenH264 => cfg!(feature = "openh264"),
+            Self::Rav1e => cfg!(feature = "rav1e"),
+        }
+    }
+
+    /// What the backend can encode.
+    pub fn capabilities(self) -> EncoderCapabilities {
+        const ALL_PROFILES: &[H264Profile] =
+            &[H264Profile::Baseline, H264Profile::Main, H264Profile::High];
+        const PLANAR_AND_NV12: &[PixelFormat] = &[PixelFormat::Nv12, PixelFormat::I420];
+
+        match self {
+            Self::Nvenc => EncoderCapabilities {
+                backend: self,
+                codec: VideoCodec::H264,
+                hardware: true,
+                profiles: ALL_PROFILES,
+                max_width: 4096,
+                max_height: 4096,
+                bframes: true,
+                presets: false,
+                input_formats: &[PixelFormat::Nv12],
+                regions_of_interest: false,
+            },
+            Self::X264 => EncoderCapabilities {
+                backend: self,
+                codec: VideoCodec::H264,
+                hardware: false,
+                profiles: ALL_PROFILES,
+                // Level 6.2 frame size limit
+                max_width: 8192,
+                max_height: 4320,
+                bframes: true,
+                presets: true,
+                input_formats: PLANAR_AND_NV12,
+                // Per-macroblock quantizer offsets
+                regions_of_interest: true,
+            },
+            Self::OpenH264 => EncoderCapabilities {
+                backend: self,
+                codec: VideoCodec::H264,
+                hardware: false,
+                // Constrained Baseline only
+                profiles: &[H264Profile::Baseline],
+                // Largest frame the bindings accept
+                max_width: 3840,
+                max_height: 2160,
+                bframes: false,
+                // The bindings cannot set the encoder complexity
+                presets: false,
+                input_formats: PLANAR_AND_NV12,
+                regions_of_interest: false,
+            },
+            Self::Rav1e => EncoderCapabilities {
+                backend: self,
+                codec: VideoCodec::Av1,
+                hardware: false,
+                profiles: &[],
+                // Level 6.3 frame size limit
+                max_width: 16384,
+                max_height: 8704,
+                // Reordering stays inside the encoder
+                bframes: false,
+                presets: true,
+                input_formats: PLANAR_AND_NV12,
+                regions_of_interest: false,
+            },
+        }
+    }
+
+    /// Open an encoder on this backend.
+    pub fn create(self, config: VideoEncoderConfig) -> EncoderResult<Box<dyn VideoEncoder>> {
+        match self {
+            Self::Nvenc => create_nvenc(config),
+            Self::X264 => create_x264(config),
+            Self::OpenH264 => create_openh264(config),
+            Self::Rav1e => create_rav1e(config),
+        }
+    }
+
+    /// Check the backend can open an encoder on this machine.
+    pub fn probe(self) -> bool {
+        if !self.is_compiled() {
+            return false;
+        }
+
+        let config = VideoEncoderConfig {
+            width: 320,
+            height: 240,
+            fps: 30,
+            // Every H.264 backend supports Baseline
+            profile: H264Profile::Baseline,
+            codec: self.capabilities().codec,
+            preset: EncoderPreset::Ultrafast,
+            ..Default::default()
+        };
+        match self.create(config) {
+            Ok(_) => true,
+            Err(e) => {
+                debug!(backend = self.name(), "Probe failed: {}", e);
+                false
+            }
+        }
+    }
+}
+
+/// Static capabilities of a video encoder backend.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncoderCapabilities {
+    /// The backend described.
+    pub backend: VideoBackend,
+
+    /// Codec produced.
+    pub codec: VideoCodec,
+
+    /// Whether encoding runs on dedicated hardware.
+    pub hardware: bool,
+
+    /// H.264 profiles the backend can produce, empty for other codecs.
+    pub profiles: &'static [H264Profile],
+
+    /// Largest frame width in pixels.
+    pub max_width: u32,
+
+    /// Largest frame height in pixels.
+    pub max_height: u32,
+
+    /// Whether B-frames are supported.
+    pub bframes: bool,
+
+    /// Whether the speed preset changes encoder cost.
+    pub presets: bool,
+
+    /// Raw frame layouts accepted as input.
+    pub input_formats: &'static [PixelFormat],
+
+    /// Whether regions of interest are encoded at their own quality.
+    pub regions_of_interest: bool,
+}
+
+impl EncoderCapabilities {
+    /// Check `config` is within the backend's capabilities.
+    pub fn supports(&self, config: &VideoEncoderConfig) -> Result<(), String> {
+        if config.codec != self.codec {
+            return Err(format!("produces {:?}, not {:?}", self.codec, config.codec));
+        }
+        if config.width > self.max_width || config.height > self.max_height {
+            return Err(format!(
+                "{}x{} exceeds the {}x{} maximum",
+                config.width, config.height, self.max_width, self.max_height
+            ));
+        }
+        if self.codec == VideoCodec::H264 && !self.profiles.contains(&config.profile) {
+            return Err(format!(
+                "{:?} profile not supported (supports {:?})",
+                config.profile, self.profiles
+            ));
+        }
+        if config.bframes > 0 && !self.bframes {
+            return Err("B-frames not supported".into());
+        }
+        if !self.input_formats.contains(&config.input_format) {
+            return Err(format!("{:?} input not supported", config.input_format));
+        }
+        if !config.regions_of_interest.is_empty() {
+            if !self.regions_of_interest {
+                return Err("Regions of interest not supported".into());
+            }
+            // A constant quantizer leaves no room for per-block offsets
+            if matches!(config.rate_control, RateControl::Cqp { .. }) {
+                return Err("Regions of interest not supported with CQP".into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Throughput measured by encoding synthetic frames.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BenchmarkResult {
+    /// Backend benchmarked.
+    pub backend: VideoBackend,
+
+    /// Preset benchmarked.
+    pub preset: EncoderPreset,
+
+    /// Frames encoded per second of encode time.
+    pub fps: f32,
+
+    /// Average time to encode one frame.
+    pub mean_encode_time: Duration,
+}
+
+impl BenchmarkResult {
+    /// Whether the measured throughput sustains `target_fps` with headroom.
+    pub fn sustains(&self, target_fps: u32) -> bool {
+        self.fps >= target_fps as f32 * BENCHMARK_HEADROOM
+    }
+}
+
+/// Encode [`BENCHMARK_FRAMES`] synthetic frames with `config` on `backend`.
+///
+/// The time includes flushing the encoder, so frames it still holds count
+/// against it.
+pub fn benchmark(
+    backend: VideoBackend,
+    config: &VideoEncoderConfig,
+) -> EncoderResult<BenchmarkResult> {
+    let mut encoder = backend.create(config.clone())?;
+
+    let frames: Vec<Vec<u8>> = (0..SYNTHETIC_FRAMES)
+        .map(|index| synthetic_frame(config.width, config.height, index))
+        .collect();
+    let frame_duration_100ns = 10_000_000 / config.fps.max(1) as u64;
+
+    // Frame threads and lookahead return from `encode` before the work is
+    // done, so the flush is timed too and only frames output are counted
+    let start = Instant::now();
+    let mut frames_output: u32 = 0;
+    for index in 0..BENCHMARK_FRAMES {
+        let frame = &frames[(index % SYNTHETIC_FRAMES) as usize];
+        let packets = encoder.encode(frame, index as u64 * frame_duration_100ns)?;
+        frames_output += packets.len() as u32;
+    }
+    frames_output += encoder.flush()?.len() as u32;
+    let encode_time = start.elapsed();
+
+    let result = BenchmarkResult {
+        backend,
+        preset: config.preset,
+        fps: frames_output as f32 / encode_time.as_secs_f32().max(f32::EPSILON),
+        mean_encode_time: encode_time / frames_output.max(1),
+    };
+    debug!(
+        backend = backend.name(),
+        preset = ?config.preset,
+        fps = result.fps,
+        "Encoder benchmark"
+    );
+    Ok(result)
+}
+
+/// Find the slowest preset at or below `config.preset` that sustains the
+/// target frame rate on `backend`.
+///
+/// Returns the fastest preset's result if none does.
+pub fn benchmark_backend(
+    backend: VideoBackend,
+    config: &VideoEncoderConfig,
+) -> EncoderResult<BenchmarkResult> {
+    let mut config = config.clone();
+    loop {
+        let result = benchmark(backend, &config)?;
+        if result.sustains(config.fps) || !backend.capabilities().presets {
+            return Ok(result);
+        }
+        match config.preset.faster() {
+            Some(preset) => config.preset = preset,
+            None => return Ok(result),
+        }
+    }
+}
+
+/// Backends that may encode `config`, most preferred first.
+///
+/// If `config.backend` names a backend only that one is returned, and it is
+/// an error for it to be missing or unable to handle the configuration.
+/// Otherwise backends that are not compiled in or cannot handle the
+/// configuration are left out.
+pub(crate) fn candidates(config: &VideoEncoderConfig) -> EncoderResult<Vec<VideoBackend>> {
+    if let Some(backend) = config.backend {
+        if !backend.is_compiled() {
+            return Err(EncoderError::NotSupported(format!(
+                "The {} encoder is not built into this binary",
+                backend.name()
+            )));
+        }
+        backend.capabilities().supports(config).map_err(|reason| {
+            EncoderError::NotSupported(format!("{}: {}", backend.name(), reason))
+        })?;
+        return Ok(vec![backend]);
+    }
+
+    Ok(VideoBackend::ALL
+        .into_iter()
+        .filter(|backend| backend.is_compiled())
+        .filter(|backend| match backend.capabilities().supports(config) {
+            Ok(()) => true,
+            Err(reason) => {
+                debug!(backend = backend.name(), "Skipping encoder: {}", reason);
+                false
+            }
+        })
+        .collect())
+}
+
+/// Lower `config` to the Baseline profile when no backend it can use
+/// produces the profile asked for but all of them produce Baseline: the one
+/// `config.backend` names, or else every H.264 backend compiled in.
+///
+/// Returns whether the profile was lowered.
+pub fn fall_back_to_baseline(config: &mut VideoEncoderConfig) -> bool {
+    if config.codec != VideoCodec::H264 || config.profile == H264Profile::Baseline {
+        return false;
+    }
+
+    let mut backends = VideoBackend::ALL
+        .into_iter()
+        .filter(|&backend| config.backend.is_none_or(|chosen| chosen == backend))
+        .filter(|backend| backend.is_compiled())
+        .map(|backend| backend.capabilities())
+        .filter(|caps| caps.codec == VideoCodec::H264)
+        .peekable();
+    if backends.peek().is_none() {
+        return false;
+    }
+
+    let baseline_only = backends.all(|caps| {
+        !caps.profiles.contains(&config.profile) && caps.profiles.contains(&H264Profile::Baseline)
+    });
+    if baseline_only {
+        config.profile = H264Profile::Baseline;
+    }
+    baseline_only
+}
+
+/// The error when no backend can encode `config`.
+pub(crate) fn no_encoder_available(config: &VideoEncoderConfig) -> EncoderError {
+    let feature = match config.codec {
+        VideoCodec::H264 => "x264",
+        VideoCodec::Av1 => "rav1e",
+    };
+    EncoderError::NotSupported(format!(
+        "No {:?} encoder available for {}x{} (build with the `{}` feature)",
+        config.codec, config.width, config.height, feature
+    ))
+}
+
+/// Pick the most preferred backend and preset that sustain `config`.
+///
+/// If `config.backend` names a backend only that one is considered, and it
+/// is an error for it to be missing or unable to handle the configuration.
+/// Otherwise backends that are not compiled in, cannot handle the
+/// configuration or fail to open are skipped. If none keeps up, the fastest
+/// one measured is returned.
+pub fn select_video_encoder(config: &VideoEncoderConfig) -> EncoderResult<BenchmarkResult> {
+    let results = candidates(config)?.into_iter().filter_map(|backend| {
+        match benchmark_backend(backend, config) {
+            Ok(result) => Some(result),
+            Err(e) => {
+                warn!(backend = backend.name(), "Encoder unavailable: {}", e);
+                None
+            }
+        }
+    });
+
+    let Some(result) = choose(results, config.fps) else {
+        return Err(no_encoder_available(config));
+    };
+
+    if result.sustains(config.fps) {
+        info!(
+            backend = result.backend.name(),
+            preset = ?result.preset,
+            fps = result.fps,
+            "Selected video encoder"
+        );
+    } else {
+        warn!(
+            backend = result.backend.name(),
+            preset = ?result.preset,
+            fps = result.fps,
+            target_fps = config.fps,
+            "No video encoder sustains the target frame rate, using the fastest"
+        );
+    }
+    Ok(result)
+}
+
+/// Choose among benchmark results given in order of preference.
+///
+/// The first that sustains `target_fps` wins, otherwise the fastest. Results
+/// are consumed lazily, so later backends are not benchmarked once one keeps
+/// up.
+pub fn choose(
+    results: impl IntoIterator<Item = BenchmarkResult>,
+    target_fps: u32,
+) -> Option<BenchmarkResult> {
+    let mut fastest: Option<BenchmarkResult> = None;
+    for result in results {
+        if result.sustains(target_fps) {
+            return Some(result);
+        }
+        if fastest.is_none_or(|best| result.fps > best.fps) {
+            fastest = Some(result);
+        }
+    }
+    fastest
+}
+
+/// A moving gradient with noise, in NV12, so the encoder has motion and
+/// detail to work on.
+fn synthetic_frame(width: u32, height: u32, index: u32) -> Vec<u8> {
+    let (width, height) = (width as usize, height as usize);
+    let mut frame = vec![128u8; width * height * 3 / 2];
+
+    let mut seed = 0x9E37_79B9u32.wrapping_mul(index + 1);
+    let mut noise = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        (seed & 0x1F) as u8
+    };
+
+    let shift = index as usize * 4;
+    for (y, row) in frame[..width * height].chunks_exact_mut(width).enumerate() {
+        for (x, pixel) in row.iter_mut().enumerate() {
+            *pixel = ((x + y + shift) & 0xDF) as u8 + noise();
+        }
+    }
+
+    frame
+}
+
+/// Create the NVENC hardware encoder.
+#[cfg(windows)]
+fn create_nvenc(config: VideoEncoderConfig) -> EncoderResult<Box<dyn VideoEncoder>> {
+    Ok(Box::new(crate::NvencEncoder::new(config)?))
+}
+
+/// No hardware encoders outside Windows.
+#[cfg(not(windows))]
+fn create_nvenc(_config: VideoEncoderConfig) -> EncoderResult<Box<dyn VideoEncoder>> {
+    Err(EncoderError::NotSupported(
+        "NVENC is only available on Windows".into(),
+    ))
+}
+
+/// Create the x264 software encoder.
+#[cfg(feature = "x264")]
+fn create_x264(config: VideoEncoderConfig) -> EncoderResult<Box<dyn VideoEncoder>> {
+    Ok(Box::new(crate::X264Encoder::new(config)?))
+}
+
+/// Stub when x264 is not compiled in.
+#[cfg(not(feature = "x264"))]
+fn create_x264(_config: VideoEncoderConfig) -> EncoderResult<Box<dyn VideoEncoder>> {
+    Err(EncoderError::NotSupported(
+        "x264 not available (build with the `x264` feature)".into(),
+    ))
+}
+
+/// Create the OpenH264 software encoder.
+#[cfg(feature = "openh264")]
+fn create_openh264(config: VideoEncoderConfig) -> EncoderResult<Box<dyn VideoEncoder>> {
+    Ok(Box::new(crate::OpenH264Encoder::new(config)?))
+}
+
+/// Stub when OpenH264 is not compiled in.
+#[cfg(not(feature = "openh264"))]
+fn create_openh264(_config: VideoEncoderConfig) -> EncoderResult<Box<dyn VideoEncoder>> {
+    Err(EncoderError::NotSupported(
+        "OpenH264 not available (build with the `openh264` feature)".into(),
+    ))
+}
+
+/// Create the rav1e AV1 encoder.
+#[cfg(feature = "rav1e")]
+fn create_rav1e(config: VideoEncoderConfig) -> EncoderResult<Box<dyn VideoEncoder>> {
+    Ok(Box::new(crate::Rav1eEncoder::new(config)?))
+}
+
+/// Stub when rav1e is not compiled in.
+#[cfg(not(feature = "rav1e"))]
+fn create_rav1e(_config: VideoEncoderConfig) -> EncoderResult<Box<dyn VideoEncoder>> {
+    Err(EncoderError::NotSupported(
+        "rav1e not available (build with the `rav1e` feature)".into(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RegionOfInterest;
+
+    #[test]
+    fn test_backend_names_round_trip() {
+        for backend in VideoBackend::ALL {
+            assert_eq!(VideoBackend::from_name(backend.name()), Some(backend));
+        }
+        assert_eq!(VideoBackend::from_name("X264"), Some(VideoBackend::X264));
+        assert_eq!(VideoBackend::from_name("vp8"), None);
+    }
+
+    #[test]
+    fn test_capabilities_reject_oversized_frames() {
+        let caps = VideoBackend::Nvenc.capabilities();
+        let config = VideoEncoderConfig {
+            width: 7680,
+            height: 4320,
+            ..Default::default()
+        };
+        assert!(caps.supports(&config).is_err());
+        assert_eq!(caps.supports(&VideoEncoderConfig::default()), Ok(()));
+    }
+
+    #[test]
+    fn test_chosen_backend_must_support_config() {
+        let config = VideoEncoderConfig {
+            backend: Some(VideoBackend::OpenH264),
+            profile: H264Profile::High,
+            ..Default::default()
+        };
+        assert!(matches!(
+            select_video_encoder(&config),
+            Err(EncoderError::NotSupported(_))
+        ));
+    }
+
+    #[test]
+    fn test_baseline_only_backend_lowers_profile() {
+        let mut config = VideoEncoderConfig {
+            backend: Some(VideoBackend::OpenH264),
+            ..Default::default()
+        };
+        assert_eq!(config.profile, H264Profile::High);
+        assert_eq!(
+            fall_back_to_baseline(&mut config),
+            VideoBackend::OpenH264.is_compiled()
+        );
+        if VideoBackend::OpenH264.is_compiled() {
+            assert_eq!(config.profile, H264Profile::Baseline);
+            assert_eq!(
+                VideoBackend::OpenH264.capabilities().supports(&config),
+                Ok(())
+            );
+        }
+
+        // x264 produces High itself
+        let mut config = VideoEncoderConfig {
+            backend: Some(VideoBackend::X264),
+            ..Default::default()
+        };
+        assert!(!fall_back_to_baseline(&mut config));
+        assert_eq!(config.profile, H264Profile::High);
+    }
+
+    #[test]
+    fn test_regions_of_interest_need_support() {
+        let config = VideoEncoderConfig {
+            regions_of_interest: vec![RegionOfInterest {
+                x: 0,
+                y: 0,
+                width: 640,
+                height: 360,
+                quality_offset: 4,
+            }],
+            ..Default::default()
+        };
+        assert_eq!(VideoBackend::X264.capabilities().supports(&config), Ok(()));
+        assert!(VideoBackend::Nvenc.capabilities().supports(&config).is_err());
+
+        let cqp = VideoEncoderConfig {
+            rate_control: RateControl::Cqp { qp: 23 },
+            ..config
+        };
+        assert!(VideoBackend::X264.capabilities().supports(&cqp).is_err());
+    }
+
+    #[test]
+    fn test_codec_must_match() {
+        let av1 = VideoEncoderConfig {
+            codec: VideoCodec::Av1,
+            ..Default::default()
+        };
+        assert!(VideoBackend::X264.capabilities().supports(&av1).is_err());
+        assert_eq!(VideoBackend::Rav1e.capabilities().supports(&av1), Ok(()));
+    }
+
+    #[test]
+    fn test_choose_prefers_first_that_keeps_up() {
+        let result = |backend, fps| BenchmarkResult {
+            backend,
+            preset: EncoderPreset::Veryfast,
+            fps,
+            mean_encode_time: Duration::from_secs_f32(1.0 / fps),
+        };
+
+        let chosen = choose(
+            [
+                result(VideoBackend::Nvenc, 80.0),
+                result(VideoBackend::X264, 200.0),
+            ],
+            60,
+        );
+        assert_eq!(chosen.unwrap().backend, VideoBackend::Nvenc);
+
+        let chosen = choose(
+            [
+                result(VideoBackend::Nvenc, 40.0),
+                result(VideoBackend::X264, 50.0),
+            ],
+            60,
+        );
+        assert_eq!(chosen.unwrap().backend, VideoBackend::X264);
+        assert_eq!(choose([], 60), None);
+    }
+
+    #[test]
+    fn test_synthetic_frames_differ() {
+        let first = synthetic_frame(64, 48, 0);
+        assert_eq!(first.len(), 64 * 48 * 3 / 2);
+        assert_ne!(first, synthetic_frame(64, 48, 1));
+    }
+
+    #[cfg(feature = "x264")]
+    #[test]
+    fn test_selects_software_preset_that_keeps_up() {
+        let config = VideoEncoderConfig {
+            width: 320,
+            height: 240,
+            fps: 30,
+            preset: EncoderPreset::Medium,
+            ..Default::default()
+        };
+
+        let result = select_video_encoder(&config).unwrap();
+        assert!(result.fps > 0.0);
+        assert!(result.sustains(30) || result.preset == EncoderPreset::Ultrafast);
+    }
+}
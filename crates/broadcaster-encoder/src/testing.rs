@@ -0,0 +1,790 @@
+//! Video encoders for tests.
+//!
+//! None of these compress anything, so the pipeline, muxer and transport can
+//! be exercised on any machine without x264 or a GPU:
+//!
+//! - [`NullEncoder`] accepts frames and produces nothing.
+//! - [`RawDumpEncoder`] writes the frames it receives as raw video or Y4M,
+//!   for checking what the capture pipeline delivers.
+//! - [`FakeH264Encoder`] produces deterministic Annex B access units with
+//!   real SPS/PPS and slice headers, following the configured GOP.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use tracing::debug;
+
+use crate::error::EncoderError;
+use crate::{
+    nv12_to_i420, EncodedVideoPacket, EncoderResult, FrameType, H264Profile, PixelFormat,
+    VideoCodec, VideoEncoder, VideoEncoderConfig, VideoEncoderParams,
+};
+
+/// Annex B start code written before every NAL unit.
+const START_CODE: [u8; 4] = [0, 0, 0, 1];
+
+/// NAL unit types produced by [`FakeH264Encoder`].
+const NAL_TYPE_NON_IDR: u8 = 1;
+const NAL_TYPE_IDR: u8 = 5;
+const NAL_TYPE_SPS: u8 = 7;
+const NAL_TYPE_PPS: u8 = 8;
+
+/// `slice_type` values meaning every slice of the picture has this type.
+const SLICE_TYPE_P_ALL: u32 = 5;
+const SLICE_TYPE_I_ALL: u32 = 7;
+
+/// Frame numbers wrap at 2^LOG2_MAX_FRAME_NUM.
+const LOG2_MAX_FRAME_NUM: u32 = 4;
+
+/// Level signalled when the config leaves it open (4.2 covers 1080p60).
+const DEFAULT_LEVEL_IDC: u8 = 42;
+
+/// Keyframes are this many times larger than other frames by default.
+const KEYFRAME_SIZE_RATIO: usize = 4;
+
+/// Smallest slice payload, so every frame has some data after its header.
+const MIN_FRAME_BYTES: usize = 16;
+
+/// Check that `frame` holds one frame in the configured input format.
+fn check_frame_size(config: &VideoEncoderConfig, frame: &[u8]) -> EncoderResult<()> {
+    let format = config.input_format;
+    let expected_size = format.frame_size(config.width, config.height);
+    if frame.len() != expected_size {
+        return Err(EncoderError::InvalidInput(format!(
+            "Expected {} bytes ({}x{} {:?}), got {}",
+            expected_size,
+            config.width,
+            config.height,
+            format,
+            frame.len()
+        )));
+    }
+    Ok(())
+}
+
+/// An encoder that accepts frames and discards them.
+pub struct NullEncoder {
+    config: VideoEncoderConfig,
+    frames: u64,
+}
+
+impl NullEncoder {
+    /// Create a null encoder for frames of the given configuration.
+    pub fn new(config: VideoEncoderConfig) -> Self {
+        Self { config, frames: 0 }
+    }
+
+    /// Number of frames submitted so far.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+}
+
+impl VideoEncoder for NullEncoder {
+    fn encode(&mut self, frame: &[u8], _pts_100ns: u64) -> EncoderResult<Vec<EncodedVideoPacket>> {
+        check_frame_size(&self.config, frame)?;
+        self.frames += 1;
+        Ok(Vec::new())
+    }
+
+    fn flush(&mut self) -> EncoderResult<Vec<EncodedVideoPacket>> {
+        Ok(Vec::new())
+    }
+
+    fn reconfigure(&mut self, params: &VideoEncoderParams) -> EncoderResult<()> {
+        self.config.apply(params);
+        Ok(())
+    }
+
+    fn request_keyframe(&mut self) {}
+
+    fn is_hardware_accelerated(&self) -> bool {
+        false
+    }
+
+    fn name(&self) -> &'static str {
+        "null"
+    }
+
+    fn codec(&self) -> VideoCodec {
+        self.config.codec
+    }
+
+    fn get_headers(&self) -> Option<Bytes> {
+        None
+    }
+}
+
+/// File layout written by [`RawDumpEncoder`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// Frames back to back, exactly as submitted.
+    #[default]
+    Raw,
+    /// YUV4MPEG2, playable with `ffplay` or `mpv`. NV12 input is converted
+    /// to the planar layout Y4M requires.
+    Y4m,
+}
+
+/// An encoder that writes the frames it receives to a file or other sink.
+///
+/// It produces no packets; use it in place of a real encoder to inspect what
+/// capture and scaling hand to the encoder.
+pub struct RawDumpEncoder<W: Write + Send> {
+    config: VideoEncoderConfig,
+    writer: W,
+    format: DumpFormat,
+    frames: u64,
+    i420: Vec<u8>,
+}
+
+impl RawDumpEncoder<BufWriter<File>> {
+    /// Dump to a file, as Y4M if `path` ends in `.y4m` and raw otherwise.
+    pub fn create(config: VideoEncoderConfig, path: impl AsRef<Path>) -> EncoderResult<Self> {
+        let path = path.as_ref();
+        let format = match path.extension() {
+            Some(extension) if extension.eq_ignore_ascii_case("y4m") => DumpFormat::Y4m,
+            _ => DumpFormat::Raw,
+        };
+        let file = File::create(path).map_err(|e| {
+            EncoderError::Initialization(format!("Failed to create {}: {}", path.display(), e))
+        })?;
+
+        debug!(path = %path.display(), ?format, "Dumping raw video");
+        Self::new(config, BufWriter::new(file), format)
+    }
+}
+
+impl<W: Write + Send> RawDumpEncoder<W> {
+    /// Dump to `writer` in the given format.
+    ///
+    /// The Y4M stream header is written immediately.
+    pub fn new(
+        config: VideoEncoderConfig,
+        mut writer: W,
+        format: DumpFormat,
+    ) -> EncoderResult<Self> {
+        if format == DumpFormat::Y4m {
+            // NV12 and I420 capture both use MPEG-2 chroma siting
+            writeln!(
+                writer,
+                "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C420mpeg2",
+                config.width, config.height, config.fps
+            )
+            .map_err(|e| {
+                EncoderError::Initialization(format!("Failed to write Y4M header: {}", e))
+            })?;
+        }
+
+        Ok(Self {
+            config,
+            writer,
+            format,
+            frames: 0,
+            i420: Vec::new(),
+        })
+    }
+
+    /// Number of frames written so far.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Flush and return the underlying writer.
+    pub fn into_inner(mut self) -> EncoderResult<W> {
+        self.flush()?;
+        Ok(self.writer)
+    }
+
+    fn write_frame(&mut self, frame: &[u8]) -> std::io::Result<()> {
+        if self.format == DumpFormat::Raw {
+            return self.writer.write_all(frame);
+        }
+
+        self.writer.write_all(b"FRAME\n")?;
+        match self.config.input_format {
+            PixelFormat::I420 => self.writer.write_all(frame),
+            PixelFormat::Nv12 => {
+                nv12_to_i420(frame, self.config.width, self.config.height, &mut self.i420);
+                self.writer.write_all(&self.i420)
+            }
+        }
+    }
+}
+
+impl<W: Write + Send> VideoEncoder for RawDumpEncoder<W> {
+    fn encode(&mut self, frame: &[u8], _pts_100ns: u64) -> EncoderResult<Vec<EncodedVideoPacket>> {
+        check_frame_size(&self.config, frame)?;
+        self.write_frame(frame)
+            .map_err(|e| EncoderError::Encoding(format!("Failed to write frame: {}", e)))?;
+        self.frames += 1;
+        Ok(Vec::new())
+    }
+
+    fn flush(&mut self) -> EncoderResult<Vec<EncodedVideoPacket>> {
+        self.writer
+            .flush()
+            .map_err(|e| EncoderError::Encoding(format!("Failed to flush dump: {}", e)))?;
+        Ok(Vec::new())
+    }
+
+    fn reconfigure(&mut self, params: &VideoEncoderParams) -> EncoderResult<()> {
+        self.config.apply(params);
+        Ok(())
+    }
+
+    fn request_keyframe(&mut self) {}
+
+    fn is_hardware_accelerated(&self) -> bool {
+        false
+    }
+
+    fn name(&self) -> &'static str {
+        "raw-dump"
+    }
+
+    fn codec(&self) -> VideoCodec {
+        self.config.codec
+    }
+
+    fn get_headers(&self) -> Option<Bytes> {
+        None
+    }
+}
+
+/// Sizes of the slice NAL units [`FakeH264Encoder`] produces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameSizes {
+    /// Bytes in an IDR slice.
+    pub keyframe_bytes: usize,
+    /// Bytes in a non-IDR slice.
+    pub frame_bytes: usize,
+}
+
+impl FrameSizes {
+    /// Sizes that average out to the configured bitrate over a GOP.
+    pub fn from_bitrate(config: &VideoEncoderConfig) -> Self {
+        let budget = config.bitrate_kbps as usize * 1000 / 8 / config.fps.max(1) as usize;
+        let gop = config.keyframe_interval_frames() as usize;
+        // One keyframe weighs as much as KEYFRAME_SIZE_RATIO other frames
+        let frame_bytes = match gop {
+            0 => budget,
+            gop => budget * gop / (gop - 1 + KEYFRAME_SIZE_RATIO),
+        }
+        .max(MIN_FRAME_BYTES);
+        Self {
+            keyframe_bytes: frame_bytes * KEYFRAME_SIZE_RATIO,
+            frame_bytes,
+        }
+    }
+}
+
+/// Writes the bits of an RBSP, most significant bit first.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn bits(&mut self, value: u32, count: u32) {
+        for i in (0..count).rev() {
+            self.flag((value >> i) & 1 == 1);
+        }
+    }
+
+    fn flag(&mut self, set: bool) {
+        if self.bits.is_multiple_of(8) {
+            self.bytes.push(0);
+        }
+        if set {
+            *self.bytes.last_mut().unwrap() |= 0x80 >> (self.bits % 8);
+        }
+        self.bits += 1;
+    }
+
+    /// Unsigned Exp-Golomb code.
+    fn ue(&mut self, value: u32) {
+        let coded = value as u64 + 1;
+        let length = 64 - coded.leading_zeros();
+        self.bits(0, length - 1);
+        for i in (0..length).rev() {
+            self.flag((coded >> i) & 1 == 1);
+        }
+    }
+
+    /// Signed Exp-Golomb code.
+    fn se(&mut self, value: i32) {
+        let mapped = if value > 0 {
+            value as u32 * 2 - 1
+        } else {
+            value.unsigned_abs() * 2
+        };
+        self.ue(mapped);
+    }
+
+    /// Finish with the RBSP stop bit and alignment.
+    fn finish(mut self) -> Vec<u8> {
+        self.flag(true);
+        self.bytes
+    }
+}
+
+/// Append one NAL unit with a start code, escaping start code emulation.
+fn put_nal(out: &mut BytesMut, nal_ref_idc: u8, nal_type: u8, rbsp: &[u8]) {
+    out.put_slice(&START_CODE);
+    out.put_u8((nal_ref_idc << 5) | nal_type);
+
+    let mut zeros = 0;
+    for &byte in rbsp {
+        if zeros == 2 && byte <= 3 {
+            out.put_u8(3);
+            zeros = 0;
+        }
+        out.put_u8(byte);
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+    }
+}
+
+/// An H.264 "encoder" that produces deterministic access units without
+/// looking at the frames.
+///
+/// The SPS and PPS are valid and describe the configured resolution,
+/// profile, level and frame rate. Each frame becomes one slice with a valid
+/// header followed by filler, sized by [`FrameSizes`]: IDR slices with the
+/// parameter sets at every keyframe interval or requested keyframe, P slices
+/// otherwise. Output is one packet per frame with DTS equal to PTS, so
+/// everything downstream of the encoder can be tested byte for byte.
+pub struct FakeH264Encoder {
+    config: VideoEncoderConfig,
+    sizes: Option<FrameSizes>,
+    headers: Bytes,
+    frames_since_keyframe: u32,
+    idr_count: u32,
+    keyframe_pending: bool,
+}
+
+impl FakeH264Encoder {
+    /// Create an encoder whose frame sizes follow the configured bitrate.
+    pub fn new(config: VideoEncoderConfig) -> EncoderResult<Self> {
+        Self::open(config, None)
+    }
+
+    /// Create an encoder producing slices of fixed sizes.
+    pub fn with_sizes(config: VideoEncoderConfig, sizes: FrameSizes) -> EncoderResult<Self> {
+        Self::open(config, Some(sizes))
+    }
+
+    fn open(config: VideoEncoderConfig, sizes: Option<FrameSizes>) -> EncoderResult<Self> {
+        if config.codec != VideoCodec::H264 {
+            return Err(EncoderError::NotSupported(format!(
+                "FakeH264Encoder cannot produce {:?}",
+                config.codec
+            )));
+        }
+        if config.width == 0
+            || config.height == 0
+            || !config.width.is_multiple_of(2)
+            || !config.height.is_multiple_of(2)
+        {
+            return Err(EncoderError::Initialization(format!(
+                "{}x{} is not a valid 4:2:0 frame size",
+                config.width, config.height
+            )));
+        }
+        if config.bframes > 0 {
+            return Err(EncoderError::NotSupported(
+                "FakeH264Encoder does not produce B-frames".into(),
+            ));
+        }
+
+        let mut headers = BytesMut::new();
+        put_nal(&mut headers, 3, NAL_TYPE_SPS, &Self::sps(&config));
+        put_nal(&mut headers, 3, NAL_TYPE_PPS, &Self::pps());
+
+        Ok(Self {
+            config,
+            sizes,
+            headers: headers.freeze(),
+            frames_since_keyframe: 0,
+            idr_count: 0,
+            keyframe_pending: true,
+        })
+    }
+
+    /// Sizes of the slices currently produced.
+    pub fn sizes(&self) -> FrameSizes {
+        self.sizes
+            .unwrap_or_else(|| FrameSizes::from_bitrate(&self.config))
+    }
+
+    fn sps(config: &VideoEncoderConfig) -> Vec<u8> {
+        let mut w = BitWriter::default();
+
+        let constraint_flags = match config.profile {
+            // Constrained Baseline: constraint_set0 and constraint_set1
+            H264Profile::Baseline => 0xC0,
+            H264Profile::Main => 0x40,
+            H264Profile::High => 0x00,
+        };
+        w.bits(config.profile.profile_idc() as u32, 8);
+        w.bits(constraint_flags, 8);
+        w.bits(config.level_idc.unwrap_or(DEFAULT_LEVEL_IDC) as u32, 8);
+        w.ue(0); // seq_parameter_set_id
+
+        if config.profile == H264Profile::High {
+            w.ue(1); // chroma_format_idc: 4:2:0
+            w.ue(0); // bit_depth_luma_minus8
+            w.ue(0); // bit_depth_chroma_minus8
+            w.flag(false); // qpprime_y_zero_transform_bypass_flag
+            w.flag(false); // seq_scaling_matrix_present_flag
+        }
+
+        w.ue(LOG2_MAX_FRAME_NUM - 4);
+        // Picture order follows frame_num, which is all a stream without
+        // B-frames needs
+        w.ue(2); // pic_order_cnt_type
+        w.ue(1); // max_num_ref_frames
+        w.flag(false); // gaps_in_frame_num_value_allowed_flag
+
+        let width_mbs = config.width.div_ceil(16);
+        let height_mbs = config.height.div_ceil(16);
+        w.ue(width_mbs - 1);
+        w.ue(height_mbs - 1);
+        w.flag(true); // frame_mbs_only_flag
+        w.flag(true); // direct_8x8_inference_flag
+
+        // Crop offsets count pairs of pixels in 4:2:0
+        let crop_right = (width_mbs * 16 - config.width) / 2;
+        let crop_bottom = (height_mbs * 16 - config.height) / 2;
+        w.flag(crop_right > 0 || crop_bottom > 0);
+        if crop_right > 0 || crop_bottom > 0 {
+            w.ue(0);
+            w.ue(crop_right);
+            w.ue(0);
+            w.ue(crop_bottom);
+        }
+
+        w.flag(true); // vui_parameters_present_flag
+        w.flag(false); // aspect_ratio_info_present_flag
+        w.flag(false); // overscan_info_present_flag
+        w.flag(false); // video_signal_type_present_flag
+        w.flag(false); // chroma_loc_info_present_flag
+        w.flag(true); // timing_info_present_flag
+        w.bits(1, 32); // num_units_in_tick
+        w.bits(config.fps * 2, 32); // time_scale
+        w.flag(true); // fixed_frame_rate_flag
+        w.flag(false); // nal_hrd_parameters_present_flag
+        w.flag(false); // vcl_hrd_parameters_present_flag
+        w.flag(false); // pic_struct_present_flag
+        w.flag(true); // bitstream_restriction_flag
+        w.flag(true); // motion_vectors_over_pic_boundaries_flag
+        w.ue(0); // max_bytes_per_pic_denom
+        w.ue(0); // max_bits_per_mb_denom
+        w.ue(16); // log2_max_mv_length_horizontal
+        w.ue(16); // log2_max_mv_length_vertical
+        w.ue(0); // max_num_reorder_frames
+        w.ue(1); // max_dec_frame_buffering
+
+        w.finish()
+    }
+
+    fn pps() -> Vec<u8> {
+        let mut w = BitWriter::default();
+        w.ue(0); // pic_parameter_set_id
+        w.ue(0); // seq_parameter_set_id
+        w.flag(false); // entropy_coding_mode_flag: CAVLC
+        w.flag(false); // bottom_field_pic_order_in_frame_present_flag
+        w.ue(0); // num_slice_groups_minus1
+        w.ue(0); // num_ref_idx_l0_default_active_minus1
+        w.ue(0); // num_ref_idx_l1_default_active_minus1
+        w.flag(false); // weighted_pred_flag
+        w.bits(0, 2); // weighted_bipred_idc
+        w.se(0); // pic_init_qp_minus26
+        w.se(0); // pic_init_qs_minus26
+        w.se(0); // chroma_qp_index_offset
+        w.flag(false); // deblocking_filter_control_present_flag
+        w.flag(false); // constrained_intra_pred_flag
+        w.flag(false); // redundant_pic_cnt_present_flag
+        w.finish()
+    }
+
+    /// A slice header for the whole picture followed by filler, making a
+    /// NAL unit of `size` bytes.
+    fn slice(&self, idr: bool, size: usize) -> Vec<u8> {
+        let mut w = BitWriter::default();
+        w.ue(0); // first_mb_in_slice
+        w.ue(if idr {
+            SLICE_TYPE_I_ALL
+        } else {
+            SLICE_TYPE_P_ALL
+        });
+        w.ue(0); // pic_parameter_set_id
+        w.bits(
+            self.frames_since_keyframe % (1 << LOG2_MAX_FRAME_NUM),
+            LOG2_MAX_FRAME_NUM,
+        );
+        if idr {
+            w.ue(self.idr_count % 2); // idr_pic_id differs between neighbours
+            w.flag(false); // no_output_of_prior_pics_flag
+            w.flag(false); // long_term_reference_flag
+        } else {
+            w.flag(false); // num_ref_idx_active_override_flag
+            w.flag(false); // ref_pic_list_modification_flag_l0
+            w.flag(false); // adaptive_ref_pic_marking_mode_flag
+        }
+        w.se(0); // slice_qp_delta
+
+        // Filler bytes start and end with a set bit, so however they are
+        // shifted no zero byte appears and nothing needs escaping
+        let seed = self.frames_since_keyframe as usize * 31 + self.idr_count as usize * 7;
+        let header_bytes = (w.bits + 1).div_ceil(8) as usize;
+        for i in 0..size.saturating_sub(1 + header_bytes) {
+            w.bits(((seed + i * 151) as u32 & 0xFF) | 0x81, 8);
+        }
+        w.finish()
+    }
+}
+
+impl VideoEncoder for FakeH264Encoder {
+    fn encode(&mut self, frame: &[u8], pts_100ns: u64) -> EncoderResult<Vec<EncodedVideoPacket>> {
+        check_frame_size(&self.config, frame)?;
+
+        let gop = self.config.keyframe_interval_frames();
+        let idr = self.keyframe_pending || (gop > 0 && self.frames_since_keyframe >= gop);
+        if idr {
+            self.frames_since_keyframe = 0;
+            self.keyframe_pending = false;
+        }
+
+        let sizes = self.sizes();
+        let mut data = BytesMut::new();
+        if idr {
+            data.put_slice(&self.headers);
+            let slice = self.slice(true, sizes.keyframe_bytes);
+            put_nal(&mut data, 3, NAL_TYPE_IDR, &slice);
+            self.idr_count += 1;
+        } else {
+            let slice = self.slice(false, sizes.frame_bytes);
+            put_nal(&mut data, 2, NAL_TYPE_NON_IDR, &slice);
+        }
+        self.frames_since_keyframe += 1;
+
+        Ok(vec![EncodedVideoPacket {
+            data: data.freeze(),
+            pts_100ns,
+            dts_100ns: pts_100ns as i64,
+            is_keyframe: idr,
+            frame_type: if idr { FrameType::I } else { FrameType::P },
+        }])
+    }
+
+    fn flush(&mut self) -> EncoderResult<Vec<EncodedVideoPacket>> {
+        Ok(Vec::new())
+    }
+
+    fn reconfigure(&mut self, params: &VideoEncoderParams) -> EncoderResult<()> {
+        self.config.apply(params);
+        Ok(())
+    }
+
+    fn request_keyframe(&mut self) {
+        self.keyframe_pending = true;
+    }
+
+    fn is_hardware_accelerated(&self) -> bool {
+        false
+    }
+
+    fn name(&self) -> &'static str {
+        "fake-h264"
+    }
+
+    fn codec(&self) -> VideoCodec {
+        VideoCodec::H264
+    }
+
+    fn get_headers(&self) -> Option<Bytes> {
+        Some(self.headers.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> VideoEncoderConfig {
+        VideoEncoderConfig {
+            width: 320,
+            height: 180,
+            fps: 30,
+            bitrate_kbps: 500,
+            keyframe_interval_secs: 1,
+            profile: H264Profile::Baseline,
+            ..Default::default()
+        }
+    }
+
+    fn frame() -> Vec<u8> {
+        vec![0u8; PixelFormat::Nv12.frame_size(320, 180)]
+    }
+
+    fn nal_types(data: &[u8]) -> Vec<u8> {
+        data.windows(4)
+            .filter(|w| w[..3] == [0, 0, 1])
+            .map(|w| w[3] & 0x1F)
+            .collect()
+    }
+
+    #[test]
+    fn test_exp_golomb() {
+        let mut w = BitWriter::default();
+        w.ue(0); // 1
+        w.ue(3); // 00100
+        w.se(-1); // 011
+        w.se(2); // 00100
+        assert_eq!(w.finish(), vec![0b1001_0001, 0b1001_0010]);
+    }
+
+    #[test]
+    fn test_start_code_emulation_is_escaped() {
+        let mut out = BytesMut::new();
+        put_nal(&mut out, 3, NAL_TYPE_SPS, &[0, 0, 1, 0, 0, 0]);
+        assert_eq!(&out[5..], &[0, 0, 3, 1, 0, 0, 3, 0]);
+    }
+
+    #[test]
+    fn test_fake_h264_follows_gop() {
+        let mut encoder = FakeH264Encoder::new(config()).unwrap();
+        let packets: Vec<_> = (0..61)
+            .flat_map(|i| encoder.encode(&frame(), i * 333_333).unwrap())
+            .collect();
+
+        let keyframes: Vec<_> = packets
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| p.is_keyframe)
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(keyframes, vec![0, 30, 60]);
+
+        assert_eq!(nal_types(&packets[0].data), vec![7, 8, 5]);
+        assert_eq!(nal_types(&packets[1].data), vec![1]);
+        assert_eq!(packets[1].frame_type, FrameType::P);
+    }
+
+    #[test]
+    fn test_fake_h264_sizes_and_determinism() {
+        let sizes = FrameSizes {
+            keyframe_bytes: 4000,
+            frame_bytes: 1000,
+        };
+        let run = || {
+            let mut encoder = FakeH264Encoder::with_sizes(config(), sizes).unwrap();
+            encoder.request_keyframe();
+            (0..3)
+                .flat_map(|i| encoder.encode(&frame(), i).unwrap())
+                .map(|packet| packet.data)
+                .collect::<Vec<_>>()
+        };
+
+        let packets = run();
+        assert_eq!(packets, run());
+
+        let headers_len = FakeH264Encoder::new(config())
+            .unwrap()
+            .get_headers()
+            .unwrap()
+            .len();
+        // Start code and NAL header on top of each slice
+        assert_eq!(packets[0].len(), headers_len + 4 + 4000);
+        assert_eq!(packets[1].len(), 4 + 1000);
+        assert_ne!(packets[1], packets[2]);
+    }
+
+    #[test]
+    fn test_requested_keyframe_restarts_gop() {
+        let mut encoder = FakeH264Encoder::new(config()).unwrap();
+        for i in 0..10 {
+            encoder.encode(&frame(), i).unwrap();
+        }
+        encoder.request_keyframe();
+        assert!(encoder.encode(&frame(), 10).unwrap()[0].is_keyframe);
+        let next_keyframe = (11..50)
+            .find(|&i| encoder.encode(&frame(), i).unwrap()[0].is_keyframe)
+            .unwrap();
+        assert_eq!(next_keyframe, 40);
+    }
+
+    #[test]
+    fn test_bitrate_sizes_follow_reconfigure() {
+        let mut encoder = FakeH264Encoder::new(config()).unwrap();
+        let before = encoder.sizes();
+        encoder
+            .reconfigure(&VideoEncoderParams {
+                bitrate_kbps: Some(1000),
+                ..Default::default()
+            })
+            .unwrap();
+        let after = encoder.sizes();
+        assert!(after.frame_bytes.abs_diff(before.frame_bytes * 2) <= 1);
+        assert_eq!(
+            after.keyframe_bytes,
+            after.frame_bytes * KEYFRAME_SIZE_RATIO
+        );
+    }
+
+    #[test]
+    fn test_rejects_odd_size() {
+        let odd = VideoEncoderConfig {
+            width: 321,
+            ..config()
+        };
+        assert!(FakeH264Encoder::new(odd).is_err());
+    }
+
+    #[test]
+    fn test_null_encoder_counts_frames() {
+        let mut encoder = NullEncoder::new(config());
+        assert!(encoder.encode(&frame(), 0).unwrap().is_empty());
+        assert!(encoder.encode(&frame()[1..], 1).is_err());
+        assert_eq!(encoder.frames(), 1);
+    }
+
+    #[test]
+    fn test_y4m_dump() {
+        let config = VideoEncoderConfig {
+            width: 4,
+            height: 2,
+            fps: 25,
+            ..Default::default()
+        };
+        let mut encoder = RawDumpEncoder::new(config, Vec::new(), DumpFormat::Y4m).unwrap();
+        // Y plane, then interleaved U/V
+        let nv12 = [1, 2, 3, 4, 5, 6, 7, 8, 10, 20, 11, 21];
+        encoder.encode(&nv12, 0).unwrap();
+
+        let out = encoder.into_inner().unwrap();
+        let expected_header = b"YUV4MPEG2 W4 H2 F25:1 Ip A1:1 C420mpeg2\nFRAME\n";
+        assert_eq!(&out[..expected_header.len()], expected_header);
+        assert_eq!(
+            &out[expected_header.len()..],
+            &[1, 2, 3, 4, 5, 6, 7, 8, 10, 11, 20, 21]
+        );
+    }
+
+    #[test]
+    fn test_raw_dump_is_verbatim() {
+        let mut encoder = RawDumpEncoder::new(config(), Vec::new(), DumpFormat::Raw).unwrap();
+        let frame: Vec<u8> = (0..frame().len()).map(|i| i as u8).collect();
+        encoder.encode(&frame, 0).unwrap();
+        encoder.encode(&frame, 1).unwrap();
+        assert_eq!(encoder.frames(), 2);
+        assert_eq!(
+            encoder.into_inner().unwrap(),
+            [frame.clone(), frame].concat()
+        );
+    }
+}
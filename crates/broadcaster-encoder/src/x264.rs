@@ -1,229 +1,695 @@
 //! x264 software video encoder.
 
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::mem::MaybeUninit;
+use std::ptr::{self, NonNull};
+
 use bytes::Bytes;
-use tracing::{debug, instrument, trace};
+use tracing::{debug, info, instrument, trace, warn};
 
 use crate::error::EncoderError;
 use crate::{
-    EncodedVideoPacket, EncoderResult, FrameType, H264Profile, VideoEncoder, VideoEncoderConfig,
+    EncodedVideoPacket, EncoderPreset, EncoderResult, EncoderTune, FrameType, H264Profile,
+    PixelFormat, RateControl, RegionOfInterest, VideoCodec, VideoEncoder, VideoEncoderConfig,
+    VideoEncoderParams,
 };
 
+/// x264 `rc.i_rc_method` values.
+const X264_RC_CQP: i32 = 0;
+const X264_RC_CRF: i32 = 1;
+const X264_RC_ABR: i32 = 2;
+
+/// x264 `rc.i_aq_mode` value for variance adaptive quantization.
+const X264_AQ_VARIANCE: i32 = 1;
+
+/// Adaptive quantization strength used for regions of interest when the
+/// preset turns it off. x264 only applies quantizer offsets with adaptive
+/// quantization on, and turns it off at zero strength.
+const MIN_AQ_STRENGTH: f32 = 0.01;
+
+/// Macroblock size in pixels.
+const MB_SIZE: u32 = 16;
+
+/// Map a preset onto x264's name for it.
+fn x264_preset(preset: EncoderPreset) -> &'static CStr {
+    match preset {
+        EncoderPreset::Ultrafast => c"ultrafast",
+        EncoderPreset::Superfast => c"superfast",
+        EncoderPreset::Veryfast => c"veryfast",
+        EncoderPreset::Faster => c"faster",
+        EncoderPreset::Fast => c"fast",
+        EncoderPreset::Medium => c"medium",
+        EncoderPreset::Slow => c"slow",
+        EncoderPreset::Slower => c"slower",
+        EncoderPreset::Veryslow => c"veryslow",
+        EncoderPreset::Placebo => c"placebo",
+    }
+}
+
+/// x264's comma-separated tune list for `config`.
+///
+/// zerolatency disables B-frames and lookahead, so it is only added when
+/// neither is requested.
+fn x264_tune(config: &VideoEncoderConfig) -> CString {
+    let mut tunes = Vec::new();
+    match config.tune {
+        EncoderTune::None => {}
+        EncoderTune::Film => tunes.push("film"),
+        EncoderTune::Animation => tunes.push("animation"),
+        EncoderTune::Grain => tunes.push("grain"),
+        EncoderTune::StillImage => tunes.push("stillimage"),
+        EncoderTune::Psnr => tunes.push("psnr"),
+        EncoderTune::Ssim => tunes.push("ssim"),
+        EncoderTune::FastDecode => tunes.push("fastdecode"),
+        EncoderTune::ZeroLatency => {}
+    }
+    if config.tune == EncoderTune::ZeroLatency || config.is_low_latency() {
+        tunes.push("zerolatency");
+    }
+    CString::new(tunes.join(",")).expect("tune names contain no NUL")
+}
+
+/// Map a profile onto x264's name for it.
+fn x264_profile(profile: H264Profile) -> &'static CStr {
+    match profile {
+        H264Profile::Baseline => c"baseline",
+        H264Profile::Main => c"main",
+        H264Profile::High => c"high",
+    }
+}
+
+/// Map an input layout onto x264's colorspace.
+fn x264_colorspace(format: PixelFormat) -> i32 {
+    match format {
+        PixelFormat::Nv12 => x264_sys::X264_CSP_NV12 as i32,
+        PixelFormat::I420 => x264_sys::X264_CSP_I420 as i32,
+    }
+}
+
+/// Per-macroblock quantizer offsets for `regions` in a `width` x `height`
+/// frame, in x264's raster order, or `None` without regions.
+///
+/// A macroblock takes the offset of the last region touching it, so text at
+/// a region's edge is covered by the region.
+fn quant_offsets(regions: &[RegionOfInterest], width: u32, height: u32) -> Option<Vec<f32>> {
+    if regions.is_empty() {
+        return None;
+    }
+
+    let mb_width = width.div_ceil(MB_SIZE);
+    let mb_height = height.div_ceil(MB_SIZE);
+    let mut offsets = vec![0.0; (mb_width * mb_height) as usize];
+
+    for region in regions {
+        let right = region.x.saturating_add(region.width).min(width);
+        let bottom = region.y.saturating_add(region.height).min(height);
+        if region.x >= right || region.y >= bottom {
+            continue;
+        }
+
+        // x264 lowers the quantizer for negative offsets
+        let offset = -(region.quality_offset as f32);
+        for mb_y in region.y / MB_SIZE..bottom.div_ceil(MB_SIZE) {
+            let row = (mb_y * mb_width) as usize;
+            offsets[row + (region.x / MB_SIZE) as usize..row + right.div_ceil(MB_SIZE) as usize]
+                .fill(offset);
+        }
+    }
+
+    Some(offsets)
+}
+
+/// Check x264 can encode the regions of interest of `config`.
+///
+/// Under CQP x264 turns adaptive quantization off and pins every macroblock
+/// to the one quantizer, so quantizer offsets have no effect.
+fn check_regions_of_interest(config: &VideoEncoderConfig) -> EncoderResult<()> {
+    if !config.regions_of_interest.is_empty()
+        && matches!(config.rate_control, RateControl::Cqp { .. })
+    {
+        return Err(EncoderError::NotSupported(
+            "Regions of interest are not supported with CQP".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Set the rate control of `params` from `config`.
+fn set_rate_control(params: &mut x264_sys::x264_param_t, config: &VideoEncoderConfig) {
+    match config.rate_control {
+        RateControl::Cbr { .. } | RateControl::Vbr { .. } => {
+            params.rc.i_rc_method = X264_RC_ABR;
+            params.rc.i_bitrate = config.bitrate_kbps as i32;
+        }
+        RateControl::Crf { crf } => {
+            params.rc.i_rc_method = X264_RC_CRF;
+            params.rc.f_rf_constant = crf as f32;
+        }
+        RateControl::Cqp { qp } => {
+            params.rc.i_rc_method = X264_RC_CQP;
+            params.rc.i_qp_constant = qp as i32;
+        }
+    }
+
+    // Without a VBV, ABR overshoots far above the target on scene cuts
+    if let Some((max_bitrate_kbps, buffer_kbits)) = config.vbv() {
+        params.rc.i_vbv_max_bitrate = i32::try_from(max_bitrate_kbps).unwrap_or(i32::MAX);
+        params.rc.i_vbv_buffer_size = i32::try_from(buffer_kbits).unwrap_or(i32::MAX);
+    }
+    if matches!(config.rate_control, RateControl::Cbr { .. }) {
+        // Pad to the target so the bitrate stays constant on static content
+        params.rc.b_filler = 1;
+    }
+}
+
+/// Whether `x264_encoder_reconfig` can take an encoder from `old` to `new`.
+///
+/// x264 cannot switch rate control methods, set a new quantizer or change
+/// the keyframe interval of an open encoder, and only changes the bitrate
+/// along with a VBV, which CBR and VBR always have.
+fn can_reconfig(old: &VideoEncoderConfig, new: &VideoEncoderConfig) -> bool {
+    old.keyframe_interval_secs == new.keyframe_interval_secs
+        && matches!(
+            (old.rate_control, new.rate_control),
+            (RateControl::Cbr { .. }, RateControl::Cbr { .. })
+                | (RateControl::Vbr { .. }, RateControl::Vbr { .. })
+                | (RateControl::Crf { .. }, RateControl::Crf { .. })
+        )
+}
+
+/// x264 parameters for `config`.
+///
+/// Built the way the x264 CLI does: the preset and tune first, then our
+/// settings, then the profile, which caps them.
+fn x264_params(config: &VideoEncoderConfig) -> EncoderResult<x264_sys::x264_param_t> {
+    let tune = x264_tune(config);
+    let mut params = MaybeUninit::<x264_sys::x264_param_t>::uninit();
+    // SAFETY: x264_param_default_preset fills in every field before
+    // applying the preset, which only fails for unknown names
+    let mut params = unsafe {
+        if x264_sys::x264_param_default_preset(
+            params.as_mut_ptr(),
+            x264_preset(config.preset).as_ptr(),
+            tune.as_ptr(),
+        ) < 0
+        {
+            return Err(EncoderError::Initialization(format!(
+                "x264 rejected preset {:?} with tune {:?}",
+                config.preset, tune
+            )));
+        }
+        params.assume_init()
+    };
+
+    params.i_fps_num = config.fps;
+    params.i_fps_den = 1;
+    params.i_keyint_max = config.keyframe_interval_frames() as i32;
+    // Disable scenecut for predictable keyframes
+    params.i_scenecut_threshold = 0;
+
+    // Set reordering before the profile, which caps it (Baseline has no B-frames)
+    params.i_bframe = config.bframes as i32;
+    params.rc.i_lookahead = config.lookahead_frames as i32;
+    params.i_threads = config.threads as i32;
+    // zerolatency already turns sliced threads on
+    if config.slice_threads {
+        params.b_sliced_threads = 1;
+    }
+    if let Some(level_idc) = config.level_idc {
+        params.i_level_idc = level_idc as i32;
+    }
+    // x264 writes the timebase into the VUI timing, so time by the frame
+    // rate and hand it frame numbers; variable frame rate input would
+    // signal the timebase as the frame rate
+    params.b_vfr_input = 0;
+    params.i_timebase_num = 1;
+    params.i_timebase_den = config.fps;
+
+    set_rate_control(&mut params, config);
+
+    // Quantizer offsets need adaptive quantization, so turn it on for
+    // regions of interest, barely adapting, when the preset or tune had it
+    // off. Otherwise it is left as they set it.
+    if !config.regions_of_interest.is_empty()
+        && (params.rc.i_aq_mode == 0 || params.rc.f_aq_strength == 0.0)
+    {
+        params.rc.i_aq_mode = X264_AQ_VARIANCE;
+        params.rc.f_aq_strength = MIN_AQ_STRENGTH;
+    }
+
+    if config.bframes > 0 && config.profile == H264Profile::Baseline {
+        warn!("Baseline profile does not support B-frames, disabling them");
+    }
+    // SAFETY: params was initialized by x264_param_default_preset
+    if unsafe {
+        x264_sys::x264_param_apply_profile(&mut params, x264_profile(config.profile).as_ptr())
+    } < 0
+    {
+        return Err(EncoderError::Initialization(format!(
+            "x264 cannot apply the {:?} profile to these settings",
+            config.profile
+        )));
+    }
+
+    params.i_csp = x264_colorspace(config.input_format);
+    params.i_width = config.width as i32;
+    params.i_height = config.height as i32;
+    Ok(params)
+}
+
+/// One picture of encoder output.
+struct EncodedPicture {
+    data: Vec<u8>,
+    keyframe: bool,
+    pts: i64,
+    dts: i64,
+}
+
+/// An open x264 encoder.
+struct RawEncoder(NonNull<x264_sys::x264_t>);
+
+impl RawEncoder {
+    /// Open an encoder for `params`, as built by [`x264_params`].
+    fn open(params: &mut x264_sys::x264_param_t) -> EncoderResult<Self> {
+        // SAFETY: x264 copies the parameters and does not keep the pointer
+        let raw = unsafe { x264_sys::x264_encoder_open(params) };
+        NonNull::new(raw)
+            .map(Self)
+            .ok_or_else(|| EncoderError::Initialization("x264 setup failed".to_string()))
+    }
+
+    /// The SPS/PPS in Annex B format.
+    fn headers(&mut self) -> EncoderResult<Bytes> {
+        let mut nals = ptr::null_mut();
+        let mut nal_count = 0;
+        // SAFETY: the NAL units stay valid until the next call on the encoder
+        unsafe {
+            if x264_sys::x264_encoder_headers(self.0.as_ptr(), &mut nals, &mut nal_count) < 0 {
+                return Err(EncoderError::Encoding("x264 headers failed".to_string()));
+            }
+            Ok(Bytes::copy_from_slice(nal_payload(nals, nal_count)))
+        }
+    }
+
+    /// Encode `picture`, or with `None` output a delayed one.
+    ///
+    /// Returns `None` while the encoder buffers frames.
+    fn encode(
+        &mut self,
+        picture: Option<&mut x264_sys::x264_picture_t>,
+    ) -> EncoderResult<Option<EncodedPicture>> {
+        let mut nals = ptr::null_mut();
+        let mut nal_count = 0;
+        let mut output = MaybeUninit::<x264_sys::x264_picture_t>::zeroed();
+
+        // SAFETY: x264 reads the input picture's planes and quantizer offsets
+        // before returning, and the NAL units stay valid until the next call
+        unsafe {
+            let size = x264_sys::x264_encoder_encode(
+                self.0.as_ptr(),
+                &mut nals,
+                &mut nal_count,
+                picture.map_or(ptr::null_mut(), |picture| picture as *mut _),
+                output.as_mut_ptr(),
+            );
+            if size < 0 {
+                return Err(EncoderError::Encoding(format!(
+                    "x264 encode failed: {}",
+                    size
+                )));
+            }
+            if size == 0 {
+                return Ok(None);
+            }
+
+            let output = output.assume_init();
+            Ok(Some(EncodedPicture {
+                data: nal_payload(nals, nal_count).to_vec(),
+                keyframe: output.b_keyframe != 0,
+                pts: output.i_pts,
+                dts: output.i_dts,
+            }))
+        }
+    }
+
+    /// Apply new rate control settings without restarting the stream.
+    fn reconfig(&mut self, config: &VideoEncoderConfig) -> EncoderResult<()> {
+        let mut params = MaybeUninit::<x264_sys::x264_param_t>::zeroed();
+        // SAFETY: x264 fills in the parameters of the open encoder, which
+        // are handed back to it unchanged apart from the rate control
+        unsafe {
+            x264_sys::x264_encoder_parameters(self.0.as_ptr(), params.as_mut_ptr());
+            let mut params = params.assume_init();
+            set_rate_control(&mut params, config);
+            if x264_sys::x264_encoder_reconfig(self.0.as_ptr(), &mut params) < 0 {
+                return Err(EncoderError::Encoding("x264 reconfig failed".to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the encoder uses adaptive quantization, and so applies
+    /// quantizer offsets.
+    fn adaptive_quantization(&self) -> bool {
+        let mut params = MaybeUninit::<x264_sys::x264_param_t>::zeroed();
+        // SAFETY: x264 fills in the parameters of the open encoder
+        let params = unsafe {
+            x264_sys::x264_encoder_parameters(self.0.as_ptr(), params.as_mut_ptr());
+            params.assume_init()
+        };
+        // x264 turns it off for CQP and at zero strength
+        params.rc.i_aq_mode != 0
+    }
+
+    /// Number of frames still held for reordering or lookahead.
+    fn delayed_frames(&self) -> i32 {
+        // SAFETY: the encoder is open until dropped
+        unsafe { x264_sys::x264_encoder_delayed_frames(self.0.as_ptr()) }
+    }
+}
+
+impl Drop for RawEncoder {
+    fn drop(&mut self) {
+        // SAFETY: the encoder is open and not used again
+        unsafe { x264_sys::x264_encoder_close(self.0.as_ptr()) };
+    }
+}
+
+/// The bytes of `count` NAL units, which x264 lays out back to back.
+///
+/// # Safety
+///
+/// `nals` must point to `count` NAL units returned by the last x264 call.
+unsafe fn nal_payload<'a>(nals: *const x264_sys::x264_nal_t, count: i32) -> &'a [u8] {
+    if nals.is_null() || count <= 0 {
+        return &[];
+    }
+    let nals = std::slice::from_raw_parts(nals, count as usize);
+    let len = nals.iter().map(|nal| nal.i_payload as usize).sum();
+    std::slice::from_raw_parts(nals[0].p_payload, len)
+}
+
 /// x264 software encoder wrapper.
 pub struct X264Encoder {
-    encoder: Option<x264::Encoder>,
+    encoder: Option<RawEncoder>,
     config: VideoEncoderConfig,
     frame_count: u64,
-    #[allow(dead_code)]
-    keyframe_interval: u64,
+    /// Caller PTS of frames inside the encoder, by frame number.
+    pending_pts: HashMap<i64, u64>,
     /// Cached SPS/PPS header data in Annex B format.
     headers: Bytes,
+    /// Highest PTS output so far, to tell B-frames from P-frames.
+    max_output_pts: Option<i64>,
+    /// Encoded frames waiting to be returned, drained from a replaced encoder.
+    queued: Vec<EncodedVideoPacket>,
+    /// Force the next frame to be an IDR.
+    keyframe_pending: bool,
+    /// Quantizer offset of each macroblock, from the regions of interest.
+    quant_offsets: Option<Vec<f32>>,
+    /// Whether the encoder applies quantizer offsets, so regions of interest
+    /// can be set while encoding.
+    adaptive_quantization: bool,
 }
 
 impl X264Encoder {
     /// Create a new x264 encoder.
     #[instrument(name = "x264_new", skip_all)]
     pub fn new(config: VideoEncoderConfig) -> EncoderResult<Self> {
+        check_regions_of_interest(&config)?;
+        let (encoder, headers) = Self::open(&config)?;
+        let quant_offsets = quant_offsets(&config.regions_of_interest, config.width, config.height);
+        let adaptive_quantization = encoder.adaptive_quantization();
+
+        Ok(Self {
+            encoder: Some(encoder),
+            config,
+            frame_count: 0,
+            pending_pts: HashMap::new(),
+            headers,
+            max_output_pts: None,
+            queued: Vec::new(),
+            keyframe_pending: false,
+            quant_offsets,
+            adaptive_quantization,
+        })
+    }
+
+    /// Open an x264 encoder for `config`, returning it with its SPS/PPS.
+    fn open(config: &VideoEncoderConfig) -> EncoderResult<(RawEncoder, Bytes)> {
         debug!(
             width = config.width,
             height = config.height,
             fps = config.fps,
             bitrate_kbps = config.bitrate_kbps,
+            rate_control = ?config.rate_control,
+            preset = ?config.preset,
+            tune = ?config.tune,
+            threads = config.threads,
+            slice_threads = config.slice_threads,
+            bframes = config.bframes,
+            lookahead_frames = config.lookahead_frames,
             "Initializing x264 encoder"
         );
 
-        let keyframe_interval = (config.fps * config.keyframe_interval_secs) as u64;
+        let mut params = x264_params(config)?;
+        let mut encoder = RawEncoder::open(&mut params)?;
 
-        // Build x264 encoder using the Setup builder
-        // Use None for tune to use default, enable zero_latency flag
-        let mut setup = x264::Setup::preset(
-            x264::Preset::Veryfast,
-            x264::Tune::None,
-            false, // fast_decode
-            true,  // zero_latency
-        )
-        .fps(config.fps, 1)
-        .bitrate(config.bitrate_kbps as i32)
-        .max_keyframe_interval(keyframe_interval as i32)
-        .scenecut_threshold(0); // Disable scenecut for predictable keyframes
-
-        // Apply H.264 profile
-        setup = match config.profile {
-            H264Profile::Baseline => setup.baseline(),
-            H264Profile::Main => setup.main(),
-            H264Profile::High => setup.high(),
+        // Get SPS/PPS headers
+        let headers = encoder.headers().unwrap_or_default();
+
+        debug!(header_size = headers.len(), "x264 encoder initialized");
+
+        Ok((encoder, headers))
+    }
+
+    /// Replace the encoder with a fresh one built from the current config.
+    ///
+    /// Used for settings `x264_encoder_reconfig` cannot apply; a new encoder
+    /// always starts with an IDR frame. Frames still delayed in the old
+    /// encoder are drained and handed out with the next frame, so none are
+    /// lost.
+    fn rebuild(&mut self) -> EncoderResult<()> {
+        let (encoder, headers) = Self::open(&self.config)?;
+
+        let delayed = self.drain_encoder();
+        self.queued.extend(delayed);
+
+        self.adaptive_quantization = encoder.adaptive_quantization();
+        self.encoder = Some(encoder);
+        self.headers = headers;
+        self.max_output_pts = None;
+
+        debug!(queued = self.queued.len(), "x264 encoder rebuilt");
+        Ok(())
+    }
+
+    /// Take the encoder and collect the frames it still holds.
+    fn drain_encoder(&mut self) -> Vec<EncodedVideoPacket> {
+        let mut packets = Vec::new();
+
+        // Take ownership of the encoder for flushing
+        let mut encoder = match self.encoder.take() {
+            Some(e) => e,
+            None => return packets, // Already flushed
         };
 
-        // Build encoder with NV12 colorspace
-        let mut encoder = setup
-            .build(
-                x264::Colorspace::NV12,
-                config.width as i32,
-                config.height as i32,
-            )
-            .map_err(|e| EncoderError::Initialization(format!("x264 setup failed: {:?}", e)))?;
+        while encoder.delayed_frames() > 0 {
+            match encoder.encode(None) {
+                Ok(Some(picture)) => packets.push(self.output_packet(picture)),
+                Ok(None) => {}
+                Err(e) => {
+                    debug!("Flush ended: {}", e);
+                    break;
+                }
+            }
+        }
 
-        // Get SPS/PPS headers
-        let headers = encoder
-            .headers()
-            .map_or_else(|_| Bytes::new(), |h| Bytes::from(h.entirety().to_vec()));
+        packets
+    }
 
-        debug!(header_size = headers.len(), "x264 encoder initialized");
+    /// Build a packet from encoder output.
+    ///
+    /// x264 times frames by frame number, so both timestamps are looked up
+    /// among the caller's PTS. With B-frames the first DTS fall before any
+    /// input, so they are counted back from the PTS in frame durations.
+    fn output_packet(&mut self, picture: EncodedPicture) -> EncodedVideoPacket {
+        let EncodedPicture {
+            data,
+            keyframe: is_keyframe,
+            pts: pts_frame,
+            dts: dts_frame,
+        } = picture;
 
-        Ok(Self {
-            encoder: Some(encoder),
-            config,
-            frame_count: 0,
-            keyframe_interval,
-            headers,
-        })
+        let frame_duration = 10_000_000 / self.config.fps.max(1) as i64;
+        let pts = self
+            .pending_pts
+            .get(&pts_frame)
+            .map_or(pts_frame * frame_duration, |&pts| pts as i64);
+        let dts = self.pending_pts.get(&dts_frame).map_or_else(
+            || pts - (pts_frame - dts_frame) * frame_duration,
+            |&dts| dts as i64,
+        );
+        // Later frames are presented and decoded no earlier than this DTS
+        self.pending_pts.retain(|&frame, _| frame >= dts_frame);
+
+        // A frame presented before one already output was reordered: a B-frame
+        let frame_type = if is_keyframe {
+            FrameType::I
+        } else if self.max_output_pts.is_some_and(|max| pts < max) {
+            FrameType::B
+        } else {
+            FrameType::P
+        };
+        self.max_output_pts = Some(self.max_output_pts.map_or(pts, |max| max.max(pts)));
+
+        EncodedVideoPacket {
+            data: Bytes::from(data),
+            pts_100ns: pts.max(0) as u64,
+            dts_100ns: dts,
+            is_keyframe,
+            frame_type,
+        }
     }
 }
 
 impl VideoEncoder for X264Encoder {
     #[instrument(name = "x264_encode", skip(self, frame))]
-    fn encode(
-        &mut self,
-        frame: &[u8],
-        pts_100ns: u64,
-    ) -> EncoderResult<Option<EncodedVideoPacket>> {
-        let expected_size = (self.config.width * self.config.height * 3 / 2) as usize;
+    fn encode(&mut self, frame: &[u8], pts_100ns: u64) -> EncoderResult<Vec<EncodedVideoPacket>> {
+        let format = self.config.input_format;
+        let expected_size = format.frame_size(self.config.width, self.config.height);
         if frame.len() != expected_size {
             return Err(EncoderError::InvalidInput(format!(
-                "Expected {} bytes ({}x{} NV12), got {}",
+                "Expected {} bytes ({}x{} {:?}), got {}",
                 expected_size,
                 self.config.width,
                 self.config.height,
+                format,
                 frame.len()
             )));
         }
 
         trace!(frame = self.frame_count, pts = pts_100ns, "Encoding frame");
 
-        // NV12 format: Y plane followed by interleaved UV plane
-        let y_size = (self.config.width * self.config.height) as usize;
-        let uv_size = y_size / 2;
-
-        let y_plane = &frame[..y_size];
-        let uv_plane = &frame[y_size..y_size + uv_size];
-
-        // Create x264 Image from NV12 planes
-        // NV12: Y plane (stride = width), UV plane interleaved (stride = width)
-        let y_stride = self.config.width as i32;
-        let uv_stride = self.config.width as i32;
-
-        let image = x264::Image::new(
-            x264::Colorspace::NV12,
-            self.config.width as i32,
-            self.config.height as i32,
-            &[
-                x264::Plane {
-                    data: y_plane,
-                    stride: y_stride,
-                },
-                x264::Plane {
-                    data: uv_plane,
-                    stride: uv_stride,
-                },
-            ],
-        );
+        let width = self.config.width as usize;
+        let y_size = width * self.config.height as usize;
+
+        // Plane offsets and strides
+        let planes: &[(usize, usize)] = match format {
+            // NV12: Y plane, then interleaved UV at full width stride
+            PixelFormat::Nv12 => &[(0, width), (y_size, width)],
+            // I420: Y plane, then U and V at half width stride
+            PixelFormat::I420 => &[(0, width), (y_size, width / 2), (y_size * 5 / 4, width / 2)],
+        };
 
-        // Convert 100ns units to encoder timebase (fps)
-        // pts_100ns is in 100-nanosecond units
-        let pts = (pts_100ns * self.config.fps as u64) / 10_000_000;
+        let mut picture = MaybeUninit::<x264_sys::x264_picture_t>::uninit();
+        // SAFETY: x264_picture_init sets every field
+        let mut picture = unsafe {
+            x264_sys::x264_picture_init(picture.as_mut_ptr());
+            picture.assume_init()
+        };
+        let frame_number = self.frame_count as i64;
+        picture.i_pts = frame_number;
+        picture.img.i_csp = x264_colorspace(format);
+        picture.img.i_plane = planes.len() as i32;
+        for (i, &(offset, stride)) in planes.iter().enumerate() {
+            // x264 only reads the input planes
+            picture.img.plane[i] = frame[offset..].as_ptr() as *mut u8;
+            picture.img.i_stride[i] = stride as i32;
+        }
+        if let Some(ref mut offsets) = self.quant_offsets {
+            picture.prop.quant_offsets = offsets.as_mut_ptr();
+        }
+        if std::mem::take(&mut self.keyframe_pending) {
+            picture.i_type = x264_sys::X264_TYPE_IDR as i32;
+        }
 
         // Encode the frame
         let encoder = self
             .encoder
             .as_mut()
             .ok_or_else(|| EncoderError::Encoding("Encoder has been flushed".to_string()))?;
-        let (data, picture) = encoder
-            .encode(pts as i64, image)
-            .map_err(|e| EncoderError::Encoding(format!("x264 encode failed: {:?}", e)))?;
+        // Without delay the frame comes straight back out
+        self.pending_pts.insert(frame_number, pts_100ns);
+        let output = encoder.encode(Some(&mut picture))?;
+        self.frame_count += 1;
 
+        // Frames drained by a rebuild go out first
+        let mut packets = std::mem::take(&mut self.queued);
         // If no data was produced, the frame is being buffered
-        if data.len() == 0 {
-            self.frame_count += 1;
-            return Ok(None);
+        if let Some(output) = output {
+            // Output may be an earlier frame than the one just submitted, so
+            // map its timestamps back from the picture
+            packets.push(self.output_packet(output));
         }
+        Ok(packets)
+    }
 
-        // Get all NAL units as a single byte slice
-        let nal_data = data.entirety().to_vec();
+    fn flush(&mut self) -> EncoderResult<Vec<EncodedVideoPacket>> {
+        debug!("Flushing x264 encoder");
 
-        // Use picture's keyframe method for reliable keyframe detection
-        let is_keyframe = picture.keyframe();
+        let mut packets = std::mem::take(&mut self.queued);
+        packets.extend(self.drain_encoder());
 
-        // Determine frame type from picture info or keyframe detection
-        let frame_type = if is_keyframe {
-            FrameType::I
-        } else if self.frame_count == 0 {
-            // First frame should be a keyframe
-            FrameType::I
-        } else {
-            FrameType::P
-        };
+        debug!(delayed_frames = packets.len(), "x264 encoder flushed");
 
-        // Get DTS from picture (convert back to 100ns units)
-        let dts_100ns = (picture.dts() as u64 * 10_000_000) / self.config.fps as u64;
+        Ok(packets)
+    }
 
-        let packet = EncodedVideoPacket {
-            data: Bytes::from(nal_data),
-            pts_100ns,
-            dts_100ns,
-            is_keyframe,
-            frame_type,
-        };
+    fn reconfigure(&mut self, params: &VideoEncoderParams) -> EncoderResult<()> {
+        let mut config = self.config.clone();
+        if !config.apply(params) {
+            return Ok(());
+        }
+        check_regions_of_interest(&config)?;
 
-        self.frame_count += 1;
+        info!(
+            bitrate_kbps = config.bitrate_kbps,
+            rate_control = ?config.rate_control,
+            keyframe_interval_secs = config.keyframe_interval_secs,
+            "Reconfiguring x264 encoder"
+        );
 
-        Ok(Some(packet))
-    }
+        if can_reconfig(&self.config, &config) {
+            if let Some(ref mut encoder) = self.encoder {
+                encoder.reconfig(&config)?;
+                self.config = config;
+                return Ok(());
+            }
+        }
 
-    fn flush(&mut self) -> EncoderResult<Vec<EncodedVideoPacket>> {
-        debug!("Flushing x264 encoder");
+        // Keep the old encoder running if the new settings are rejected
+        let previous = std::mem::replace(&mut self.config, config);
+        if let Err(e) = self.rebuild() {
+            self.config = previous;
+            return Err(e);
+        }
+        Ok(())
+    }
 
-        let mut packets = Vec::new();
+    fn request_keyframe(&mut self) {
+        debug!("Keyframe requested");
+        self.keyframe_pending = true;
+    }
 
-        // Take ownership of the encoder for flushing
-        let encoder = match self.encoder.take() {
-            Some(e) => e,
-            None => return Ok(packets), // Already flushed
+    fn set_regions_of_interest(&mut self, regions: &[RegionOfInterest]) -> EncoderResult<()> {
+        let config = VideoEncoderConfig {
+            regions_of_interest: regions.to_vec(),
+            ..self.config.clone()
         };
-        let mut flush = encoder.flush();
-
-        loop {
-            match flush.next() {
-                Some(Ok((data, picture))) => {
-                    if data.len() > 0 {
-                        let nal_data = data.entirety().to_vec();
-                        let is_keyframe = picture.keyframe();
-
-                        let pts_100ns =
-                            (picture.pts() as u64 * 10_000_000) / self.config.fps as u64;
-                        let dts_100ns =
-                            (picture.dts() as u64 * 10_000_000) / self.config.fps as u64;
-
-                        packets.push(EncodedVideoPacket {
-                            data: Bytes::from(nal_data),
-                            pts_100ns,
-                            dts_100ns,
-                            is_keyframe,
-                            frame_type: if is_keyframe {
-                                FrameType::I
-                            } else {
-                                FrameType::P
-                            },
-                        });
-                    }
-                }
-                Some(Err(e)) => {
-                    debug!("Flush iteration ended: {:?}", e);
-                    break;
-                }
-                None => break,
-            }
+        check_regions_of_interest(&config)?;
+        if !regions.is_empty() && !self.adaptive_quantization {
+            return Err(EncoderError::NotSupported(
+                "Regions of interest need adaptive quantization, which the preset and tune \
+                 turned off; set them before the encoder opens"
+                    .to_string(),
+            ));
         }
 
-        Ok(packets)
+        debug!(regions = regions.len(), "Regions of interest updated");
+        self.quant_offsets = quant_offsets(regions, config.width, config.height);
+        self.config = config;
+        Ok(())
     }
 
     fn is_hardware_accelerated(&self) -> bool {
@@ -234,6 +700,10 @@ impl VideoEncoder for X264Encoder {
         "x264"
     }
 
+    fn codec(&self) -> VideoCodec {
+        VideoCodec::H264
+    }
+
     fn get_headers(&self) -> Option<Bytes> {
         if self.headers.is_empty() {
             None
@@ -250,6 +720,430 @@ impl Drop for X264Encoder {
     }
 }
 
-// SAFETY: x264::Encoder uses raw pointers internally but is designed for
-// single-threaded use. The encoder is only accessed from one thread at a time.
-unsafe impl Send for X264Encoder {}
+// SAFETY: an x264 encoder is not tied to the thread that opened it, and is
+// only accessed from one thread at a time.
+unsafe impl Send for RawEncoder {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: u32 = 320;
+    const HEIGHT: u32 = 240;
+    const FPS: u32 = 30;
+    const SECONDS: usize = 6;
+
+    /// NV12 frame of noise, which gives the rate control no easy frames.
+    fn noise_frame(index: u32) -> Vec<u8> {
+        let mut state = index.wrapping_mul(0x9E37_79B9) | 1;
+        (0..WIDTH * HEIGHT * 3 / 2)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state >> 24) as u8
+            })
+            .collect()
+    }
+
+    /// Encode noise and return the output bitrate of each second in kbps.
+    fn kbps_per_second(bitrate_kbps: u32, rate_control: RateControl) -> Vec<u32> {
+        let mut encoder = X264Encoder::new(VideoEncoderConfig {
+            width: WIDTH,
+            height: HEIGHT,
+            fps: FPS,
+            bitrate_kbps,
+            rate_control,
+            ..Default::default()
+        })
+        .unwrap();
+
+        let mut bytes = [0usize; SECONDS];
+        let mut count = |packet: EncodedVideoPacket| {
+            bytes[(packet.pts_100ns / 10_000_000) as usize] += packet.data.len();
+        };
+        for i in 0..SECONDS as u32 * FPS {
+            let pts_100ns = i as u64 * 10_000_000 / FPS as u64;
+            encoder
+                .encode(&noise_frame(i), pts_100ns)
+                .unwrap()
+                .into_iter()
+                .for_each(&mut count);
+        }
+        encoder.flush().unwrap().into_iter().for_each(&mut count);
+
+        bytes.iter().map(|&b| (b * 8 / 1000) as u32).collect()
+    }
+
+    #[test]
+    fn test_cbr_output_matches_target() {
+        let kbps = kbps_per_second(
+            800,
+            RateControl::Cbr {
+                vbv_buffer_ms: 1000,
+            },
+        );
+
+        // Skip the first second, where the VBV buffer starts full
+        let average = kbps[1..].iter().sum::<u32>() / (SECONDS as u32 - 1);
+        assert!(
+            (680..=920).contains(&average),
+            "average {} kbps, per second {:?}",
+            average,
+            kbps
+        );
+    }
+
+    #[test]
+    fn test_vbr_stays_under_max_bitrate() {
+        let kbps = kbps_per_second(
+            400,
+            RateControl::Vbr {
+                max_bitrate_kbps: 600,
+                vbv_buffer_ms: 1000,
+            },
+        );
+
+        for &second in &kbps[1..] {
+            assert!(second <= 660, "per second {:?}", kbps);
+        }
+    }
+
+    #[test]
+    fn test_cqp_ignores_bitrate() {
+        let low_qp = kbps_per_second(100, RateControl::Cqp { qp: 18 });
+        let high_qp = kbps_per_second(100, RateControl::Cqp { qp: 40 });
+
+        // Noise at a fixed quantizer is far above the nominal 100 kbps
+        assert!(low_qp[1] > 1000, "per second {:?}", low_qp);
+        assert!(low_qp[1] > high_qp[1] * 2);
+    }
+
+    /// Encode 20 frames of noise, changing the settings before frame 10, and
+    /// return which came out as keyframes.
+    fn keyframes_around_reconfigure(
+        rate_control: RateControl,
+        params: VideoEncoderParams,
+    ) -> Vec<bool> {
+        let mut encoder = X264Encoder::new(VideoEncoderConfig {
+            width: WIDTH,
+            height: HEIGHT,
+            fps: FPS,
+            rate_control,
+            ..Default::default()
+        })
+        .unwrap();
+
+        let mut packets = Vec::new();
+        for i in 0..20 {
+            if i == 10 {
+                encoder.reconfigure(&params).unwrap();
+            }
+            let pts_100ns = i as u64 * 10_000_000 / FPS as u64;
+            packets.extend(encoder.encode(&noise_frame(i), pts_100ns).unwrap());
+        }
+        packets.extend(encoder.flush().unwrap());
+        assert_eq!(packets.len(), 20);
+        packets.iter().map(|packet| packet.is_keyframe).collect()
+    }
+
+    #[test]
+    fn test_bitrate_change_keeps_gop() {
+        let keyframes = keyframes_around_reconfigure(
+            RateControl::Cbr {
+                vbv_buffer_ms: 1000,
+            },
+            VideoEncoderParams {
+                bitrate_kbps: Some(300),
+                ..Default::default()
+            },
+        );
+        assert!(keyframes[0]);
+        assert!(keyframes[1..].iter().all(|&keyframe| !keyframe));
+    }
+
+    #[test]
+    fn test_rate_control_switch_restarts_gop() {
+        let keyframes = keyframes_around_reconfigure(
+            RateControl::Crf { crf: 23 },
+            VideoEncoderParams {
+                rate_control: Some(RateControl::Cqp { qp: 30 }),
+                ..Default::default()
+            },
+        );
+        assert!(keyframes[0]);
+        assert!(keyframes[10]);
+    }
+
+    #[test]
+    fn test_rebuild_returns_delayed_frames_at_once() {
+        let mut encoder = X264Encoder::new(VideoEncoderConfig {
+            width: WIDTH,
+            height: HEIGHT,
+            fps: FPS,
+            bframes: 3,
+            rate_control: RateControl::Crf { crf: 23 },
+            ..Default::default()
+        })
+        .unwrap();
+        let frame_duration = 10_000_000 / FPS as u64;
+
+        let mut packets = Vec::new();
+        for i in 0..10 {
+            packets.extend(
+                encoder
+                    .encode(&noise_frame(i), i as u64 * frame_duration)
+                    .unwrap(),
+            );
+        }
+        let delayed = 10 - packets.len();
+        assert!(delayed > 1, "{} delayed", delayed);
+
+        // A new rate control method needs a new encoder, and the frames
+        // delayed in the old one all come out with the next frame
+        let to_cbr = VideoEncoderParams {
+            rate_control: Some(RateControl::Cbr {
+                vbv_buffer_ms: 1000,
+            }),
+            ..Default::default()
+        };
+        encoder.reconfigure(&to_cbr).unwrap();
+        let next = encoder
+            .encode(&noise_frame(10), 10 * frame_duration)
+            .unwrap();
+        assert!(next.len() >= delayed, "{} of {}", next.len(), delayed);
+
+        packets.extend(next);
+        packets.extend(encoder.flush().unwrap());
+        assert_eq!(packets.len(), 11);
+    }
+
+    #[test]
+    fn test_pts_pass_through_with_bframes() {
+        let mut encoder = X264Encoder::new(VideoEncoderConfig {
+            width: WIDTH,
+            height: HEIGHT,
+            fps: FPS,
+            bframes: 3,
+            ..Default::default()
+        })
+        .unwrap();
+
+        // 30 fps in 100ns units does not divide evenly, so frames are not a
+        // whole number of frame ticks apart
+        let input: Vec<u64> = (0..FPS as u64)
+            .map(|i| i * 10_000_000 / FPS as u64)
+            .collect();
+        let mut packets = Vec::new();
+        for (i, &pts_100ns) in input.iter().enumerate() {
+            packets.extend(encoder.encode(&noise_frame(i as u32), pts_100ns).unwrap());
+        }
+        packets.extend(encoder.flush().unwrap());
+        assert_eq!(packets.len(), input.len());
+
+        // In decode order: DTS rises, each packet carries one of the input
+        // PTS exactly, and B-frames are the ones presented early
+        let mut seen = Vec::new();
+        let mut max_pts = None;
+        for (i, packet) in packets.iter().enumerate() {
+            assert!(input.contains(&packet.pts_100ns), "{}", packet.pts_100ns);
+            assert!(!seen.contains(&packet.pts_100ns), "{}", packet.pts_100ns);
+            assert!(packet.dts_100ns <= packet.pts_100ns as i64);
+            if i > 0 {
+                assert!(packet.dts_100ns > packets[i - 1].dts_100ns);
+            }
+            let reordered = max_pts.is_some_and(|max| packet.pts_100ns < max);
+            assert_eq!(packet.frame_type == FrameType::B, reordered);
+            max_pts = max_pts.max(Some(packet.pts_100ns));
+            seen.push(packet.pts_100ns);
+        }
+    }
+
+    #[test]
+    fn test_quant_offsets_cover_touched_macroblocks() {
+        assert!(quant_offsets(&[], WIDTH, HEIGHT).is_none());
+
+        let region = |x, y, width, height, quality_offset| RegionOfInterest {
+            x,
+            y,
+            width,
+            height,
+            quality_offset,
+        };
+        // 20x15 macroblocks; the first region spans macroblocks 1..=2 in
+        // both directions, the second overrides its corner and runs off the
+        // frame
+        let offsets = quant_offsets(
+            &[region(20, 16, 20, 17, 6), region(32, 32, 400, 400, -3)],
+            WIDTH,
+            HEIGHT,
+        )
+        .unwrap();
+        assert_eq!(offsets.len(), 20 * 15);
+
+        let at = |mb_x: usize, mb_y: usize| offsets[mb_y * 20 + mb_x];
+        assert_eq!(at(0, 0), 0.0);
+        assert_eq!(at(1, 1), -6.0);
+        assert_eq!(at(2, 1), -6.0);
+        assert_eq!(at(1, 2), -6.0);
+        assert_eq!(at(3, 1), 0.0);
+        assert_eq!(at(2, 2), 3.0);
+        assert_eq!(at(19, 14), 3.0);
+        assert_eq!(at(1, 3), 0.0);
+    }
+
+    /// Region of interest over the top half of the frame.
+    fn top_half(quality_offset: i8) -> RegionOfInterest {
+        RegionOfInterest {
+            x: 0,
+            y: 0,
+            width: WIDTH,
+            height: HEIGHT / 2,
+            quality_offset,
+        }
+    }
+
+    /// Encode a second of noise and return the bytes output.
+    fn encoded_bytes(config: VideoEncoderConfig) -> usize {
+        let mut encoder = X264Encoder::new(VideoEncoderConfig {
+            width: WIDTH,
+            height: HEIGHT,
+            fps: FPS,
+            ..config
+        })
+        .unwrap();
+
+        let mut bytes = 0;
+        for i in 0..FPS {
+            let pts_100ns = i as u64 * 10_000_000 / FPS as u64;
+            for packet in encoder.encode(&noise_frame(i), pts_100ns).unwrap() {
+                bytes += packet.data.len();
+            }
+        }
+        for packet in encoder.flush().unwrap() {
+            bytes += packet.data.len();
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_regions_of_interest_change_region_quality() {
+        // Ultrafast turns adaptive quantization off, which x264 needs on to
+        // apply quantizer offsets at all
+        let config = |regions_of_interest| VideoEncoderConfig {
+            preset: EncoderPreset::Ultrafast,
+            rate_control: RateControl::Crf { crf: 23 },
+            regions_of_interest,
+            ..Default::default()
+        };
+        let plain = encoded_bytes(config(vec![]));
+        let sharper = encoded_bytes(config(vec![top_half(12)]));
+        let softer = encoded_bytes(config(vec![top_half(-12)]));
+
+        // CRF does not move bits between macroblocks, so the difference is
+        // all in the region
+        assert!(sharper > plain * 5 / 4, "{} vs {}", sharper, plain);
+        assert!(softer < plain * 4 / 5, "{} vs {}", softer, plain);
+    }
+
+    #[test]
+    fn test_regions_of_interest_rejected_with_cqp() {
+        let cqp = VideoEncoderConfig {
+            width: WIDTH,
+            height: HEIGHT,
+            fps: FPS,
+            rate_control: RateControl::Cqp { qp: 23 },
+            ..Default::default()
+        };
+        assert!(matches!(
+            X264Encoder::new(VideoEncoderConfig {
+                regions_of_interest: vec![top_half(12)],
+                ..cqp.clone()
+            }),
+            Err(EncoderError::NotSupported(_))
+        ));
+
+        let mut encoder = X264Encoder::new(cqp.clone()).unwrap();
+        assert!(encoder.set_regions_of_interest(&[top_half(12)]).is_err());
+        assert!(encoder.quant_offsets.is_none());
+        assert!(encoder.config.regions_of_interest.is_empty());
+
+        let mut encoder = X264Encoder::new(VideoEncoderConfig {
+            regions_of_interest: vec![top_half(12)],
+            rate_control: RateControl::Crf { crf: 23 },
+            ..cqp
+        })
+        .unwrap();
+        let to_cqp = VideoEncoderParams {
+            rate_control: Some(RateControl::Cqp { qp: 23 }),
+            ..Default::default()
+        };
+        assert!(encoder.reconfigure(&to_cqp).is_err());
+        assert_eq!(encoder.config.rate_control, RateControl::Crf { crf: 23 });
+    }
+
+    #[test]
+    fn test_regions_of_interest_need_adaptive_quantization() {
+        // Ultrafast turns adaptive quantization off and keeps it off
+        // without regions at open
+        let ultrafast = VideoEncoderConfig {
+            width: WIDTH,
+            height: HEIGHT,
+            fps: FPS,
+            preset: EncoderPreset::Ultrafast,
+            ..Default::default()
+        };
+        let mut encoder = X264Encoder::new(ultrafast.clone()).unwrap();
+        assert!(!encoder.adaptive_quantization);
+        assert!(matches!(
+            encoder.set_regions_of_interest(&[top_half(12)]),
+            Err(EncoderError::NotSupported(_))
+        ));
+        assert!(encoder.quant_offsets.is_none());
+        assert!(encoder.set_regions_of_interest(&[]).is_ok());
+
+        // Opened with regions, they can be cleared and set again
+        let mut encoder = X264Encoder::new(VideoEncoderConfig {
+            regions_of_interest: vec![top_half(12)],
+            ..ultrafast
+        })
+        .unwrap();
+        assert!(encoder.adaptive_quantization);
+        encoder.set_regions_of_interest(&[]).unwrap();
+        encoder.set_regions_of_interest(&[top_half(-12)]).unwrap();
+        assert!(encoder.quant_offsets.is_some());
+    }
+
+    #[test]
+    fn test_regions_of_interest_update_while_encoding() {
+        let config = VideoEncoderConfig {
+            width: WIDTH,
+            height: HEIGHT,
+            fps: FPS,
+            regions_of_interest: vec![RegionOfInterest {
+                x: 0,
+                y: 0,
+                width: WIDTH / 2,
+                height: HEIGHT / 2,
+                quality_offset: 8,
+            }],
+            ..Default::default()
+        };
+        let mut encoder = X264Encoder::new(config).unwrap();
+        let frame_duration = 10_000_000 / FPS as u64;
+
+        let mut packets = 0;
+        for i in 0..FPS {
+            if i == FPS / 2 {
+                encoder.set_regions_of_interest(&[]).unwrap();
+                assert!(encoder.quant_offsets.is_none());
+            }
+            packets += encoder
+                .encode(&noise_frame(i), i as u64 * frame_duration)
+                .unwrap()
+                .len();
+        }
+        packets += encoder.flush().unwrap().len();
+        assert_eq!(packets, FPS as usize);
+    }
+}
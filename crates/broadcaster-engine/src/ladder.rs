@@ -0,0 +1,321 @@
+//! Multi-rendition encoding.
+//!
+//! Each rendition has its own encoder, fed from the same captured frames.
+//! Renditions at a lower frame rate take every n-th frame, so all of them
+//! share the main stream's frame grid and timestamps. Keyframes stay aligned
+//! because every encoder counts the same keyframe interval over the same
+//! frames; [`LadderSchedule`] forces a keyframe on the shared grid wherever
+//! a dropped frame or a keyframe request would otherwise let one encoder's
+//! count drift from the others.
+
+use broadcaster_encoder::{RateControl, RegionOfInterest, VideoBackend, VideoEncoderConfig};
+use broadcaster_ipc::RenditionConfig;
+
+/// Rendition ID of the main stream in metrics.
+pub(crate) const MAIN_RENDITION_ID: &str = "main";
+
+/// What one rendition does with a captured frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FrameAction {
+    /// Not part of this rendition's frame rate.
+    Skip,
+    /// Encode the frame.
+    Encode,
+    /// Encode the frame as a requested keyframe.
+    Keyframe,
+}
+
+/// Decides which captured frames each rendition encodes and where keyframes
+/// must be forced to keep the renditions aligned.
+pub(crate) struct LadderSchedule {
+    /// Captured frames per encoded frame, per rendition.
+    steps: Vec<u32>,
+    /// Captured frames per GOP, or 0 for keyframes only on request.
+    gop_frames: u32,
+    /// Captured frames since the current GOP started.
+    index: u32,
+    /// Renditions that dropped a frame in the current GOP.
+    drifted: Vec<bool>,
+    keyframe_requested: bool,
+}
+
+impl LadderSchedule {
+    /// Schedule renditions taking every `steps[i]`-th frame, with a GOP of
+    /// `gop_frames` captured frames.
+    pub(crate) fn new(steps: Vec<u32>, gop_frames: u32) -> Self {
+        let drifted = vec![false; steps.len()];
+        Self {
+            steps,
+            gop_frames,
+            index: 0,
+            drifted,
+            keyframe_requested: false,
+        }
+    }
+
+    /// Change the GOP length, e.g. after the keyframe interval changed.
+    ///
+    /// The encoders restart their GOPs when reconfigured, so this also
+    /// requests a keyframe to bring them back in step.
+    pub(crate) fn set_gop_frames(&mut self, gop_frames: u32) {
+        self.gop_frames = gop_frames;
+        self.request_keyframe();
+    }
+
+    /// Make the next captured frame a keyframe in every rendition.
+    pub(crate) fn request_keyframe(&mut self) {
+        self.keyframe_requested = true;
+    }
+
+    /// Record that `rendition` dropped a frame it should have encoded.
+    ///
+    /// Its encoder now counts one frame behind the others, so it gets a
+    /// forced keyframe at the next GOP boundary. A single rendition has
+    /// nothing to stay aligned with and is left alone.
+    pub(crate) fn record_drop(&mut self, rendition: usize) {
+        if self.steps.len() > 1 {
+            self.drifted[rendition] = true;
+        }
+    }
+
+    /// Advance by one captured frame, returning what each rendition does
+    /// with it.
+    pub(crate) fn next_frame(&mut self) -> Vec<FrameAction> {
+        let forced = std::mem::take(&mut self.keyframe_requested);
+        if forced {
+            // Every encoder restarts its GOP at the forced keyframe
+            self.index = 0;
+        }
+        let boundary = self.index == 0;
+
+        let actions = self
+            .steps
+            .iter()
+            .zip(&mut self.drifted)
+            .map(|(&step, drifted)| {
+                if !self.index.is_multiple_of(step) {
+                    return FrameAction::Skip;
+                }
+                let drifted = boundary && std::mem::take(drifted);
+                if forced || drifted {
+                    FrameAction::Keyframe
+                } else {
+                    FrameAction::Encode
+                }
+            })
+            .collect();
+
+        self.index += 1;
+        if self.gop_frames > 0 && self.index >= self.gop_frames {
+            self.index = 0;
+        }
+        actions
+    }
+}
+
+/// Captured frames per encoded frame for a rendition at `fps` within a
+/// stream at `main_fps`, if it divides evenly.
+pub(crate) fn frame_step(main_fps: u32, fps: u32) -> Option<u32> {
+    (fps > 0 && main_fps.is_multiple_of(fps)).then(|| main_fps / fps)
+}
+
+/// Scale regions of interest from a frame of size `from` to one of size `to`.
+///
+/// Edges are rounded outward so a scaled region still covers all of what it
+/// covered before.
+pub(crate) fn scale_regions(
+    regions: &[RegionOfInterest],
+    from: (u32, u32),
+    to: (u32, u32),
+) -> Vec<RegionOfInterest> {
+    let scale = |start: u32, len: u32, from: u32, to: u32| {
+        let from = from.max(1) as u64;
+        let to = to as u64;
+        let start_scaled = start as u64 * to / from;
+        let end_scaled = ((start as u64 + len as u64) * to).div_ceil(from).min(to);
+        (
+            start_scaled as u32,
+            end_scaled.saturating_sub(start_scaled) as u32,
+        )
+    };
+
+    regions
+        .iter()
+        .map(|region| {
+            let (x, width) = scale(region.x, region.width, from.0, to.0);
+            let (y, height) = scale(region.y, region.height, from.1, to.1);
+            RegionOfInterest {
+                x,
+                y,
+                width,
+                height,
+                ..*region
+            }
+        })
+        .collect()
+}
+
+/// Encoder settings of a rendition, derived from the main stream's.
+///
+/// The rendition uses the same backend as the main encoder so it does not
+/// benchmark every backend again. A VBR cap is scaled with the bitrate,
+/// regions of interest with the frame, and the level is left to the encoder
+/// as the main stream's may not fit.
+pub(crate) fn rendition_encoder_config(
+    main: &VideoEncoderConfig,
+    rendition: &RenditionConfig,
+    backend: Option<VideoBackend>,
+) -> VideoEncoderConfig {
+    let rate_control = match main.rate_control {
+        RateControl::Vbr {
+            max_bitrate_kbps,
+            vbv_buffer_ms,
+        } => RateControl::Vbr {
+            max_bitrate_kbps: (max_bitrate_kbps as u64 * rendition.video_bitrate_kbps as u64
+                / main.bitrate_kbps.max(1) as u64) as u32,
+            vbv_buffer_ms,
+        },
+        rate_control => rate_control,
+    };
+
+    VideoEncoderConfig {
+        width: rendition.width,
+        height: rendition.height,
+        fps: rendition.fps,
+        bitrate_kbps: rendition.video_bitrate_kbps,
+        rate_control,
+        level_idc: None,
+        backend,
+        regions_of_interest: scale_regions(
+            &main.regions_of_interest,
+            (main.width, main.height),
+            (rendition.width, rendition.height),
+        ),
+        ..main.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use FrameAction::{Encode, Keyframe, Skip};
+
+    #[test]
+    fn test_lower_frame_rates_take_every_nth_frame() {
+        // 60, 30 and 20 fps renditions of a 60 fps stream, GOP of 6 frames
+        let mut schedule = LadderSchedule::new(vec![1, 2, 3], 6);
+        let frames: Vec<_> = (0..7).map(|_| schedule.next_frame()).collect();
+
+        assert_eq!(frames[0], vec![Encode, Encode, Encode]);
+        assert_eq!(frames[1], vec![Encode, Skip, Skip]);
+        assert_eq!(frames[2], vec![Encode, Encode, Skip]);
+        assert_eq!(frames[3], vec![Encode, Skip, Encode]);
+        // The next GOP starts on a frame every rendition encodes
+        assert_eq!(frames[6], vec![Encode, Encode, Encode]);
+    }
+
+    #[test]
+    fn test_drop_forces_keyframe_at_next_boundary() {
+        let mut schedule = LadderSchedule::new(vec![1, 2], 4);
+        schedule.next_frame();
+        schedule.record_drop(1);
+
+        for _ in 1..4 {
+            assert!(!schedule.next_frame().contains(&Keyframe));
+        }
+        assert_eq!(schedule.next_frame(), vec![Encode, Keyframe]);
+        // Only once
+        for _ in 0..4 {
+            assert!(!schedule.next_frame().contains(&Keyframe));
+        }
+    }
+
+    #[test]
+    fn test_single_rendition_ignores_drops() {
+        let mut schedule = LadderSchedule::new(vec![1], 2);
+        schedule.record_drop(0);
+        for _ in 0..4 {
+            assert_eq!(schedule.next_frame(), vec![Encode]);
+        }
+    }
+
+    #[test]
+    fn test_requested_keyframe_restarts_gop() {
+        let mut schedule = LadderSchedule::new(vec![1, 2], 4);
+        schedule.next_frame();
+        schedule.request_keyframe();
+
+        // Forced on the next frame in every rendition, even mid-step
+        assert_eq!(schedule.next_frame(), vec![Keyframe, Keyframe]);
+        assert_eq!(schedule.next_frame(), vec![Encode, Skip]);
+        assert_eq!(schedule.next_frame(), vec![Encode, Encode]);
+    }
+
+    #[test]
+    fn test_frame_step() {
+        assert_eq!(frame_step(60, 30), Some(2));
+        assert_eq!(frame_step(60, 60), Some(1));
+        assert_eq!(frame_step(60, 25), None);
+        assert_eq!(frame_step(60, 0), None);
+    }
+
+    #[test]
+    fn test_rendition_config_scales_vbr_cap() {
+        let main = VideoEncoderConfig {
+            bitrate_kbps: 6000,
+            rate_control: RateControl::Vbr {
+                max_bitrate_kbps: 9000,
+                vbv_buffer_ms: 2000,
+            },
+            level_idc: Some(42),
+            ..Default::default()
+        };
+        let rendition = RenditionConfig {
+            id: "480p30".into(),
+            width: 854,
+            height: 480,
+            fps: 30,
+            video_bitrate_kbps: 1200,
+            rtmp_url: None,
+            stream_key: "key".into(),
+        };
+
+        let config = rendition_encoder_config(&main, &rendition, Some(VideoBackend::X264));
+        assert_eq!((config.width, config.height, config.fps), (854, 480, 30));
+        assert_eq!(
+            config.rate_control,
+            RateControl::Vbr {
+                max_bitrate_kbps: 1800,
+                vbv_buffer_ms: 2000,
+            }
+        );
+        assert_eq!(config.level_idc, None);
+        assert_eq!(config.keyframe_interval_secs, main.keyframe_interval_secs);
+        assert_eq!(config.backend, Some(VideoBackend::X264));
+    }
+
+    #[test]
+    fn test_regions_scale_outward() {
+        let region = |x, y, width, height| RegionOfInterest {
+            x,
+            y,
+            width,
+            height,
+            quality_offset: 6,
+        };
+        let regions = [
+            region(0, 0, 1920, 1080),
+            region(100, 50, 3, 1),
+            region(1900, 1000, 20, 80),
+        ];
+
+        // 1080p to 480p
+        let scaled = scale_regions(&regions, (1920, 1080), (854, 480));
+        assert_eq!(scaled[0], region(0, 0, 854, 480));
+        // 44.48..45.81 and 22.22..22.67
+        assert_eq!(scaled[1], region(44, 22, 2, 1));
+        assert_eq!(scaled[2], region(845, 444, 9, 36));
+        assert!(scaled.iter().all(|region| region.quality_offset == 6));
+    }
+}
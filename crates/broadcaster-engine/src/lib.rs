@@ -3,17 +3,30 @@
 //! This crate coordinates capture, audio, encoding, and transport
 //! subsystems to provide a unified streaming engine.
 
+// Only the pipeline drives the ladder and scaler, but they are tested on
+// every platform
+#[cfg_attr(not(windows), allow(dead_code))]
+mod ladder;
 mod metrics;
 #[cfg(windows)]
 mod orchestrator;
 #[cfg(windows)]
+mod pipeline;
+#[cfg_attr(not(windows), allow(dead_code))]
+mod scale;
+#[cfg(windows)]
 mod state;
+mod validation;
 
 pub use metrics::MetricsCollector;
 #[cfg(windows)]
 pub use orchestrator::Engine;
 #[cfg(windows)]
 pub use state::{InitializedResources, ResourceManager};
+pub use validation::{
+    build_stream_metadata, validate_encoder_config, validate_regions_of_interest,
+    validate_renditions, validate_video_headers,
+};
 
 use broadcaster_ipc::{EngineCommand, EngineEvent};
 use crossbeam_channel::{Receiver, Sender};
@@ -1,11 +1,61 @@
 //! Metrics collection and reporting.
 
+use std::collections::VecDeque;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 
-use broadcaster_ipc::{StreamMetrics, WarningType};
+use broadcaster_ipc::{QueueDepths, RenditionMetrics, StreamMetrics, WarningType};
+
+/// Length of the encode time window in seconds of video.
+const ENCODE_WINDOW_SECS: f32 = 2.0;
+
+/// Encoder load above which an overload warning is raised.
+const ENCODER_OVERLOAD_PERCENT: f32 = 90.0;
+
+/// Encode times of the most recent frames.
+struct EncodeTimes {
+    samples: VecDeque<Duration>,
+    capacity: usize,
+}
+
+impl EncodeTimes {
+    fn new(capacity: usize) -> Self {
+        Self {
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    fn push(&mut self, time: Duration) {
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(time);
+    }
+
+    fn mean(&self) -> Duration {
+        if self.samples.is_empty() {
+            return Duration::ZERO;
+        }
+        self.samples.iter().sum::<Duration>() / self.samples.len() as u32
+    }
+
+    /// Nearest-rank percentiles for each of `percents`.
+    fn percentiles<const N: usize>(&self, percents: [f32; N]) -> [Duration; N] {
+        let mut sorted: Vec<_> = self.samples.iter().copied().collect();
+        sorted.sort_unstable();
+
+        percents.map(|percent| {
+            if sorted.is_empty() {
+                return Duration::ZERO;
+            }
+            let rank = (percent / 100.0 * sorted.len() as f32).ceil() as usize;
+            sorted[rank.clamp(1, sorted.len()) - 1]
+        })
+    }
+}
 
 /// Collects and reports stream metrics.
 pub struct MetricsCollector {
@@ -20,7 +70,9 @@ pub struct MetricsCollector {
     target_fps: f32,
     target_bitrate_kbps: u32,
     encoder_load: RwLock<f32>,
+    encode_times: Mutex<EncodeTimes>,
     buffer_fullness: RwLock<f32>,
+    queue_depths: RwLock<QueueDepths>,
 }
 
 impl MetricsCollector {
@@ -38,7 +90,11 @@ impl MetricsCollector {
             target_fps,
             target_bitrate_kbps,
             encoder_load: RwLock::new(0.0),
+            encode_times: Mutex::new(EncodeTimes::new(
+                (target_fps * ENCODE_WINDOW_SECS).max(1.0) as usize
+            )),
             buffer_fullness: RwLock::new(0.0),
+            queue_depths: RwLock::new(QueueDepths::default()),
         }
     }
 
@@ -83,11 +139,41 @@ impl MetricsCollector {
         *self.encoder_load.write() = load.clamp(0.0, 100.0);
     }
 
+    /// Record how long one frame took to encode.
+    ///
+    /// The encoder load is the average over the recent window relative to
+    /// the frame interval, so single slow frames do not trip the warning.
+    pub fn record_encode_time(&self, time: Duration) {
+        let mean = {
+            let mut encode_times = self.encode_times.lock();
+            encode_times.push(time);
+            encode_times.mean()
+        };
+
+        if self.target_fps > 0.0 {
+            let frame_budget = 1.0 / self.target_fps;
+            self.update_encoder_load(mean.as_secs_f32() / frame_budget * 100.0);
+        }
+    }
+
+    /// Run an encode call and record its duration.
+    pub fn time_encode<T>(&self, encode: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = encode();
+        self.record_encode_time(start.elapsed());
+        result
+    }
+
     /// Update buffer fullness percentage.
     pub fn update_buffer_fullness(&self, fullness: f32) {
         *self.buffer_fullness.write() = fullness.clamp(0.0, 100.0);
     }
 
+    /// Update the number of items waiting in each pipeline queue.
+    pub fn update_queue_depths(&self, depths: QueueDepths) {
+        *self.queue_depths.write() = depths;
+    }
+
     /// Get current metrics snapshot.
     pub fn snapshot(&self) -> StreamMetrics {
         let now = Instant::now();
@@ -124,6 +210,10 @@ impl MetricsCollector {
             .map(|s| now.duration_since(s).as_secs())
             .unwrap_or(0);
 
+        let [encode_p50, encode_p95, encode_p99] =
+            self.encode_times.lock().percentiles([50.0, 95.0, 99.0]);
+        let as_ms = |time: Duration| time.as_secs_f32() * 1000.0;
+
         let capture_drops = self.capture_drops.load(Ordering::Relaxed);
         let encode_drops = self.encode_drops.load(Ordering::Relaxed);
         let network_drops = self.network_drops.load(Ordering::Relaxed);
@@ -138,8 +228,32 @@ impl MetricsCollector {
             encode_drops,
             network_drops,
             encoder_load_percent: *self.encoder_load.read(),
+            encode_time_p50_ms: as_ms(encode_p50),
+            encode_time_p95_ms: as_ms(encode_p95),
+            encode_time_p99_ms: as_ms(encode_p99),
             buffer_fullness_percent: *self.buffer_fullness.read(),
+            queue_depths: *self.queue_depths.read(),
             uptime_seconds,
+            renditions: Vec::new(),
+        }
+    }
+
+    /// Metrics of one rendition, for a collector that covers only that
+    /// rendition's encoder and output.
+    pub fn rendition_snapshot(&self, id: &str, width: u32, height: u32) -> RenditionMetrics {
+        let snapshot = self.snapshot();
+        RenditionMetrics {
+            id: id.to_string(),
+            width,
+            height,
+            fps: snapshot.fps,
+            target_fps: snapshot.target_fps,
+            bitrate_kbps: snapshot.bitrate_kbps,
+            target_bitrate_kbps: snapshot.target_bitrate_kbps,
+            encode_drops: snapshot.encode_drops,
+            encoder_load_percent: snapshot.encoder_load_percent,
+            encode_time_p95_ms: snapshot.encode_time_p95_ms,
+            buffer_fullness_percent: snapshot.buffer_fullness_percent,
         }
     }
 
@@ -148,7 +262,7 @@ impl MetricsCollector {
         let mut warnings = Vec::new();
 
         let encoder_load = *self.encoder_load.read();
-        if encoder_load > 90.0 {
+        if encoder_load > ENCODER_OVERLOAD_PERCENT {
             warnings.push(WarningType::EncoderOverload {
                 load_percent: encoder_load,
             });
@@ -177,3 +291,110 @@ impl Default for MetricsCollector {
         Self::new(60.0, 6000)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use bytes::Bytes;
+
+    use broadcaster_encoder::{
+        EncodedVideoPacket, EncoderResult, VideoCodec, VideoEncoder, VideoEncoderParams,
+    };
+
+    use super::*;
+
+    /// Encoder that takes a fixed time per frame.
+    struct MockEncoder {
+        delay: Duration,
+    }
+
+    impl VideoEncoder for MockEncoder {
+        fn encode(
+            &mut self,
+            _frame: &[u8],
+            _pts_100ns: u64,
+        ) -> EncoderResult<Vec<EncodedVideoPacket>> {
+            thread::sleep(self.delay);
+            Ok(Vec::new())
+        }
+
+        fn flush(&mut self) -> EncoderResult<Vec<EncodedVideoPacket>> {
+            Ok(Vec::new())
+        }
+
+        fn reconfigure(&mut self, _params: &VideoEncoderParams) -> EncoderResult<()> {
+            Ok(())
+        }
+
+        fn request_keyframe(&mut self) {}
+
+        fn is_hardware_accelerated(&self) -> bool {
+            false
+        }
+
+        fn name(&self) -> &'static str {
+            "mock"
+        }
+
+        fn codec(&self) -> VideoCodec {
+            VideoCodec::H264
+        }
+
+        fn get_headers(&self) -> Option<Bytes> {
+            None
+        }
+    }
+
+    /// Encode `frames` frames at 100 fps (10 ms budget).
+    fn run(delay: Duration, frames: u64) -> MetricsCollector {
+        let metrics = MetricsCollector::new(100.0, 6000);
+        let mut encoder = MockEncoder { delay };
+        for pts in 0..frames {
+            metrics
+                .time_encode(|| encoder.encode(&[], pts * 100_000))
+                .unwrap();
+        }
+        metrics
+    }
+
+    #[test]
+    fn test_slow_encoder_raises_overload() {
+        let metrics = run(Duration::from_millis(15), 20);
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.encoder_load_percent, 100.0);
+        assert!(snapshot.encode_time_p50_ms >= 15.0);
+        assert!(metrics
+            .check_warnings()
+            .iter()
+            .any(|w| matches!(w, WarningType::EncoderOverload { .. })));
+    }
+
+    #[test]
+    fn test_fast_encoder_has_headroom() {
+        let metrics = run(Duration::from_millis(1), 20);
+
+        let snapshot = metrics.snapshot();
+        assert!(snapshot.encoder_load_percent < ENCODER_OVERLOAD_PERCENT);
+        assert!(metrics.check_warnings().is_empty());
+    }
+
+    #[test]
+    fn test_encode_time_percentiles() {
+        let metrics = MetricsCollector::new(100.0, 6000);
+        for ms in 1..=100 {
+            metrics.record_encode_time(Duration::from_millis(ms));
+        }
+
+        let snapshot = metrics.snapshot();
+        let percentiles = [
+            snapshot.encode_time_p50_ms,
+            snapshot.encode_time_p95_ms,
+            snapshot.encode_time_p99_ms,
+        ];
+        for (actual, expected) in percentiles.into_iter().zip([50.0, 95.0, 99.0]) {
+            assert!((actual - expected).abs() < 0.01, "{:?}", percentiles);
+        }
+    }
+}
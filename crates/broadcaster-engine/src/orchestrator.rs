@@ -1,29 +1,26 @@
 //! Main engine orchestrator.
 
-use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::thread::{self, JoinHandle};
-use std::time::{Duration, Instant};
+use std::time::Duration;
 
-use bytes::Bytes;
 use crossbeam_channel::{Receiver, Sender};
 use parking_lot::RwLock;
 use tracing::{debug, error, info, instrument, warn};
 
+use crate::metrics::MetricsCollector;
+use crate::pipeline::Pipeline;
+use crate::state::{encoder_info, ui_preset, video_rate_control, video_regions, ResourceManager};
+use crate::validation::validate_encoder_config;
 use broadcaster_audio::enumerate_audio_devices;
-use broadcaster_capture::{enumerate_monitors, enumerate_windows, CapturedFrame};
-use broadcaster_ipc::{
-    EngineCommand, EngineEvent, EngineState, ShutdownPhase, StartupPhase, StopReason, StreamConfig,
-    StreamMetrics,
+use broadcaster_capture::{enumerate_monitors, enumerate_windows};
+use broadcaster_encoder::{
+    benchmark_backend, choose, EncoderError, VideoBackend, VideoEncoderConfig, VideoEncoderParams,
 };
-use broadcaster_transport::{
-    build_avc_decoder_config, build_flv_video_tag, extract_sps_pps, filter_parameter_sets,
-    nals_to_avcc, parse_annex_b, RtmpPacket,
+use broadcaster_ipc::{
+    EncoderSettingsUpdate, EngineCommand, EngineEvent, EngineState, RegionOfInterest,
+    ShutdownPhase, StartupPhase, StopReason, StreamConfig, StreamMetrics, WarningType,
 };
 
-use crate::metrics::MetricsCollector;
-use crate::state::ResourceManager;
-
 /// The main broadcast engine.
 pub struct Engine {
     command_rx: Receiver<EngineCommand>,
@@ -31,8 +28,7 @@ pub struct Engine {
     state: Arc<RwLock<EngineState>>,
     resource_manager: Arc<ResourceManager>,
     metrics: Arc<MetricsCollector>,
-    engine_thread: Option<JoinHandle<()>>,
-    should_stop: Arc<AtomicBool>,
+    pipeline: Option<Pipeline>,
 }
 
 impl Engine {
@@ -44,8 +40,7 @@ impl Engine {
             state: Arc::new(RwLock::new(EngineState::Idle)),
             resource_manager: Arc::new(ResourceManager::new()),
             metrics: Arc::new(MetricsCollector::default()),
-            engine_thread: None,
-            should_stop: Arc::new(AtomicBool::new(false)),
+            pipeline: None,
         }
     }
 
@@ -83,14 +78,23 @@ impl Engine {
         debug!(?command, "Handling command");
 
         match command {
-            EngineCommand::Start { config } => self.start_stream(config),
+            EngineCommand::Start { config } => self.start_stream(*config),
             EngineCommand::Stop => self.stop_stream(StopReason::UserRequested),
             EngineCommand::SetMicVolume(volume) => self.set_mic_volume(volume),
             EngineCommand::SetSystemVolume(volume) => self.set_system_volume(volume),
             EngineCommand::SetMicMuted(muted) => self.set_mic_muted(muted),
             EngineCommand::SetSystemMuted(muted) => self.set_system_muted(muted),
+            EngineCommand::UpdateEncoderSettings { settings } => {
+                self.update_encoder_settings(&settings)
+            }
+            EngineCommand::ForceKeyframe => self.force_keyframe(),
+            EngineCommand::SetRegionsOfInterest { regions } => {
+                self.set_regions_of_interest(regions)
+            }
+            EngineCommand::SendCaption { text } => self.send_caption(&text),
             EngineCommand::GetCaptureSources => self.send_capture_sources(),
             EngineCommand::GetAudioDevices => self.send_audio_devices(),
+            EngineCommand::GetEncoders { benchmark } => self.send_encoders(benchmark),
             EngineCommand::GetState => self.send_state(),
             EngineCommand::Shutdown => {
                 self.stop_stream(StopReason::UserRequested);
@@ -104,7 +108,7 @@ impl Engine {
 
     /// Start streaming.
     #[instrument(name = "start_stream", skip(self, config))]
-    fn start_stream(&mut self, config: StreamConfig) {
+    fn start_stream(&mut self, mut config: StreamConfig) {
         // Idempotent: ignore if already starting or live
         {
             let state = self.state.read();
@@ -125,18 +129,41 @@ impl Engine {
             .initialize(&config, StartupPhase::StartTransmission)
         {
             Ok(()) => {
+                // Report a preset lowered by the startup benchmark
+                let selected = self
+                    .resource_manager
+                    .resources()
+                    .lock()
+                    .video_config
+                    .as_ref()
+                    .map(|video_config| ui_preset(video_config.preset));
+                if let Some(selected) = selected.filter(|&p| p != config.encoder.preset) {
+                    self.send_event(EngineEvent::PerformanceWarning(
+                        WarningType::PresetLowered {
+                            requested: config.encoder.preset,
+                            selected,
+                        },
+                    ));
+                    config.encoder.preset = selected;
+                }
+
                 // Start metrics collection
-                self.metrics = Arc::new(MetricsCollector::new(60.0, config.video_bitrate_kbps));
+                self.metrics = Arc::new(MetricsCollector::new(
+                    config.encoder.fps as f32,
+                    config.video_bitrate_kbps,
+                ));
                 self.metrics.start();
 
+                let loop_config = config.clone();
+
                 // Transition to live
                 self.transition_to(EngineState::Live {
-                    config,
+                    config: Box::new(config),
                     metrics: StreamMetrics::default(),
                 });
 
-                // Start the streaming loop
-                self.start_stream_loop();
+                // Start the capture, encode and mux threads
+                self.start_pipeline(&loop_config);
 
                 info!("Stream started successfully");
             }
@@ -154,28 +181,13 @@ impl Engine {
         }
     }
 
-    /// Start the main streaming loop in a separate thread.
-    fn start_stream_loop(&mut self) {
-        let resources = Arc::clone(&self.resource_manager);
-        let metrics = Arc::clone(&self.metrics);
-        let state = Arc::clone(&self.state);
-        let should_stop = Arc::clone(&self.should_stop);
-
-        // Extract the RTMP packet sender
-        let packet_tx = resources
-            .resources()
-            .lock()
-            .rtmp_packet_tx
-            .clone()
-            .expect("RTMP packet sender should be initialized");
-
-        should_stop.store(false, Ordering::SeqCst);
-
-        let handle = thread::spawn(move || {
-            stream_loop(resources, metrics, state, should_stop, packet_tx);
-        });
-
-        self.engine_thread = Some(handle);
+    /// Start the streaming pipeline threads.
+    fn start_pipeline(&mut self, config: &StreamConfig) {
+        self.pipeline = Some(Pipeline::start(
+            &self.resource_manager,
+            Arc::clone(&self.metrics),
+            config,
+        ));
     }
 
     /// Stop streaming.
@@ -192,12 +204,9 @@ impl Engine {
 
         info!(?reason, "Stopping stream");
 
-        // Signal stream loop to stop
-        self.should_stop.store(true, Ordering::SeqCst);
-
-        // Wait for stream thread to finish
-        if let Some(handle) = self.engine_thread.take() {
-            let _ = handle.join();
+        // Stop capture and wait for the encoders to drain
+        if let Some(pipeline) = self.pipeline.take() {
+            pipeline.stop();
         }
 
         self.transition_to(EngineState::Stopping {
@@ -243,6 +252,104 @@ impl Engine {
         }
     }
 
+    fn update_encoder_settings(&self, settings: &EncoderSettingsUpdate) {
+        if !self.state.read().is_live() {
+            debug!("Not live, ignoring encoder settings");
+            return;
+        }
+
+        let params = VideoEncoderParams {
+            bitrate_kbps: settings.video_bitrate_kbps,
+            keyframe_interval_secs: settings.keyframe_interval_secs,
+            rate_control: settings.rate_control.map(video_rate_control),
+        };
+
+        let Some(ref pipeline) = self.pipeline else {
+            return;
+        };
+
+        // Check the settings the encoder would end up with
+        let mut merged = pipeline.video_config();
+        merged.apply(&params);
+        let result = validate_encoder_config(&merged)
+            .map_err(EncoderError::InvalidInput)
+            .and_then(|()| pipeline.reconfigure(&params));
+
+        match result {
+            Ok(()) => {
+                info!(?params, "Encoder settings updated");
+                // Keep the reported config in step with the encoder
+                if let EngineState::Live { ref mut config, .. } = *self.state.write() {
+                    if let Some(bitrate_kbps) = settings.video_bitrate_kbps {
+                        config.video_bitrate_kbps = bitrate_kbps;
+                    }
+                    if let Some(rate_control) = settings.rate_control {
+                        config.rate_control = rate_control;
+                    }
+                    if let Some(keyframe_interval_secs) = settings.keyframe_interval_secs {
+                        config.encoder.keyframe_interval_secs = keyframe_interval_secs;
+                    }
+                }
+            }
+            Err(e) => {
+                warn!("Failed to update encoder settings: {}", e);
+                self.send_event(EngineEvent::Error {
+                    recoverable: true,
+                    message: format!("Failed to update encoder settings: {}", e),
+                });
+            }
+        }
+    }
+
+    fn force_keyframe(&self) {
+        if !self.state.read().is_live() {
+            debug!("Not live, ignoring keyframe request");
+            return;
+        }
+
+        if let Some(ref pipeline) = self.pipeline {
+            pipeline.request_keyframe();
+        }
+    }
+
+    fn set_regions_of_interest(&self, regions: Vec<RegionOfInterest>) {
+        if !self.state.read().is_live() {
+            debug!("Not live, ignoring regions of interest");
+            return;
+        }
+
+        let result = match self.pipeline {
+            Some(ref pipeline) => pipeline.set_regions_of_interest(&video_regions(&regions)),
+            None => return,
+        };
+
+        match result {
+            Ok(()) => {
+                info!(regions = regions.len(), "Regions of interest updated");
+                if let EngineState::Live { ref mut config, .. } = *self.state.write() {
+                    config.encoder.regions_of_interest = regions;
+                }
+            }
+            Err(e) => {
+                warn!("Failed to update regions of interest: {}", e);
+                self.send_event(EngineEvent::Error {
+                    recoverable: true,
+                    message: format!("Failed to update regions of interest: {}", e),
+                });
+            }
+        }
+    }
+
+    fn send_caption(&self, text: &str) {
+        if !self.state.read().is_live() {
+            debug!("Not live, ignoring caption");
+            return;
+        }
+        if let Some(ref pipeline) = self.pipeline {
+            pipeline.send_caption(text);
+        }
+    }
+
     fn send_capture_sources(&self) {
         let mut sources = Vec::new();
 
@@ -268,6 +375,61 @@ impl Engine {
         self.send_event(EngineEvent::AudioDevices(devices));
     }
 
+    /// Report the video encoder backends, optionally benchmarked at the
+    /// default encoder settings.
+    ///
+    /// While a stream is active the backends are neither probed nor
+    /// benchmarked, so a hardware encoder's session is not contended.
+    fn send_encoders(&self, benchmark: bool) {
+        let idle = {
+            let state = self.state.read();
+            !(state.is_starting() || state.is_live())
+        };
+        if benchmark && !idle {
+            debug!("Stream active, skipping encoder benchmark");
+        }
+        let benchmark = benchmark && idle;
+
+        let config = VideoEncoderConfig::default();
+        let backends: Vec<_> = VideoBackend::ALL
+            .into_iter()
+            .map(|backend| {
+                let available = if idle {
+                    backend.probe()
+                } else {
+                    backend.is_compiled()
+                };
+                let benchmarked =
+                    benchmark && available && backend.capabilities().supports(&config).is_ok();
+                let result = if benchmarked {
+                    match benchmark_backend(backend, &config) {
+                        Ok(result) => Some(result),
+                        Err(e) => {
+                            warn!(backend = backend.name(), "Encoder benchmark failed: {}", e);
+                            None
+                        }
+                    }
+                } else {
+                    None
+                };
+                (backend, available, result)
+            })
+            .collect();
+
+        let selected = choose(
+            backends.iter().filter_map(|(_, _, result)| *result),
+            config.fps,
+        );
+        let encoders = backends
+            .into_iter()
+            .map(|(backend, available, result)| {
+                encoder_info(backend, available, result, selected, config.fps)
+            })
+            .collect();
+
+        self.send_event(EngineEvent::Encoders(encoders));
+    }
+
     fn send_state(&self) {
         let state = self.state.read().clone();
         self.send_event(EngineEvent::StateChanged {
@@ -277,7 +439,14 @@ impl Engine {
     }
 
     fn emit_metrics(&self) {
-        let metrics = self.metrics.snapshot();
+        if let Some(ref pipeline) = self.pipeline {
+            pipeline.report_queue_depths(&self.metrics);
+        }
+
+        let mut metrics = self.metrics.snapshot();
+        if let Some(ref pipeline) = self.pipeline {
+            metrics.renditions = pipeline.rendition_metrics(&self.metrics);
+        }
         self.send_event(EngineEvent::Metrics(metrics));
 
         // Check for warnings
@@ -286,6 +455,15 @@ impl Engine {
         }
 
         self.metrics.mark_reported();
+
+        if let Some(ref pipeline) = self.pipeline {
+            for collector in pipeline.rendition_collectors() {
+                for warning in collector.check_warnings() {
+                    self.send_event(EngineEvent::PerformanceWarning(warning));
+                }
+                collector.mark_reported();
+            }
+        }
     }
 
     fn transition_to(&self, new_state: EngineState) {
@@ -317,233 +495,8 @@ impl Engine {
 
 impl Drop for Engine {
     fn drop(&mut self) {
-        self.should_stop.store(true, Ordering::SeqCst);
-        if let Some(handle) = self.engine_thread.take() {
-            let _ = handle.join();
+        if let Some(pipeline) = self.pipeline.take() {
+            pipeline.stop();
         }
     }
 }
-
-/// Main streaming loop.
-fn stream_loop(
-    resources: Arc<ResourceManager>,
-    metrics: Arc<MetricsCollector>,
-    _state: Arc<RwLock<EngineState>>,
-    should_stop: Arc<AtomicBool>,
-    packet_tx: crossbeam_channel::Sender<RtmpPacket>,
-) {
-    debug!("Stream loop starting");
-
-    let frame_interval = Duration::from_nanos(1_000_000_000 / 60); // 60 FPS
-    let start_time = Instant::now();
-    let mut frames_received: u64 = 0;
-    let mut frames_encoded: u64 = 0;
-    let mut frames_sent: u64 = 0;
-    let mut frames_duplicated: u64 = 0;
-    let mut last_log_time = Instant::now();
-
-    // Store last frame for duplication when no new frame available
-    let mut last_frame: Option<CapturedFrame> = None;
-
-    // Track whether we've sent the AVC sequence header
-    let mut sequence_header_sent = false;
-
-    while !should_stop.load(Ordering::SeqCst) {
-        let frame_start = Instant::now();
-
-        // Periodic status logging every 5 seconds
-        if last_log_time.elapsed() >= Duration::from_secs(5) {
-            info!(
-                "Stream stats: received={}, encoded={}, sent={}, duplicated={}, uptime={:.1}s",
-                frames_received,
-                frames_encoded,
-                frames_sent,
-                frames_duplicated,
-                start_time.elapsed().as_secs_f32()
-            );
-            last_log_time = Instant::now();
-        }
-
-        // Determine which frame to encode (new or duplicated)
-        let frame_to_encode: Option<CapturedFrame>;
-
-        {
-            let res = resources.resources().lock();
-
-            if let Some(ref frame_rx) = res.frame_rx {
-                match frame_rx.try_recv() {
-                    Ok(frame) => {
-                        frames_received += 1;
-                        if frames_received <= 5 || frames_received % 100 == 0 {
-                            debug!(
-                                "Frame received: #{}, size={}x{}",
-                                frames_received, frame.width, frame.height
-                            );
-                        }
-                        last_frame = Some(frame.clone());
-                        frame_to_encode = Some(frame);
-                    }
-                    Err(crossbeam_channel::TryRecvError::Empty) => {
-                        // No new frame - duplicate last frame to maintain constant FPS
-                        if let Some(ref frame) = last_frame {
-                            frame_to_encode = Some(frame.clone());
-                            frames_duplicated += 1;
-                        } else {
-                            frame_to_encode = None;
-                        }
-                    }
-                    Err(crossbeam_channel::TryRecvError::Disconnected) => {
-                        warn!("Frame channel disconnected");
-                        break;
-                    }
-                }
-            } else {
-                frame_to_encode = None;
-            }
-        }
-
-        // Encode and send frame
-        if let Some(frame) = frame_to_encode {
-            let mut res = resources.resources().lock();
-
-            // Use current stream time for PTS (not frame's original timestamp)
-            let pts_100ns = (start_time.elapsed().as_nanos() / 100) as u64;
-
-            if let Some(ref mut encoder) = res.video_encoder {
-                // Send AVC sequence header before first encoded frame
-                if !sequence_header_sent {
-                    if let Some(headers) = encoder.get_headers() {
-                        if let Some((sps, pps)) = extract_sps_pps(&headers) {
-                            if let Some(avc_config) = build_avc_decoder_config(&sps, &pps) {
-                                // Wrap in FLV video tag format
-                                let flv_data = build_flv_video_tag(&avc_config, true, true, 0);
-                                let seq_header_packet = RtmpPacket {
-                                    data: flv_data,
-                                    timestamp_ms: 0,
-                                    is_video: true,
-                                    is_keyframe: true,
-                                    is_sequence_header: true,
-                                };
-                                match packet_tx.try_send(seq_header_packet) {
-                                    Ok(()) => {
-                                        info!("Sent AVC sequence header");
-                                        sequence_header_sent = true;
-                                    }
-                                    Err(e) => {
-                                        warn!("Failed to send AVC sequence header: {}", e);
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-
-                match encoder.encode(&frame.data, pts_100ns) {
-                    Ok(Some(packet)) => {
-                        frames_encoded += 1;
-
-                        // Parse Annex B NAL units from x264 output
-                        let nals = parse_annex_b(&packet.data);
-
-                        // Filter out SPS/PPS (already sent in sequence header)
-                        let filtered_nals = filter_parameter_sets(nals);
-
-                        if !filtered_nals.is_empty() {
-                            // Convert to AVCC format (4-byte length prefix)
-                            let avcc_data = nals_to_avcc(&filtered_nals);
-
-                            // Wrap in FLV video tag format
-                            let flv_data =
-                                build_flv_video_tag(&avcc_data, packet.is_keyframe, false, 0);
-
-                            let rtmp_packet = RtmpPacket {
-                                data: flv_data,
-                                timestamp_ms: start_time.elapsed().as_millis() as u32,
-                                is_video: true,
-                                is_keyframe: packet.is_keyframe,
-                                is_sequence_header: false,
-                            };
-                            match packet_tx.try_send(rtmp_packet) {
-                                Ok(()) => {
-                                    frames_sent += 1;
-                                    metrics.record_frame();
-                                    metrics.record_bytes_sent(packet.data.len() as u64);
-                                }
-                                Err(e) => {
-                                    warn!("Failed to send RTMP packet: {}", e);
-                                }
-                            }
-                        }
-                    }
-                    Ok(None) => {
-                        // Encoder buffering, no output yet
-                    }
-                    Err(e) => {
-                        warn!("Encode error: {}", e);
-                        metrics.record_encode_drop();
-                    }
-                }
-            }
-        }
-
-        // Process audio
-        {
-            let mut res = resources.resources().lock();
-
-            // Collect chunks first to avoid borrow conflict
-            let audio_chunks: Vec<_> = res
-                .audio_rx
-                .as_ref()
-                .map(|rx| {
-                    let mut chunks = Vec::new();
-                    while let Ok(chunk) = rx.try_recv() {
-                        chunks.push(chunk);
-                    }
-                    chunks
-                })
-                .unwrap_or_default();
-
-            for chunk in audio_chunks {
-                if let Some(ref mut encoder) = res.audio_encoder {
-                    let samples = unsafe {
-                        std::slice::from_raw_parts(
-                            chunk.data.as_ptr() as *const f32,
-                            chunk.data.len() / std::mem::size_of::<f32>(),
-                        )
-                    };
-
-                    match encoder.encode(samples, chunk.pts_100ns) {
-                        Ok(Some(packet)) => {
-                            // Convert to RTMP packet and send
-                            let rtmp_packet = RtmpPacket {
-                                data: Bytes::from(packet.data.clone()),
-                                timestamp_ms: start_time.elapsed().as_millis() as u32,
-                                is_video: false,
-                                is_keyframe: false,
-                                is_sequence_header: false,
-                            };
-                            if packet_tx.try_send(rtmp_packet).is_ok() {
-                                metrics.record_bytes_sent(packet.data.len() as u64);
-                            }
-                        }
-                        Ok(None) => {}
-                        Err(e) => {
-                            warn!("Audio encode error: {}", e);
-                        }
-                    }
-                }
-            }
-        }
-
-        // Rate limiting to target 60 FPS
-        let elapsed = frame_start.elapsed();
-        if elapsed < frame_interval {
-            thread::sleep(frame_interval - elapsed);
-        }
-    }
-
-    info!(
-        "Stream loop stopped: total received={}, encoded={}, sent={}, duplicated={}",
-        frames_received, frames_encoded, frames_sent, frames_duplicated
-    );
-}
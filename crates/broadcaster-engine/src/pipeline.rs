@@ -0,0 +1,1105 @@
+//! Threaded streaming pipeline.
+//!
+//! Each stage runs on its own thread, connected by bounded channels:
+//!
+//! ```text
+//! capture ──frames──▶ video encode ──┐
+//!                                    ├──packets──▶ mux ──FLV tags──▶ RTMP
+//! mixer ───chunks───▶ audio encode ──┘
+//! ```
+//!
+//! The encoders are moved out of the shared resources when the pipeline
+//! starts, so no stage takes the resource lock. A slow video frame only backs
+//! up the video queue; once that is full the capture stage drops frames
+//! rather than holding up audio.
+//!
+//! Each extra rendition adds a video encode stage of its own, fed the same
+//! captured frames scaled to its size. The mux stage routes every rendition's
+//! packets, together with a copy of the audio, to that rendition's output.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use crossbeam_channel::{Receiver, Sender, TrySendError};
+use parking_lot::Mutex;
+use tracing::{debug, info, warn};
+
+use broadcaster_audio::MixedAudioChunk;
+use broadcaster_capture::CapturedFrame;
+use broadcaster_encoder::{
+    AudioCodec, AudioCodecConfig, AudioEncoder, EncodedAudioPacket, EncodedVideoPacket,
+    EncoderError, EncoderResult, RegionOfInterest, VideoEncoder, VideoEncoderConfig,
+    VideoEncoderParams, ENCODED_CHANNEL_CAPACITY,
+};
+use broadcaster_ipc::{QueueDepths, RenditionConfig, RenditionMetrics, StreamConfig};
+use broadcaster_transport::{
+    apply_sps_metadata, build_avc_decoder_config, build_flv_aac_tag, build_flv_video_tag,
+    encode_metadata, extract_sps_pps, filter_parameter_sets, insert_sei, nals_to_avcc,
+    parse_annex_b_bytes, CaptionInserter, FlvTimestamps, LatencyTimestamp, ParameterSetTracker,
+    RtmpPacket, Sps, StreamMetadata, PACKET_CHANNEL_CAPACITY,
+};
+
+use crate::ladder::{frame_step, scale_regions, FrameAction, LadderSchedule, MAIN_RENDITION_ID};
+use crate::metrics::MetricsCollector;
+use crate::scale::FrameScaler;
+use crate::state::ResourceManager;
+use crate::validation::validate_regions_of_interest;
+
+/// Frames that may wait for the video encoder before capture drops them.
+const VIDEO_QUEUE_CAPACITY: usize = 4;
+
+/// Pending encoder control requests.
+const CONTROL_QUEUE_CAPACITY: usize = 4;
+
+/// How long to wait for the video encoder to apply new settings.
+const RECONFIGURE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How often the audio stage checks for shutdown while no audio arrives.
+const AUDIO_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A frame queued for the video encoder.
+struct VideoJob {
+    frame: CapturedFrame,
+    pts_100ns: u64,
+    /// Encode the frame as a keyframe.
+    keyframe: bool,
+}
+
+/// Requests handled by the video-encode stage between frames.
+enum EncoderControl {
+    Reconfigure {
+        params: VideoEncoderParams,
+        reply: Sender<EncoderResult<()>>,
+    },
+    RegionsOfInterest {
+        regions: Vec<RegionOfInterest>,
+        reply: Sender<EncoderResult<()>>,
+    },
+}
+
+/// Input to the mux stage.
+///
+/// Video is tagged with the index of its rendition, 0 being the main stream.
+enum MuxInput {
+    /// Out-of-band SPS/PPS in Annex B format.
+    VideoHeaders { rendition: usize, headers: Bytes },
+    Video {
+        rendition: usize,
+        packet: EncodedVideoPacket,
+        latency: Option<LatencyTimestamp>,
+    },
+    /// Decoder configuration of the audio encoder, sent before its output.
+    AudioConfig(AudioCodecConfig),
+    Audio {
+        packet: EncodedAudioPacket,
+        /// Stream time of the packet's first sample.
+        timestamp_ms: i64,
+    },
+}
+
+/// The running capture, encode and mux threads of one stream.
+pub struct Pipeline {
+    should_stop: Arc<AtomicBool>,
+    /// Control channel of each rendition's encoder, the main stream first.
+    control_txs: Vec<Sender<EncoderControl>>,
+    /// Caption queue of each rendition's output, the main stream first.
+    captions: Vec<Arc<Mutex<CaptionInserter>>>,
+    schedule: Arc<Mutex<LadderSchedule>>,
+    /// Settings of the main encoder, kept in step with live changes.
+    video_config: Mutex<VideoEncoderConfig>,
+    fps: u32,
+    main_size: (u32, u32),
+    renditions: Vec<RenditionOutput>,
+    queues: Queues,
+    threads: Vec<JoinHandle<()>>,
+}
+
+/// Reporting handles of an extra rendition.
+struct RenditionOutput {
+    config: RenditionConfig,
+    metrics: Arc<MetricsCollector>,
+    packet_tx: Sender<RtmpPacket>,
+}
+
+/// Where the capture stage sends one rendition's frames.
+struct Lane {
+    rendition: usize,
+    video_tx: Sender<VideoJob>,
+    metrics: Arc<MetricsCollector>,
+}
+
+/// One rendition's encoder and output, taken from the resources.
+struct LaneResources {
+    encoder: Option<Box<dyn VideoEncoder>>,
+    /// Size to scale captured frames to, or `None` for the captured size.
+    size: Option<(u32, u32)>,
+    metrics: Arc<MetricsCollector>,
+    stream_metadata: Option<StreamMetadata>,
+    packet_tx: Sender<RtmpPacket>,
+}
+
+/// Channel handles kept for reporting queue depths.
+struct Queues {
+    frame_rx: Option<Receiver<CapturedFrame>>,
+    video_rx: Receiver<VideoJob>,
+    audio_rx: Option<Receiver<MixedAudioChunk>>,
+    mux_rx: Receiver<MuxInput>,
+    packet_tx: Sender<RtmpPacket>,
+}
+
+impl Pipeline {
+    /// Take the encoders and channels from `resources` and start all stages.
+    pub fn start(
+        resources: &ResourceManager,
+        metrics: Arc<MetricsCollector>,
+        config: &StreamConfig,
+    ) -> Self {
+        let (lanes, audio_encoder, video_config, frame_rx, audio_rx, rendition_configs) = {
+            let mut res = resources.resources().lock();
+            let packet_tx = res
+                .rtmp_packet_tx
+                .clone()
+                .expect("RTMP packet sender should be initialized");
+
+            let mut lanes = vec![LaneResources {
+                encoder: res.video_encoder.take(),
+                size: None,
+                metrics: Arc::clone(&metrics),
+                stream_metadata: res.stream_metadata.clone(),
+                packet_tx,
+            }];
+            let mut rendition_configs = Vec::new();
+            for rendition in &mut res.renditions {
+                let rendition_metrics = Arc::new(MetricsCollector::new(
+                    rendition.config.fps as f32,
+                    rendition.config.video_bitrate_kbps,
+                ));
+                rendition_metrics.start();
+                lanes.push(LaneResources {
+                    encoder: rendition.video_encoder.take(),
+                    size: Some((rendition.config.width, rendition.config.height)),
+                    metrics: rendition_metrics,
+                    stream_metadata: rendition.stream_metadata.clone(),
+                    packet_tx: rendition
+                        .rtmp_packet_tx
+                        .clone()
+                        .expect("Rendition RTMP packet sender should be initialized"),
+                });
+                rendition_configs.push(rendition.config.clone());
+            }
+
+            (
+                lanes,
+                res.audio_encoder.take(),
+                res.video_config.clone().unwrap_or_default(),
+                res.frame_rx.clone(),
+                res.audio_rx.clone(),
+                rendition_configs,
+            )
+        };
+
+        let should_stop = Arc::new(AtomicBool::new(false));
+        let start_time = Instant::now();
+        let fps = config.encoder.fps;
+        let main_size = lanes[0]
+            .stream_metadata
+            .as_ref()
+            .map_or((0, 0), |metadata| {
+                (
+                    metadata.video_width.unwrap_or(0),
+                    metadata.video_height.unwrap_or(0),
+                )
+            });
+
+        // Every rendition starts its GOPs on the same captured frames
+        let steps = rendition_configs
+            .iter()
+            .map(|rendition| frame_step(fps, rendition.fps).unwrap_or(1));
+        let schedule = Arc::new(Mutex::new(LadderSchedule::new(
+            std::iter::once(1).chain(steps).collect(),
+            fps * config.encoder.keyframe_interval_secs,
+        )));
+
+        let (mux_tx, mux_rx) = crossbeam_channel::bounded(ENCODED_CHANNEL_CAPACITY);
+
+        let mut threads = Vec::new();
+        let mut capture_lanes = Vec::new();
+        let mut video_rxs = Vec::new();
+        let mut control_txs = Vec::new();
+        let mut captions = Vec::new();
+        let mut muxers = Vec::new();
+
+        for (rendition, lane) in lanes.into_iter().enumerate() {
+            let (video_tx, video_rx) = crossbeam_channel::bounded(VIDEO_QUEUE_CAPACITY);
+            let (control_tx, control_rx) = crossbeam_channel::bounded(CONTROL_QUEUE_CAPACITY);
+
+            // Only renditions with an encoder and a capture source are fed
+            if let (Some(encoder), Some(_)) = (lane.encoder, frame_rx.as_ref()) {
+                capture_lanes.push(Lane {
+                    rendition,
+                    video_tx,
+                    metrics: Arc::clone(&lane.metrics),
+                });
+
+                let stage = VideoEncodeStage {
+                    rendition,
+                    video_rx: video_rx.clone(),
+                    control_rx,
+                    mux_tx: mux_tx.clone(),
+                    metrics: Arc::clone(&lane.metrics),
+                    schedule: Arc::clone(&schedule),
+                    scaler: lane
+                        .size
+                        .map(|(width, height)| FrameScaler::new(width, height)),
+                    latency_sei: config.latency_sei,
+                };
+                threads.push(spawn_stage(
+                    &format!("video-encode-{}", rendition),
+                    move || video_encode_stage(encoder, stage),
+                ));
+            }
+
+            let lane_captions = Arc::new(Mutex::new(CaptionInserter::new()));
+            muxers.push(Muxer::new(
+                lane.packet_tx,
+                lane.metrics,
+                Arc::clone(&lane_captions),
+                lane.stream_metadata,
+                config.latency_sei,
+            ));
+            video_rxs.push(video_rx);
+            control_txs.push(control_tx);
+            captions.push(lane_captions);
+        }
+
+        if let Some(frame_rx) = frame_rx.clone().filter(|_| !capture_lanes.is_empty()) {
+            let capture_schedule = Arc::clone(&schedule);
+            let capture_stop = Arc::clone(&should_stop);
+            threads.push(spawn_stage("capture", move || {
+                capture_stage(
+                    frame_rx,
+                    capture_lanes,
+                    capture_schedule,
+                    capture_stop,
+                    start_time,
+                    fps,
+                )
+            }));
+        }
+
+        if let (Some(audio_rx), Some(encoder)) = (audio_rx.clone(), audio_encoder) {
+            let mux_tx = mux_tx.clone();
+            let audio_stop = Arc::clone(&should_stop);
+            threads.push(spawn_stage("audio-encode", move || {
+                audio_encode_stage(encoder, audio_rx, mux_tx, audio_stop, start_time)
+            }));
+        }
+
+        // The mux stage finishes once all encoders have dropped their senders
+        drop(mux_tx);
+
+        let renditions = rendition_configs
+            .into_iter()
+            .zip(&muxers[1..])
+            .map(|(config, muxer)| RenditionOutput {
+                config,
+                metrics: Arc::clone(&muxer.metrics),
+                packet_tx: muxer.packet_tx.clone(),
+            })
+            .collect();
+        let packet_tx = muxers[0].packet_tx.clone();
+
+        let mux_input = mux_rx.clone();
+        threads.push(spawn_stage("mux", move || mux_stage(mux_input, muxers)));
+
+        Self {
+            should_stop,
+            control_txs,
+            captions,
+            schedule,
+            video_config: Mutex::new(video_config),
+            fps,
+            main_size,
+            renditions,
+            queues: Queues {
+                frame_rx,
+                // Queue depths are reported for the main stream
+                video_rx: video_rxs.swap_remove(0),
+                audio_rx,
+                mux_rx,
+                packet_tx,
+            },
+            threads,
+        }
+    }
+
+    /// Apply new settings to the video encoders, waiting for the result.
+    ///
+    /// The main stream takes all of `params`. Renditions keep their own
+    /// bitrate and only follow a change of keyframe interval.
+    pub fn reconfigure(&self, params: &VideoEncoderParams) -> EncoderResult<()> {
+        let (main_control, rendition_controls) = self
+            .control_txs
+            .split_first()
+            .ok_or(EncoderError::NotInitialized)?;
+        reconfigure_encoder(main_control, params)?;
+
+        if let Some(keyframe_interval_secs) = params.keyframe_interval_secs {
+            let rendition_params = VideoEncoderParams {
+                keyframe_interval_secs: Some(keyframe_interval_secs),
+                ..Default::default()
+            };
+            for control_tx in rendition_controls {
+                reconfigure_encoder(control_tx, &rendition_params)?;
+            }
+        }
+
+        if !self.renditions.is_empty() {
+            // The main encoder may have restarted its GOP with the new
+            // settings, so bring every rendition back in step
+            let mut schedule = self.schedule.lock();
+            match params.keyframe_interval_secs {
+                Some(secs) => schedule.set_gop_frames(self.fps * secs),
+                None => schedule.request_keyframe(),
+            }
+        }
+
+        self.video_config.lock().apply(params);
+        Ok(())
+    }
+
+    /// Settings of the main encoder, including changes made while live.
+    pub fn video_config(&self) -> VideoEncoderConfig {
+        self.video_config.lock().clone()
+    }
+
+    /// Replace the regions of interest of every rendition's encoder.
+    ///
+    /// Regions are given in the main stream's frame and scaled to each
+    /// rendition's.
+    pub fn set_regions_of_interest(&self, regions: &[RegionOfInterest]) -> EncoderResult<()> {
+        let (width, height) = self.main_size;
+        validate_regions_of_interest(regions, width, height).map_err(EncoderError::InvalidInput)?;
+
+        let (main_control, rendition_controls) = self
+            .control_txs
+            .split_first()
+            .ok_or(EncoderError::NotInitialized)?;
+        control_encoder(main_control, |reply| EncoderControl::RegionsOfInterest {
+            regions: regions.to_vec(),
+            reply,
+        })?;
+
+        for (control_tx, rendition) in rendition_controls.iter().zip(&self.renditions) {
+            let size = (rendition.config.width, rendition.config.height);
+            control_encoder(control_tx, |reply| EncoderControl::RegionsOfInterest {
+                regions: scale_regions(regions, self.main_size, size),
+                reply,
+            })?;
+        }
+
+        self.video_config.lock().regions_of_interest = regions.to_vec();
+        Ok(())
+    }
+
+    /// Make the next captured frame a keyframe in every rendition.
+    pub fn request_keyframe(&self) {
+        self.schedule.lock().request_keyframe();
+    }
+
+    /// Queue caption text for every rendition's output.
+    pub fn send_caption(&self, text: &str) {
+        for captions in &self.captions {
+            captions.lock().push_text(text);
+        }
+    }
+
+    /// Metrics of each rendition, the main stream first, or none if the
+    /// stream has no extra renditions.
+    pub fn rendition_metrics(&self, main: &MetricsCollector) -> Vec<RenditionMetrics> {
+        if self.renditions.is_empty() {
+            return Vec::new();
+        }
+
+        let (width, height) = self.main_size;
+        std::iter::once(main.rendition_snapshot(MAIN_RENDITION_ID, width, height))
+            .chain(self.renditions.iter().map(|rendition| {
+                let config = &rendition.config;
+                rendition
+                    .metrics
+                    .rendition_snapshot(&config.id, config.width, config.height)
+            }))
+            .collect()
+    }
+
+    /// Metrics collectors of the extra renditions.
+    pub fn rendition_collectors(&self) -> impl Iterator<Item = &MetricsCollector> {
+        self.renditions
+            .iter()
+            .map(|rendition| rendition.metrics.as_ref())
+    }
+
+    /// Report the number of items waiting in each queue.
+    pub fn report_queue_depths(&self, metrics: &MetricsCollector) {
+        let queues = &self.queues;
+        let depths = QueueDepths {
+            capture: queues.frame_rx.as_ref().map_or(0, |rx| rx.len()) as u32,
+            video_encode: queues.video_rx.len() as u32,
+            audio_encode: queues.audio_rx.as_ref().map_or(0, |rx| rx.len()) as u32,
+            mux: queues.mux_rx.len() as u32,
+            network: queues.packet_tx.len() as u32,
+        };
+
+        metrics.update_queue_depths(depths);
+        metrics
+            .update_buffer_fullness(depths.network as f32 / PACKET_CHANNEL_CAPACITY as f32 * 100.0);
+
+        for rendition in &self.renditions {
+            rendition.metrics.update_buffer_fullness(
+                rendition.packet_tx.len() as f32 / PACKET_CHANNEL_CAPACITY as f32 * 100.0,
+            );
+        }
+    }
+
+    /// Stop capturing, drain the encoders and wait for all stages to finish.
+    pub fn stop(self) {
+        self.should_stop.store(true, Ordering::SeqCst);
+
+        for handle in self.threads {
+            let _ = handle.join();
+        }
+
+        for rendition in &self.renditions {
+            rendition.metrics.stop();
+        }
+    }
+}
+
+/// Apply new settings through an encoder's control channel, waiting for the
+/// result.
+fn reconfigure_encoder(
+    control_tx: &Sender<EncoderControl>,
+    params: &VideoEncoderParams,
+) -> EncoderResult<()> {
+    control_encoder(control_tx, |reply| EncoderControl::Reconfigure {
+        params: params.clone(),
+        reply,
+    })
+}
+
+/// Send a request built by `control` to an encoder and wait for its reply.
+fn control_encoder(
+    control_tx: &Sender<EncoderControl>,
+    control: impl FnOnce(Sender<EncoderResult<()>>) -> EncoderControl,
+) -> EncoderResult<()> {
+    let (reply_tx, reply_rx) = crossbeam_channel::bounded(1);
+    control_tx
+        .try_send(control(reply_tx))
+        .map_err(|e| match e {
+            TrySendError::Full(_) => EncoderError::Overload(control_tx.len()),
+            TrySendError::Disconnected(_) => EncoderError::NotInitialized,
+        })?;
+
+    reply_rx.recv_timeout(RECONFIGURE_TIMEOUT).map_err(|_| {
+        EncoderError::Encoding("Video encoder did not apply the new settings".into())
+    })?
+}
+
+fn spawn_stage(name: &str, stage: impl FnOnce() + Send + 'static) -> JoinHandle<()> {
+    thread::Builder::new()
+        .name(format!("pipeline-{}", name))
+        .spawn(stage)
+        .expect("failed to spawn pipeline thread")
+}
+
+/// Pace frames to the target rate and queue them for the video encoders.
+///
+/// The last frame is repeated when capture has nothing new, to keep a
+/// constant frame rate. Frames are dropped when an encoder falls behind.
+fn capture_stage(
+    frame_rx: Receiver<CapturedFrame>,
+    lanes: Vec<Lane>,
+    schedule: Arc<Mutex<LadderSchedule>>,
+    should_stop: Arc<AtomicBool>,
+    start_time: Instant,
+    fps: u32,
+) {
+    debug!("Capture stage starting");
+
+    let frame_interval = Duration::from_nanos(1_000_000_000 / fps as u64);
+    let mut frames_received: u64 = 0;
+    let mut frames_duplicated: u64 = 0;
+    let mut frames_dropped: u64 = 0;
+    let mut last_log_time = Instant::now();
+
+    // Store last frame for duplication when no new frame available
+    let mut last_frame: Option<CapturedFrame> = None;
+
+    'capture: while !should_stop.load(Ordering::SeqCst) {
+        let frame_start = Instant::now();
+
+        // Periodic status logging every 5 seconds
+        if last_log_time.elapsed() >= Duration::from_secs(5) {
+            info!(
+                "Capture stats: received={}, duplicated={}, dropped={}, uptime={:.1}s",
+                frames_received,
+                frames_duplicated,
+                frames_dropped,
+                start_time.elapsed().as_secs_f32()
+            );
+            last_log_time = Instant::now();
+        }
+
+        let frame = match frame_rx.try_recv() {
+            Ok(frame) => {
+                frames_received += 1;
+                if frames_received <= 5 || frames_received % 100 == 0 {
+                    debug!(
+                        "Frame received: #{}, size={}x{}",
+                        frames_received, frame.width, frame.height
+                    );
+                }
+                last_frame = Some(frame.clone());
+                Some(frame)
+            }
+            Err(crossbeam_channel::TryRecvError::Empty) => {
+                // No new frame - duplicate last frame to maintain constant FPS
+                if last_frame.is_some() {
+                    frames_duplicated += 1;
+                }
+                last_frame.clone()
+            }
+            Err(crossbeam_channel::TryRecvError::Disconnected) => {
+                warn!("Frame channel disconnected");
+                break;
+            }
+        };
+
+        if let Some(frame) = frame {
+            // Use current stream time for PTS (not frame's original timestamp),
+            // the same in every rendition
+            let pts_100ns = (start_time.elapsed().as_nanos() / 100) as u64;
+            let actions = schedule.lock().next_frame();
+
+            for lane in &lanes {
+                let rendition = lane.rendition;
+                let action = actions[rendition];
+                if action == FrameAction::Skip {
+                    continue;
+                }
+
+                let job = VideoJob {
+                    frame: frame.clone(),
+                    pts_100ns,
+                    keyframe: action == FrameAction::Keyframe,
+                };
+
+                match lane.video_tx.try_send(job) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(_)) => {
+                        frames_dropped += 1;
+                        lane.metrics.record_encode_drop();
+                        schedule.lock().record_drop(rendition);
+                        debug!(
+                            rendition,
+                            "Dropping frame: {}",
+                            EncoderError::Overload(lane.video_tx.len())
+                        );
+                    }
+                    Err(TrySendError::Disconnected(_)) => {
+                        warn!(rendition, "Video encoder stopped");
+                        break 'capture;
+                    }
+                }
+            }
+        }
+
+        // Rate limiting to the target frame rate
+        let elapsed = frame_start.elapsed();
+        if elapsed < frame_interval {
+            thread::sleep(frame_interval - elapsed);
+        }
+    }
+
+    info!(
+        "Capture stage stopped: received={}, duplicated={}, dropped={}",
+        frames_received, frames_duplicated, frames_dropped
+    );
+}
+
+/// Channels and settings of one rendition's video encode stage.
+struct VideoEncodeStage {
+    rendition: usize,
+    video_rx: Receiver<VideoJob>,
+    control_rx: Receiver<EncoderControl>,
+    mux_tx: Sender<MuxInput>,
+    metrics: Arc<MetricsCollector>,
+    /// Told about frames dropped here, to realign keyframes.
+    schedule: Arc<Mutex<LadderSchedule>>,
+    /// Scales captured frames to the rendition's size.
+    scaler: Option<FrameScaler>,
+    latency_sei: bool,
+}
+
+/// Encode queued frames until capture stops, then drain the encoder.
+fn video_encode_stage(mut encoder: Box<dyn VideoEncoder>, stage: VideoEncodeStage) {
+    let VideoEncodeStage {
+        rendition,
+        video_rx,
+        control_rx,
+        mux_tx,
+        metrics,
+        schedule,
+        mut scaler,
+        latency_sei,
+    } = stage;
+
+    debug!(rendition, "Video encode stage starting");
+
+    let mut frames_encoded: u64 = 0;
+    let mut headers_sent = false;
+
+    // Capture timestamps by input PTS, as frames may leave the encoder reordered
+    let mut pending_latency: HashMap<u64, LatencyTimestamp> = HashMap::new();
+
+    'encode: loop {
+        crossbeam_channel::select! {
+            recv(control_rx) -> control => match control {
+                Ok(EncoderControl::Reconfigure { params, reply }) => {
+                    let _ = reply.send(encoder.reconfigure(&params));
+                }
+                Ok(EncoderControl::RegionsOfInterest { regions, reply }) => {
+                    let _ = reply.send(encoder.set_regions_of_interest(&regions));
+                }
+                Err(_) => {
+                    // Pipeline dropped without stopping
+                    break;
+                }
+            },
+            recv(video_rx) -> job => {
+                let Ok(job) = job else {
+                    // Capture stage stopped
+                    break;
+                };
+
+                // Send the sequence header ahead of the first encoded frame
+                if !headers_sent {
+                    if let Some(headers) = encoder.get_headers() {
+                        headers_sent = mux_tx
+                            .send(MuxInput::VideoHeaders { rendition, headers })
+                            .is_ok();
+                    }
+                }
+
+                let frame = &job.frame;
+                let data = match scaler {
+                    Some(ref mut scaler) => scaler.scale(&frame.data, frame.width, frame.height),
+                    None => Some(&frame.data[..]),
+                };
+                let Some(data) = data else {
+                    warn!(
+                        rendition,
+                        "Skipping {}x{} frame of {} bytes",
+                        frame.width,
+                        frame.height,
+                        frame.data.len()
+                    );
+                    metrics.record_encode_drop();
+                    schedule.lock().record_drop(rendition);
+                    continue;
+                };
+
+                if job.keyframe {
+                    encoder.request_keyframe();
+                }
+
+                if latency_sei {
+                    pending_latency.insert(
+                        job.pts_100ns,
+                        LatencyTimestamp::new(job.frame.timestamp.wall_clock, job.frame.sequence),
+                    );
+                }
+
+                match metrics.time_encode(|| encoder.encode(data, job.pts_100ns)) {
+                    // Empty while the encoder buffers
+                    Ok(packets) => {
+                        for packet in packets {
+                            frames_encoded += 1;
+                            let latency = pending_latency.remove(&packet.pts_100ns);
+                            let input = MuxInput::Video {
+                                rendition,
+                                packet,
+                                latency,
+                            };
+                            if mux_tx.send(input).is_err() {
+                                warn!("Mux stage stopped");
+                                break 'encode;
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        warn!(rendition, "Encode error: {}", e);
+                        pending_latency.remove(&job.pts_100ns);
+                        metrics.record_encode_drop();
+                        schedule.lock().record_drop(rendition);
+                    }
+                }
+            }
+        }
+    }
+
+    // Drain frames held back for B-frame reordering and lookahead
+    match encoder.flush() {
+        Ok(packets) => {
+            debug!(rendition, "Flushed {} delayed video packets", packets.len());
+            for packet in packets {
+                frames_encoded += 1;
+                let latency = pending_latency.remove(&packet.pts_100ns);
+                let input = MuxInput::Video {
+                    rendition,
+                    packet,
+                    latency,
+                };
+                if mux_tx.send(input).is_err() {
+                    break;
+                }
+            }
+        }
+        Err(e) => warn!(rendition, "Failed to flush video encoder: {}", e),
+    }
+
+    info!(
+        rendition,
+        "Video encode stage stopped: encoded={}", frames_encoded
+    );
+}
+
+/// Encode mixed audio until the stream stops.
+fn audio_encode_stage(
+    mut encoder: Box<dyn AudioEncoder>,
+    audio_rx: Receiver<MixedAudioChunk>,
+    mux_tx: Sender<MuxInput>,
+    should_stop: Arc<AtomicBool>,
+    start_time: Instant,
+) {
+    debug!("Audio encode stage starting");
+
+    if mux_tx
+        .send(MuxInput::AudioConfig(encoder.codec_config()))
+        .is_err()
+    {
+        return;
+    }
+
+    // Mixer timestamps count from when the mixer started; the first chunk
+    // fixes their offset to stream time
+    let mut clock_offset_100ns: Option<i64> = None;
+
+    while !should_stop.load(Ordering::SeqCst) {
+        let chunk = match audio_rx.recv_timeout(AUDIO_POLL_INTERVAL) {
+            Ok(chunk) => chunk,
+            Err(crossbeam_channel::RecvTimeoutError::Timeout) => continue,
+            Err(crossbeam_channel::RecvTimeoutError::Disconnected) => {
+                warn!("Audio channel disconnected");
+                break;
+            }
+        };
+
+        let samples = unsafe {
+            std::slice::from_raw_parts(
+                chunk.data.as_ptr() as *const f32,
+                chunk.data.len() / std::mem::size_of::<f32>(),
+            )
+        };
+
+        let clock_offset = *clock_offset_100ns.get_or_insert_with(|| {
+            (start_time.elapsed().as_nanos() / 100) as i64 - chunk.pts_100ns as i64
+        });
+
+        match encoder.encode(samples, chunk.pts_100ns) {
+            Ok(Some(packet)) => {
+                let timestamp_ms = (packet.pts_100ns as i64 + clock_offset).div_euclid(10_000);
+                if mux_tx
+                    .send(MuxInput::Audio {
+                        packet,
+                        timestamp_ms,
+                    })
+                    .is_err()
+                {
+                    warn!("Mux stage stopped");
+                    break;
+                }
+            }
+            Ok(None) => {}
+            Err(e) => {
+                warn!("Audio encode error: {}", e);
+            }
+        }
+    }
+
+    debug!("Audio encode stage stopped");
+}
+
+/// Package encoded packets into FLV tags until all encoders finish.
+///
+/// Each rendition has its own muxer and output; audio goes to all of them.
+fn mux_stage(mux_rx: Receiver<MuxInput>, mut muxers: Vec<Muxer>) {
+    debug!("Mux stage starting");
+
+    // Send onMetaData ahead of any audio or video
+    for muxer in &muxers {
+        if let Some(ref metadata) = muxer.stream_metadata {
+            send_metadata(&muxer.packet_tx, metadata, 0);
+        }
+    }
+
+    for input in mux_rx.iter() {
+        match input {
+            MuxInput::VideoHeaders { rendition, headers } => {
+                muxers[rendition].send_video_headers(&headers)
+            }
+            MuxInput::Video {
+                rendition,
+                packet,
+                latency,
+            } => muxers[rendition].send_video(&packet, latency),
+            MuxInput::AudioConfig(config) => {
+                for muxer in &mut muxers {
+                    muxer.send_audio_config(&config);
+                }
+            }
+            MuxInput::Audio {
+                packet,
+                timestamp_ms,
+            } => {
+                for muxer in &muxers {
+                    muxer.send_audio(&packet, timestamp_ms);
+                }
+            }
+        }
+    }
+
+    for (rendition, muxer) in muxers.iter().enumerate() {
+        info!(
+            rendition,
+            "Mux stage stopped: video frames sent={}", muxer.frames_sent
+        );
+    }
+}
+
+/// Packages encoded audio and video into FLV tags and queues them for sending.
+struct Muxer {
+    packet_tx: Sender<RtmpPacket>,
+    metrics: Arc<MetricsCollector>,
+    captions: Arc<Mutex<CaptionInserter>>,
+    stream_metadata: Option<StreamMetadata>,
+    /// Parameter sets announced in the last sequence header
+    parameter_sets: ParameterSetTracker,
+    /// Maps encoder PTS/DTS to FLV timestamp and composition time
+    timestamps: FlvTimestamps,
+    /// Codec announced by the audio encoder
+    audio_codec: Option<AudioCodec>,
+    latency_sei: bool,
+    frames_sent: u64,
+}
+
+impl Muxer {
+    fn new(
+        packet_tx: Sender<RtmpPacket>,
+        metrics: Arc<MetricsCollector>,
+        captions: Arc<Mutex<CaptionInserter>>,
+        stream_metadata: Option<StreamMetadata>,
+        latency_sei: bool,
+    ) -> Self {
+        Self {
+            packet_tx,
+            metrics,
+            captions,
+            stream_metadata,
+            parameter_sets: ParameterSetTracker::new(),
+            timestamps: FlvTimestamps::new(),
+            audio_codec: None,
+            latency_sei,
+            frames_sent: 0,
+        }
+    }
+
+    /// Send the AVC sequence header built from out-of-band SPS/PPS.
+    fn send_video_headers(&mut self, headers: &[u8]) {
+        if self.parameter_sets.active_sps().is_some() {
+            return;
+        }
+
+        if let Some((sps, pps)) = extract_sps_pps(headers) {
+            if let Some(avc_config) = build_avc_decoder_config(&sps, &pps) {
+                if send_sequence_header(&self.packet_tx, &avc_config, 0) {
+                    self.parameter_sets.set_active(sps, pps);
+                }
+            }
+        }
+    }
+
+    /// Send one encoded access unit.
+    fn send_video(&mut self, packet: &EncodedVideoPacket, latency: Option<LatencyTimestamp>) {
+        // Parse Annex B NAL units from encoder output
+        let nals = parse_annex_b_bytes(&packet.data);
+
+        let timing = self.timestamps.video(
+            (packet.pts_100ns / 10_000) as i64,
+            packet.dts_100ns.div_euclid(10_000),
+        );
+
+        // Re-announce the stream if the encoder switched parameter sets
+        if let Some(change) = self.parameter_sets.process(&nals, packet.is_keyframe) {
+            if let Some(ref mut metadata) = self.stream_metadata {
+                match Sps::parse(&change.sps) {
+                    Ok(sps) => {
+                        apply_sps_metadata(metadata, &sps);
+                        send_metadata(&self.packet_tx, metadata, timing.timestamp_ms);
+                    }
+                    Err(e) => warn!("Failed to parse in-band SPS: {}", e),
+                }
+            }
+
+            send_sequence_header(&self.packet_tx, &change.decoder_config, timing.timestamp_ms);
+        }
+
+        // Filter out SPS/PPS (carried in the sequence header)
+        let mut filtered_nals = filter_parameter_sets(nals);
+        if filtered_nals.is_empty() {
+            return;
+        }
+
+        // Carry queued closed captions in this access unit, paced by display time
+        self.captions
+            .lock()
+            .insert(&mut filtered_nals, timing.pts_ms());
+
+        // Embed the capture time for latency measurement
+        if self.latency_sei {
+            if let Some(latency) = latency {
+                insert_sei(&mut filtered_nals, latency.to_sei());
+            }
+        }
+
+        // Convert to AVCC format (4-byte length prefix)
+        let avcc_data = nals_to_avcc(&filtered_nals);
+
+        // Wrap in FLV video tag format
+        let flv_data = build_flv_video_tag(
+            &avcc_data,
+            packet.is_keyframe,
+            false,
+            timing.composition_time_ms,
+        );
+
+        let rtmp_packet = RtmpPacket {
+            data: flv_data,
+            timestamp_ms: timing.timestamp_ms,
+            is_video: true,
+            is_keyframe: packet.is_keyframe,
+            is_sequence_header: false,
+            is_metadata: false,
+        };
+        match self.packet_tx.try_send(rtmp_packet) {
+            Ok(()) => {
+                self.frames_sent += 1;
+                self.metrics.record_frame();
+                self.metrics.record_bytes_sent(packet.data.len() as u64);
+            }
+            Err(e) => {
+                warn!("Failed to send RTMP packet: {}", e);
+            }
+        }
+    }
+
+    /// Send the AAC sequence header built from the encoder's AudioSpecificConfig.
+    fn send_audio_config(&mut self, config: &AudioCodecConfig) {
+        self.audio_codec = Some(config.codec);
+
+        if config.codec != AudioCodec::Aac {
+            warn!("FLV cannot carry {:?} audio, dropping audio", config.codec);
+            return;
+        }
+
+        let seq_header_packet = RtmpPacket {
+            data: build_flv_aac_tag(&config.data, true),
+            timestamp_ms: 0,
+            is_video: false,
+            is_keyframe: false,
+            is_sequence_header: true,
+            is_metadata: false,
+        };
+        match self.packet_tx.try_send(seq_header_packet) {
+            Ok(()) => info!("Sent AAC sequence header"),
+            Err(e) => warn!("Failed to send AAC sequence header: {}", e),
+        }
+    }
+
+    /// Send one encoded audio frame.
+    fn send_audio(&self, packet: &EncodedAudioPacket, timestamp_ms: i64) {
+        if self.audio_codec != Some(AudioCodec::Aac) {
+            return;
+        }
+
+        let rtmp_packet = RtmpPacket {
+            data: build_flv_aac_tag(&packet.data, false),
+            timestamp_ms: self.timestamps.audio(timestamp_ms),
+            is_video: false,
+            is_keyframe: false,
+            is_sequence_header: false,
+            is_metadata: false,
+        };
+        if self.packet_tx.try_send(rtmp_packet).is_ok() {
+            self.metrics.record_bytes_sent(packet.data.len() as u64);
+        }
+    }
+}
+
+/// Send onMetaData.
+fn send_metadata(packet_tx: &Sender<RtmpPacket>, metadata: &StreamMetadata, timestamp_ms: u32) {
+    let metadata_packet = RtmpPacket {
+        data: encode_metadata(metadata),
+        timestamp_ms,
+        is_video: false,
+        is_keyframe: false,
+        is_sequence_header: false,
+        is_metadata: true,
+    };
+    match packet_tx.try_send(metadata_packet) {
+        Ok(()) => info!("Sent stream metadata"),
+        Err(e) => warn!("Failed to send stream metadata: {}", e),
+    }
+}
+
+/// Send an AVC sequence header. Returns whether it was queued.
+fn send_sequence_header(
+    packet_tx: &Sender<RtmpPacket>,
+    avc_config: &[u8],
+    timestamp_ms: u32,
+) -> bool {
+    // Wrap in FLV video tag format
+    let flv_data = build_flv_video_tag(avc_config, true, true, 0);
+    let seq_header_packet = RtmpPacket {
+        data: flv_data,
+        timestamp_ms,
+        is_video: true,
+        is_keyframe: true,
+        is_sequence_header: true,
+        is_metadata: false,
+    };
+    match packet_tx.try_send(seq_header_packet) {
+        Ok(()) => {
+            info!("Sent AVC sequence header");
+            true
+        }
+        Err(e) => {
+            warn!("Failed to send AVC sequence header: {}", e);
+            false
+        }
+    }
+}
@@ -0,0 +1,191 @@
+//! NV12 frame scaling for renditions.
+//!
+//! Renditions are encoded from the captured frame downscaled on the CPU with
+//! an area (box) filter: every output sample averages the source samples it
+//! covers, which avoids the aliasing of point or bilinear sampling at the
+//! 1.5x and 2.25x ratios of a typical ladder.
+
+use std::ops::Range;
+
+/// Source samples covered by each output sample along one axis.
+fn spans(src: usize, dst: usize) -> Vec<Range<usize>> {
+    (0..dst)
+        .map(|i| {
+            let start = i * src / dst;
+            let end = ((i + 1) * src).div_ceil(dst).max(start + 1);
+            start..end
+        })
+        .collect()
+}
+
+/// Scales NV12 frames from one size to another.
+///
+/// Both sizes must be even, as NV12 subsamples chroma by two in each
+/// direction.
+pub(crate) struct Nv12Scaler {
+    src_width: usize,
+    src_height: usize,
+    luma_x: Vec<Range<usize>>,
+    luma_y: Vec<Range<usize>>,
+    chroma_x: Vec<Range<usize>>,
+    chroma_y: Vec<Range<usize>>,
+}
+
+impl Nv12Scaler {
+    pub(crate) fn new(src_width: u32, src_height: u32, dst_width: u32, dst_height: u32) -> Self {
+        let (src_width, src_height) = (src_width as usize, src_height as usize);
+        let (dst_width, dst_height) = (dst_width as usize, dst_height as usize);
+
+        Self {
+            src_width,
+            src_height,
+            luma_x: spans(src_width, dst_width),
+            luma_y: spans(src_height, dst_height),
+            chroma_x: spans(src_width / 2, dst_width / 2),
+            chroma_y: spans(src_height / 2, dst_height / 2),
+        }
+    }
+
+    /// Size of the frames this scaler takes.
+    pub(crate) fn source_size(&self) -> (u32, u32) {
+        (self.src_width as u32, self.src_height as u32)
+    }
+
+    /// Scale one frame into `dst`, reusing its allocation.
+    pub(crate) fn scale(&self, src: &[u8], dst: &mut Vec<u8>) {
+        let luma_size = self.src_width * self.src_height;
+        let (luma, chroma) = src.split_at(luma_size);
+
+        dst.clear();
+        scale_plane::<1>(luma, self.src_width, &self.luma_x, &self.luma_y, dst);
+        // The chroma plane holds interleaved U/V pairs, one per two pixels
+        scale_plane::<2>(chroma, self.src_width, &self.chroma_x, &self.chroma_y, dst);
+    }
+}
+
+/// Scales frames to one output size, whatever size they are captured at.
+///
+/// The capture size can change mid-stream, e.g. when a captured window is
+/// resized, so the scaler is rebuilt whenever it does.
+pub(crate) struct FrameScaler {
+    width: u32,
+    height: u32,
+    scaler: Option<Nv12Scaler>,
+    buffer: Vec<u8>,
+}
+
+impl FrameScaler {
+    pub(crate) fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            scaler: None,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Scale an NV12 frame of `width`x`height` to the output size.
+    ///
+    /// Frames already at the output size are passed through. Returns `None`
+    /// if the frame is too short for its size.
+    pub(crate) fn scale<'a>(
+        &'a mut self,
+        data: &'a [u8],
+        width: u32,
+        height: u32,
+    ) -> Option<&'a [u8]> {
+        if (width, height) == (self.width, self.height) {
+            return Some(data);
+        }
+        if data.len() < width as usize * height as usize * 3 / 2 {
+            return None;
+        }
+
+        let scaler = match &mut self.scaler {
+            Some(scaler) if scaler.source_size() == (width, height) => scaler,
+            scaler => scaler.insert(Nv12Scaler::new(width, height, self.width, self.height)),
+        };
+        scaler.scale(data, &mut self.buffer);
+        Some(&self.buffer)
+    }
+}
+
+/// Box-filter one plane of `N` interleaved channels, appending to `dst`.
+fn scale_plane<const N: usize>(
+    src: &[u8],
+    stride: usize,
+    spans_x: &[Range<usize>],
+    spans_y: &[Range<usize>],
+    dst: &mut Vec<u8>,
+) {
+    for span_y in spans_y {
+        for span_x in spans_x {
+            let mut sums = [0u32; N];
+            for row in src[span_y.start * stride..span_y.end * stride].chunks_exact(stride) {
+                for x in span_x.clone() {
+                    for (channel, sum) in sums.iter_mut().enumerate() {
+                        *sum += row[x * N + channel] as u32;
+                    }
+                }
+            }
+
+            let count = (span_x.len() * span_y.len()) as u32;
+            dst.extend(sums.map(|sum| ((sum + count / 2) / count) as u8));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spans_cover_source() {
+        // At 1.5x neighbouring outputs share the source sample between them
+        assert_eq!(spans(6, 4), vec![0..2, 1..3, 3..5, 4..6]);
+        assert_eq!(spans(4, 2), vec![0..2, 2..4]);
+        assert_eq!(spans(3, 3), vec![0..1, 1..2, 2..3]);
+    }
+
+    #[test]
+    fn test_halving_averages_blocks() {
+        let src = [
+            0, 4, 8, 12, //
+            4, 8, 12, 16, //
+            8, 12, 16, 20, //
+            12, 16, 20, 24, //
+            100, 200, 110, 210, //
+            120, 220, 130, 230,
+        ];
+        let mut dst = Vec::new();
+        Nv12Scaler::new(4, 4, 2, 2).scale(&src, &mut dst);
+        assert_eq!(dst, vec![4, 12, 12, 20, 115, 215]);
+    }
+
+    #[test]
+    fn test_output_size() {
+        let (width, height) = (64, 36);
+        let src = vec![128u8; width * height * 3 / 2];
+        let mut dst = Vec::new();
+        let scaler = Nv12Scaler::new(width as u32, height as u32, 42, 24);
+        scaler.scale(&src, &mut dst);
+        assert_eq!(dst.len(), 42 * 24 * 3 / 2);
+        assert!(dst.iter().all(|&sample| sample == 128));
+        assert_eq!(scaler.source_size(), (64, 36));
+    }
+
+    #[test]
+    fn test_frame_scaler_follows_capture_size() {
+        let mut scaler = FrameScaler::new(4, 2);
+
+        let native = vec![7u8; 4 * 2 * 3 / 2];
+        assert_eq!(scaler.scale(&native, 4, 2), Some(&native[..]));
+
+        let larger = vec![9u8; 8 * 4 * 3 / 2];
+        assert_eq!(scaler.scale(&larger, 8, 4).map(<[u8]>::len), Some(12));
+        let resized = vec![11u8; 12 * 6 * 3 / 2];
+        assert_eq!(scaler.scale(&resized, 12, 6), Some(&[11u8; 12][..]));
+
+        assert_eq!(scaler.scale(&resized, 16, 8), None);
+    }
+}
@@ -1,17 +1,24 @@
 //! Resource management and initialization tracking.
 
-use crossbeam_channel::Receiver;
+use crossbeam_channel::{Receiver, Sender};
 use parking_lot::Mutex;
 use tracing::{debug, info, instrument, warn};
 
 use broadcaster_audio::{AudioCaptureSession, AudioMixer, MixedAudioChunk};
 use broadcaster_capture::{CaptureSession, CaptureSource, CapturedFrame};
 use broadcaster_encoder::{
-    create_audio_encoder, create_video_encoder, AudioEncoder, AudioEncoderConfig, VideoEncoder,
-    VideoEncoderConfig,
+    create_audio_encoder, create_video_encoder, fall_back_to_baseline, select_video_encoder,
+    AudioEncoder, AudioEncoderConfig, Av1Config, BenchmarkResult, EncoderPreset, EncoderTune,
+    H264Profile, PixelFormat, RateControl, RegionOfInterest, VideoBackend, VideoCodec,
+    VideoEncoder, VideoEncoderConfig,
+};
+use broadcaster_ipc::{EncoderBenchmark, EncoderInfo, RenditionConfig, StartupPhase, StreamConfig};
+use broadcaster_transport::{RtmpClient, RtmpPacket, Sps, StreamMetadata};
+
+use crate::ladder::rendition_encoder_config;
+use crate::validation::{
+    build_stream_metadata, validate_encoder_config, validate_renditions, validate_video_headers,
 };
-use broadcaster_ipc::{StartupPhase, StreamConfig};
-use broadcaster_transport::{RtmpClient, RtmpPacket};
 
 /// Resources that have been initialized during startup.
 #[derive(Default)]
@@ -29,14 +36,23 @@ pub struct InitializedResources {
     /// Video encoder.
     pub video_encoder: Option<Box<dyn VideoEncoder>>,
 
+    /// Settings the video encoder was built with.
+    pub video_config: Option<VideoEncoderConfig>,
+
     /// Audio encoder.
     pub audio_encoder: Option<Box<dyn AudioEncoder>>,
 
+    /// onMetaData describing the encoded stream.
+    pub stream_metadata: Option<StreamMetadata>,
+
     /// RTMP client.
     pub rtmp_client: Option<RtmpClient>,
 
     /// RTMP packet sender for transmitting encoded data.
-    pub rtmp_packet_tx: Option<crossbeam_channel::Sender<RtmpPacket>>,
+    pub rtmp_packet_tx: Option<Sender<RtmpPacket>>,
+
+    /// Extra renditions encoded alongside the main stream.
+    pub renditions: Vec<RenditionResources>,
 
     /// Frame receiver from capture.
     pub frame_rx: Option<Receiver<CapturedFrame>>,
@@ -45,6 +61,24 @@ pub struct InitializedResources {
     pub audio_rx: Option<Receiver<MixedAudioChunk>>,
 }
 
+/// Encoder and output of one extra rendition.
+pub struct RenditionResources {
+    /// Rendition settings.
+    pub config: RenditionConfig,
+
+    /// Video encoder at the rendition's size and bitrate.
+    pub video_encoder: Option<Box<dyn VideoEncoder>>,
+
+    /// onMetaData describing the rendition's stream.
+    pub stream_metadata: Option<StreamMetadata>,
+
+    /// RTMP client of the rendition's output.
+    pub rtmp_client: Option<RtmpClient>,
+
+    /// RTMP packet sender of the rendition's output.
+    pub rtmp_packet_tx: Option<Sender<RtmpPacket>>,
+}
+
 impl InitializedResources {
     /// Create empty resources.
     pub fn new() -> Self {
@@ -168,17 +202,71 @@ impl ResourceManager {
         };
 
         // Create video encoder
-        let video_config = VideoEncoderConfig {
+        let settings = &config.encoder;
+        let backend = settings
+            .encoder
+            .as_deref()
+            .map(|name| {
+                VideoBackend::from_name(name)
+                    .ok_or_else(|| format!("Unknown video encoder '{}'", name))
+            })
+            .transpose()?;
+        let mut video_config = VideoEncoderConfig {
             width,
             height,
-            fps: 60,
+            fps: settings.fps,
             bitrate_kbps: config.video_bitrate_kbps,
-            keyframe_interval_secs: 2,
-            ..Default::default()
+            rate_control: video_rate_control(config.rate_control),
+            keyframe_interval_secs: settings.keyframe_interval_secs,
+            profile: video_profile(settings.profile),
+            level_idc: settings.level,
+            preset: video_preset(settings.preset),
+            tune: video_tune(settings.tune),
+            threads: settings.threads,
+            slice_threads: settings.slice_threads,
+            bframes: settings.bframes,
+            lookahead_frames: settings.lookahead_frames,
+            // Captured frames are NV12
+            input_format: PixelFormat::Nv12,
+            // Picking an AV1 backend by name asks for AV1
+            codec: backend.map_or(VideoCodec::H264, |backend| backend.capabilities().codec),
+            av1: Av1Config::default(),
+            backend,
+            regions_of_interest: video_regions(&settings.regions_of_interest),
         };
+        // Reject other codecs before an encoder is benchmarked or opened
+        if let Some(backend) = backend {
+            check_streamable(backend.name(), video_config.codec)?;
+        }
+        if fall_back_to_baseline(&mut video_config) {
+            warn!(
+                requested = ?settings.profile,
+                "Encoder only supports the Baseline profile, falling back to it"
+            );
+        }
+        validate_encoder_config(&video_config)
+            .map_err(|e| format!("Invalid encoder settings: {}", e))?;
+        validate_renditions(&config.renditions, &video_config)
+            .map_err(|e| format!("Invalid renditions: {}", e))?;
+
+        if settings.auto_preset {
+            let selected = select_video_encoder(&video_config)
+                .map_err(|e| format!("Video encoder selection failed: {}", e))?;
+            if selected.preset != video_config.preset {
+                warn!(
+                    backend = selected.backend.name(),
+                    requested = ?video_config.preset,
+                    selected = ?selected.preset,
+                    "Lowered encoder preset to sustain the frame rate"
+                );
+            }
+            video_config.backend = Some(selected.backend);
+            video_config.preset = selected.preset;
+        }
 
-        let video_encoder = create_video_encoder(video_config)
+        let video_encoder = create_video_encoder(video_config.clone())
             .map_err(|e| format!("Video encoder init failed: {}", e))?;
+        let sps = check_video_encoder(video_encoder.as_ref(), &video_config)?;
 
         // Create audio encoder
         let audio_config = AudioEncoderConfig {
@@ -186,11 +274,49 @@ impl ResourceManager {
             ..Default::default()
         };
 
-        let audio_encoder = create_audio_encoder(audio_config)
+        let audio_encoder = create_audio_encoder(audio_config.clone())
             .map_err(|e| format!("Audio encoder init failed: {}", e))?;
 
+        // Renditions use the backend picked for the main stream
+        let backend = VideoBackend::from_name(video_encoder.name());
+        let mut renditions = Vec::with_capacity(config.renditions.len());
+        for rendition in &config.renditions {
+            let rendition_config = rendition_encoder_config(&video_config, rendition, backend);
+            validate_encoder_config(&rendition_config)
+                .map_err(|e| format!("Invalid settings for rendition '{}': {}", rendition.id, e))?;
+
+            let encoder = create_video_encoder(rendition_config.clone()).map_err(|e| {
+                format!(
+                    "Video encoder init failed for rendition '{}': {}",
+                    rendition.id, e
+                )
+            })?;
+            let sps = check_video_encoder(encoder.as_ref(), &rendition_config)?;
+
+            renditions.push(RenditionResources {
+                config: rendition.clone(),
+                stream_metadata: Some(build_stream_metadata(
+                    sps.as_ref(),
+                    &rendition_config,
+                    &audio_config,
+                    encoder.name(),
+                )),
+                video_encoder: Some(encoder),
+                rtmp_client: None,
+                rtmp_packet_tx: None,
+            });
+        }
+
+        resources.stream_metadata = Some(build_stream_metadata(
+            sps.as_ref(),
+            &video_config,
+            &audio_config,
+            video_encoder.name(),
+        ));
         resources.video_encoder = Some(video_encoder);
+        resources.video_config = Some(video_config);
         resources.audio_encoder = Some(audio_encoder);
+        resources.renditions = renditions;
 
         debug!("Encoders initialized");
         Ok(())
@@ -214,6 +340,32 @@ impl ResourceManager {
         resources.rtmp_client = Some(client);
         resources.rtmp_packet_tx = Some(packet_tx);
 
+        // Each rendition is stored as soon as it connects, so a later
+        // failure rolls back the ones already connected
+        for rendition in &mut resources.renditions {
+            let rtmp_url = rendition
+                .config
+                .rtmp_url
+                .clone()
+                .unwrap_or_else(|| config.rtmp_url.clone());
+            let mut client = RtmpClient::new(rtmp_url, rendition.config.stream_key.clone())
+                .map_err(|e| {
+                    format!(
+                        "RTMP client init failed for rendition '{}': {}",
+                        rendition.config.id, e
+                    )
+                })?;
+
+            let packet_tx = client.connect().map_err(|e| {
+                format!(
+                    "RTMP connect failed for rendition '{}': {}",
+                    rendition.config.id, e
+                )
+            })?;
+            rendition.rtmp_client = Some(client);
+            rendition.rtmp_packet_tx = Some(packet_tx);
+        }
+
         debug!("RTMP connected");
         Ok(())
     }
@@ -256,10 +408,19 @@ impl ResourceManager {
                 if let Some(mut client) = resources.rtmp_client.take() {
                     let _ = client.disconnect();
                 }
+                for rendition in &mut resources.renditions {
+                    rendition.rtmp_packet_tx = None;
+                    if let Some(mut client) = rendition.rtmp_client.take() {
+                        let _ = client.disconnect();
+                    }
+                }
             }
             StartupPhase::InitEncoder => {
                 resources.video_encoder = None;
+                resources.video_config = None;
                 resources.audio_encoder = None;
+                resources.stream_metadata = None;
+                resources.renditions.clear();
             }
             StartupPhase::InitAudio => {
                 if let Some(mut mixer) = resources.mixer.take() {
@@ -306,3 +467,167 @@ impl Drop for ResourceManager {
         self.shutdown();
     }
 }
+
+/// Check that video from encoder `name` in `codec` can be streamed.
+fn check_streamable(name: &str, codec: VideoCodec) -> Result<(), String> {
+    // The FLV muxer only carries H.264 until Enhanced RTMP is supported
+    if codec != VideoCodec::H264 {
+        return Err(format!(
+            "{} produces {:?}, which cannot be streamed over RTMP yet",
+            name, codec
+        ));
+    }
+    Ok(())
+}
+
+/// Check that a video encoder can be streamed and produces what `config`
+/// asked for, returning its SPS if it provides headers.
+fn check_video_encoder(
+    encoder: &dyn VideoEncoder,
+    config: &VideoEncoderConfig,
+) -> Result<Option<Sps>, String> {
+    check_streamable(encoder.name(), encoder.codec())?;
+
+    match encoder.get_headers() {
+        Some(headers) => validate_video_headers(&headers, config).map(Some),
+        None => {
+            warn!(
+                encoder = encoder.name(),
+                "Encoder provides no headers, skipping output validation"
+            );
+            Ok(None)
+        }
+    }
+}
+
+/// Map the UI rate-control setting onto the encoder's.
+pub(crate) fn video_rate_control(rate_control: broadcaster_ipc::RateControl) -> RateControl {
+    match rate_control {
+        broadcaster_ipc::RateControl::Cbr { vbv_buffer_ms } => RateControl::Cbr { vbv_buffer_ms },
+        broadcaster_ipc::RateControl::Vbr {
+            max_bitrate_kbps,
+            vbv_buffer_ms,
+        } => RateControl::Vbr {
+            max_bitrate_kbps,
+            vbv_buffer_ms,
+        },
+        broadcaster_ipc::RateControl::Crf { crf } => RateControl::Crf { crf },
+        broadcaster_ipc::RateControl::Cqp { qp } => RateControl::Cqp { qp },
+    }
+}
+
+/// Map the UI profile setting onto the encoder's.
+fn video_profile(profile: broadcaster_ipc::H264Profile) -> H264Profile {
+    match profile {
+        broadcaster_ipc::H264Profile::Baseline => H264Profile::Baseline,
+        broadcaster_ipc::H264Profile::Main => H264Profile::Main,
+        broadcaster_ipc::H264Profile::High => H264Profile::High,
+    }
+}
+
+/// Map the UI preset setting onto the encoder's.
+fn video_preset(preset: broadcaster_ipc::EncoderPreset) -> EncoderPreset {
+    match preset {
+        broadcaster_ipc::EncoderPreset::Ultrafast => EncoderPreset::Ultrafast,
+        broadcaster_ipc::EncoderPreset::Superfast => EncoderPreset::Superfast,
+        broadcaster_ipc::EncoderPreset::Veryfast => EncoderPreset::Veryfast,
+        broadcaster_ipc::EncoderPreset::Faster => EncoderPreset::Faster,
+        broadcaster_ipc::EncoderPreset::Fast => EncoderPreset::Fast,
+        broadcaster_ipc::EncoderPreset::Medium => EncoderPreset::Medium,
+        broadcaster_ipc::EncoderPreset::Slow => EncoderPreset::Slow,
+        broadcaster_ipc::EncoderPreset::Slower => EncoderPreset::Slower,
+        broadcaster_ipc::EncoderPreset::Veryslow => EncoderPreset::Veryslow,
+        broadcaster_ipc::EncoderPreset::Placebo => EncoderPreset::Placebo,
+    }
+}
+
+/// Map the UI tune setting onto the encoder's.
+fn video_tune(tune: broadcaster_ipc::EncoderTune) -> EncoderTune {
+    match tune {
+        broadcaster_ipc::EncoderTune::None => EncoderTune::None,
+        broadcaster_ipc::EncoderTune::Film => EncoderTune::Film,
+        broadcaster_ipc::EncoderTune::Animation => EncoderTune::Animation,
+        broadcaster_ipc::EncoderTune::Grain => EncoderTune::Grain,
+        broadcaster_ipc::EncoderTune::StillImage => EncoderTune::StillImage,
+        broadcaster_ipc::EncoderTune::Psnr => EncoderTune::Psnr,
+        broadcaster_ipc::EncoderTune::Ssim => EncoderTune::Ssim,
+        broadcaster_ipc::EncoderTune::FastDecode => EncoderTune::FastDecode,
+        broadcaster_ipc::EncoderTune::ZeroLatency => EncoderTune::ZeroLatency,
+    }
+}
+
+/// Map the UI regions of interest onto the encoder's.
+pub(crate) fn video_regions(
+    regions: &[broadcaster_ipc::RegionOfInterest],
+) -> Vec<RegionOfInterest> {
+    regions
+        .iter()
+        .map(|region| RegionOfInterest {
+            x: region.x,
+            y: region.y,
+            width: region.width,
+            height: region.height,
+            quality_offset: region.quality_offset,
+        })
+        .collect()
+}
+
+/// Describe a video encoder backend for the UI.
+///
+/// `selected` is the result the stream would use, from
+/// [`broadcaster_encoder::choose`].
+pub(crate) fn encoder_info(
+    backend: VideoBackend,
+    available: bool,
+    benchmark: Option<BenchmarkResult>,
+    selected: Option<BenchmarkResult>,
+    target_fps: u32,
+) -> EncoderInfo {
+    let capabilities = backend.capabilities();
+    EncoderInfo {
+        name: backend.name().to_string(),
+        hardware: capabilities.hardware,
+        available,
+        profiles: capabilities
+            .profiles
+            .iter()
+            .copied()
+            .map(ui_profile)
+            .collect(),
+        max_width: capabilities.max_width,
+        max_height: capabilities.max_height,
+        bframes: capabilities.bframes,
+        regions_of_interest: capabilities.regions_of_interest,
+        benchmark: benchmark.map(|result| EncoderBenchmark {
+            preset: ui_preset(result.preset),
+            fps: result.fps,
+            sustains_target: result.sustains(target_fps),
+            selected: selected == Some(result),
+        }),
+    }
+}
+
+/// Map an encoder profile back onto the UI's.
+fn ui_profile(profile: H264Profile) -> broadcaster_ipc::H264Profile {
+    match profile {
+        H264Profile::Baseline => broadcaster_ipc::H264Profile::Baseline,
+        H264Profile::Main => broadcaster_ipc::H264Profile::Main,
+        H264Profile::High => broadcaster_ipc::H264Profile::High,
+    }
+}
+
+/// Map an encoder preset back onto the UI's.
+pub(crate) fn ui_preset(preset: EncoderPreset) -> broadcaster_ipc::EncoderPreset {
+    match preset {
+        EncoderPreset::Ultrafast => broadcaster_ipc::EncoderPreset::Ultrafast,
+        EncoderPreset::Superfast => broadcaster_ipc::EncoderPreset::Superfast,
+        EncoderPreset::Veryfast => broadcaster_ipc::EncoderPreset::Veryfast,
+        EncoderPreset::Faster => broadcaster_ipc::EncoderPreset::Faster,
+        EncoderPreset::Fast => broadcaster_ipc::EncoderPreset::Fast,
+        EncoderPreset::Medium => broadcaster_ipc::EncoderPreset::Medium,
+        EncoderPreset::Slow => broadcaster_ipc::EncoderPreset::Slow,
+        EncoderPreset::Slower => broadcaster_ipc::EncoderPreset::Slower,
+        EncoderPreset::Veryslow => broadcaster_ipc::EncoderPreset::Veryslow,
+        EncoderPreset::Placebo => broadcaster_ipc::EncoderPreset::Placebo,
+    }
+}
@@ -0,0 +1,630 @@
+//! Startup validation of encoder settings and output.
+//!
+//! The encoder configuration is checked for impossible combinations before
+//! the encoder is built. The encoder's SPS is then parsed and compared against
+//! the configuration, so a misconfigured encoder fails the stream start
+//! instead of producing a stream the ingest or players reject.
+
+use tracing::{debug, warn};
+
+use broadcaster_encoder::{
+    AudioCodec, AudioEncoderConfig, EncoderTune, H264Profile, RateControl, RegionOfInterest,
+    VideoEncoderConfig,
+};
+use broadcaster_ipc::RenditionConfig;
+use broadcaster_transport::{
+    apply_sps_metadata, extract_sps_pps, Pps, Sps, StreamMetadata, FLV_CODEC_AAC,
+};
+
+use crate::ladder::{frame_step, MAIN_RENDITION_ID};
+
+/// Tolerance when comparing the signalled frame rate with the configured one.
+const FRAME_RATE_TOLERANCE: f64 = 0.01;
+
+/// Highest frame rate accepted.
+const MAX_FPS: u32 = 240;
+
+/// Longest keyframe interval accepted, in seconds.
+const MAX_KEYFRAME_INTERVAL_SECS: u32 = 20;
+
+/// Longest VBV buffer accepted, in milliseconds.
+const MAX_VBV_BUFFER_MS: u32 = 10_000;
+
+/// x264's thread limit.
+const MAX_THREADS: u32 = 128;
+
+/// Highest quantizer for 8-bit H.264.
+const MAX_QP: u8 = 51;
+
+/// H.264 level limits (Table A-1): `level_idc`, max macroblocks per second,
+/// max frame size in macroblocks, max Baseline/Main bitrate in kbps.
+/// Level 1b is signalled as 9 here.
+const H264_LEVELS: &[(u8, u32, u32, u32)] = &[
+    (10, 1_485, 99, 64),
+    (9, 1_485, 99, 128),
+    (11, 3_000, 396, 192),
+    (12, 6_000, 396, 384),
+    (13, 11_880, 396, 768),
+    (20, 11_880, 396, 2_000),
+    (21, 19_800, 792, 4_000),
+    (22, 20_250, 1_620, 4_000),
+    (30, 40_500, 1_620, 10_000),
+    (31, 108_000, 3_600, 14_000),
+    (32, 216_000, 5_120, 20_000),
+    (40, 245_760, 8_192, 20_000),
+    (41, 245_760, 8_192, 50_000),
+    (42, 522_240, 8_704, 50_000),
+    (50, 589_824, 22_080, 135_000),
+    (51, 983_040, 36_864, 240_000),
+    (52, 2_073_600, 36_864, 240_000),
+];
+
+/// Check a video encoder configuration for impossible combinations.
+pub fn validate_encoder_config(config: &VideoEncoderConfig) -> Result<(), String> {
+    if config.width == 0
+        || config.height == 0
+        || !config.width.is_multiple_of(2)
+        || !config.height.is_multiple_of(2)
+    {
+        return Err(format!(
+            "Resolution {}x{} must be non-zero and even for 4:2:0 video",
+            config.width, config.height
+        ));
+    }
+
+    if config.fps == 0 || config.fps > MAX_FPS {
+        return Err(format!(
+            "Frame rate must be between 1 and {} fps, got {}",
+            MAX_FPS, config.fps
+        ));
+    }
+
+    if config.keyframe_interval_secs == 0
+        || config.keyframe_interval_secs > MAX_KEYFRAME_INTERVAL_SECS
+    {
+        return Err(format!(
+            "Keyframe interval must be between 1 and {} seconds, got {}",
+            MAX_KEYFRAME_INTERVAL_SECS, config.keyframe_interval_secs
+        ));
+    }
+
+    if config.bframes > 0 && config.profile == H264Profile::Baseline {
+        return Err("B-frames are not allowed in the Baseline profile".to_string());
+    }
+
+    if config.tune == EncoderTune::ZeroLatency && !config.is_low_latency() {
+        return Err(format!(
+            "The zero-latency tune cannot be combined with B-frames ({}) or lookahead ({})",
+            config.bframes, config.lookahead_frames
+        ));
+    }
+
+    if config.threads > MAX_THREADS {
+        return Err(format!(
+            "Thread count must be at most {}, got {}",
+            MAX_THREADS, config.threads
+        ));
+    }
+
+    if config.slice_threads && config.threads == 1 {
+        return Err("Slice threading needs more than one thread".to_string());
+    }
+
+    match config.rate_control {
+        RateControl::Cbr { vbv_buffer_ms } | RateControl::Vbr { vbv_buffer_ms, .. }
+            if vbv_buffer_ms == 0 =>
+        {
+            return Err("VBV buffer must not be empty".to_string());
+        }
+        RateControl::Cbr { vbv_buffer_ms } | RateControl::Vbr { vbv_buffer_ms, .. }
+            if vbv_buffer_ms > MAX_VBV_BUFFER_MS =>
+        {
+            return Err(format!(
+                "VBV buffer must be at most {} ms, got {} ms",
+                MAX_VBV_BUFFER_MS, vbv_buffer_ms
+            ));
+        }
+        RateControl::Vbr {
+            max_bitrate_kbps, ..
+        } if max_bitrate_kbps < config.bitrate_kbps => {
+            return Err(format!(
+                "VBR maximum bitrate {} kbps is below the target of {} kbps",
+                max_bitrate_kbps, config.bitrate_kbps
+            ));
+        }
+        RateControl::Crf { crf: quality } | RateControl::Cqp { qp: quality }
+            if quality > MAX_QP =>
+        {
+            return Err(format!(
+                "Quality {} is out of range (0-{})",
+                quality, MAX_QP
+            ));
+        }
+        _ => {}
+    }
+
+    if let Some(level_idc) = config.level_idc {
+        validate_level(config, level_idc)?;
+    }
+
+    validate_regions_of_interest(&config.regions_of_interest, config.width, config.height)?;
+
+    Ok(())
+}
+
+/// Check that resolution, frame rate and bitrate fit in an H.264 level.
+fn validate_level(config: &VideoEncoderConfig, level_idc: u8) -> Result<(), String> {
+    let level_name = if level_idc == 9 {
+        "1b".to_string()
+    } else {
+        format!("{}.{}", level_idc / 10, level_idc % 10)
+    };
+
+    let &(_, max_mbps, max_fs, max_br_kbps) = H264_LEVELS
+        .iter()
+        .find(|&&(idc, ..)| idc == level_idc)
+        .ok_or_else(|| format!("Unknown H.264 level_idc {}", level_idc))?;
+
+    let frame_mbs = config.width.div_ceil(16) * config.height.div_ceil(16);
+    if frame_mbs > max_fs {
+        return Err(format!(
+            "Level {} allows at most {} macroblocks per frame, {}x{} needs {}",
+            level_name, max_fs, config.width, config.height, frame_mbs
+        ));
+    }
+
+    let mbps = frame_mbs * config.fps;
+    if mbps > max_mbps {
+        return Err(format!(
+            "Level {} allows at most {} macroblocks per second, {}x{} at {} fps needs {}",
+            level_name, max_mbps, config.width, config.height, config.fps, mbps
+        ));
+    }
+
+    // High profile allows 25% more (cpbBrVclFactor 1250 vs 1000)
+    let max_br_kbps = match config.profile {
+        H264Profile::High => max_br_kbps * 5 / 4,
+        H264Profile::Baseline | H264Profile::Main => max_br_kbps,
+    };
+    if let Some((peak_kbps, _)) = config.vbv() {
+        if peak_kbps > max_br_kbps {
+            return Err(format!(
+                "Level {} with {:?} profile allows at most {} kbps, got {} kbps",
+                level_name, config.profile, max_br_kbps, peak_kbps
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Parse the encoder's Annex B headers and check them against `config`.
+///
+/// Returns the parsed SPS on success.
+pub fn validate_video_headers(headers: &[u8], config: &VideoEncoderConfig) -> Result<Sps, String> {
+    let (sps_nal, pps_nal) =
+        extract_sps_pps(headers).ok_or("Encoder headers contain no SPS/PPS")?;
+
+    let sps = Sps::parse(&sps_nal).map_err(|e| format!("Encoder produced invalid SPS: {}", e))?;
+    Pps::parse(&pps_nal, Some(&sps)).map_err(|e| format!("Encoder produced invalid PPS: {}", e))?;
+
+    debug!(
+        profile_idc = sps.profile_idc,
+        level_idc = sps.level_idc,
+        width = sps.width(),
+        height = sps.height(),
+        "Parsed encoder SPS"
+    );
+
+    if sps.width() != config.width || sps.height() != config.height {
+        return Err(format!(
+            "Encoder resolution {}x{} does not match requested {}x{}",
+            sps.width(),
+            sps.height(),
+            config.width,
+            config.height
+        ));
+    }
+
+    // Encoders may pick a lower profile when no higher-profile tools are used
+    if sps.profile_idc > config.profile.profile_idc() {
+        return Err(format!(
+            "Encoder profile_idc {} exceeds requested {:?}",
+            sps.profile_idc, config.profile
+        ));
+    }
+
+    if let Some(level_idc) = config.level_idc {
+        if sps.level_idc != level_idc {
+            warn!(
+                signalled = sps.level_idc,
+                requested = level_idc,
+                "Encoder level does not match requested level"
+            );
+        }
+    }
+
+    if sps.chroma_format_idc != 1 {
+        return Err(format!(
+            "Encoder chroma format {} is not 4:2:0",
+            sps.chroma_format_idc
+        ));
+    }
+
+    if sps.bit_depth_luma != 8 || sps.bit_depth_chroma != 8 {
+        return Err(format!(
+            "Encoder bit depth {}/{} is not 8-bit",
+            sps.bit_depth_luma, sps.bit_depth_chroma
+        ));
+    }
+
+    match sps.frame_rate() {
+        Some(fps) if (fps - config.fps as f64).abs() > FRAME_RATE_TOLERANCE => {
+            warn!(
+                signalled = fps,
+                requested = config.fps,
+                "Encoder VUI frame rate does not match requested fps"
+            );
+        }
+        Some(_) => {}
+        None => debug!("Encoder SPS carries no VUI timing information"),
+    }
+
+    Ok(sps)
+}
+
+/// Check regions of interest against a `width` x `height` frame.
+///
+/// Regions must lie inside the frame, and their offsets must stay within the
+/// quantizer range.
+pub fn validate_regions_of_interest(
+    regions: &[RegionOfInterest],
+    width: u32,
+    height: u32,
+) -> Result<(), String> {
+    for region in regions {
+        if region.width == 0 || region.height == 0 {
+            return Err(format!(
+                "Region of interest at {},{} is empty",
+                region.x, region.y
+            ));
+        }
+
+        let right = region.x as u64 + region.width as u64;
+        let bottom = region.y as u64 + region.height as u64;
+        if right > width as u64 || bottom > height as u64 {
+            return Err(format!(
+                "Region of interest {}x{} at {},{} is outside the {}x{} frame",
+                region.width, region.height, region.x, region.y, width, height
+            ));
+        }
+
+        if region.quality_offset.unsigned_abs() > MAX_QP {
+            return Err(format!(
+                "Region of interest quality offset must be between -{} and {}, got {}",
+                MAX_QP, MAX_QP, region.quality_offset
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Check the renditions of a stream encoded with `main`.
+///
+/// Renditions are scaled down from the captured frames and take every n-th
+/// of them, so they can be no larger than the main stream and their frame
+/// rate must divide its frame rate.
+pub fn validate_renditions(
+    renditions: &[RenditionConfig],
+    main: &VideoEncoderConfig,
+) -> Result<(), String> {
+    for (i, rendition) in renditions.iter().enumerate() {
+        let id = &rendition.id;
+        if id.is_empty() || id == MAIN_RENDITION_ID {
+            return Err(format!(
+                "Rendition IDs must be non-empty and not '{}'",
+                MAIN_RENDITION_ID
+            ));
+        }
+        if renditions[..i].iter().any(|other| other.id == *id) {
+            return Err(format!("Rendition '{}' is defined twice", id));
+        }
+
+        if rendition.width > main.width || rendition.height > main.height {
+            return Err(format!(
+                "Rendition '{}' at {}x{} is larger than the {}x{} capture",
+                id, rendition.width, rendition.height, main.width, main.height
+            ));
+        }
+
+        if frame_step(main.fps, rendition.fps).is_none() {
+            return Err(format!(
+                "Rendition '{}' at {} fps does not divide the main {} fps",
+                id, rendition.fps, main.fps
+            ));
+        }
+
+        if rendition.video_bitrate_kbps == 0 {
+            return Err(format!("Rendition '{}' needs a bitrate", id));
+        }
+
+        if rendition.stream_key.is_empty() {
+            return Err(format!("Rendition '{}' needs a stream key", id));
+        }
+    }
+
+    Ok(())
+}
+
+/// Build the onMetaData for a stream.
+///
+/// Video fields come from the SPS when available and fall back to `video`,
+/// apart from the frame rate, which is always the configured one.
+pub fn build_stream_metadata(
+    sps: Option<&Sps>,
+    video: &VideoEncoderConfig,
+    audio: &AudioEncoderConfig,
+    encoder_name: &str,
+) -> StreamMetadata {
+    let mut metadata = StreamMetadata::new();
+
+    metadata.video_width = Some(video.width);
+    metadata.video_height = Some(video.height);
+    metadata.video_frame_rate = Some(video.fps as f32);
+    if let Some(sps) = sps {
+        apply_sps_metadata(&mut metadata, sps);
+    }
+    // Quality-based rate control has no meaningful bitrate to announce
+    metadata.video_bitrate_kbps = video.vbv().map(|_| video.bitrate_kbps);
+
+    // Legacy FLV has no codec ID for Opus
+    metadata.audio_codec_id = (audio.codec == AudioCodec::Aac).then_some(FLV_CODEC_AAC);
+    metadata.audio_bitrate_kbps = Some(audio.bitrate_kbps);
+    metadata.audio_sample_rate = Some(audio.sample_rate);
+    metadata.audio_channels = Some(audio.channels as u32);
+    metadata.audio_is_stereo = Some(audio.channels == 2);
+
+    metadata.encoder = Some(format!("broadcaster ({})", encoder_name));
+
+    metadata
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use broadcaster_encoder::testing::FakeH264Encoder;
+    use broadcaster_encoder::VideoEncoder;
+
+    #[test]
+    fn test_default_config_is_valid() {
+        assert_eq!(
+            validate_encoder_config(&VideoEncoderConfig::default()),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_impossible_combinations_are_rejected() {
+        let baseline_bframes = VideoEncoderConfig {
+            profile: H264Profile::Baseline,
+            bframes: 2,
+            ..Default::default()
+        };
+        assert!(validate_encoder_config(&baseline_bframes)
+            .unwrap_err()
+            .contains("Baseline"));
+
+        let zero_latency_lookahead = VideoEncoderConfig {
+            tune: EncoderTune::ZeroLatency,
+            lookahead_frames: 10,
+            ..Default::default()
+        };
+        assert!(validate_encoder_config(&zero_latency_lookahead).is_err());
+    }
+
+    #[test]
+    fn test_vbv_buffer_bounds() {
+        let long_buffer = VideoEncoderConfig {
+            bitrate_kbps: 100_000,
+            rate_control: RateControl::Cbr {
+                vbv_buffer_ms: 60_000,
+            },
+            ..Default::default()
+        };
+        assert_eq!(long_buffer.vbv(), Some((100_000, 6_000_000)));
+        assert!(validate_encoder_config(&long_buffer)
+            .unwrap_err()
+            .contains("VBV buffer"));
+
+        // The kbps x ms product overflows u32; saturates instead of wrapping
+        let huge = VideoEncoderConfig {
+            rate_control: RateControl::Vbr {
+                max_bitrate_kbps: u32::MAX,
+                vbv_buffer_ms: u32::MAX,
+            },
+            ..Default::default()
+        };
+        assert_eq!(huge.vbv(), Some((u32::MAX, u32::MAX)));
+        assert!(validate_encoder_config(&huge).is_err());
+    }
+
+    #[test]
+    fn test_level_limits() {
+        // 1080p60 needs level 4.2
+        let config = VideoEncoderConfig {
+            level_idc: Some(41),
+            ..Default::default()
+        };
+        assert!(validate_encoder_config(&config)
+            .unwrap_err()
+            .contains("macroblocks per second"));
+
+        let config = VideoEncoderConfig {
+            level_idc: Some(42),
+            ..Default::default()
+        };
+        assert_eq!(validate_encoder_config(&config), Ok(()));
+
+        let config = VideoEncoderConfig {
+            level_idc: Some(31),
+            ..Default::default()
+        };
+        assert!(validate_encoder_config(&config)
+            .unwrap_err()
+            .contains("macroblocks per frame"));
+    }
+
+    #[test]
+    fn test_fake_encoder_headers_validate() {
+        for profile in [H264Profile::Baseline, H264Profile::Main, H264Profile::High] {
+            // 1080 rows are cropped from 1088
+            let config = VideoEncoderConfig {
+                profile,
+                ..Default::default()
+            };
+            let encoder = FakeH264Encoder::new(config.clone()).unwrap();
+            let sps = validate_video_headers(&encoder.get_headers().unwrap(), &config).unwrap();
+            assert_eq!(sps.profile_idc, profile.profile_idc());
+            assert_eq!(sps.frame_rate(), Some(60.0));
+        }
+    }
+
+    #[test]
+    fn test_mismatched_headers_are_rejected() {
+        let encoder = FakeH264Encoder::new(VideoEncoderConfig {
+            width: 1280,
+            height: 720,
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(
+            validate_video_headers(&encoder.get_headers().unwrap(), &Default::default())
+                .unwrap_err()
+                .contains("resolution")
+        );
+    }
+
+    #[test]
+    fn test_metadata_keeps_configured_frame_rate() {
+        let encoder = FakeH264Encoder::new(VideoEncoderConfig {
+            fps: 60,
+            ..Default::default()
+        })
+        .unwrap();
+        let (sps, _) = extract_sps_pps(&encoder.get_headers().unwrap()).unwrap();
+        let sps = Sps::parse(&sps).unwrap();
+
+        let video = VideoEncoderConfig {
+            fps: 30,
+            ..Default::default()
+        };
+        let metadata =
+            build_stream_metadata(Some(&sps), &video, &AudioEncoderConfig::default(), "fake");
+        assert_eq!(metadata.video_frame_rate, Some(30.0));
+    }
+
+    #[cfg(feature = "x264")]
+    #[test]
+    fn test_x264_signals_frame_rate_with_bframes() {
+        let config = VideoEncoderConfig {
+            width: 320,
+            height: 240,
+            fps: 30,
+            bframes: 3,
+            lookahead_frames: 10,
+            ..Default::default()
+        };
+        let mut encoder = broadcaster_encoder::X264Encoder::new(config.clone()).unwrap();
+        let frame = vec![128u8; config.input_format.frame_size(320, 240)];
+        let mut packets = Vec::new();
+        for i in 0..10 {
+            packets.extend(encoder.encode(&frame, i * 10_000_000 / 30).unwrap());
+        }
+        packets.extend(encoder.flush().unwrap());
+
+        let sps = validate_video_headers(&encoder.get_headers().unwrap(), &config).unwrap();
+        assert_eq!(sps.frame_rate(), Some(30.0));
+
+        // The SPS repeated in front of the IDR frame
+        let keyframe = packets.iter().find(|packet| packet.is_keyframe).unwrap();
+        let (sps, _) = extract_sps_pps(&keyframe.data).unwrap();
+        assert_eq!(Sps::parse(&sps).unwrap().frame_rate(), Some(30.0));
+    }
+
+    fn rendition(id: &str, width: u32, height: u32, fps: u32) -> RenditionConfig {
+        RenditionConfig {
+            id: id.into(),
+            width,
+            height,
+            fps,
+            video_bitrate_kbps: 3000,
+            rtmp_url: None,
+            stream_key: "key".into(),
+        }
+    }
+
+    #[test]
+    fn test_rendition_ladder() {
+        let main = VideoEncoderConfig::default();
+        let ladder = [
+            rendition("720p30", 1280, 720, 30),
+            rendition("480p30", 854, 480, 30),
+        ];
+        assert_eq!(validate_renditions(&ladder, &main), Ok(()));
+
+        let duplicate = [
+            rendition("720p", 1280, 720, 30),
+            rendition("720p", 1280, 720, 60),
+        ];
+        assert!(validate_renditions(&duplicate, &main)
+            .unwrap_err()
+            .contains("twice"));
+
+        let upscaled = [rendition("1440p", 2560, 1440, 60)];
+        assert!(validate_renditions(&upscaled, &main)
+            .unwrap_err()
+            .contains("larger"));
+
+        let uneven_rate = [rendition("720p25", 1280, 720, 25)];
+        assert!(validate_renditions(&uneven_rate, &main)
+            .unwrap_err()
+            .contains("divide"));
+
+        let reserved = [rendition(MAIN_RENDITION_ID, 1280, 720, 30)];
+        assert!(validate_renditions(&reserved, &main).is_err());
+    }
+
+    #[test]
+    fn test_regions_of_interest_fit_the_frame() {
+        let region = |x, y, width, height, quality_offset| RegionOfInterest {
+            x,
+            y,
+            width,
+            height,
+            quality_offset,
+        };
+
+        let config = VideoEncoderConfig {
+            regions_of_interest: vec![
+                region(0, 0, 1920, 1080, 10),
+                region(1600, 40, 320, 600, -51),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(validate_encoder_config(&config), Ok(()));
+
+        assert!(
+            validate_regions_of_interest(&[region(1800, 0, 200, 100, 4)], 1920, 1080)
+                .unwrap_err()
+                .contains("outside")
+        );
+        assert!(validate_regions_of_interest(&[region(u32::MAX, 0, 2, 2, 4)], 1920, 1080).is_err());
+        assert!(
+            validate_regions_of_interest(&[region(0, 0, 0, 100, 4)], 1920, 1080)
+                .unwrap_err()
+                .contains("empty")
+        );
+        assert!(validate_regions_of_interest(&[region(0, 0, 16, 16, 52)], 1920, 1080).is_err());
+    }
+}
@@ -2,13 +2,13 @@
 
 use serde::{Deserialize, Serialize};
 
-use crate::types::StreamConfig;
+use crate::types::{EncoderSettingsUpdate, RegionOfInterest, StreamConfig};
 
 /// Commands that the UI can send to the engine.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum EngineCommand {
     /// Start streaming with the given configuration.
-    Start { config: StreamConfig },
+    Start { config: Box<StreamConfig> },
 
     /// Stop the current stream.
     Stop,
@@ -25,9 +25,28 @@ pub enum EngineCommand {
     /// Mute or unmute system audio.
     SetSystemMuted(bool),
 
+    /// Change video encoder settings while live.
+    UpdateEncoderSettings { settings: EncoderSettingsUpdate },
+
+    /// Make the next video frame a keyframe.
+    ForceKeyframe,
+
+    /// Replace the regions encoded at their own quality while live.
+    SetRegionsOfInterest { regions: Vec<RegionOfInterest> },
+
+    /// Send a line of closed caption text with the live video.
+    SendCaption { text: String },
+
     /// Request the list of available capture sources.
     GetCaptureSources,
 
+    /// Request the list of video encoder backends.
+    ///
+    /// With `benchmark` set, each available backend encodes synthetic frames
+    /// at the default resolution and frame rate to find a preset that keeps
+    /// up. This takes a few seconds and is skipped while live.
+    GetEncoders { benchmark: bool },
+
     /// Request the list of available audio devices.
     GetAudioDevices,
 
@@ -3,7 +3,7 @@
 use serde::{Deserialize, Serialize};
 
 use crate::state::EngineState;
-use crate::types::{AudioDevice, CaptureSource, StreamMetrics, WarningType};
+use crate::types::{AudioDevice, CaptureSource, EncoderInfo, StreamMetrics, WarningType};
 
 /// Events that the engine can send to the UI.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -38,6 +38,9 @@ pub enum EngineEvent {
     /// List of available audio devices.
     AudioDevices(Vec<AudioDevice>),
 
+    /// Video encoder backends, most preferred first.
+    Encoders(Vec<EncoderInfo>),
+
     /// Engine is ready.
     Ready,
 
@@ -12,7 +12,9 @@ pub use commands::EngineCommand;
 pub use events::EngineEvent;
 pub use state::{EngineState, ShutdownPhase, StartupPhase, StopReason};
 pub use types::{
-    AudioDevice, AudioDeviceType, CaptureSource, CaptureSourceType, StreamConfig, StreamMetrics,
+    AudioDevice, AudioDeviceType, CaptureSource, CaptureSourceType, EncoderBenchmark, EncoderInfo,
+    EncoderPreset, EncoderSettings, EncoderSettingsUpdate, EncoderTune, H264Profile, QueueDepths,
+    RateControl, RegionOfInterest, RenditionConfig, RenditionMetrics, StreamConfig, StreamMetrics,
     WarningType,
 };
 
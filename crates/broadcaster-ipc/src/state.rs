@@ -20,7 +20,7 @@ pub enum EngineState {
     /// Engine is live and streaming.
     Live {
         /// Active stream configuration.
-        config: StreamConfig,
+        config: Box<StreamConfig>,
 
         /// Current stream metrics.
         metrics: StreamMetrics,
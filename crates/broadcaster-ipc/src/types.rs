@@ -26,8 +26,26 @@ pub struct StreamConfig {
     /// Video bitrate in kbps (default: 6000).
     pub video_bitrate_kbps: u32,
 
+    /// Video rate-control mode (default: CBR with a one second VBV buffer).
+    #[serde(default)]
+    pub rate_control: RateControl,
+
     /// Audio bitrate in kbps (default: 128).
     pub audio_bitrate_kbps: u32,
+
+    /// Embed capture wall-clock timestamps in the video for latency
+    /// measurement (default: false).
+    #[serde(default)]
+    pub latency_sei: bool,
+
+    /// Video encoder tuning (default: veryfast, High profile, 60 fps).
+    #[serde(default)]
+    pub encoder: EncoderSettings,
+
+    /// Further renditions encoded from the same capture alongside the main
+    /// stream, each sent to its own output (default: none).
+    #[serde(default)]
+    pub renditions: Vec<RenditionConfig>,
 }
 
 impl Default for StreamConfig {
@@ -40,7 +58,218 @@ impl Default for StreamConfig {
             mic_volume: 1.0,
             system_volume: 1.0,
             video_bitrate_kbps: 6000,
+            rate_control: RateControl::default(),
             audio_bitrate_kbps: 128,
+            latency_sei: false,
+            encoder: EncoderSettings::default(),
+            renditions: Vec::new(),
+        }
+    }
+}
+
+/// A rendition of the stream at its own resolution, frame rate and bitrate.
+///
+/// Renditions share the main stream's encoder settings and keyframe
+/// interval, and their keyframes fall on the same captured frames.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RenditionConfig {
+    /// Name used in metrics and logs, e.g. "720p30".
+    pub id: String,
+
+    /// Width in pixels, at most the capture width.
+    pub width: u32,
+
+    /// Height in pixels, at most the capture height.
+    pub height: u32,
+
+    /// Frames per second; must divide the main stream's frame rate.
+    pub fps: u32,
+
+    /// Video bitrate in kbps.
+    pub video_bitrate_kbps: u32,
+
+    /// RTMP server URL (default: the main stream's).
+    #[serde(default)]
+    pub rtmp_url: Option<String>,
+
+    /// Stream key of this rendition's output.
+    pub stream_key: String,
+}
+
+/// Video encoder tuning chosen when the stream starts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EncoderSettings {
+    /// Video encoder backend by name, e.g. "x264" or "openh264" (default:
+    /// the most preferred one that handles the settings).
+    pub encoder: Option<String>,
+
+    /// Speed/quality tradeoff (default: veryfast).
+    pub preset: EncoderPreset,
+
+    /// Benchmark the encoders at startup, picking the first that sustains
+    /// the frame rate and stepping down to faster presets if needed
+    /// (default: false).
+    pub auto_preset: bool,
+
+    /// Content tuning (default: none).
+    pub tune: EncoderTune,
+
+    /// H.264 profile (default: High). Lowered to Baseline when the encoder
+    /// picked by name, or the only one available, is Baseline-only
+    /// (OpenH264).
+    pub profile: H264Profile,
+
+    /// H.264 `level_idc`, e.g. 41 for level 4.1 (default: chosen by the
+    /// encoder).
+    pub level: Option<u8>,
+
+    /// Keyframe interval in seconds (default: 2).
+    pub keyframe_interval_secs: u32,
+
+    /// Frames per second (default: 60).
+    pub fps: u32,
+
+    /// Encoder threads, 0 for automatic (default: 0).
+    pub threads: u32,
+
+    /// Split frames into slices encoded in parallel instead of encoding
+    /// several frames at once. Lower latency, lower efficiency (default: false).
+    pub slice_threads: bool,
+
+    /// Maximum consecutive B-frames (default: 0, lowest latency).
+    pub bframes: u32,
+
+    /// Rate-control lookahead in frames (default: 0).
+    pub lookahead_frames: u32,
+
+    /// Regions encoded at their own quality, e.g. an IDE panel or chat box
+    /// in a screen share (default: none). Only x264 supports them, and not
+    /// with CQP rate control. With a preset or tune that turns adaptive
+    /// quantization off (ultrafast, psnr), regions can only be changed while
+    /// live if the stream started with some.
+    pub regions_of_interest: Vec<RegionOfInterest>,
+}
+
+impl Default for EncoderSettings {
+    fn default() -> Self {
+        Self {
+            encoder: None,
+            preset: EncoderPreset::Veryfast,
+            auto_preset: false,
+            tune: EncoderTune::None,
+            profile: H264Profile::High,
+            level: None,
+            keyframe_interval_secs: 2,
+            fps: 60,
+            threads: 0,
+            slice_threads: false,
+            bframes: 0,
+            lookahead_frames: 0,
+            regions_of_interest: Vec::new(),
+        }
+    }
+}
+
+/// A rectangle of the captured frame encoded at its own quality.
+///
+/// Later regions take precedence where regions overlap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegionOfInterest {
+    /// Left edge in pixels.
+    pub x: u32,
+
+    /// Top edge in pixels.
+    pub y: u32,
+
+    /// Width in pixels.
+    pub width: u32,
+
+    /// Height in pixels.
+    pub height: u32,
+
+    /// Quantizer steps to lower (positive, sharper) or raise (negative)
+    /// the quality by, at most 51 either way.
+    pub quality_offset: i8,
+}
+
+/// Encoder speed preset, fastest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EncoderPreset {
+    Ultrafast,
+    Superfast,
+    Veryfast,
+    Faster,
+    Fast,
+    Medium,
+    Slow,
+    Slower,
+    Veryslow,
+    Placebo,
+}
+
+/// Encoder tuning for the content or use case.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EncoderTune {
+    None,
+    Film,
+    Animation,
+    Grain,
+    StillImage,
+    Psnr,
+    Ssim,
+    FastDecode,
+    ZeroLatency,
+}
+
+/// H.264 profile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum H264Profile {
+    Baseline,
+    Main,
+    High,
+}
+
+/// Encoder settings changed while live.
+///
+/// Fields left at `None` keep their current value.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EncoderSettingsUpdate {
+    /// Video bitrate in kbps.
+    pub video_bitrate_kbps: Option<u32>,
+
+    /// Keyframe interval in seconds.
+    pub keyframe_interval_secs: Option<u32>,
+
+    /// Video rate-control mode.
+    pub rate_control: Option<RateControl>,
+}
+
+/// Video rate-control mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RateControl {
+    /// Constant bitrate at `video_bitrate_kbps`, enforced by a VBV buffer
+    /// holding `vbv_buffer_ms` of video.
+    Cbr { vbv_buffer_ms: u32 },
+
+    /// Variable bitrate averaging `video_bitrate_kbps`, capped at
+    /// `max_bitrate_kbps` over a VBV buffer of `vbv_buffer_ms`.
+    Vbr {
+        max_bitrate_kbps: u32,
+        vbv_buffer_ms: u32,
+    },
+
+    /// Constant quality, for recording (lower is better).
+    Crf { crf: u8 },
+
+    /// Constant quantizer, for recording.
+    Cqp { qp: u8 },
+}
+
+impl Default for RateControl {
+    fn default() -> Self {
+        Self::Cbr {
+            vbv_buffer_ms: 1000,
         }
     }
 }
@@ -72,14 +301,92 @@ pub struct StreamMetrics {
     /// Frames dropped at network stage.
     pub network_drops: u64,
 
-    /// Encoder load percentage (0-100).
+    /// Encoder load percentage (0-100): average encode time relative to
+    /// the frame interval.
     pub encoder_load_percent: f32,
 
+    /// Median time to encode a frame in milliseconds.
+    #[serde(default)]
+    pub encode_time_p50_ms: f32,
+
+    /// 95th percentile time to encode a frame in milliseconds.
+    #[serde(default)]
+    pub encode_time_p95_ms: f32,
+
+    /// 99th percentile time to encode a frame in milliseconds.
+    #[serde(default)]
+    pub encode_time_p99_ms: f32,
+
     /// Network buffer fullness percentage (0-100).
     pub buffer_fullness_percent: f32,
 
+    /// Items waiting in each pipeline queue.
+    #[serde(default)]
+    pub queue_depths: QueueDepths,
+
     /// Stream uptime in seconds.
     pub uptime_seconds: u64,
+
+    /// Metrics of each rendition, the main stream first. Empty when only
+    /// the main stream is encoded.
+    #[serde(default)]
+    pub renditions: Vec<RenditionMetrics>,
+}
+
+/// Real-time metrics of one rendition.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RenditionMetrics {
+    /// Rendition name; "main" for the main stream.
+    pub id: String,
+
+    /// Width in pixels.
+    pub width: u32,
+
+    /// Height in pixels.
+    pub height: u32,
+
+    /// Current video frames per second.
+    pub fps: f32,
+
+    /// Target video frames per second.
+    pub target_fps: f32,
+
+    /// Current bitrate in kbps, including audio.
+    pub bitrate_kbps: u32,
+
+    /// Target video bitrate in kbps.
+    pub target_bitrate_kbps: u32,
+
+    /// Frames this rendition's encoder dropped or failed to encode.
+    pub encode_drops: u64,
+
+    /// Encoder load percentage (0-100).
+    pub encoder_load_percent: f32,
+
+    /// 95th percentile time to encode a frame in milliseconds.
+    pub encode_time_p95_ms: f32,
+
+    /// Output buffer fullness percentage (0-100).
+    pub buffer_fullness_percent: f32,
+}
+
+/// Number of items waiting in front of each pipeline stage.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueDepths {
+    /// Captured frames not yet picked up by the capture stage.
+    pub capture: u32,
+
+    /// Frames waiting for the video encoder.
+    pub video_encode: u32,
+
+    /// Mixed audio chunks waiting for the audio encoder.
+    pub audio_encode: u32,
+
+    /// Encoded packets waiting to be muxed into FLV tags.
+    pub mux: u32,
+
+    /// FLV tags waiting to be sent over RTMP.
+    pub network: u32,
 }
 
 /// Types of performance warnings.
@@ -99,6 +406,13 @@ pub enum WarningType {
 
     /// Low available memory.
     LowMemory { available_mb: u64 },
+
+    /// The startup benchmark lowered the encoder preset to sustain the
+    /// frame rate.
+    PresetLowered {
+        requested: EncoderPreset,
+        selected: EncoderPreset,
+    },
 }
 
 /// A capture source (monitor or window).
@@ -155,3 +469,50 @@ pub enum AudioDeviceType {
     /// Output device (for loopback capture).
     Output,
 }
+
+/// A video encoder backend and what it can do.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncoderInfo {
+    /// Backend name, e.g. "nvenc" or "x264".
+    pub name: String,
+
+    /// Whether encoding runs on dedicated hardware.
+    pub hardware: bool,
+
+    /// Whether the backend is built in and opens on this machine.
+    pub available: bool,
+
+    /// H.264 profiles the backend can produce.
+    pub profiles: Vec<H264Profile>,
+
+    /// Largest frame width in pixels.
+    pub max_width: u32,
+
+    /// Largest frame height in pixels.
+    pub max_height: u32,
+
+    /// Whether B-frames are supported.
+    pub bframes: bool,
+
+    /// Whether regions of interest are supported.
+    pub regions_of_interest: bool,
+
+    /// Benchmark result, if one was requested and the backend is available.
+    pub benchmark: Option<EncoderBenchmark>,
+}
+
+/// Synthetic-frame benchmark of an encoder backend.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncoderBenchmark {
+    /// Slowest preset that kept up, or the fastest one tried.
+    pub preset: EncoderPreset,
+
+    /// Frames encoded per second.
+    pub fps: f32,
+
+    /// Whether the backend sustains the target frame rate.
+    pub sustains_target: bool,
+
+    /// Whether this backend and preset would be used for a stream.
+    pub selected: bool,
+}
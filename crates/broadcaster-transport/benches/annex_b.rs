@@ -0,0 +1,159 @@
+//! Annex B parsing benchmarks.
+//!
+//! Compares the original byte-by-byte parser with the copying, zero-copy and
+//! streaming parsers on frames shaped like x264 output: an AUD, an SEI and
+//! four slices.
+
+use broadcaster_transport::{
+    filter_parameter_sets, nals_to_avcc, parse_annex_b, parse_annex_b_bytes, AnnexBStreamParser,
+    NalUnit, NalUnitType,
+};
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+/// Build an Annex B access unit with `slice_size` bytes per slice.
+fn access_unit(slice_size: usize) -> Bytes {
+    let mut data = Vec::new();
+
+    data.extend_from_slice(&[0x00, 0x00, 0x00, 0x01, 0x09, 0xF0]); // AUD
+    data.extend_from_slice(&[0x00, 0x00, 0x01, 0x06, 0x05, 0x10]); // SEI
+    data.extend(std::iter::repeat_n(0xA5, 16));
+    data.push(0x80);
+
+    for i in 0..4 {
+        data.extend_from_slice(&[0x00, 0x00, 0x01, 0x65]);
+        // Pseudo-random payload without start code emulation
+        let mut x = 0x1234_5678u32 ^ i;
+        data.extend((0..slice_size).map(|_| {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            (x as u8) | 0x01
+        }));
+    }
+
+    Bytes::from(data)
+}
+
+/// The parser as it was before the zero-copy rewrite, kept as a baseline.
+fn legacy_parse_annex_b(data: &[u8]) -> Vec<NalUnit> {
+    let mut nals = Vec::new();
+    let mut i = 0;
+    let len = data.len();
+
+    while i < len {
+        // Find start code (0x000001 or 0x00000001)
+        let start_code_len = if i + 3 < len && data[i] == 0 && data[i + 1] == 0 {
+            if data[i + 2] == 1 {
+                3 // 0x000001
+            } else if i + 4 <= len && data[i + 2] == 0 && data[i + 3] == 1 {
+                4 // 0x00000001
+            } else {
+                i += 1;
+                continue;
+            }
+        } else {
+            i += 1;
+            continue;
+        };
+
+        let nal_start = i + start_code_len;
+
+        // Find next start code or end of data
+        let mut nal_end = len;
+        let mut j = nal_start;
+        while j + 2 < len {
+            if data[j] == 0
+                && data[j + 1] == 0
+                && (data[j + 2] == 1 || (j + 3 < len && data[j + 2] == 0 && data[j + 3] == 1))
+            {
+                nal_end = j;
+                break;
+            }
+            j += 1;
+        }
+
+        if nal_start < nal_end {
+            let nal_data = &data[nal_start..nal_end];
+            if !nal_data.is_empty() {
+                let nal_type = NalUnitType::from(nal_data[0]);
+                nals.push(NalUnit {
+                    nal_type,
+                    data: Bytes::copy_from_slice(nal_data),
+                });
+            }
+        }
+
+        i = nal_end;
+    }
+
+    nals
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+
+    for slice_size in [2_000, 25_000, 100_000] {
+        let frame = access_unit(slice_size);
+        group.throughput(Throughput::Bytes(frame.len() as u64));
+
+        group.bench_with_input(
+            BenchmarkId::new("legacy", frame.len()),
+            &frame,
+            |b, frame| b.iter(|| legacy_parse_annex_b(frame)),
+        );
+
+        group.bench_with_input(BenchmarkId::new("copy", frame.len()), &frame, |b, frame| {
+            b.iter(|| parse_annex_b(frame))
+        });
+
+        group.bench_with_input(
+            BenchmarkId::new("zero_copy", frame.len()),
+            &frame,
+            |b, frame| b.iter(|| parse_annex_b_bytes(frame)),
+        );
+
+        group.bench_with_input(
+            BenchmarkId::new("streaming_4k_chunks", frame.len()),
+            &frame,
+            |b, frame| {
+                b.iter(|| {
+                    let mut parser = AnnexBStreamParser::new();
+                    let mut count = 0;
+                    for chunk in frame.chunks(4096) {
+                        count += parser.push(chunk).len();
+                    }
+                    count + parser.finish().map_or(0, |_| 1)
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+fn bench_to_avcc(c: &mut Criterion) {
+    let mut group = c.benchmark_group("to_avcc");
+
+    for slice_size in [2_000, 25_000, 100_000] {
+        let frame = access_unit(slice_size);
+        group.throughput(Throughput::Bytes(frame.len() as u64));
+
+        group.bench_with_input(
+            BenchmarkId::new("legacy", frame.len()),
+            &frame,
+            |b, frame| b.iter(|| nals_to_avcc(&filter_parameter_sets(legacy_parse_annex_b(frame)))),
+        );
+
+        group.bench_with_input(
+            BenchmarkId::new("zero_copy", frame.len()),
+            &frame,
+            |b, frame| b.iter(|| nals_to_avcc(&filter_parameter_sets(parse_annex_b_bytes(frame)))),
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse, bench_to_avcc);
+criterion_main!(benches);
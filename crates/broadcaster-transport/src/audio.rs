@@ -0,0 +1,47 @@
+//! FLV audio tags.
+//!
+//! AAC in FLV is carried as raw access units behind a two-byte tag header.
+//! The first tag is an AAC sequence header holding the encoder's
+//! AudioSpecificConfig; decoders need it before any audio data.
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+/// Sound format/rate/size/type byte for AAC.
+///
+/// FLV requires AAC to signal 44 kHz, 16-bit stereo here whatever the real
+/// format; decoders take the actual values from the AudioSpecificConfig.
+const FLV_AAC_SOUND_FLAGS: u8 = 0xAF;
+
+/// Build an FLV audio tag payload for AAC data.
+///
+/// FLV audio tag format:
+/// - Sound Format (4 bits) + Rate (2) + Size (1) + Type (1): 1 byte, 0xAF
+/// - AAC Packet Type: 1 byte
+///   - 0=AAC sequence header (AudioSpecificConfig)
+///   - 1=AAC raw
+/// - Data: variable
+pub fn build_flv_aac_tag(data: &[u8], is_sequence_header: bool) -> Bytes {
+    let mut buf = BytesMut::with_capacity(2 + data.len());
+    buf.put_u8(FLV_AAC_SOUND_FLAGS);
+    buf.put_u8(if is_sequence_header { 0x00 } else { 0x01 });
+    buf.put_slice(data);
+    buf.freeze()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequence_header_carries_config() {
+        // AAC-LC, 48 kHz, stereo
+        let asc = [0x11, 0x90];
+        assert_eq!(&build_flv_aac_tag(&asc, true)[..], [0xAF, 0x00, 0x11, 0x90]);
+    }
+
+    #[test]
+    fn test_raw_frame() {
+        let tag = build_flv_aac_tag(&[0x21, 0x10, 0x05], false);
+        assert_eq!(&tag[..], [0xAF, 0x01, 0x21, 0x10, 0x05]);
+    }
+}
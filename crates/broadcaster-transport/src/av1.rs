@@ -0,0 +1,734 @@
+//! AV1 OBU parsing and AV1CodecConfigurationRecord.
+//!
+//! AV1 has no start codes. Encoders emit the low overhead bitstream format:
+//! a sequence of OBUs (open bitstream units), each with a 1-byte header, an
+//! optional extension byte and usually a leb128 payload size:
+//!
+//! ```text
+//! forbidden (1) | obu_type (4) | extension_flag (1) | has_size_field (1) | reserved (1)
+//! ```
+//!
+//! The sequence header OBU plays the role of the H.264 SPS and is carried in
+//! the `av1C` record (AV1 Codec ISO Media File Format Binding, section 2.3)
+//! used by Enhanced RTMP and fMP4.
+
+use std::ops::Range;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use tracing::debug;
+
+use crate::error::TransportError;
+use crate::nal::rbsp::BitReader;
+use crate::TransportResult;
+
+/// OBU types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ObuType {
+    SequenceHeader = 1,
+    TemporalDelimiter = 2,
+    FrameHeader = 3,
+    TileGroup = 4,
+    Metadata = 5,
+    Frame = 6,
+    RedundantFrameHeader = 7,
+    TileList = 8,
+    Padding = 15,
+    /// Reserved OBU type.
+    Other = 0,
+}
+
+impl From<u8> for ObuType {
+    /// Decode the type from the OBU header byte.
+    fn from(byte: u8) -> Self {
+        match (byte >> 3) & 0x0F {
+            1 => Self::SequenceHeader,
+            2 => Self::TemporalDelimiter,
+            3 => Self::FrameHeader,
+            4 => Self::TileGroup,
+            5 => Self::Metadata,
+            6 => Self::Frame,
+            7 => Self::RedundantFrameHeader,
+            8 => Self::TileList,
+            15 => Self::Padding,
+            _ => Self::Other,
+        }
+    }
+}
+
+/// A single OBU.
+#[derive(Debug, Clone)]
+pub struct Obu {
+    /// The OBU type.
+    pub obu_type: ObuType,
+    /// `(temporal_id, spatial_id)` from the extension header, if present.
+    pub extension: Option<(u8, u8)>,
+    /// The complete OBU (header, optional size field and payload).
+    pub data: Bytes,
+    /// Location of the payload within `data`.
+    payload: Range<usize>,
+}
+
+impl Obu {
+    /// The OBU payload, without header and size field.
+    pub fn payload(&self) -> &[u8] {
+        &self.data[self.payload.clone()]
+    }
+
+    /// Whether the OBU header carries `obu_has_size_field`.
+    pub fn has_size_field(&self) -> bool {
+        self.data[0] & 0x02 != 0
+    }
+}
+
+/// Read a leb128 value, returning it and the number of bytes consumed.
+pub fn read_leb128(data: &[u8]) -> TransportResult<(u64, usize)> {
+    let mut value = 0u64;
+
+    // At most 8 bytes are allowed by the spec
+    for (i, &byte) in data.iter().take(8).enumerate() {
+        value |= ((byte & 0x7F) as u64) << (i * 7);
+        if byte & 0x80 == 0 {
+            return Ok((value, i + 1));
+        }
+    }
+
+    Err(TransportError::InvalidBitstream(
+        "Invalid leb128 value".to_string(),
+    ))
+}
+
+/// Append `value` as leb128 using the minimum number of bytes.
+pub fn write_leb128(buf: &mut BytesMut, mut value: u64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf.put_u8(byte);
+            break;
+        }
+        buf.put_u8(byte | 0x80);
+    }
+}
+
+/// Parse a low overhead bitstream format buffer into OBUs.
+///
+/// An OBU without a size field extends to the end of the buffer.
+pub fn parse_obus(data: &[u8]) -> TransportResult<Vec<Obu>> {
+    let mut obus = Vec::new();
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let header = data[pos];
+        if header & 0x80 != 0 {
+            return Err(TransportError::InvalidBitstream(
+                "OBU forbidden bit set".to_string(),
+            ));
+        }
+
+        let has_extension = header & 0x04 != 0;
+        let has_size_field = header & 0x02 != 0;
+        let mut offset = pos + 1;
+
+        let extension = if has_extension {
+            let ext = *data.get(offset).ok_or_else(|| {
+                TransportError::InvalidBitstream("Truncated OBU extension header".to_string())
+            })?;
+            offset += 1;
+            Some((ext >> 5, (ext >> 3) & 0x03))
+        } else {
+            None
+        };
+
+        let payload_size = if has_size_field {
+            let (size, len) = read_leb128(&data[offset..])?;
+            offset += len;
+            size as usize
+        } else {
+            data.len() - offset
+        };
+
+        let end = offset
+            .checked_add(payload_size)
+            .filter(|&end| end <= data.len())
+            .ok_or_else(|| TransportError::InvalidBitstream("Truncated OBU payload".to_string()))?;
+
+        obus.push(Obu {
+            obu_type: ObuType::from(header),
+            extension,
+            data: Bytes::copy_from_slice(&data[pos..end]),
+            payload: (offset - pos)..(end - pos),
+        });
+
+        pos = end;
+    }
+
+    Ok(obus)
+}
+
+/// Serialize OBUs for a sample, giving every OBU a size field.
+///
+/// ISO BMFF and Enhanced RTMP samples require `obu_has_size_field` on all
+/// OBUs; OBUs that already have one are copied unchanged.
+pub fn obus_to_sample(obus: &[Obu]) -> Bytes {
+    let mut buf = BytesMut::new();
+
+    for obu in obus {
+        if obu.has_size_field() {
+            buf.put_slice(&obu.data);
+            continue;
+        }
+
+        buf.put_u8(obu.data[0] | 0x02);
+        if let Some((temporal_id, spatial_id)) = obu.extension {
+            buf.put_u8(temporal_id << 5 | spatial_id << 3);
+        }
+        write_leb128(&mut buf, obu.payload.len() as u64);
+        buf.put_slice(obu.payload());
+    }
+
+    buf.freeze()
+}
+
+/// Remove temporal delimiter and padding OBUs.
+///
+/// Samples must not contain temporal delimiters; the container marks the
+/// temporal unit boundaries instead.
+pub fn filter_temporal_delimiters(obus: Vec<Obu>) -> Vec<Obu> {
+    obus.into_iter()
+        .filter(|obu| !matches!(obu.obu_type, ObuType::TemporalDelimiter | ObuType::Padding))
+        .collect()
+}
+
+/// Find the sequence header OBU in a buffer of OBUs.
+pub fn extract_sequence_header(data: &[u8]) -> Option<Obu> {
+    parse_obus(data)
+        .ok()?
+        .into_iter()
+        .find(|obu| obu.obu_type == ObuType::SequenceHeader)
+}
+
+/// Whether a temporal unit starts with a key frame.
+///
+/// Looks at the first frame (or frame header) OBU. A sequence header in the
+/// same temporal unit is used to detect `reduced_still_picture_header`,
+/// which implies a key frame.
+pub fn is_keyframe(obus: &[Obu]) -> bool {
+    let reduced_still_picture_header = obus
+        .iter()
+        .find(|obu| obu.obu_type == ObuType::SequenceHeader)
+        .and_then(|obu| SequenceHeader::parse(obu.payload()).ok())
+        .is_some_and(|seq| seq.reduced_still_picture_header);
+
+    let Some(frame) = obus
+        .iter()
+        .find(|obu| matches!(obu.obu_type, ObuType::Frame | ObuType::FrameHeader))
+    else {
+        return false;
+    };
+
+    if reduced_still_picture_header {
+        return true;
+    }
+
+    let mut r = BitReader::new(frame.payload());
+    match (r.read_flag(), r.read_bits(2)) {
+        // show_existing_frame = 0, frame_type = KEY_FRAME
+        (Ok(false), Ok(0)) => true,
+        _ => false,
+    }
+}
+
+/// Timing information from the sequence header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Av1TimingInfo {
+    pub num_units_in_display_tick: u32,
+    pub time_scale: u32,
+    /// Ticks per picture when `equal_picture_interval` is set.
+    pub num_ticks_per_picture: Option<u32>,
+}
+
+/// A parsed sequence header OBU payload (AV1 spec 5.5).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SequenceHeader {
+    pub seq_profile: u8,
+    pub still_picture: bool,
+    pub reduced_still_picture_header: bool,
+    pub timing_info: Option<Av1TimingInfo>,
+    /// Level of operating point 0.
+    pub seq_level_idx_0: u8,
+    /// Tier of operating point 0.
+    pub seq_tier_0: bool,
+    /// `initial_display_delay_minus_1` of operating point 0, if present.
+    pub initial_display_delay_minus_one_0: Option<u8>,
+    pub max_frame_width: u32,
+    pub max_frame_height: u32,
+    pub bit_depth: u8,
+    pub mono_chrome: bool,
+    pub color_primaries: u8,
+    pub transfer_characteristics: u8,
+    pub matrix_coefficients: u8,
+    pub color_range: bool,
+    pub subsampling_x: bool,
+    pub subsampling_y: bool,
+    pub chroma_sample_position: u8,
+    pub film_grain_params_present: bool,
+}
+
+impl SequenceHeader {
+    /// Parse a sequence header OBU payload.
+    pub fn parse(payload: &[u8]) -> TransportResult<Self> {
+        let mut r = BitReader::new(payload);
+
+        let seq_profile = r.read_bits(3)? as u8;
+        let still_picture = r.read_flag()?;
+        let reduced_still_picture_header = r.read_flag()?;
+
+        let mut timing_info = None;
+        let mut initial_display_delay_minus_one_0 = None;
+        let mut seq_tier_0 = false;
+
+        let seq_level_idx_0 = if reduced_still_picture_header {
+            r.read_bits(5)? as u8
+        } else {
+            let mut decoder_model_info_present = false;
+            let mut buffer_delay_length = 0;
+
+            if r.read_flag()? {
+                let num_units_in_display_tick = r.read_bits(32)?;
+                let time_scale = r.read_bits(32)?;
+                let num_ticks_per_picture = if r.read_flag()? {
+                    Some(read_uvlc(&mut r)?.saturating_add(1))
+                } else {
+                    None
+                };
+                timing_info = Some(Av1TimingInfo {
+                    num_units_in_display_tick,
+                    time_scale,
+                    num_ticks_per_picture,
+                });
+
+                decoder_model_info_present = r.read_flag()?;
+                if decoder_model_info_present {
+                    buffer_delay_length = r.read_bits(5)? + 1;
+                    r.skip_bits(32)?; // num_units_in_decoding_tick
+                    r.skip_bits(5)?; // buffer_removal_time_length_minus_1
+                    r.skip_bits(5)?; // frame_presentation_time_length_minus_1
+                }
+            }
+
+            let initial_display_delay_present = r.read_flag()?;
+            let operating_points = r.read_bits(5)? + 1;
+
+            let mut level_0 = 0;
+            for i in 0..operating_points {
+                r.skip_bits(12)?; // operating_point_idc
+                let level = r.read_bits(5)? as u8;
+                let tier = level > 7 && r.read_flag()?;
+                if decoder_model_info_present && r.read_flag()? {
+                    // operating_parameters_info
+                    r.skip_bits(buffer_delay_length as usize * 2 + 1)?;
+                }
+                let display_delay = if initial_display_delay_present && r.read_flag()? {
+                    Some(r.read_bits(4)? as u8)
+                } else {
+                    None
+                };
+
+                if i == 0 {
+                    level_0 = level;
+                    seq_tier_0 = tier;
+                    initial_display_delay_minus_one_0 = display_delay;
+                }
+            }
+            level_0
+        };
+
+        let frame_width_bits = r.read_bits(4)? + 1;
+        let frame_height_bits = r.read_bits(4)? + 1;
+        let max_frame_width = r.read_bits(frame_width_bits)? + 1;
+        let max_frame_height = r.read_bits(frame_height_bits)? + 1;
+
+        if !reduced_still_picture_header && r.read_flag()? {
+            // delta_frame_id_length_minus_2, additional_frame_id_length_minus_1
+            r.skip_bits(7)?;
+        }
+
+        // use_128x128_superblock, enable_filter_intra, enable_intra_edge_filter
+        r.skip_bits(3)?;
+
+        if !reduced_still_picture_header {
+            // enable_interintra_compound, enable_masked_compound,
+            // enable_warped_motion, enable_dual_filter
+            r.skip_bits(4)?;
+            let enable_order_hint = r.read_flag()?;
+            if enable_order_hint {
+                r.skip_bits(2)?; // enable_jnt_comp, enable_ref_frame_mvs
+            }
+
+            let seq_force_screen_content_tools = if r.read_flag()? { 2 } else { r.read_bits(1)? };
+            if seq_force_screen_content_tools > 0 && !r.read_flag()? {
+                r.skip_bits(1)?; // seq_force_integer_mv
+            }
+
+            if enable_order_hint {
+                r.skip_bits(3)?; // order_hint_bits_minus_1
+            }
+        }
+
+        // enable_superres, enable_cdef, enable_restoration
+        r.skip_bits(3)?;
+
+        // color_config()
+        let high_bitdepth = r.read_flag()?;
+        let bit_depth = if seq_profile == 2 && high_bitdepth {
+            if r.read_flag()? {
+                12
+            } else {
+                10
+            }
+        } else if high_bitdepth {
+            10
+        } else {
+            8
+        };
+
+        let mono_chrome = seq_profile != 1 && r.read_flag()?;
+
+        let (color_primaries, transfer_characteristics, matrix_coefficients) = if r.read_flag()? {
+            (
+                r.read_bits(8)? as u8,
+                r.read_bits(8)? as u8,
+                r.read_bits(8)? as u8,
+            )
+        } else {
+            // CP_UNSPECIFIED, TC_UNSPECIFIED, MC_UNSPECIFIED
+            (2, 2, 2)
+        };
+
+        let color_range;
+        let subsampling_x;
+        let subsampling_y;
+        let mut chroma_sample_position = 0;
+
+        if mono_chrome {
+            color_range = r.read_flag()?;
+            subsampling_x = true;
+            subsampling_y = true;
+        } else if color_primaries == 1 && transfer_characteristics == 13 && matrix_coefficients == 0
+        {
+            // sRGB
+            color_range = true;
+            subsampling_x = false;
+            subsampling_y = false;
+        } else {
+            color_range = r.read_flag()?;
+            match seq_profile {
+                0 => {
+                    subsampling_x = true;
+                    subsampling_y = true;
+                }
+                1 => {
+                    subsampling_x = false;
+                    subsampling_y = false;
+                }
+                _ if bit_depth == 12 => {
+                    subsampling_x = r.read_flag()?;
+                    subsampling_y = subsampling_x && r.read_flag()?;
+                }
+                _ => {
+                    subsampling_x = true;
+                    subsampling_y = false;
+                }
+            }
+            if subsampling_x && subsampling_y {
+                chroma_sample_position = r.read_bits(2)? as u8;
+            }
+        }
+
+        if !mono_chrome {
+            r.skip_bits(1)?; // separate_uv_delta_q
+        }
+
+        let film_grain_params_present = r.read_flag()?;
+
+        Ok(Self {
+            seq_profile,
+            still_picture,
+            reduced_still_picture_header,
+            timing_info,
+            seq_level_idx_0,
+            seq_tier_0,
+            initial_display_delay_minus_one_0,
+            max_frame_width,
+            max_frame_height,
+            bit_depth,
+            mono_chrome,
+            color_primaries,
+            transfer_characteristics,
+            matrix_coefficients,
+            color_range,
+            subsampling_x,
+            subsampling_y,
+            chroma_sample_position,
+            film_grain_params_present,
+        })
+    }
+
+    /// Frame rate signalled by the timing info, if it has a fixed interval.
+    pub fn frame_rate(&self) -> Option<f64> {
+        let timing = self.timing_info?;
+        let ticks = timing.num_ticks_per_picture?;
+        if timing.num_units_in_display_tick == 0 || ticks == 0 {
+            return None;
+        }
+        Some(timing.time_scale as f64 / (timing.num_units_in_display_tick as f64 * ticks as f64))
+    }
+}
+
+/// Read a `uvlc()` value.
+fn read_uvlc(r: &mut BitReader<'_>) -> TransportResult<u32> {
+    let mut leading_zeros = 0u32;
+    while !r.read_flag()? {
+        leading_zeros += 1;
+        if leading_zeros >= 32 {
+            return Ok(u32::MAX);
+        }
+    }
+    let value = r.read_bits(leading_zeros)?;
+    Ok(value + ((1u64 << leading_zeros) - 1) as u32)
+}
+
+/// A decoded AV1CodecConfigurationRecord.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Av1CodecConfigurationRecord {
+    pub seq_profile: u8,
+    pub seq_level_idx_0: u8,
+    pub seq_tier_0: bool,
+    pub high_bitdepth: bool,
+    pub twelve_bit: bool,
+    pub monochrome: bool,
+    pub chroma_subsampling_x: bool,
+    pub chroma_subsampling_y: bool,
+    pub chroma_sample_position: u8,
+    pub initial_presentation_delay_minus_one: Option<u8>,
+    /// Configuration OBUs, normally the sequence header OBU with size field.
+    pub config_obus: Bytes,
+}
+
+impl Av1CodecConfigurationRecord {
+    /// Build a record from a sequence header OBU.
+    pub fn from_sequence_header(obu: &Obu) -> TransportResult<Self> {
+        if obu.obu_type != ObuType::SequenceHeader {
+            return Err(TransportError::InvalidBitstream(format!(
+                "Expected sequence header OBU, got {:?}",
+                obu.obu_type
+            )));
+        }
+
+        let seq = SequenceHeader::parse(obu.payload())?;
+        let config_obus = obus_to_sample(std::slice::from_ref(obu));
+
+        Ok(Self {
+            seq_profile: seq.seq_profile,
+            seq_level_idx_0: seq.seq_level_idx_0,
+            seq_tier_0: seq.seq_tier_0,
+            high_bitdepth: seq.bit_depth > 8,
+            twelve_bit: seq.bit_depth == 12,
+            monochrome: seq.mono_chrome,
+            chroma_subsampling_x: seq.subsampling_x,
+            chroma_subsampling_y: seq.subsampling_y,
+            chroma_sample_position: seq.chroma_sample_position,
+            initial_presentation_delay_minus_one: seq.initial_display_delay_minus_one_0,
+            config_obus,
+        })
+    }
+
+    /// Serialize the record.
+    pub fn to_bytes(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(4 + self.config_obus.len());
+
+        // marker (1) + version (7)
+        buf.put_u8(0x81);
+        buf.put_u8((self.seq_profile & 0x07) << 5 | (self.seq_level_idx_0 & 0x1F));
+        buf.put_u8(
+            (self.seq_tier_0 as u8) << 7
+                | (self.high_bitdepth as u8) << 6
+                | (self.twelve_bit as u8) << 5
+                | (self.monochrome as u8) << 4
+                | (self.chroma_subsampling_x as u8) << 3
+                | (self.chroma_subsampling_y as u8) << 2
+                | (self.chroma_sample_position & 0x03),
+        );
+        buf.put_u8(match self.initial_presentation_delay_minus_one {
+            Some(delay) => 0x10 | (delay & 0x0F),
+            None => 0x00,
+        });
+        buf.put_slice(&self.config_obus);
+
+        debug!(
+            profile = self.seq_profile,
+            level = self.seq_level_idx_0,
+            total_len = buf.len(),
+            "Built AV1 codec configuration record"
+        );
+
+        buf.freeze()
+    }
+
+    /// Parse a serialized record.
+    pub fn parse(data: &[u8]) -> TransportResult<Self> {
+        if data.len() < 4 {
+            return Err(TransportError::InvalidBitstream(
+                "Truncated AV1 codec configuration record".to_string(),
+            ));
+        }
+        if data[0] != 0x81 {
+            return Err(TransportError::InvalidBitstream(format!(
+                "Unsupported av1C marker/version 0x{:02X}",
+                data[0]
+            )));
+        }
+
+        Ok(Self {
+            seq_profile: data[1] >> 5,
+            seq_level_idx_0: data[1] & 0x1F,
+            seq_tier_0: data[2] & 0x80 != 0,
+            high_bitdepth: data[2] & 0x40 != 0,
+            twelve_bit: data[2] & 0x20 != 0,
+            monochrome: data[2] & 0x10 != 0,
+            chroma_subsampling_x: data[2] & 0x08 != 0,
+            chroma_subsampling_y: data[2] & 0x04 != 0,
+            chroma_sample_position: data[2] & 0x03,
+            initial_presentation_delay_minus_one: (data[3] & 0x10 != 0).then_some(data[3] & 0x0F),
+            config_obus: Bytes::copy_from_slice(&data[4..]),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nal::rbsp::BitWriter;
+
+    /// Build a Main profile, level 4.0, 8-bit 4:2:0 sequence header OBU for
+    /// 1920x1080 at 30 fps.
+    fn sequence_header_obu() -> Vec<u8> {
+        let mut w = BitWriter::new();
+        w.write_bits(0, 3); // seq_profile
+        w.write_bit(false); // still_picture
+        w.write_bit(false); // reduced_still_picture_header
+        w.write_bit(true); // timing_info_present_flag
+        w.write_bits(1, 32); // num_units_in_display_tick
+        w.write_bits(30, 32); // time_scale
+        w.write_bit(true); // equal_picture_interval
+        w.write_bit(true); // num_ticks_per_picture_minus_1 = 0 (uvlc)
+        w.write_bit(false); // decoder_model_info_present_flag
+        w.write_bit(false); // initial_display_delay_present_flag
+        w.write_bits(0, 5); // operating_points_cnt_minus_1
+        w.write_bits(0, 12); // operating_point_idc
+        w.write_bits(8, 5); // seq_level_idx (4.0)
+        w.write_bit(false); // seq_tier
+        w.write_bits(10, 4); // frame_width_bits_minus_1
+        w.write_bits(10, 4); // frame_height_bits_minus_1
+        w.write_bits(1919, 11);
+        w.write_bits(1079, 11);
+        w.write_bit(false); // frame_id_numbers_present_flag
+        w.write_bits(0b011, 3); // superblock, filter intra, intra edge
+        w.write_bits(0, 4); // compound/warped/dual filter tools
+        w.write_bit(true); // enable_order_hint
+        w.write_bits(0b11, 2); // enable_jnt_comp, enable_ref_frame_mvs
+        w.write_bit(true); // seq_choose_screen_content_tools
+        w.write_bit(true); // seq_choose_integer_mv
+        w.write_bits(6, 3); // order_hint_bits_minus_1
+        w.write_bits(0b011, 3); // superres, cdef, restoration
+        w.write_bit(false); // high_bitdepth
+        w.write_bit(false); // mono_chrome
+        w.write_bit(false); // color_description_present_flag
+        w.write_bit(false); // color_range
+        w.write_bits(0, 2); // chroma_sample_position
+        w.write_bit(false); // separate_uv_delta_q
+        w.write_bit(false); // film_grain_params_present
+        w.write_trailing_bits();
+        let payload = w.into_bytes();
+
+        let mut obu = BytesMut::new();
+        obu.put_u8(0x0A); // sequence header, has_size_field
+        write_leb128(&mut obu, payload.len() as u64);
+        obu.put_slice(&payload);
+        obu.to_vec()
+    }
+
+    fn temporal_unit(frame_header_byte: u8) -> Vec<u8> {
+        let mut data = vec![0x12, 0x00]; // temporal delimiter
+        data.extend(sequence_header_obu());
+        data.extend([0x32, 0x02, frame_header_byte, 0xAA]); // frame OBU
+        data
+    }
+
+    #[test]
+    fn test_leb128_round_trip() {
+        for value in [0u64, 1, 127, 128, 300, 16_384, u32::MAX as u64] {
+            let mut buf = BytesMut::new();
+            write_leb128(&mut buf, value);
+            assert_eq!(read_leb128(&buf).unwrap(), (value, buf.len()));
+        }
+        assert!(read_leb128(&[0x80, 0x80]).is_err());
+    }
+
+    #[test]
+    fn test_parse_temporal_unit() {
+        let obus = parse_obus(&temporal_unit(0x10)).unwrap();
+        assert_eq!(obus.len(), 3);
+        assert_eq!(obus[0].obu_type, ObuType::TemporalDelimiter);
+        assert_eq!(obus[1].obu_type, ObuType::SequenceHeader);
+        assert_eq!(obus[2].obu_type, ObuType::Frame);
+        assert_eq!(obus[2].payload(), &[0x10, 0xAA]);
+
+        let filtered = filter_temporal_delimiters(obus);
+        assert_eq!(filtered.len(), 2);
+    }
+
+    #[test]
+    fn test_sequence_header_parse() {
+        let obu = extract_sequence_header(&sequence_header_obu()).unwrap();
+        let seq = SequenceHeader::parse(obu.payload()).unwrap();
+
+        assert_eq!(seq.seq_profile, 0);
+        assert_eq!(seq.seq_level_idx_0, 8);
+        assert_eq!(seq.max_frame_width, 1920);
+        assert_eq!(seq.max_frame_height, 1080);
+        assert_eq!(seq.bit_depth, 8);
+        assert!(seq.subsampling_x && seq.subsampling_y);
+        assert_eq!(seq.frame_rate(), Some(30.0));
+    }
+
+    #[test]
+    fn test_keyframe_detection() {
+        // show_existing_frame = 0, frame_type = KEY_FRAME (0)
+        let key = parse_obus(&temporal_unit(0x10)).unwrap();
+        assert!(is_keyframe(&key));
+
+        // frame_type = INTER_FRAME (1)
+        let inter = parse_obus(&temporal_unit(0x30)).unwrap();
+        assert!(!is_keyframe(&inter));
+    }
+
+    #[test]
+    fn test_av1c_round_trip() {
+        let obu = extract_sequence_header(&sequence_header_obu()).unwrap();
+        let record = Av1CodecConfigurationRecord::from_sequence_header(&obu).unwrap();
+        let bytes = record.to_bytes();
+
+        assert_eq!(&bytes[..4], &[0x81, 0x08, 0x0C, 0x00]);
+        assert_eq!(&bytes[4..], &obu.data[..]);
+        assert_eq!(Av1CodecConfigurationRecord::parse(&bytes).unwrap(), record);
+    }
+
+    #[test]
+    fn test_obus_to_sample_adds_size_field() {
+        let obus = parse_obus(&[0x30, 0x10, 0xAA]).unwrap();
+        assert!(!obus[0].has_size_field());
+        assert_eq!(&obus_to_sample(&obus)[..], &[0x32, 0x02, 0x10, 0xAA]);
+    }
+}
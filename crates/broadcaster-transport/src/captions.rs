@@ -0,0 +1,347 @@
+//! CEA-608 closed captions carried in H.264 SEI.
+//!
+//! Caption text is encoded as CEA-608 byte pairs for field 1 (caption
+//! channel CC1) in roll-up mode. The pairs travel in CEA-708 `cc_data()`
+//! inside an ATSC A/53 `user_data_registered_itu_t_t35` SEI message, which is
+//! what players and ingest servers extract captions from.
+//!
+//! [`CaptionInserter`] queues caption text and adds the SEI to outgoing
+//! access units, paced to the 608 field 1 data rate by video timestamp.
+
+use std::collections::VecDeque;
+
+use crate::error::TransportError;
+use crate::nal::{
+    build_sei_nal, insert_sei, parse_sei_nal, NalUnit, SeiMessage, SEI_USER_DATA_REGISTERED,
+};
+use crate::TransportResult;
+
+/// ITU-T T.35 country code of the United States.
+const T35_COUNTRY_CODE_US: u8 = 0xB5;
+
+/// ITU-T T.35 provider code of ATSC.
+const T35_PROVIDER_CODE_ATSC: u16 = 0x0031;
+
+/// ATSC A/53 user identifier.
+const ATSC_USER_IDENTIFIER: &[u8; 4] = b"GA94";
+
+/// A/53 user_data_type_code of `cc_data()`.
+const USER_DATA_TYPE_CC_DATA: u8 = 0x03;
+
+/// cc_type of NTSC field 1 (CEA-608 CC1/CC2) data.
+const CC_TYPE_608_FIELD_1: u8 = 0;
+
+/// Maximum number of constructs in one `cc_data()` (5-bit cc_count).
+const MAX_CC_COUNT: usize = 31;
+
+/// CEA-608 field 1 carries one byte pair per NTSC frame.
+const CEA608_PAIRS_PER_SECOND: u64 = 30;
+
+/// Characters per caption row.
+const CEA608_ROW_LENGTH: usize = 32;
+
+/// Roll-up captions, 2 rows (CC1).
+const CMD_ROLL_UP_2: [u8; 2] = [0x14, 0x25];
+
+/// Carriage return: roll the display up one row (CC1).
+const CMD_CARRIAGE_RETURN: [u8; 2] = [0x14, 0x2D];
+
+/// Preamble address code: row 15, white, indent 0 (CC1).
+const CMD_PAC_ROW_15: [u8; 2] = [0x14, 0x70];
+
+/// Characters of the CEA-608 basic set that differ from ASCII.
+const CEA608_SPECIAL_CHARS: [(u8, char); 10] = [
+    (0x2A, 'á'),
+    (0x5C, 'é'),
+    (0x5E, 'í'),
+    (0x5F, 'ó'),
+    (0x60, 'ú'),
+    (0x7B, 'ç'),
+    (0x7C, '÷'),
+    (0x7D, 'Ñ'),
+    (0x7E, 'ñ'),
+    (0x7F, '█'),
+];
+
+/// Encode caption text as CEA-608 byte pairs with odd parity.
+///
+/// Each line of `text` becomes a roll-up row, wrapped at 32 characters.
+/// Control codes are sent twice as recommended for broadcast. Characters
+/// outside the basic character set are replaced by `?`.
+pub fn encode_cea608_text(text: &str) -> Vec<[u8; 2]> {
+    let mut pairs = vec![CMD_ROLL_UP_2, CMD_ROLL_UP_2];
+
+    for line in text.lines() {
+        let codes: Vec<u8> = line.chars().map(char_to_cea608).collect();
+        for row in codes.chunks(CEA608_ROW_LENGTH) {
+            pairs.extend([CMD_CARRIAGE_RETURN, CMD_CARRIAGE_RETURN]);
+            pairs.extend([CMD_PAC_ROW_15, CMD_PAC_ROW_15]);
+            for chars in row.chunks(2) {
+                pairs.push([chars[0], chars.get(1).copied().unwrap_or(0)]);
+            }
+        }
+    }
+
+    pairs
+        .into_iter()
+        .map(|[b1, b2]| [with_odd_parity(b1), with_odd_parity(b2)])
+        .collect()
+}
+
+/// Decode the text of CEA-608 byte pairs produced by [`encode_cea608_text`].
+///
+/// Rows are separated by newlines; other control codes are skipped.
+pub fn decode_cea608_text(pairs: &[[u8; 2]]) -> String {
+    let mut text = String::new();
+    let mut last_control: Option<[u8; 2]> = None;
+
+    for pair in pairs {
+        let pair = [pair[0] & 0x7F, pair[1] & 0x7F];
+
+        if (0x10..=0x1F).contains(&pair[0]) {
+            // Skip the redundant copy of a control code
+            if last_control.take() == Some(pair) {
+                continue;
+            }
+            last_control = Some(pair);
+            if pair == CMD_CARRIAGE_RETURN && !text.is_empty() {
+                text.push('\n');
+            }
+            continue;
+        }
+
+        last_control = None;
+        text.extend(
+            pair.iter()
+                .filter(|&&b| b >= 0x20)
+                .map(|&b| cea608_to_char(b)),
+        );
+    }
+
+    text
+}
+
+/// Build an SEI NAL unit carrying CEA-608 field 1 byte pairs.
+///
+/// At most 31 pairs fit in one SEI; the rest are ignored.
+pub fn build_caption_sei(pairs: &[[u8; 2]]) -> NalUnit {
+    let pairs = &pairs[..pairs.len().min(MAX_CC_COUNT)];
+
+    let mut payload = Vec::with_capacity(10 + pairs.len() * 3);
+    payload.push(T35_COUNTRY_CODE_US);
+    payload.extend_from_slice(&T35_PROVIDER_CODE_ATSC.to_be_bytes());
+    payload.extend_from_slice(ATSC_USER_IDENTIFIER);
+    payload.push(USER_DATA_TYPE_CC_DATA);
+
+    // process_em_data_flag, process_cc_data_flag, additional_data_flag, cc_count
+    payload.push(0xC0 | pairs.len() as u8);
+    // em_data
+    payload.push(0xFF);
+    for pair in pairs {
+        // marker_bits, cc_valid, cc_type
+        payload.push(0xF8 | 0x04 | CC_TYPE_608_FIELD_1);
+        payload.extend_from_slice(pair);
+    }
+    // marker_bits
+    payload.push(0xFF);
+
+    build_sei_nal(&[SeiMessage::new(SEI_USER_DATA_REGISTERED, payload)])
+}
+
+/// Extract the valid CEA-608 field 1 byte pairs from an SEI NAL unit.
+///
+/// SEI messages other than A/53 caption data are ignored.
+pub fn decode_caption_sei(nal: &[u8]) -> TransportResult<Vec<[u8; 2]>> {
+    let mut pairs = Vec::new();
+
+    for message in parse_sei_nal(nal)? {
+        if message.payload_type != SEI_USER_DATA_REGISTERED {
+            continue;
+        }
+        let payload = &message.payload;
+        if payload.len() < 10
+            || payload[0] != T35_COUNTRY_CODE_US
+            || payload[1..3] != T35_PROVIDER_CODE_ATSC.to_be_bytes()
+            || &payload[3..7] != ATSC_USER_IDENTIFIER
+            || payload[7] != USER_DATA_TYPE_CC_DATA
+        {
+            continue;
+        }
+
+        let cc_count = (payload[8] & 0x1F) as usize;
+        let constructs = &payload[10..];
+        if constructs.len() < cc_count * 3 {
+            return Err(TransportError::InvalidBitstream(format!(
+                "cc_data truncated: {} constructs signalled, {} bytes present",
+                cc_count,
+                constructs.len()
+            )));
+        }
+
+        for construct in constructs.as_chunks::<3>().0.iter().take(cc_count) {
+            let cc_valid = construct[0] & 0x04 != 0;
+            let cc_type = construct[0] & 0x03;
+            if cc_valid && cc_type == CC_TYPE_608_FIELD_1 {
+                pairs.push([construct[1], construct[2]]);
+            }
+        }
+    }
+
+    Ok(pairs)
+}
+
+/// Queues caption text and inserts it into outgoing access units.
+#[derive(Debug, Default)]
+pub struct CaptionInserter {
+    pending: VecDeque<[u8; 2]>,
+    /// Timestamp of the first access unit since the queue was last empty.
+    start_pts_ms: Option<u32>,
+    /// Pairs sent since `start_pts_ms`.
+    pairs_sent: u64,
+}
+
+impl CaptionInserter {
+    /// Create an inserter with an empty queue.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue caption text for the following access units.
+    pub fn push_text(&mut self, text: &str) {
+        self.pending.extend(encode_cea608_text(text));
+    }
+
+    /// Whether caption data is waiting to be sent.
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Drop all queued caption data.
+    pub fn clear(&mut self) {
+        self.pending.clear();
+        self.start_pts_ms = None;
+        self.pairs_sent = 0;
+    }
+
+    /// Add queued caption data to the access unit presented at `pts_ms`.
+    ///
+    /// Sends as many pairs as the 608 data rate allows up to `pts_ms`.
+    /// Returns whether an SEI was inserted.
+    pub fn insert(&mut self, nals: &mut Vec<NalUnit>, pts_ms: u32) -> bool {
+        if self.pending.is_empty() {
+            return false;
+        }
+
+        let start = *self.start_pts_ms.get_or_insert(pts_ms);
+        let elapsed_ms = pts_ms.saturating_sub(start) as u64;
+        let allowed = elapsed_ms * CEA608_PAIRS_PER_SECOND / 1000 + 1;
+        let count = (allowed.saturating_sub(self.pairs_sent) as usize)
+            .min(MAX_CC_COUNT)
+            .min(self.pending.len());
+        if count == 0 {
+            return false;
+        }
+
+        let pairs: Vec<[u8; 2]> = self.pending.drain(..count).collect();
+        insert_sei(nals, build_caption_sei(&pairs));
+
+        self.pairs_sent += count as u64;
+        if self.pending.is_empty() {
+            self.start_pts_ms = None;
+            self.pairs_sent = 0;
+        }
+        true
+    }
+}
+
+/// Set bit 7 so the byte has an odd number of set bits.
+fn with_odd_parity(byte: u8) -> u8 {
+    let byte = byte & 0x7F;
+    if byte.count_ones().is_multiple_of(2) {
+        byte | 0x80
+    } else {
+        byte
+    }
+}
+
+fn char_to_cea608(c: char) -> u8 {
+    if let Some(&(code, _)) = CEA608_SPECIAL_CHARS.iter().find(|(_, ch)| *ch == c) {
+        return code;
+    }
+    match c {
+        ' '..='~'
+            if !CEA608_SPECIAL_CHARS
+                .iter()
+                .any(|&(code, _)| code == c as u8) =>
+        {
+            c as u8
+        }
+        _ => b'?',
+    }
+}
+
+fn cea608_to_char(code: u8) -> char {
+    CEA608_SPECIAL_CHARS
+        .iter()
+        .find(|&&(c, _)| c == code)
+        .map_or(code as char, |&(_, ch)| ch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nal::NalUnitType;
+    use bytes::Bytes;
+
+    #[test]
+    fn test_cea608_pairs_have_odd_parity() {
+        for pair in encode_cea608_text("Parity check") {
+            assert_eq!(pair[0].count_ones() % 2, 1);
+            assert_eq!(pair[1].count_ones() % 2, 1);
+        }
+    }
+
+    #[test]
+    fn test_caption_sei_round_trip() {
+        let text = "Hello, world! Ñandú";
+        let pairs = encode_cea608_text(text);
+        let sei = build_caption_sei(&pairs);
+        assert_eq!(sei.nal_type, NalUnitType::Sei);
+
+        let decoded = decode_caption_sei(&sei.data).unwrap();
+        assert_eq!(decoded, pairs);
+        assert_eq!(decode_cea608_text(&decoded), text);
+    }
+
+    #[test]
+    fn test_long_lines_wrap_into_rows() {
+        let text = "a".repeat(40);
+        let decoded = decode_cea608_text(&encode_cea608_text(&text));
+        assert_eq!(decoded, format!("{}\n{}", "a".repeat(32), "a".repeat(8)));
+    }
+
+    #[test]
+    fn test_inserter_paces_pairs_by_pts() {
+        let text = "Captions over several frames";
+        let mut inserter = CaptionInserter::new();
+        inserter.push_text(text);
+
+        let mut pairs = Vec::new();
+        let mut pts_ms = 0;
+        while inserter.has_pending() {
+            let mut nals = vec![NalUnit::new(Bytes::from_static(&[0x65, 0x88]))];
+            if inserter.insert(&mut nals, pts_ms) {
+                assert_eq!(nals[0].nal_type, NalUnitType::Sei);
+                let frame_pairs = decode_caption_sei(&nals[0].data).unwrap();
+                // 60 fps: at most one pair every other frame after the first
+                assert!(frame_pairs.len() <= 1);
+                pairs.extend(frame_pairs);
+            } else {
+                assert_eq!(nals.len(), 1);
+            }
+            pts_ms += 16;
+        }
+
+        assert_eq!(decode_cea608_text(&pairs), text);
+    }
+}
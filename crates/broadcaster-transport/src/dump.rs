@@ -0,0 +1,390 @@
+//! Packet capture and replay for debugging streams.
+//!
+//! A [`PacketRecorder`] writes every [`RtmpPacket`] handed to the network,
+//! together with its flags and the wall-clock time it was sent, to a compact
+//! binary dump. A [`PacketReplayer`] reads such a dump back and pushes the
+//! packets into an [`RtmpClient`](crate::RtmpClient) packet channel, either at
+//! the original pacing or as fast as the channel accepts them.
+//!
+//! Dump format (all integers big-endian):
+//! - Header: magic `b"BRPD"` (4 bytes), format version (1 byte)
+//! - Records, repeated until end of file:
+//!   - sent_at_us: 8 bytes (wall-clock microseconds since the Unix epoch)
+//!   - timestamp_ms: 4 bytes (RTMP timestamp)
+//!   - flags: 1 byte (see `FLAG_*`)
+//!   - length: 4 bytes
+//!   - data: `length` bytes
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use crossbeam_channel::Sender;
+use tracing::{debug, info};
+
+use crate::error::TransportError;
+use crate::rtmp::RtmpPacket;
+use crate::TransportResult;
+
+/// Magic bytes at the start of every dump file.
+const DUMP_MAGIC: &[u8; 4] = b"BRPD";
+
+/// Current dump format version.
+const DUMP_VERSION: u8 = 1;
+
+/// Size of the fixed part of a record (everything except the data).
+const RECORD_HEADER_LEN: usize = 8 + 4 + 1 + 4;
+
+/// Record flag: packet carries video.
+const FLAG_VIDEO: u8 = 0x01;
+/// Record flag: packet is a keyframe.
+const FLAG_KEYFRAME: u8 = 0x02;
+/// Record flag: packet is a sequence header.
+const FLAG_SEQUENCE_HEADER: u8 = 0x04;
+/// Record flag: packet is onMetaData.
+const FLAG_METADATA: u8 = 0x08;
+
+/// Upper bound on a single record's payload, to reject corrupt dumps early.
+const MAX_RECORD_LEN: usize = 64 * 1024 * 1024;
+
+/// A packet read back from a dump.
+#[derive(Debug, Clone)]
+pub struct RecordedPacket {
+    /// Wall-clock send time in microseconds since the Unix epoch.
+    pub sent_at_us: u64,
+
+    /// The packet as it was handed to the network.
+    pub packet: RtmpPacket,
+}
+
+/// Pacing used when replaying a dump.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReplayPacing {
+    /// Reproduce the original gaps between packets.
+    #[default]
+    Original,
+
+    /// Push packets as fast as the receiving channel accepts them.
+    AsFastAsPossible,
+}
+
+/// Writes RTMP packets to a binary dump.
+pub struct PacketRecorder<W: Write = BufWriter<File>> {
+    writer: W,
+    packets_recorded: u64,
+}
+
+impl PacketRecorder {
+    /// Create a dump file at `path`, truncating any existing file.
+    pub fn create(path: impl AsRef<Path>) -> TransportResult<Self> {
+        let file = File::create(path.as_ref())?;
+        info!(path = %path.as_ref().display(), "Recording RTMP packets");
+        Self::new(BufWriter::new(file))
+    }
+}
+
+impl<W: Write> PacketRecorder<W> {
+    /// Create a recorder over an arbitrary writer and write the dump header.
+    pub fn new(mut writer: W) -> TransportResult<Self> {
+        writer.write_all(DUMP_MAGIC)?;
+        writer.write_all(&[DUMP_VERSION])?;
+
+        Ok(Self {
+            writer,
+            packets_recorded: 0,
+        })
+    }
+
+    /// Record a packet, stamped with the current wall-clock time.
+    pub fn record(&mut self, packet: &RtmpPacket) -> TransportResult<()> {
+        self.record_at(packet, SystemTime::now())
+    }
+
+    /// Record a packet with an explicit send time.
+    pub fn record_at(&mut self, packet: &RtmpPacket, sent_at: SystemTime) -> TransportResult<()> {
+        let sent_at_us = sent_at
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0);
+
+        let mut flags = 0u8;
+        if packet.is_video {
+            flags |= FLAG_VIDEO;
+        }
+        if packet.is_keyframe {
+            flags |= FLAG_KEYFRAME;
+        }
+        if packet.is_sequence_header {
+            flags |= FLAG_SEQUENCE_HEADER;
+        }
+        if packet.is_metadata {
+            flags |= FLAG_METADATA;
+        }
+
+        let mut header = [0u8; RECORD_HEADER_LEN];
+        header[0..8].copy_from_slice(&sent_at_us.to_be_bytes());
+        header[8..12].copy_from_slice(&packet.timestamp_ms.to_be_bytes());
+        header[12] = flags;
+        header[13..17].copy_from_slice(&(packet.data.len() as u32).to_be_bytes());
+
+        self.writer.write_all(&header)?;
+        self.writer.write_all(&packet.data)?;
+        self.packets_recorded += 1;
+
+        Ok(())
+    }
+
+    /// Flush buffered records to the underlying writer.
+    pub fn flush(&mut self) -> TransportResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// Number of packets recorded so far.
+    pub fn packets_recorded(&self) -> u64 {
+        self.packets_recorded
+    }
+
+    /// Flush and return the underlying writer.
+    pub fn into_inner(mut self) -> TransportResult<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Reads RTMP packets back from a binary dump.
+pub struct PacketReplayer<R: Read = BufReader<File>> {
+    reader: R,
+}
+
+impl PacketReplayer {
+    /// Open a dump file for replay.
+    pub fn open(path: impl AsRef<Path>) -> TransportResult<Self> {
+        let file = File::open(path.as_ref())?;
+        Self::new(BufReader::new(file))
+    }
+}
+
+impl<R: Read> PacketReplayer<R> {
+    /// Create a replayer over an arbitrary reader, validating the dump header.
+    pub fn new(mut reader: R) -> TransportResult<Self> {
+        let mut header = [0u8; 5];
+        reader.read_exact(&mut header).map_err(|e| {
+            TransportError::InvalidDump(format!("Failed to read dump header: {}", e))
+        })?;
+
+        if &header[..4] != DUMP_MAGIC {
+            return Err(TransportError::InvalidDump("Bad magic bytes".to_string()));
+        }
+        if header[4] != DUMP_VERSION {
+            return Err(TransportError::InvalidDump(format!(
+                "Unsupported dump version {}",
+                header[4]
+            )));
+        }
+
+        Ok(Self { reader })
+    }
+
+    /// Read the next packet, or `None` at a clean end of the dump.
+    pub fn next_packet(&mut self) -> TransportResult<Option<RecordedPacket>> {
+        let mut header = [0u8; RECORD_HEADER_LEN];
+
+        // Distinguish a clean end of file from a truncated record
+        let mut filled = 0;
+        while filled < header.len() {
+            match self.reader.read(&mut header[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => {
+                    return Err(TransportError::InvalidDump(
+                        "Truncated record header".to_string(),
+                    ))
+                }
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(TransportError::Io(e)),
+            }
+        }
+
+        let sent_at_us = u64::from_be_bytes(header[0..8].try_into().unwrap());
+        let timestamp_ms = u32::from_be_bytes(header[8..12].try_into().unwrap());
+        let flags = header[12];
+        let len = u32::from_be_bytes(header[13..17].try_into().unwrap()) as usize;
+
+        if len > MAX_RECORD_LEN {
+            return Err(TransportError::InvalidDump(format!(
+                "Record length {} exceeds limit",
+                len
+            )));
+        }
+
+        let mut data = vec![0u8; len];
+        self.reader
+            .read_exact(&mut data)
+            .map_err(|e| TransportError::InvalidDump(format!("Truncated record data: {}", e)))?;
+
+        Ok(Some(RecordedPacket {
+            sent_at_us,
+            packet: RtmpPacket {
+                data: Bytes::from(data),
+                timestamp_ms,
+                is_video: flags & FLAG_VIDEO != 0,
+                is_keyframe: flags & FLAG_KEYFRAME != 0,
+                is_sequence_header: flags & FLAG_SEQUENCE_HEADER != 0,
+                is_metadata: flags & FLAG_METADATA != 0,
+            },
+        }))
+    }
+
+    /// Push every remaining packet into `sender`.
+    ///
+    /// `sender` is typically the channel returned by
+    /// [`RtmpClient::connect`](crate::RtmpClient::connect). Returns the number
+    /// of packets replayed.
+    pub fn replay(
+        &mut self,
+        sender: &Sender<RtmpPacket>,
+        pacing: ReplayPacing,
+    ) -> TransportResult<u64> {
+        let start = Instant::now();
+        let mut first_sent_at_us: Option<u64> = None;
+        let mut replayed = 0u64;
+
+        while let Some(recorded) = self.next_packet()? {
+            if pacing == ReplayPacing::Original {
+                let first = *first_sent_at_us.get_or_insert(recorded.sent_at_us);
+                let offset = Duration::from_micros(recorded.sent_at_us.saturating_sub(first));
+                let elapsed = start.elapsed();
+                if offset > elapsed {
+                    thread::sleep(offset - elapsed);
+                }
+            }
+
+            sender
+                .send(recorded.packet)
+                .map_err(|_| TransportError::ChannelDisconnected)?;
+            replayed += 1;
+        }
+
+        debug!(replayed, ?pacing, "Dump replay complete");
+        Ok(replayed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn packet(
+        data: &'static [u8],
+        timestamp_ms: u32,
+        is_video: bool,
+        is_keyframe: bool,
+    ) -> RtmpPacket {
+        RtmpPacket {
+            data: Bytes::from_static(data),
+            timestamp_ms,
+            is_video,
+            is_keyframe,
+            is_sequence_header: false,
+            is_metadata: false,
+        }
+    }
+
+    fn record(packets: &[(RtmpPacket, u64)]) -> Vec<u8> {
+        let mut recorder = PacketRecorder::new(Vec::new()).unwrap();
+        for (packet, sent_at_us) in packets {
+            let sent_at = UNIX_EPOCH + Duration::from_micros(*sent_at_us);
+            recorder.record_at(packet, sent_at).unwrap();
+        }
+        assert_eq!(recorder.packets_recorded(), packets.len() as u64);
+        recorder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_dump_round_trip() {
+        let mut seq_header = packet(&[0x17, 0x00, 0x01], 0, true, true);
+        seq_header.is_sequence_header = true;
+        let dump = record(&[
+            (seq_header, 1_000),
+            (packet(&[0x17, 0x01, 0xAA], 0, true, true), 1_500),
+            (packet(&[0xAF, 0x01, 0xBB, 0xCC], 21, false, false), 2_000),
+        ]);
+
+        let mut replayer = PacketReplayer::new(Cursor::new(dump)).unwrap();
+
+        let first = replayer.next_packet().unwrap().unwrap();
+        assert_eq!(first.sent_at_us, 1_000);
+        assert!(first.packet.is_sequence_header);
+        assert!(first.packet.is_keyframe);
+
+        let second = replayer.next_packet().unwrap().unwrap();
+        assert_eq!(second.packet.data.as_ref(), &[0x17, 0x01, 0xAA]);
+        assert!(!second.packet.is_sequence_header);
+
+        let third = replayer.next_packet().unwrap().unwrap();
+        assert_eq!(third.sent_at_us, 2_000);
+        assert_eq!(third.packet.timestamp_ms, 21);
+        assert!(!third.packet.is_video);
+
+        assert!(replayer.next_packet().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_dump_rejects_bad_header() {
+        let result = PacketReplayer::new(Cursor::new(b"NOPE\x01".to_vec()));
+        assert!(matches!(result, Err(TransportError::InvalidDump(_))));
+    }
+
+    #[test]
+    fn test_dump_rejects_truncated_record() {
+        let mut dump = record(&[(packet(&[1, 2, 3, 4], 0, true, false), 0)]);
+        dump.truncate(dump.len() - 2);
+
+        let mut replayer = PacketReplayer::new(Cursor::new(dump)).unwrap();
+        assert!(matches!(
+            replayer.next_packet(),
+            Err(TransportError::InvalidDump(_))
+        ));
+    }
+
+    #[test]
+    fn test_replay_original_pacing() {
+        let dump = record(&[
+            (packet(&[1], 0, true, true), 10_000),
+            (packet(&[2], 33, true, false), 40_000),
+        ]);
+        let (tx, rx) = crossbeam_channel::unbounded();
+
+        let start = Instant::now();
+        let mut replayer = PacketReplayer::new(Cursor::new(dump)).unwrap();
+        let replayed = replayer.replay(&tx, ReplayPacing::Original).unwrap();
+
+        assert_eq!(replayed, 2);
+        assert!(start.elapsed() >= Duration::from_millis(30));
+        assert_eq!(rx.try_recv().unwrap().data.as_ref(), &[1]);
+        assert_eq!(rx.try_recv().unwrap().data.as_ref(), &[2]);
+    }
+
+    #[test]
+    fn test_replay_as_fast_as_possible() {
+        let dump = record(&[
+            (packet(&[1], 0, true, true), 0),
+            (packet(&[2], 1_000, true, false), 10_000_000),
+        ]);
+        let (tx, rx) = crossbeam_channel::unbounded();
+
+        let start = Instant::now();
+        let mut replayer = PacketReplayer::new(Cursor::new(dump)).unwrap();
+        replayer
+            .replay(&tx, ReplayPacing::AsFastAsPossible)
+            .unwrap();
+
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(rx.len(), 2);
+    }
+}
@@ -56,4 +56,12 @@ pub enum TransportError {
     /// RTMP protocol error.
     #[error("RTMP protocol error: {0}")]
     Protocol(String),
+
+    /// Malformed H.264 bitstream.
+    #[error("Invalid bitstream: {0}")]
+    InvalidBitstream(String),
+
+    /// Packet dump is malformed.
+    #[error("Invalid packet dump: {0}")]
+    InvalidDump(String),
 }
@@ -0,0 +1,193 @@
+//! Capture timestamps carried in H.264 SEI for latency measurement.
+//!
+//! Each access unit can carry a `user_data_unregistered` SEI message tagged
+//! with [`LATENCY_SEI_UUID`] holding the wall-clock time the frame was
+//! captured and its capture sequence number. A receiver that shares a clock
+//! with the broadcaster (or is NTP-synchronised to it) compares the embedded
+//! time with its own to get the glass-to-glass latency up to that point.
+//!
+//! Payload layout after the UUID, all big-endian:
+//!
+//! | Bytes | Field                                          |
+//! |-------|------------------------------------------------|
+//! | 8     | Capture time in microseconds since UNIX epoch  |
+//! | 8     | Capture sequence number                        |
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::error::TransportError;
+use crate::nal::{
+    build_sei_nal, parse_avcc, parse_sei_nal, NalUnit, NalUnitType, SeiMessage,
+    SEI_USER_DATA_UNREGISTERED,
+};
+use crate::TransportResult;
+
+/// UUID identifying the latency timestamp SEI message.
+pub const LATENCY_SEI_UUID: [u8; 16] = [
+    0xB7, 0xC3, 0xE2, 0xA1, 0x5F, 0x4D, 0x4C, 0x8E, 0x9A, 0x6B, 0x2D, 0x1F, 0x0E, 0x3C, 0x7A, 0x58,
+];
+
+/// Payload size after the UUID.
+const LATENCY_PAYLOAD_LEN: usize = 16;
+
+/// FLV video tag header size (frame type/codec, packet type, composition time).
+const FLV_VIDEO_TAG_HEADER_LEN: usize = 5;
+
+/// A capture timestamp embedded in the video.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyTimestamp {
+    /// Capture wall-clock time in microseconds since the UNIX epoch.
+    pub wall_clock_us: u64,
+    /// Capture sequence number of the frame.
+    pub sequence: u64,
+}
+
+impl LatencyTimestamp {
+    /// Create a timestamp for a frame captured at `captured_at`.
+    pub fn new(captured_at: SystemTime, sequence: u64) -> Self {
+        let wall_clock_us = captured_at
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_micros() as u64);
+        Self {
+            wall_clock_us,
+            sequence,
+        }
+    }
+
+    /// The capture time as a [`SystemTime`].
+    pub fn captured_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_micros(self.wall_clock_us)
+    }
+
+    /// Time elapsed between capture and `now`.
+    ///
+    /// Returns `None` if `now` is before the capture time, i.e. the clocks
+    /// of sender and receiver are not in sync.
+    pub fn latency_at(&self, now: SystemTime) -> Option<Duration> {
+        now.duration_since(self.captured_at()).ok()
+    }
+
+    /// Build the SEI NAL unit carrying this timestamp.
+    pub fn to_sei(self) -> NalUnit {
+        let mut payload = BytesMut::with_capacity(LATENCY_SEI_UUID.len() + LATENCY_PAYLOAD_LEN);
+        payload.put_slice(&LATENCY_SEI_UUID);
+        payload.put_u64(self.wall_clock_us);
+        payload.put_u64(self.sequence);
+
+        build_sei_nal(&[SeiMessage::new(
+            SEI_USER_DATA_UNREGISTERED,
+            payload.freeze(),
+        )])
+    }
+
+    /// Find the timestamp in an SEI NAL unit.
+    ///
+    /// Returns `None` if the SEI carries no latency timestamp message.
+    pub fn from_sei(nal: &[u8]) -> TransportResult<Option<Self>> {
+        for message in parse_sei_nal(nal)? {
+            if message.payload_type != SEI_USER_DATA_UNREGISTERED
+                || !message.payload.starts_with(&LATENCY_SEI_UUID)
+            {
+                continue;
+            }
+
+            let data = &message.payload[LATENCY_SEI_UUID.len()..];
+            if data.len() < LATENCY_PAYLOAD_LEN {
+                return Err(TransportError::InvalidBitstream(format!(
+                    "Latency SEI payload too short: {} bytes",
+                    data.len()
+                )));
+            }
+
+            let field = |i: usize| u64::from_be_bytes(data[i..i + 8].try_into().unwrap());
+            return Ok(Some(Self {
+                wall_clock_us: field(0),
+                sequence: field(8),
+            }));
+        }
+
+        Ok(None)
+    }
+
+    /// Find the timestamp among the NAL units of an access unit.
+    pub fn from_nals(nals: &[NalUnit]) -> TransportResult<Option<Self>> {
+        for nal in nals.iter().filter(|nal| nal.nal_type == NalUnitType::Sei) {
+            if let Some(timestamp) = Self::from_sei(&nal.data)? {
+                return Ok(Some(timestamp));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Find the timestamp in an FLV/RTMP AVC video tag payload.
+    ///
+    /// NAL units are expected to use 4-byte length prefixes, as produced by
+    /// this crate. Sequence headers carry no timestamp.
+    pub fn from_flv_video_tag(tag: &[u8]) -> TransportResult<Option<Self>> {
+        if tag.len() < FLV_VIDEO_TAG_HEADER_LEN {
+            return Err(TransportError::InvalidBitstream(format!(
+                "FLV video tag too short: {} bytes",
+                tag.len()
+            )));
+        }
+        // AVC NALU packets only
+        if tag[1] != 0x01 {
+            return Ok(None);
+        }
+
+        let nals = parse_avcc(&Bytes::copy_from_slice(&tag[FLV_VIDEO_TAG_HEADER_LEN..]), 4)?;
+        Self::from_nals(&nals)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nal::{build_flv_video_tag, insert_sei, nals_to_avcc};
+
+    #[test]
+    fn test_latency_sei_round_trip() {
+        let captured_at = UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456);
+        let timestamp = LatencyTimestamp::new(captured_at, 42);
+
+        let sei = timestamp.to_sei();
+        assert_eq!(sei.nal_type, NalUnitType::Sei);
+        assert_eq!(
+            LatencyTimestamp::from_sei(&sei.data).unwrap(),
+            Some(timestamp)
+        );
+        assert_eq!(timestamp.captured_at(), captured_at);
+        assert_eq!(
+            timestamp.latency_at(captured_at + Duration::from_millis(250)),
+            Some(Duration::from_millis(250))
+        );
+        assert_eq!(timestamp.latency_at(UNIX_EPOCH), None);
+    }
+
+    #[test]
+    fn test_latency_from_flv_video_tag() {
+        let timestamp = LatencyTimestamp::new(SystemTime::now(), 7);
+        let mut nals = vec![NalUnit::new(Bytes::from_static(&[0x65, 0x88, 0x84]))];
+        insert_sei(&mut nals, timestamp.to_sei());
+
+        let tag = build_flv_video_tag(&nals_to_avcc(&nals), true, false, 0);
+        assert_eq!(
+            LatencyTimestamp::from_flv_video_tag(&tag).unwrap(),
+            Some(timestamp)
+        );
+
+        let sequence_header = build_flv_video_tag(&[0x01, 0x42, 0x00, 0x1E], true, true, 0);
+        assert_eq!(
+            LatencyTimestamp::from_flv_video_tag(&sequence_header).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_other_unregistered_sei_is_ignored() {
+        let sei = build_sei_nal(&[SeiMessage::new(SEI_USER_DATA_UNREGISTERED, vec![0u8; 32])]);
+        assert_eq!(LatencyTimestamp::from_sei(&sei.data).unwrap(), None);
+    }
+}
@@ -3,18 +3,40 @@
 //! This crate provides RTMP transport functionality for streaming
 //! encoded video and audio to servers.
 
+mod audio;
+pub mod av1;
+mod captions;
 mod connection;
+mod dump;
 mod error;
+mod latency;
+mod metadata;
 mod nal;
 mod rtmp;
+mod timestamps;
 
+pub use audio::build_flv_aac_tag;
+pub use captions::{
+    build_caption_sei, decode_caption_sei, decode_cea608_text, encode_cea608_text, CaptionInserter,
+};
 pub use connection::{ConnectionState, ReconnectPolicy};
+pub use dump::{PacketRecorder, PacketReplayer, RecordedPacket, ReplayPacing};
 pub use error::TransportError;
+pub use latency::{LatencyTimestamp, LATENCY_SEI_UUID};
+pub use metadata::{
+    apply_sps_metadata, decode_metadata, encode_metadata, StreamMetadata, FLV_CODEC_AAC,
+    FLV_CODEC_AVC,
+};
 pub use nal::{
-    build_avc_decoder_config, build_flv_video_tag, extract_sps_pps, filter_parameter_sets,
-    nals_to_avcc, parse_annex_b, NalUnit, NalUnitType,
+    build_avc_decoder_config, build_flv_video_tag, build_sei_nal, extract_sps_pps,
+    filter_parameter_sets, hevc, insert_sei, nals_to_avcc, nals_to_avcc_with_length_size,
+    parse_annex_b, parse_annex_b_bytes, parse_avcc, parse_sei_nal, rbsp, AnnexBStreamParser,
+    AvcDecoderConfigurationRecord, AvcHighProfileExt, FrameCropping, HrdParameters, NalUnit,
+    NalUnitType, ParameterSetChange, ParameterSetTracker, Pps, SeiMessage, Sps, TimingInfo,
+    VuiParameters, SEI_USER_DATA_REGISTERED, SEI_USER_DATA_UNREGISTERED,
 };
 pub use rtmp::{RtmpClient, RtmpPacket};
+pub use timestamps::{FlvTimestamps, VideoTagTiming};
 
 /// Channel capacity for outgoing packets.
 pub const PACKET_CHANNEL_CAPACITY: usize = 300;
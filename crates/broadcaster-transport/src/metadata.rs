@@ -0,0 +1,113 @@
+//! onMetaData stream metadata.
+//!
+//! Metadata travels through the packet channel like audio and video: the
+//! engine encodes it into an [`RtmpPacket`](crate::RtmpPacket) with
+//! `is_metadata` set, and the connection task decodes it again to publish it
+//! through the RTMP session as `@setDataFrame onMetaData`.
+
+use std::collections::HashMap;
+
+use bytes::Bytes;
+use rml_amf0::Amf0Value;
+
+pub use rml_rtmp::sessions::StreamMetadata;
+
+use crate::error::TransportError;
+use crate::nal::Sps;
+use crate::TransportResult;
+
+/// FLV video codec ID for AVC.
+pub const FLV_CODEC_AVC: u32 = 7;
+
+/// FLV audio codec ID for AAC.
+pub const FLV_CODEC_AAC: u32 = 10;
+
+/// Fill the video fields of `metadata` from a parsed SPS.
+///
+/// A frame rate already set, normally the configured one, is kept: the VUI
+/// timing describes the encoder's clock, which need not tick once per
+/// frame. Otherwise it is taken from the VUI timing information, if any.
+pub fn apply_sps_metadata(metadata: &mut StreamMetadata, sps: &Sps) {
+    metadata.video_width = Some(sps.width());
+    metadata.video_height = Some(sps.height());
+    metadata.video_codec_id = Some(FLV_CODEC_AVC);
+    if metadata.video_frame_rate.is_none() {
+        metadata.video_frame_rate = sps.frame_rate().map(|fps| fps as f32);
+    }
+}
+
+/// Encode metadata as an AMF0 object using the standard onMetaData keys.
+pub fn encode_metadata(metadata: &StreamMetadata) -> Bytes {
+    let mut properties = HashMap::new();
+
+    let mut number = |key: &str, value: Option<f64>| {
+        if let Some(v) = value {
+            properties.insert(key.to_string(), Amf0Value::Number(v));
+        }
+    };
+    number("width", metadata.video_width.map(f64::from));
+    number("height", metadata.video_height.map(f64::from));
+    number("videocodecid", metadata.video_codec_id.map(f64::from));
+    number("framerate", metadata.video_frame_rate.map(f64::from));
+    number("videodatarate", metadata.video_bitrate_kbps.map(f64::from));
+    number("audiocodecid", metadata.audio_codec_id.map(f64::from));
+    number("audiodatarate", metadata.audio_bitrate_kbps.map(f64::from));
+    number("audiosamplerate", metadata.audio_sample_rate.map(f64::from));
+    number("audiochannels", metadata.audio_channels.map(f64::from));
+
+    if let Some(stereo) = metadata.audio_is_stereo {
+        properties.insert("stereo".to_string(), Amf0Value::Boolean(stereo));
+    }
+    if let Some(ref encoder) = metadata.encoder {
+        properties.insert(
+            "encoder".to_string(),
+            Amf0Value::Utf8String(encoder.clone()),
+        );
+    }
+
+    // Serializing numbers, booleans and strings cannot fail
+    let data = rml_amf0::serialize(&vec![Amf0Value::Object(properties)]).unwrap_or_default();
+    Bytes::from(data)
+}
+
+/// Decode metadata previously produced by [`encode_metadata`].
+pub fn decode_metadata(data: &[u8]) -> TransportResult<StreamMetadata> {
+    let mut cursor = std::io::Cursor::new(data);
+    let values = rml_amf0::deserialize(&mut cursor)
+        .map_err(|e| TransportError::Protocol(format!("Invalid metadata: {:?}", e)))?;
+
+    let properties = values
+        .into_iter()
+        .next()
+        .and_then(Amf0Value::get_object_properties)
+        .ok_or_else(|| TransportError::Protocol("Metadata is not an AMF0 object".to_string()))?;
+
+    let mut metadata = StreamMetadata::new();
+    metadata.apply_metadata_values(properties);
+    Ok(metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata_round_trip() {
+        let mut metadata = StreamMetadata::new();
+        metadata.video_width = Some(1280);
+        metadata.video_height = Some(720);
+        metadata.video_codec_id = Some(FLV_CODEC_AVC);
+        metadata.video_frame_rate = Some(60.0);
+        metadata.audio_sample_rate = Some(48000);
+        metadata.audio_is_stereo = Some(true);
+        metadata.encoder = Some("x264".to_string());
+
+        let decoded = decode_metadata(&encode_metadata(&metadata)).unwrap();
+        assert_eq!(decoded, metadata);
+    }
+
+    #[test]
+    fn test_decode_metadata_rejects_garbage() {
+        assert!(decode_metadata(&[0xFF, 0x00]).is_err());
+    }
+}
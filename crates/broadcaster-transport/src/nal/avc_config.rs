@@ -0,0 +1,403 @@
+//! AVCDecoderConfigurationRecord (ISO/IEC 14496-15 5.3.3.1).
+//!
+//! This is the payload of the RTMP/FLV AVC sequence header and of the MP4
+//! `avcC` box. Besides the SPS/PPS lists, High profiles (100/110/122/244)
+//! carry a trailer with the chroma format, bit depths and any SPS extensions.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use tracing::debug;
+
+use crate::error::TransportError;
+use crate::nal::{NalUnitType, Sps};
+use crate::TransportResult;
+
+/// NAL unit type of an SPS extension.
+const NAL_TYPE_SPS_EXT: u8 = 13;
+
+/// Maximum SPS count (5-bit field).
+const MAX_SPS_COUNT: usize = 31;
+
+/// Profiles that require the chroma format / bit depth trailer.
+const HIGH_PROFILE_EXT_IDCS: [u8; 4] = [100, 110, 122, 244];
+
+/// High-profile trailer of the configuration record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AvcHighProfileExt {
+    /// 0 = monochrome, 1 = 4:2:0, 2 = 4:2:2, 3 = 4:4:4.
+    pub chroma_format_idc: u8,
+    pub bit_depth_luma: u8,
+    pub bit_depth_chroma: u8,
+    /// SPS extension NAL units.
+    pub sps_ext: Vec<Bytes>,
+}
+
+/// A decoded AVCDecoderConfigurationRecord.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AvcDecoderConfigurationRecord {
+    pub profile_idc: u8,
+    pub profile_compatibility: u8,
+    pub level_idc: u8,
+    /// Size of the NAL unit length prefix in bytes (1, 2 or 4).
+    pub length_size: u8,
+    /// SPS NAL units (including NAL header).
+    pub sps: Vec<Bytes>,
+    /// PPS NAL units (including NAL header).
+    pub pps: Vec<Bytes>,
+    /// High-profile trailer, present for profile_idc 100/110/122/244.
+    pub high_profile_ext: Option<AvcHighProfileExt>,
+}
+
+impl AvcDecoderConfigurationRecord {
+    /// Whether `profile_idc` requires the high-profile trailer.
+    pub fn requires_high_profile_ext(profile_idc: u8) -> bool {
+        HIGH_PROFILE_EXT_IDCS.contains(&profile_idc)
+    }
+
+    /// Build a record from parameter sets.
+    ///
+    /// Profile and level come from the first SPS; for High profiles the
+    /// trailer fields are derived by parsing it.
+    pub fn from_parameter_sets(
+        sps: &[Bytes],
+        pps: &[Bytes],
+        length_size: u8,
+    ) -> TransportResult<Self> {
+        let first = sps.first().ok_or_else(|| {
+            TransportError::InvalidBitstream("At least one SPS is required".to_string())
+        })?;
+        if first.len() < 4 {
+            return Err(TransportError::InvalidBitstream(format!(
+                "SPS too short: {} bytes",
+                first.len()
+            )));
+        }
+
+        let high_profile_ext = if Self::requires_high_profile_ext(first[1]) {
+            let parsed = Sps::parse(first)?;
+            Some(AvcHighProfileExt {
+                chroma_format_idc: parsed.chroma_format_idc as u8,
+                bit_depth_luma: parsed.bit_depth_luma as u8,
+                bit_depth_chroma: parsed.bit_depth_chroma as u8,
+                sps_ext: Vec::new(),
+            })
+        } else {
+            None
+        };
+
+        let record = Self {
+            profile_idc: first[1],
+            profile_compatibility: first[2],
+            level_idc: first[3],
+            length_size,
+            sps: sps.to_vec(),
+            pps: pps.to_vec(),
+            high_profile_ext,
+        };
+        record.validate()?;
+
+        Ok(record)
+    }
+
+    /// Check the field constraints imposed by the record syntax.
+    fn validate(&self) -> TransportResult<()> {
+        if !matches!(self.length_size, 1 | 2 | 4) {
+            return Err(TransportError::InvalidBitstream(format!(
+                "Invalid NAL length size {}",
+                self.length_size
+            )));
+        }
+        if self.sps.is_empty() || self.sps.len() > MAX_SPS_COUNT {
+            return Err(TransportError::InvalidBitstream(format!(
+                "Invalid SPS count {}",
+                self.sps.len()
+            )));
+        }
+        if self.pps.len() > u8::MAX as usize {
+            return Err(TransportError::InvalidBitstream(format!(
+                "Invalid PPS count {}",
+                self.pps.len()
+            )));
+        }
+
+        let check = |nals: &[Bytes], expected: NalUnitType| -> TransportResult<()> {
+            for nal in nals {
+                if nal.is_empty() || nal.len() > u16::MAX as usize {
+                    return Err(TransportError::InvalidBitstream(format!(
+                        "Invalid {:?} length {}",
+                        expected,
+                        nal.len()
+                    )));
+                }
+                if NalUnitType::from(nal[0]) != expected {
+                    return Err(TransportError::InvalidBitstream(format!(
+                        "Expected {:?} NAL unit, got type {}",
+                        expected,
+                        nal[0] & 0x1F
+                    )));
+                }
+            }
+            Ok(())
+        };
+        check(&self.sps, NalUnitType::Sps)?;
+        check(&self.pps, NalUnitType::Pps)?;
+
+        if let Some(ref ext) = self.high_profile_ext {
+            if ext.sps_ext.len() > u8::MAX as usize {
+                return Err(TransportError::InvalidBitstream(format!(
+                    "Invalid SPS extension count {}",
+                    ext.sps_ext.len()
+                )));
+            }
+            for nal in &ext.sps_ext {
+                if nal.is_empty() || nal.len() > u16::MAX as usize {
+                    return Err(TransportError::InvalidBitstream(
+                        "Invalid SPS extension length".to_string(),
+                    ));
+                }
+                if nal[0] & 0x1F != NAL_TYPE_SPS_EXT {
+                    return Err(TransportError::InvalidBitstream(
+                        "Expected SPS extension NAL unit".to_string(),
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Serialize the record.
+    pub fn to_bytes(&self) -> TransportResult<Bytes> {
+        self.validate()?;
+
+        let nal_bytes: usize = self
+            .sps
+            .iter()
+            .chain(self.pps.iter())
+            .map(|nal| nal.len() + 2)
+            .sum();
+        let mut buf = BytesMut::with_capacity(11 + nal_bytes);
+
+        // configurationVersion
+        buf.put_u8(0x01);
+        buf.put_u8(self.profile_idc);
+        buf.put_u8(self.profile_compatibility);
+        buf.put_u8(self.level_idc);
+
+        // 6 reserved bits (all 1s) + lengthSizeMinusOne
+        buf.put_u8(0xFC | (self.length_size - 1));
+
+        // 3 reserved bits (all 1s) + numOfSequenceParameterSets
+        buf.put_u8(0xE0 | self.sps.len() as u8);
+        for sps in &self.sps {
+            buf.put_u16(sps.len() as u16);
+            buf.put_slice(sps);
+        }
+
+        buf.put_u8(self.pps.len() as u8);
+        for pps in &self.pps {
+            buf.put_u16(pps.len() as u16);
+            buf.put_slice(pps);
+        }
+
+        if let Some(ref ext) = self.high_profile_ext {
+            // 6 reserved bits + chroma_format
+            buf.put_u8(0xFC | (ext.chroma_format_idc & 0x03));
+            // 5 reserved bits + bit_depth_luma_minus8
+            buf.put_u8(0xF8 | (ext.bit_depth_luma.saturating_sub(8) & 0x07));
+            // 5 reserved bits + bit_depth_chroma_minus8
+            buf.put_u8(0xF8 | (ext.bit_depth_chroma.saturating_sub(8) & 0x07));
+            buf.put_u8(ext.sps_ext.len() as u8);
+            for nal in &ext.sps_ext {
+                buf.put_u16(nal.len() as u16);
+                buf.put_slice(nal);
+            }
+        }
+
+        debug!(
+            sps_count = self.sps.len(),
+            pps_count = self.pps.len(),
+            high_profile_ext = self.high_profile_ext.is_some(),
+            total_len = buf.len(),
+            "Built AVC decoder configuration record"
+        );
+
+        Ok(buf.freeze())
+    }
+
+    /// Parse a serialized record.
+    ///
+    /// The high-profile trailer is optional on input, since many encoders
+    /// omit it even for High profiles.
+    pub fn parse(data: &[u8]) -> TransportResult<Self> {
+        let mut reader = ByteReader::new(data);
+
+        let version = reader.u8()?;
+        if version != 1 {
+            return Err(TransportError::InvalidBitstream(format!(
+                "Unsupported configurationVersion {}",
+                version
+            )));
+        }
+
+        let profile_idc = reader.u8()?;
+        let profile_compatibility = reader.u8()?;
+        let level_idc = reader.u8()?;
+        let length_size = (reader.u8()? & 0x03) + 1;
+
+        let sps_count = reader.u8()? & 0x1F;
+        let sps = (0..sps_count)
+            .map(|_| reader.nal())
+            .collect::<TransportResult<Vec<_>>>()?;
+
+        let pps_count = reader.u8()?;
+        let pps = (0..pps_count)
+            .map(|_| reader.nal())
+            .collect::<TransportResult<Vec<_>>>()?;
+
+        let high_profile_ext =
+            if Self::requires_high_profile_ext(profile_idc) && reader.remaining() >= 4 {
+                let chroma_format_idc = reader.u8()? & 0x03;
+                let bit_depth_luma = (reader.u8()? & 0x07) + 8;
+                let bit_depth_chroma = (reader.u8()? & 0x07) + 8;
+                let ext_count = reader.u8()?;
+                let sps_ext = (0..ext_count)
+                    .map(|_| reader.nal())
+                    .collect::<TransportResult<Vec<_>>>()?;
+                Some(AvcHighProfileExt {
+                    chroma_format_idc,
+                    bit_depth_luma,
+                    bit_depth_chroma,
+                    sps_ext,
+                })
+            } else {
+                None
+            };
+
+        let record = Self {
+            profile_idc,
+            profile_compatibility,
+            level_idc,
+            length_size,
+            sps,
+            pps,
+            high_profile_ext,
+        };
+        record.validate()?;
+
+        Ok(record)
+    }
+}
+
+/// Bounds-checked big-endian reader over a byte slice.
+pub(super) struct ByteReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    pub(super) fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    pub(super) fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    pub(super) fn take(&mut self, len: usize) -> TransportResult<&'a [u8]> {
+        if len > self.remaining() {
+            return Err(TransportError::InvalidBitstream(
+                "Truncated decoder configuration record".to_string(),
+            ));
+        }
+        let slice = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(slice)
+    }
+
+    pub(super) fn u8(&mut self) -> TransportResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(super) fn u16(&mut self) -> TransportResult<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// A 16-bit length-prefixed NAL unit.
+    pub(super) fn nal(&mut self) -> TransportResult<Bytes> {
+        let len = self.u16()? as usize;
+        Ok(Bytes::copy_from_slice(self.take(len)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nal::fixtures::{X264_PPS, X264_SPS_1080P};
+
+    #[test]
+    fn test_high_profile_record_has_trailer() {
+        let record = AvcDecoderConfigurationRecord::from_parameter_sets(
+            &[Bytes::from_static(&X264_SPS_1080P)],
+            &[Bytes::from_static(&X264_PPS)],
+            4,
+        )
+        .unwrap();
+        let bytes = record.to_bytes().unwrap();
+
+        let trailer = &bytes[bytes.len() - 4..];
+        assert_eq!(trailer, &[0xFD, 0xF8, 0xF8, 0x00]); // 4:2:0, 8-bit, no extensions
+        assert_eq!(
+            AvcDecoderConfigurationRecord::parse(&bytes).unwrap(),
+            record
+        );
+    }
+
+    #[test]
+    fn test_multiple_parameter_sets_round_trip() {
+        let sps = [
+            Bytes::from_static(&[0x67, 0x42, 0xC0, 0x1E, 0xAB]),
+            Bytes::from_static(&[0x67, 0x42, 0xC0, 0x1E, 0xCD]),
+        ];
+        let pps = [
+            Bytes::from_static(&[0x68, 0xCE, 0x3C, 0x80]),
+            Bytes::from_static(&[0x68, 0xCE, 0x3C, 0x81]),
+            Bytes::from_static(&[0x68, 0xCE, 0x3C, 0x82]),
+        ];
+
+        let record = AvcDecoderConfigurationRecord::from_parameter_sets(&sps, &pps, 2).unwrap();
+        let bytes = record.to_bytes().unwrap();
+
+        assert_eq!(bytes[4], 0xFD); // lengthSizeMinusOne = 1
+        assert_eq!(bytes[5], 0xE2); // two SPS
+        assert!(record.high_profile_ext.is_none());
+
+        let parsed = AvcDecoderConfigurationRecord::parse(&bytes).unwrap();
+        assert_eq!(parsed, record);
+        assert_eq!(parsed.length_size, 2);
+        assert_eq!(parsed.pps.len(), 3);
+    }
+
+    #[test]
+    fn test_invalid_length_size_rejected() {
+        let result = AvcDecoderConfigurationRecord::from_parameter_sets(
+            &[Bytes::from_static(&[0x67, 0x42, 0xC0, 0x1E])],
+            &[Bytes::from_static(&[0x68, 0xCE])],
+            3,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_truncated_record_fails() {
+        let record = AvcDecoderConfigurationRecord::from_parameter_sets(
+            &[Bytes::from_static(&X264_SPS_1080P)],
+            &[Bytes::from_static(&X264_PPS)],
+            4,
+        )
+        .unwrap();
+        let bytes = record.to_bytes().unwrap();
+
+        assert!(AvcDecoderConfigurationRecord::parse(&bytes[..12]).is_err());
+    }
+}
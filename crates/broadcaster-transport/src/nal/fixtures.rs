@@ -0,0 +1,11 @@
+//! Parameter sets shared by the NAL tests.
+
+/// SPS from an x264 1920x1080 High@4.0 30 fps stream.
+pub(crate) const X264_SPS_1080P: [u8; 26] = [
+    0x67, 0x64, 0x00, 0x28, 0xAC, 0xD9, 0x40, 0x78, 0x02, 0x27, 0xE5, 0x84, 0x00, 0x00, 0x03, 0x00,
+    0x04, 0x00, 0x00, 0x03, 0x00, 0xF0, 0x3C, 0x60, 0xC6, 0x58,
+];
+
+/// PPS of the same stream: id 0, sps 0, CABAC, transform_8x8_mode, chroma
+/// offsets -2.
+pub(crate) const X264_PPS: [u8; 6] = [0x68, 0xEB, 0xE3, 0xCB, 0x22, 0xC0];
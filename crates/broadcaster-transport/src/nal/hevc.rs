@@ -0,0 +1,697 @@
+//! H.265/HEVC NAL unit handling.
+//!
+//! HEVC uses the same Annex B start codes as H.264 but a 2-byte NAL header:
+//!
+//! ```text
+//! forbidden_zero_bit (1) | nal_unit_type (6) | nuh_layer_id (6) | nuh_temporal_id_plus1 (3)
+//! ```
+//!
+//! The stream is described by three parameter sets (VPS, SPS, PPS) which are
+//! carried out of band in the HEVCDecoderConfigurationRecord (`hvcC`,
+//! ISO/IEC 14496-15 8.3.3.1).
+
+use bytes::{BufMut, Bytes, BytesMut};
+use tracing::debug;
+
+use super::annex_b_nal_ranges;
+use super::avc_config::ByteReader;
+use super::rbsp::{remove_emulation_prevention, BitReader};
+use crate::error::TransportError;
+use crate::TransportResult;
+
+/// HEVC NAL unit types relevant for streaming.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum HevcNalUnitType {
+    /// Trailing picture, non-reference.
+    TrailN = 0,
+    /// Trailing picture, reference.
+    TrailR = 1,
+    /// Random access skipped leading picture, non-reference.
+    RaslN = 8,
+    /// Random access skipped leading picture, reference.
+    RaslR = 9,
+    /// Broken link access with leading pictures.
+    BlaWLp = 16,
+    /// Broken link access with decodable leading pictures.
+    BlaWRadl = 17,
+    /// Broken link access without leading pictures.
+    BlaNLp = 18,
+    /// IDR with decodable leading pictures.
+    IdrWRadl = 19,
+    /// IDR without leading pictures.
+    IdrNLp = 20,
+    /// Clean random access.
+    CraNut = 21,
+    /// Video Parameter Set.
+    Vps = 32,
+    /// Sequence Parameter Set.
+    Sps = 33,
+    /// Picture Parameter Set.
+    Pps = 34,
+    /// Access Unit Delimiter.
+    Aud = 35,
+    /// Prefix SEI.
+    PrefixSei = 39,
+    /// Suffix SEI.
+    SuffixSei = 40,
+    /// Other/unknown NAL type.
+    Other = 63,
+}
+
+impl HevcNalUnitType {
+    /// Whether this is a parameter set (VPS, SPS or PPS).
+    pub fn is_parameter_set(self) -> bool {
+        matches!(self, Self::Vps | Self::Sps | Self::Pps)
+    }
+}
+
+impl From<u8> for HevcNalUnitType {
+    /// Decode the type from the first NAL header byte.
+    fn from(byte: u8) -> Self {
+        match nal_unit_type(byte) {
+            0 => Self::TrailN,
+            1 => Self::TrailR,
+            8 => Self::RaslN,
+            9 => Self::RaslR,
+            16 => Self::BlaWLp,
+            17 => Self::BlaWRadl,
+            18 => Self::BlaNLp,
+            19 => Self::IdrWRadl,
+            20 => Self::IdrNLp,
+            21 => Self::CraNut,
+            32 => Self::Vps,
+            33 => Self::Sps,
+            34 => Self::Pps,
+            35 => Self::Aud,
+            39 => Self::PrefixSei,
+            40 => Self::SuffixSei,
+            _ => Self::Other,
+        }
+    }
+}
+
+/// Raw `nal_unit_type` from the first NAL header byte.
+pub fn nal_unit_type(byte: u8) -> u8 {
+    (byte >> 1) & 0x3F
+}
+
+/// A single HEVC NAL unit extracted from an Annex B stream.
+#[derive(Debug, Clone)]
+pub struct HevcNalUnit {
+    /// The NAL unit type.
+    pub nal_type: HevcNalUnitType,
+    /// The NAL unit data (including the 2-byte NAL header, excluding start code).
+    pub data: Bytes,
+}
+
+impl HevcNalUnit {
+    /// Whether this is an intra random access point (BLA, IDR or CRA).
+    ///
+    /// Uses the raw type so the reserved IRAP types 22 and 23 are included.
+    pub fn is_irap(&self) -> bool {
+        self.data
+            .first()
+            .is_some_and(|&b| (16..=23).contains(&nal_unit_type(b)))
+    }
+}
+
+/// Parse an Annex B byte stream into individual HEVC NAL units.
+///
+/// NAL units shorter than the 2-byte header are dropped.
+pub fn parse_annex_b(data: &[u8]) -> Vec<HevcNalUnit> {
+    annex_b_nal_ranges(data)
+        .into_iter()
+        .filter(|range| range.len() >= 2)
+        .map(|range| {
+            let nal_data = &data[range];
+            HevcNalUnit {
+                nal_type: HevcNalUnitType::from(nal_data[0]),
+                data: Bytes::copy_from_slice(nal_data),
+            }
+        })
+        .collect()
+}
+
+/// Convert NAL units to length-prefixed format with 4-byte length prefixes.
+pub fn nals_to_hvcc(nals: &[HevcNalUnit]) -> Bytes {
+    let mut buf = BytesMut::new();
+
+    for nal in nals {
+        buf.put_u32(nal.data.len() as u32);
+        buf.put_slice(&nal.data);
+    }
+
+    buf.freeze()
+}
+
+/// Whether an access unit contains an IRAP picture.
+pub fn is_keyframe(nals: &[HevcNalUnit]) -> bool {
+    nals.iter().any(HevcNalUnit::is_irap)
+}
+
+/// Extract VPS, SPS and PPS NAL units from Annex B header data.
+///
+/// If a parameter set appears more than once, the last one wins.
+pub fn extract_vps_sps_pps(annex_b_headers: &[u8]) -> Option<(Bytes, Bytes, Bytes)> {
+    let mut vps: Option<Bytes> = None;
+    let mut sps: Option<Bytes> = None;
+    let mut pps: Option<Bytes> = None;
+
+    for nal in parse_annex_b(annex_b_headers) {
+        match nal.nal_type {
+            HevcNalUnitType::Vps => {
+                debug!(len = nal.data.len(), "Found VPS NAL unit");
+                vps = Some(nal.data);
+            }
+            HevcNalUnitType::Sps => {
+                debug!(len = nal.data.len(), "Found HEVC SPS NAL unit");
+                sps = Some(nal.data);
+            }
+            HevcNalUnitType::Pps => {
+                debug!(len = nal.data.len(), "Found HEVC PPS NAL unit");
+                pps = Some(nal.data);
+            }
+            _ => {}
+        }
+    }
+
+    match (vps, sps, pps) {
+        (Some(v), Some(s), Some(p)) => Some((v, s, p)),
+        _ => None,
+    }
+}
+
+/// Filter out VPS, SPS and PPS NAL units from a list.
+///
+/// Parameter sets are sent in the hvcC record, so they are removed from the
+/// frame data the same way as for H.264.
+pub fn filter_parameter_sets(nals: Vec<HevcNalUnit>) -> Vec<HevcNalUnit> {
+    nals.into_iter()
+        .filter(|nal| !nal.nal_type.is_parameter_set())
+        .collect()
+}
+
+/// The fields of an HEVC SPS needed for the hvcC record and stream metadata.
+///
+/// Parsing stops after the bit depths; later fields are not needed here.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HevcSps {
+    pub vps_id: u8,
+    pub max_sub_layers: u8,
+    pub temporal_id_nesting: bool,
+    pub general_profile_space: u8,
+    pub general_tier_flag: bool,
+    pub general_profile_idc: u8,
+    pub general_profile_compatibility_flags: u32,
+    /// The 48 constraint indicator bits, starting at `general_progressive_source_flag`.
+    pub general_constraint_indicator_flags: u64,
+    pub general_level_idc: u8,
+    pub sps_id: u32,
+    pub chroma_format_idc: u32,
+    pub pic_width_in_luma_samples: u32,
+    pub pic_height_in_luma_samples: u32,
+    /// Conformance window offsets (left, right, top, bottom) in chroma units.
+    pub conformance_window: Option<(u32, u32, u32, u32)>,
+    pub bit_depth_luma: u32,
+    pub bit_depth_chroma: u32,
+}
+
+impl HevcSps {
+    /// Parse an SPS NAL unit (including the 2-byte NAL header).
+    pub fn parse(nal: &[u8]) -> TransportResult<Self> {
+        if nal.len() < 3 || nal_unit_type(nal[0]) != HevcNalUnitType::Sps as u8 {
+            return Err(TransportError::InvalidBitstream(
+                "Not an HEVC SPS NAL unit".to_string(),
+            ));
+        }
+
+        let rbsp = remove_emulation_prevention(&nal[2..]);
+        let mut r = BitReader::new(&rbsp);
+
+        let vps_id = r.read_bits(4)? as u8;
+        let max_sub_layers_minus1 = r.read_bits(3)? as u8;
+        let temporal_id_nesting = r.read_flag()?;
+
+        // profile_tier_level(1, sps_max_sub_layers_minus1)
+        let general_profile_space = r.read_bits(2)? as u8;
+        let general_tier_flag = r.read_flag()?;
+        let general_profile_idc = r.read_bits(5)? as u8;
+        let general_profile_compatibility_flags = r.read_bits(32)?;
+        let general_constraint_indicator_flags = r.read_bits_u64(48)?;
+        let general_level_idc = r.read_bits(8)? as u8;
+
+        let mut sub_layer_flags = Vec::with_capacity(max_sub_layers_minus1 as usize);
+        for _ in 0..max_sub_layers_minus1 {
+            let profile_present = r.read_flag()?;
+            let level_present = r.read_flag()?;
+            sub_layer_flags.push((profile_present, level_present));
+        }
+        if max_sub_layers_minus1 > 0 {
+            for _ in max_sub_layers_minus1..8 {
+                r.skip_bits(2)?; // reserved_zero_2bits
+            }
+        }
+        for (profile_present, level_present) in sub_layer_flags {
+            if profile_present {
+                r.skip_bits(88)?;
+            }
+            if level_present {
+                r.skip_bits(8)?;
+            }
+        }
+
+        let sps_id = r.read_ue()?;
+        let chroma_format_idc = r.read_ue()?;
+        if chroma_format_idc == 3 {
+            r.skip_bits(1)?; // separate_colour_plane_flag
+        }
+        let pic_width_in_luma_samples = r.read_ue()?;
+        let pic_height_in_luma_samples = r.read_ue()?;
+
+        let conformance_window = if r.read_flag()? {
+            Some((r.read_ue()?, r.read_ue()?, r.read_ue()?, r.read_ue()?))
+        } else {
+            None
+        };
+
+        let bit_depth_luma = r.read_ue()? + 8;
+        let bit_depth_chroma = r.read_ue()? + 8;
+
+        Ok(Self {
+            vps_id,
+            max_sub_layers: max_sub_layers_minus1 + 1,
+            temporal_id_nesting,
+            general_profile_space,
+            general_tier_flag,
+            general_profile_idc,
+            general_profile_compatibility_flags,
+            general_constraint_indicator_flags,
+            general_level_idc,
+            sps_id,
+            chroma_format_idc,
+            pic_width_in_luma_samples,
+            pic_height_in_luma_samples,
+            conformance_window,
+            bit_depth_luma,
+            bit_depth_chroma,
+        })
+    }
+
+    /// Chroma subsampling factors (SubWidthC, SubHeightC).
+    fn chroma_units(&self) -> (u32, u32) {
+        match self.chroma_format_idc {
+            1 => (2, 2),
+            2 => (2, 1),
+            _ => (1, 1),
+        }
+    }
+
+    /// Display width in pixels, after the conformance window.
+    pub fn width(&self) -> u32 {
+        let (sub_width, _) = self.chroma_units();
+        let crop = self
+            .conformance_window
+            .map_or(0, |(left, right, _, _)| (left + right) * sub_width);
+        self.pic_width_in_luma_samples.saturating_sub(crop)
+    }
+
+    /// Display height in pixels, after the conformance window.
+    pub fn height(&self) -> u32 {
+        let (_, sub_height) = self.chroma_units();
+        let crop = self
+            .conformance_window
+            .map_or(0, |(_, _, top, bottom)| (top + bottom) * sub_height);
+        self.pic_height_in_luma_samples.saturating_sub(crop)
+    }
+}
+
+/// One NAL unit array of an hvcC record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HvccNalArray {
+    /// Whether all NAL units of this type are in the array (none in-band).
+    pub array_completeness: bool,
+    /// Raw HEVC `nal_unit_type` of the units in this array.
+    pub nal_unit_type: u8,
+    pub nal_units: Vec<Bytes>,
+}
+
+/// A decoded HEVCDecoderConfigurationRecord.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HevcDecoderConfigurationRecord {
+    pub general_profile_space: u8,
+    pub general_tier_flag: bool,
+    pub general_profile_idc: u8,
+    pub general_profile_compatibility_flags: u32,
+    pub general_constraint_indicator_flags: u64,
+    pub general_level_idc: u8,
+    pub min_spatial_segmentation_idc: u16,
+    pub parallelism_type: u8,
+    pub chroma_format_idc: u8,
+    pub bit_depth_luma: u8,
+    pub bit_depth_chroma: u8,
+    /// Average frame rate in frames per 256 seconds, 0 if unspecified.
+    pub avg_frame_rate: u16,
+    pub constant_frame_rate: u8,
+    pub num_temporal_layers: u8,
+    pub temporal_id_nested: bool,
+    /// Size of the NAL unit length prefix in bytes (1, 2 or 4).
+    pub length_size: u8,
+    pub arrays: Vec<HvccNalArray>,
+}
+
+impl HevcDecoderConfigurationRecord {
+    /// Build a record from a VPS, SPS and PPS.
+    ///
+    /// Profile, level, chroma format and bit depths come from the parsed SPS.
+    pub fn from_parameter_sets(
+        vps: &[u8],
+        sps: &[u8],
+        pps: &[u8],
+        length_size: u8,
+    ) -> TransportResult<Self> {
+        let parsed = HevcSps::parse(sps)?;
+
+        let array = |nal_type: HevcNalUnitType, nal: &[u8]| HvccNalArray {
+            array_completeness: true,
+            nal_unit_type: nal_type as u8,
+            nal_units: vec![Bytes::copy_from_slice(nal)],
+        };
+
+        let record = Self {
+            general_profile_space: parsed.general_profile_space,
+            general_tier_flag: parsed.general_tier_flag,
+            general_profile_idc: parsed.general_profile_idc,
+            general_profile_compatibility_flags: parsed.general_profile_compatibility_flags,
+            general_constraint_indicator_flags: parsed.general_constraint_indicator_flags,
+            general_level_idc: parsed.general_level_idc,
+            min_spatial_segmentation_idc: 0,
+            parallelism_type: 0,
+            chroma_format_idc: parsed.chroma_format_idc as u8,
+            bit_depth_luma: parsed.bit_depth_luma as u8,
+            bit_depth_chroma: parsed.bit_depth_chroma as u8,
+            avg_frame_rate: 0,
+            constant_frame_rate: 0,
+            num_temporal_layers: parsed.max_sub_layers,
+            temporal_id_nested: parsed.temporal_id_nesting,
+            length_size,
+            arrays: vec![
+                array(HevcNalUnitType::Vps, vps),
+                array(HevcNalUnitType::Sps, sps),
+                array(HevcNalUnitType::Pps, pps),
+            ],
+        };
+        record.validate()?;
+
+        Ok(record)
+    }
+
+    /// Check the field constraints imposed by the record syntax.
+    fn validate(&self) -> TransportResult<()> {
+        if !matches!(self.length_size, 1 | 2 | 4) {
+            return Err(TransportError::InvalidBitstream(format!(
+                "Invalid NAL length size {}",
+                self.length_size
+            )));
+        }
+        if self.arrays.len() > u8::MAX as usize {
+            return Err(TransportError::InvalidBitstream(format!(
+                "Invalid NAL array count {}",
+                self.arrays.len()
+            )));
+        }
+        for array in &self.arrays {
+            if array.nal_units.len() > u16::MAX as usize {
+                return Err(TransportError::InvalidBitstream(format!(
+                    "Invalid NAL unit count {} for type {}",
+                    array.nal_units.len(),
+                    array.nal_unit_type
+                )));
+            }
+            for nal in &array.nal_units {
+                if nal.len() < 2 || nal.len() > u16::MAX as usize {
+                    return Err(TransportError::InvalidBitstream(format!(
+                        "Invalid NAL unit length {}",
+                        nal.len()
+                    )));
+                }
+                if nal_unit_type(nal[0]) != array.nal_unit_type {
+                    return Err(TransportError::InvalidBitstream(format!(
+                        "NAL unit of type {} in array of type {}",
+                        nal_unit_type(nal[0]),
+                        array.nal_unit_type
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// NAL units of the array with the given type.
+    pub fn nal_units(&self, nal_type: HevcNalUnitType) -> &[Bytes] {
+        self.arrays
+            .iter()
+            .find(|a| a.nal_unit_type == nal_type as u8)
+            .map_or(&[], |a| a.nal_units.as_slice())
+    }
+
+    /// Serialize the record.
+    pub fn to_bytes(&self) -> TransportResult<Bytes> {
+        self.validate()?;
+
+        let mut buf = BytesMut::with_capacity(64);
+
+        // configurationVersion
+        buf.put_u8(0x01);
+        buf.put_u8(
+            (self.general_profile_space & 0x03) << 6
+                | (self.general_tier_flag as u8) << 5
+                | (self.general_profile_idc & 0x1F),
+        );
+        buf.put_u32(self.general_profile_compatibility_flags);
+        buf.put_slice(&self.general_constraint_indicator_flags.to_be_bytes()[2..]);
+        buf.put_u8(self.general_level_idc);
+
+        // Reserved bits are all 1s
+        buf.put_u16(0xF000 | (self.min_spatial_segmentation_idc & 0x0FFF));
+        buf.put_u8(0xFC | (self.parallelism_type & 0x03));
+        buf.put_u8(0xFC | (self.chroma_format_idc & 0x03));
+        buf.put_u8(0xF8 | (self.bit_depth_luma.saturating_sub(8) & 0x07));
+        buf.put_u8(0xF8 | (self.bit_depth_chroma.saturating_sub(8) & 0x07));
+        buf.put_u16(self.avg_frame_rate);
+        buf.put_u8(
+            (self.constant_frame_rate & 0x03) << 6
+                | (self.num_temporal_layers & 0x07) << 3
+                | (self.temporal_id_nested as u8) << 2
+                | (self.length_size - 1),
+        );
+
+        buf.put_u8(self.arrays.len() as u8);
+        for array in &self.arrays {
+            buf.put_u8((array.array_completeness as u8) << 7 | (array.nal_unit_type & 0x3F));
+            buf.put_u16(array.nal_units.len() as u16);
+            for nal in &array.nal_units {
+                buf.put_u16(nal.len() as u16);
+                buf.put_slice(nal);
+            }
+        }
+
+        debug!(
+            arrays = self.arrays.len(),
+            total_len = buf.len(),
+            "Built HEVC decoder configuration record"
+        );
+
+        Ok(buf.freeze())
+    }
+
+    /// Parse a serialized record.
+    pub fn parse(data: &[u8]) -> TransportResult<Self> {
+        let mut reader = ByteReader::new(data);
+
+        let version = reader.u8()?;
+        if version != 1 {
+            return Err(TransportError::InvalidBitstream(format!(
+                "Unsupported configurationVersion {}",
+                version
+            )));
+        }
+
+        let profile = reader.u8()?;
+        let compat = reader.take(4)?;
+        let constraints = reader.take(6)?;
+        let general_level_idc = reader.u8()?;
+        let min_spatial_segmentation_idc = reader.u16()? & 0x0FFF;
+        let parallelism_type = reader.u8()? & 0x03;
+        let chroma_format_idc = reader.u8()? & 0x03;
+        let bit_depth_luma = (reader.u8()? & 0x07) + 8;
+        let bit_depth_chroma = (reader.u8()? & 0x07) + 8;
+        let avg_frame_rate = reader.u16()?;
+        let flags = reader.u8()?;
+
+        let num_arrays = reader.u8()?;
+        let mut arrays = Vec::with_capacity(num_arrays as usize);
+        for _ in 0..num_arrays {
+            let header = reader.u8()?;
+            let count = reader.u16()?;
+            let nal_units = (0..count)
+                .map(|_| reader.nal())
+                .collect::<TransportResult<Vec<_>>>()?;
+            arrays.push(HvccNalArray {
+                array_completeness: header & 0x80 != 0,
+                nal_unit_type: header & 0x3F,
+                nal_units,
+            });
+        }
+
+        let mut constraint_bytes = [0u8; 8];
+        constraint_bytes[2..].copy_from_slice(constraints);
+
+        let record = Self {
+            general_profile_space: profile >> 6,
+            general_tier_flag: profile & 0x20 != 0,
+            general_profile_idc: profile & 0x1F,
+            general_profile_compatibility_flags: u32::from_be_bytes([
+                compat[0], compat[1], compat[2], compat[3],
+            ]),
+            general_constraint_indicator_flags: u64::from_be_bytes(constraint_bytes),
+            general_level_idc,
+            min_spatial_segmentation_idc,
+            parallelism_type,
+            chroma_format_idc,
+            bit_depth_luma,
+            bit_depth_chroma,
+            avg_frame_rate,
+            constant_frame_rate: flags >> 6,
+            num_temporal_layers: (flags >> 3) & 0x07,
+            temporal_id_nested: flags & 0x04 != 0,
+            length_size: (flags & 0x03) + 1,
+            arrays,
+        };
+        record.validate()?;
+
+        Ok(record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nal::rbsp::{add_emulation_prevention, BitWriter};
+
+    /// Build a Main profile, level 4.1 SPS for a 1920x1080 stream.
+    ///
+    /// The coded height is 1088 with an 8-line conformance window, which is
+    /// what encoders produce for 1080p.
+    fn test_sps() -> Vec<u8> {
+        let mut w = BitWriter::new();
+        w.write_bits(0, 4); // sps_video_parameter_set_id
+        w.write_bits(0, 3); // sps_max_sub_layers_minus1
+        w.write_bit(true); // sps_temporal_id_nesting_flag
+        w.write_bits(0, 2); // general_profile_space
+        w.write_bit(false); // general_tier_flag
+        w.write_bits(1, 5); // general_profile_idc (Main)
+        w.write_bits(0x6000_0000, 32); // profile compatibility (Main, Main 10)
+        w.write_bits(0x9000_0000_0000, 48); // progressive, frame only
+        w.write_bits(123, 8); // general_level_idc (4.1)
+        w.write_ue(0); // sps_seq_parameter_set_id
+        w.write_ue(1); // chroma_format_idc
+        w.write_ue(1920);
+        w.write_ue(1088);
+        w.write_bit(true); // conformance_window_flag
+        w.write_ue(0);
+        w.write_ue(0);
+        w.write_ue(0);
+        w.write_ue(4); // bottom offset in chroma units
+        w.write_ue(0); // bit_depth_luma_minus8
+        w.write_ue(0); // bit_depth_chroma_minus8
+        w.write_trailing_bits();
+
+        let mut nal = vec![0x42, 0x01];
+        nal.extend(add_emulation_prevention(&w.into_bytes()));
+        nal
+    }
+
+    const VPS: [u8; 4] = [0x40, 0x01, 0x0C, 0x01];
+    const PPS: [u8; 4] = [0x44, 0x01, 0xC1, 0x72];
+
+    #[test]
+    fn test_nal_header_type() {
+        assert_eq!(HevcNalUnitType::from(0x40), HevcNalUnitType::Vps);
+        assert_eq!(HevcNalUnitType::from(0x42), HevcNalUnitType::Sps);
+        assert_eq!(HevcNalUnitType::from(0x44), HevcNalUnitType::Pps);
+        assert_eq!(HevcNalUnitType::from(0x26), HevcNalUnitType::IdrWRadl);
+        assert_eq!(HevcNalUnitType::from(0x02), HevcNalUnitType::TrailR);
+    }
+
+    #[test]
+    fn test_parse_and_extract_parameter_sets() {
+        let sps = test_sps();
+        let mut data = Vec::new();
+        for nal in [&VPS[..], &sps, &PPS, &[0x26, 0x01, 0xAF]] {
+            data.extend_from_slice(&[0x00, 0x00, 0x00, 0x01]);
+            data.extend_from_slice(nal);
+        }
+
+        let nals = parse_annex_b(&data);
+        assert_eq!(nals.len(), 4);
+        assert!(is_keyframe(&nals));
+
+        let (v, s, p) = extract_vps_sps_pps(&data).unwrap();
+        assert_eq!(&v[..], &VPS);
+        assert_eq!(&s[..], &sps[..]);
+        assert_eq!(&p[..], &PPS);
+
+        let frames = filter_parameter_sets(nals);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].nal_type, HevcNalUnitType::IdrWRadl);
+    }
+
+    #[test]
+    fn test_irap_detection() {
+        let cra = HevcNalUnit {
+            nal_type: HevcNalUnitType::from(0x2A),
+            data: Bytes::from_static(&[0x2A, 0x01]),
+        };
+        let trail = HevcNalUnit {
+            nal_type: HevcNalUnitType::from(0x02),
+            data: Bytes::from_static(&[0x02, 0x01]),
+        };
+        assert!(cra.is_irap());
+        assert!(!trail.is_irap());
+        assert!(!is_keyframe(&[trail]));
+    }
+
+    #[test]
+    fn test_sps_parse() {
+        let sps = HevcSps::parse(&test_sps()).unwrap();
+        assert_eq!(sps.general_profile_idc, 1);
+        assert_eq!(sps.general_level_idc, 123);
+        assert_eq!(sps.chroma_format_idc, 1);
+        assert_eq!(sps.width(), 1920);
+        assert_eq!(sps.height(), 1080);
+        assert_eq!(sps.bit_depth_luma, 8);
+    }
+
+    #[test]
+    fn test_hvcc_round_trip() {
+        let record =
+            HevcDecoderConfigurationRecord::from_parameter_sets(&VPS, &test_sps(), &PPS, 4)
+                .unwrap();
+        let bytes = record.to_bytes().unwrap();
+
+        assert_eq!(bytes[0], 0x01);
+        assert_eq!(bytes[1], 0x01); // profile space 0, main tier, Main
+        assert_eq!(bytes[12], 123);
+        assert_eq!(bytes[21] & 0x03, 3); // lengthSizeMinusOne
+        assert_eq!(bytes[22], 3); // VPS, SPS, PPS arrays
+
+        let parsed = HevcDecoderConfigurationRecord::parse(&bytes).unwrap();
+        assert_eq!(parsed, record);
+        assert_eq!(
+            parsed.nal_units(HevcNalUnitType::Pps),
+            &[Bytes::from_static(&PPS)]
+        );
+    }
+}
@@ -8,10 +8,39 @@
 //!
 //! This module provides utilities to convert between these formats and to build
 //! the AVC Decoder Configuration Record (sequence header) required by RTMP.
+//! [`Sps`] and [`Pps`] parse parameter sets so the stream can be checked
+//! against what the encoder was asked to produce, and [`ParameterSetTracker`]
+//! detects when the encoder switches to new ones mid-stream. SEI messages
+//! can be built, parsed and inserted into access units. [`hevc`] provides
+//! the same for H.265 streams.
+
+mod avc_config;
+#[cfg(test)]
+mod fixtures;
+pub mod hevc;
+mod param_sets;
+pub mod rbsp;
+mod sei;
+mod sps;
+mod stream;
+
+pub use avc_config::{AvcDecoderConfigurationRecord, AvcHighProfileExt};
+pub use param_sets::{ParameterSetChange, ParameterSetTracker};
+pub use sei::{
+    build_sei_nal, insert_sei, parse_sei_nal, SeiMessage, SEI_USER_DATA_REGISTERED,
+    SEI_USER_DATA_UNREGISTERED,
+};
+pub use sps::{FrameCropping, HrdParameters, Pps, Sps, TimingInfo, VuiParameters};
+pub use stream::AnnexBStreamParser;
+
+use std::ops::Range;
 
 use bytes::{BufMut, Bytes, BytesMut};
 use tracing::debug;
 
+use crate::error::TransportError;
+use crate::TransportResult;
+
 /// NAL unit types relevant for H.264.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -55,62 +84,95 @@ pub struct NalUnit {
     pub data: Bytes,
 }
 
+impl NalUnit {
+    /// Wrap NAL unit data, deriving the type from the header byte.
+    ///
+    /// `data` must not be empty.
+    pub fn new(data: Bytes) -> Self {
+        Self {
+            nal_type: NalUnitType::from(data[0]),
+            data,
+        }
+    }
+}
+
 /// Parse an Annex B byte stream into individual NAL units.
 ///
 /// Annex B format uses start codes (0x000001 or 0x00000001) to separate NAL units.
 /// This function finds all NAL units and returns them without the start codes.
 pub fn parse_annex_b(data: &[u8]) -> Vec<NalUnit> {
-    let mut nals = Vec::new();
-    let mut i = 0;
-    let len = data.len();
-
-    while i < len {
-        // Find start code (0x000001 or 0x00000001)
-        let start_code_len = if i + 3 < len && data[i] == 0 && data[i + 1] == 0 {
-            if data[i + 2] == 1 {
-                3 // 0x000001
-            } else if i + 4 <= len && data[i + 2] == 0 && data[i + 3] == 1 {
-                4 // 0x00000001
-            } else {
-                i += 1;
-                continue;
+    annex_b_nal_ranges(data)
+        .into_iter()
+        .map(|range| {
+            let nal_data = &data[range];
+            NalUnit {
+                nal_type: NalUnitType::from(nal_data[0]),
+                data: Bytes::copy_from_slice(nal_data),
             }
-        } else {
-            i += 1;
-            continue;
-        };
-
-        let nal_start = i + start_code_len;
-
-        // Find next start code or end of data
-        let mut nal_end = len;
-        let mut j = nal_start;
-        while j + 2 < len {
-            if data[j] == 0
-                && data[j + 1] == 0
-                && (data[j + 2] == 1 || (j + 3 < len && data[j + 2] == 0 && data[j + 3] == 1))
-            {
-                nal_end = j;
+        })
+        .collect()
+}
+
+/// Parse an Annex B byte stream into NAL units without copying.
+///
+/// Same as [`parse_annex_b`], but each returned NAL unit is a slice of
+/// `data` sharing its allocation.
+pub fn parse_annex_b_bytes(data: &Bytes) -> Vec<NalUnit> {
+    annex_b_nal_ranges(data)
+        .into_iter()
+        .map(|range| NalUnit::new(data.slice(range)))
+        .collect()
+}
+
+/// Locate the NAL units of an Annex B byte stream.
+///
+/// Returns the byte range of each non-empty NAL unit, excluding start codes.
+/// The scan is codec-agnostic and shared by the H.264 and HEVC parsers.
+pub(crate) fn annex_b_nal_ranges(data: &[u8]) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let Some(first) = find_start_code(data, 0) else {
+        return ranges;
+    };
+
+    let mut nal_start = first + 3;
+    while nal_start < data.len() {
+        match find_start_code(data, nal_start) {
+            Some(pos) => {
+                // The leading zero of a 4-byte start code is not NAL data
+                let nal_end = if pos > nal_start && data[pos - 1] == 0 {
+                    pos - 1
+                } else {
+                    pos
+                };
+                if nal_start < nal_end {
+                    ranges.push(nal_start..nal_end);
+                }
+                nal_start = pos + 3;
+            }
+            None => {
+                ranges.push(nal_start..data.len());
                 break;
             }
-            j += 1;
         }
+    }
 
-        if nal_start < nal_end {
-            let nal_data = &data[nal_start..nal_end];
-            if !nal_data.is_empty() {
-                let nal_type = NalUnitType::from(nal_data[0]);
-                nals.push(NalUnit {
-                    nal_type,
-                    data: Bytes::copy_from_slice(nal_data),
-                });
-            }
-        }
+    ranges
+}
 
-        i = nal_end;
+/// Position of the next 3-byte start code (`00 00 01`) at or after `from`.
+pub(crate) fn find_start_code(data: &[u8], from: usize) -> Option<usize> {
+    let mut i = from;
+    while i + 2 < data.len() {
+        if data[i + 2] > 1 {
+            // No start code can begin at i, i + 1 or i + 2
+            i += 3;
+        } else if data[i + 2] == 1 && data[i] == 0 && data[i + 1] == 0 {
+            return Some(i);
+        } else {
+            i += 1;
+        }
     }
-
-    nals
+    None
 }
 
 /// Convert NAL units to AVCC format with 4-byte length prefixes.
@@ -118,7 +180,7 @@ pub fn parse_annex_b(data: &[u8]) -> Vec<NalUnit> {
 /// AVCC format prepends each NAL unit with its length (big-endian).
 /// The length prefix size is typically 4 bytes for H.264 over RTMP.
 pub fn nals_to_avcc(nals: &[NalUnit]) -> Bytes {
-    let mut buf = BytesMut::new();
+    let mut buf = BytesMut::with_capacity(nals.iter().map(|nal| nal.data.len() + 4).sum());
 
     for nal in nals {
         // 4-byte length prefix (big-endian)
@@ -129,6 +191,72 @@ pub fn nals_to_avcc(nals: &[NalUnit]) -> Bytes {
     buf.freeze()
 }
 
+/// Convert NAL units to AVCC format with `length_size`-byte length prefixes.
+///
+/// `length_size` must be 1, 2 or 4 and match the decoder configuration
+/// record the stream is described by.
+pub fn nals_to_avcc_with_length_size(nals: &[NalUnit], length_size: u8) -> TransportResult<Bytes> {
+    let mut buf = BytesMut::new();
+
+    for nal in nals {
+        let len = nal.data.len();
+        match length_size {
+            1 if len <= u8::MAX as usize => buf.put_u8(len as u8),
+            2 if len <= u16::MAX as usize => buf.put_u16(len as u16),
+            4 => buf.put_u32(len as u32),
+            _ => {
+                return Err(TransportError::InvalidBitstream(format!(
+                    "NAL unit of {} bytes does not fit a {}-byte length prefix",
+                    len, length_size
+                )))
+            }
+        }
+        buf.put_slice(&nal.data);
+    }
+
+    Ok(buf.freeze())
+}
+
+/// Split AVCC data with `length_size`-byte length prefixes into NAL units.
+///
+/// The inverse of [`nals_to_avcc_with_length_size`]. NAL units share the
+/// allocation of `data`.
+pub fn parse_avcc(data: &Bytes, length_size: u8) -> TransportResult<Vec<NalUnit>> {
+    if !matches!(length_size, 1 | 2 | 4) {
+        return Err(TransportError::InvalidBitstream(format!(
+            "Invalid NAL length size {}",
+            length_size
+        )));
+    }
+
+    let length_size = length_size as usize;
+    let mut nals = Vec::new();
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let prefix = data.get(pos..pos + length_size).ok_or_else(|| {
+            TransportError::InvalidBitstream("Truncated NAL length prefix".to_string())
+        })?;
+        let len = prefix
+            .iter()
+            .fold(0usize, |acc, &b| (acc << 8) | b as usize);
+        pos += length_size;
+
+        if pos + len > data.len() {
+            return Err(TransportError::InvalidBitstream(format!(
+                "NAL unit of {} bytes exceeds AVCC data",
+                len
+            )));
+        }
+        if len > 0 {
+            nals.push(NalUnit::new(data.slice(pos..pos + len)));
+        }
+        pos += len;
+    }
+
+    Ok(nals)
+}
+
 /// Extract SPS and PPS NAL units from Annex B header data.
 ///
 /// The header data from x264 contains SPS and PPS NAL units that describe
@@ -160,64 +288,26 @@ pub fn extract_sps_pps(annex_b_headers: &[u8]) -> Option<(Bytes, Bytes)> {
     }
 }
 
-/// Build an AVC Decoder Configuration Record from SPS and PPS.
+/// Build an AVC Decoder Configuration Record from a single SPS and PPS.
 ///
 /// This is the "sequence header" that must be sent before any video frames
 /// in RTMP/FLV. It tells the decoder how to interpret the H.264 stream.
-///
-/// Format (ISO 14496-15):
-/// - configurationVersion: 1 byte (always 0x01)
-/// - AVCProfileIndication: 1 byte (from SPS byte 1)
-/// - profile_compatibility: 1 byte (from SPS byte 2)
-/// - AVCLevelIndication: 1 byte (from SPS byte 3)
-/// - lengthSizeMinusOne: 1 byte (0xFF = 4-byte NAL length)
-/// - numOfSequenceParameterSets: 1 byte (0xE1 = 1 SPS, upper 3 bits reserved)
-/// - sequenceParameterSetLength: 2 bytes (big-endian)
-/// - sequenceParameterSetNALUnit: variable
-/// - numOfPictureParameterSets: 1 byte
-/// - pictureParameterSetLength: 2 bytes (big-endian)
-/// - pictureParameterSetNALUnit: variable
+/// NAL units use 4-byte length prefixes. See [`AvcDecoderConfigurationRecord`]
+/// for multiple parameter sets or other length sizes.
 pub fn build_avc_decoder_config(sps: &[u8], pps: &[u8]) -> Option<Bytes> {
-    if sps.len() < 4 {
-        debug!("SPS too short: {} bytes", sps.len());
-        return None;
-    }
-
-    let mut buf = BytesMut::with_capacity(11 + sps.len() + pps.len());
-
-    // configurationVersion
-    buf.put_u8(0x01);
-
-    // AVCProfileIndication, profile_compatibility, AVCLevelIndication
-    buf.put_u8(sps[1]); // profile_idc
-    buf.put_u8(sps[2]); // constraint flags
-    buf.put_u8(sps[3]); // level_idc
-
-    // lengthSizeMinusOne (0xFF = 4-byte NAL length prefix, upper 6 bits reserved as 1s)
-    buf.put_u8(0xFF);
-
-    // numOfSequenceParameterSets (0xE1 = 1 SPS, upper 3 bits reserved as 1s)
-    buf.put_u8(0xE1);
-
-    // SPS length and data
-    buf.put_u16(sps.len() as u16);
-    buf.put_slice(sps);
-
-    // numOfPictureParameterSets
-    buf.put_u8(0x01);
-
-    // PPS length and data
-    buf.put_u16(pps.len() as u16);
-    buf.put_slice(pps);
-
-    debug!(
-        sps_len = sps.len(),
-        pps_len = pps.len(),
-        total_len = buf.len(),
-        "Built AVC decoder configuration record"
+    let record = AvcDecoderConfigurationRecord::from_parameter_sets(
+        &[Bytes::copy_from_slice(sps)],
+        &[Bytes::copy_from_slice(pps)],
+        4,
     );
 
-    Some(buf.freeze())
+    match record.and_then(|r| r.to_bytes()) {
+        Ok(bytes) => Some(bytes),
+        Err(e) => {
+            debug!("Failed to build AVC decoder configuration record: {}", e);
+            None
+        }
+    }
 }
 
 /// Build an FLV video tag payload for H.264 data.
@@ -267,7 +357,8 @@ pub fn build_flv_video_tag(
 /// Filter NAL units, removing SPS/PPS (which should be in the sequence header).
 ///
 /// When sending video frames, we don't want to include SPS/PPS NAL units
-/// because they're already in the sequence header sent at stream start.
+/// because they're carried in the sequence header. Run the frame through a
+/// [`ParameterSetTracker`] first so in-band changes trigger a new one.
 pub fn filter_parameter_sets(nals: Vec<NalUnit>) -> Vec<NalUnit> {
     nals.into_iter()
         .filter(|nal| {
@@ -315,6 +406,24 @@ mod tests {
         assert_eq!(nals[1].nal_type, NalUnitType::Pps);
     }
 
+    #[test]
+    fn test_parse_annex_b_bytes_is_zero_copy() {
+        let data = Bytes::from_static(&[
+            0x00, 0x00, 0x00, 0x01, 0x67, 0x42, 0x00, 0x1E, // SPS
+            0x00, 0x00, 0x01, 0x68, 0xCE, 0x3C, 0x80, // PPS
+        ]);
+        let nals = parse_annex_b_bytes(&data);
+
+        assert_eq!(nals.len(), 2);
+        assert_eq!(nals[0].nal_type, NalUnitType::Sps);
+        assert_eq!(nals[1].nal_type, NalUnitType::Pps);
+        assert_eq!(nals[1].data.as_ptr(), data[11..].as_ptr());
+
+        let copied = parse_annex_b(&data);
+        assert_eq!(nals[0].data, copied[0].data);
+        assert_eq!(nals[1].data, copied[1].data);
+    }
+
     #[test]
     fn test_nals_to_avcc() {
         let nals = vec![NalUnit {
@@ -326,6 +435,36 @@ mod tests {
         assert_eq!(avcc.as_ref(), &[0x00, 0x00, 0x00, 0x03, 0x65, 0x88, 0x84]);
     }
 
+    #[test]
+    fn test_nals_to_avcc_with_length_size() {
+        let nals = vec![NalUnit {
+            nal_type: NalUnitType::IdrSlice,
+            data: Bytes::from_static(&[0x65, 0xAA, 0xBB]),
+        }];
+
+        let avcc = nals_to_avcc_with_length_size(&nals, 2).unwrap();
+        assert_eq!(&avcc[..], &[0x00, 0x03, 0x65, 0xAA, 0xBB]);
+        assert!(nals_to_avcc_with_length_size(&nals, 3).is_err());
+    }
+
+    #[test]
+    fn test_parse_avcc_round_trip() {
+        let nals = vec![
+            NalUnit::new(Bytes::from_static(&[0x06, 0x05, 0x01, 0x00, 0x80])),
+            NalUnit::new(Bytes::from_static(&[0x65, 0xAA, 0xBB])),
+        ];
+
+        for length_size in [1, 2, 4] {
+            let avcc = nals_to_avcc_with_length_size(&nals, length_size).unwrap();
+            let parsed = parse_avcc(&avcc, length_size).unwrap();
+            assert_eq!(parsed.len(), 2);
+            assert_eq!(parsed[0].nal_type, NalUnitType::Sei);
+            assert_eq!(parsed[1].data, nals[1].data);
+        }
+
+        assert!(parse_avcc(&Bytes::from_static(&[0x00, 0x00, 0x00, 0x05, 0x65]), 4).is_err());
+    }
+
     #[test]
     fn test_build_avc_decoder_config() {
         let sps = [0x67, 0x42, 0x00, 0x1E, 0xAB, 0xCD]; // Fake SPS
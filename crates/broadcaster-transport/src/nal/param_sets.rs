@@ -0,0 +1,205 @@
+//! Tracking of the active H.264 parameter sets.
+//!
+//! The AVC sequence header describes exactly one SPS/PPS pair. Encoders repeat
+//! their parameter sets in-band on keyframes, and a rebuilt or reconfigured
+//! encoder may start emitting different ones. [`ParameterSetTracker`] compares
+//! in-band parameter sets with the ones last announced and reports when a new
+//! sequence header has to be sent. The switch only happens on a keyframe, as
+//! frames before it still reference the old parameter sets.
+
+use bytes::Bytes;
+use tracing::{debug, info, warn};
+
+use crate::nal::{build_avc_decoder_config, NalUnit, NalUnitType};
+
+/// Parameter sets that replace the active ones.
+#[derive(Debug, Clone)]
+pub struct ParameterSetChange {
+    /// The new SPS NAL unit (including NAL header).
+    pub sps: Bytes,
+    /// The new PPS NAL unit (including NAL header).
+    pub pps: Bytes,
+    /// AVCDecoderConfigurationRecord for the new parameter sets.
+    pub decoder_config: Bytes,
+}
+
+/// Tracks the SPS/PPS announced in the last sequence header.
+#[derive(Debug, Default)]
+pub struct ParameterSetTracker {
+    active_sps: Option<Bytes>,
+    active_pps: Option<Bytes>,
+    /// In-band SPS that differs from the active one.
+    pending_sps: Option<Bytes>,
+    /// In-band PPS that differs from the active one.
+    pending_pps: Option<Bytes>,
+}
+
+impl ParameterSetTracker {
+    /// Create a tracker with no active parameter sets.
+    ///
+    /// The first keyframe carrying an SPS and PPS then produces a change.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Mark `sps` and `pps` as announced, e.g. after sending the sequence
+    /// header built from the encoder's out-of-band headers.
+    pub fn set_active(&mut self, sps: Bytes, pps: Bytes) {
+        self.active_sps = Some(sps);
+        self.active_pps = Some(pps);
+        self.pending_sps = None;
+        self.pending_pps = None;
+    }
+
+    /// The currently announced SPS, if any.
+    pub fn active_sps(&self) -> Option<&Bytes> {
+        self.active_sps.as_ref()
+    }
+
+    /// The currently announced PPS, if any.
+    pub fn active_pps(&self) -> Option<&Bytes> {
+        self.active_pps.as_ref()
+    }
+
+    /// Whether in-band parameter sets differ from the active ones.
+    pub fn has_pending_change(&self) -> bool {
+        self.pending_sps.is_some() || self.pending_pps.is_some()
+    }
+
+    /// Inspect the NAL units of one access unit.
+    ///
+    /// Returns the new parameter sets when they differ from the active ones
+    /// and `is_keyframe` is set; the caller must send a sequence header built
+    /// from them before the frame. A change seen on a non-keyframe is held
+    /// until the next keyframe.
+    pub fn process(&mut self, nals: &[NalUnit], is_keyframe: bool) -> Option<ParameterSetChange> {
+        for nal in nals {
+            match nal.nal_type {
+                NalUnitType::Sps => {
+                    self.pending_sps =
+                        (self.active_sps.as_ref() != Some(&nal.data)).then(|| nal.data.clone());
+                }
+                NalUnitType::Pps => {
+                    self.pending_pps =
+                        (self.active_pps.as_ref() != Some(&nal.data)).then(|| nal.data.clone());
+                }
+                _ => {}
+            }
+        }
+
+        if !is_keyframe || !self.has_pending_change() {
+            return None;
+        }
+
+        let sps = self.pending_sps.clone().or_else(|| self.active_sps.clone());
+        let pps = self.pending_pps.clone().or_else(|| self.active_pps.clone());
+        let (Some(sps), Some(pps)) = (sps, pps) else {
+            debug!("Parameter set change incomplete, waiting for SPS and PPS");
+            return None;
+        };
+
+        self.pending_sps = None;
+        self.pending_pps = None;
+
+        let Some(decoder_config) = build_avc_decoder_config(&sps, &pps) else {
+            warn!("Ignoring in-band parameter sets that do not form a valid sequence header");
+            return None;
+        };
+
+        info!(
+            sps_len = sps.len(),
+            pps_len = pps.len(),
+            "In-band parameter sets changed"
+        );
+        self.active_sps = Some(sps.clone());
+        self.active_pps = Some(pps.clone());
+
+        Some(ParameterSetChange {
+            sps,
+            pps,
+            decoder_config,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nal::{parse_annex_b, AvcDecoderConfigurationRecord};
+    use broadcaster_encoder::testing::FakeH264Encoder;
+    use broadcaster_encoder::{PixelFormat, VideoEncoder, VideoEncoderConfig};
+
+    const SPS_A: &[u8] = &[0x67, 0x42, 0x00, 0x1E, 0xAB, 0xCD];
+    const SPS_B: &[u8] = &[0x67, 0x42, 0x00, 0x1F, 0xAB, 0xCD];
+    const PPS_A: &[u8] = &[0x68, 0xCE, 0x3C, 0x80];
+
+    fn access_unit(sps: &'static [u8], pps: &'static [u8]) -> Vec<NalUnit> {
+        vec![
+            NalUnit::new(Bytes::from_static(sps)),
+            NalUnit::new(Bytes::from_static(pps)),
+            NalUnit::new(Bytes::from_static(&[0x65, 0x88, 0x84])),
+        ]
+    }
+
+    #[test]
+    fn test_repeated_parameter_sets_are_not_a_change() {
+        let mut tracker = ParameterSetTracker::new();
+        tracker.set_active(Bytes::from_static(SPS_A), Bytes::from_static(PPS_A));
+
+        assert!(tracker.process(&access_unit(SPS_A, PPS_A), true).is_none());
+        assert!(!tracker.has_pending_change());
+    }
+
+    #[test]
+    fn test_first_keyframe_activates_parameter_sets() {
+        let mut tracker = ParameterSetTracker::new();
+
+        let change = tracker.process(&access_unit(SPS_A, PPS_A), true).unwrap();
+        assert_eq!(change.sps.as_ref(), SPS_A);
+        assert_eq!(change.decoder_config[3], 0x1E);
+        assert_eq!(tracker.active_sps().map(|s| s.as_ref()), Some(SPS_A));
+    }
+
+    #[test]
+    fn test_change_waits_for_keyframe() {
+        let mut tracker = ParameterSetTracker::new();
+        tracker.set_active(Bytes::from_static(SPS_A), Bytes::from_static(PPS_A));
+
+        let sps_only = [NalUnit::new(Bytes::from_static(SPS_B))];
+        assert!(tracker.process(&sps_only, false).is_none());
+        assert!(tracker.has_pending_change());
+
+        let idr = [NalUnit::new(Bytes::from_static(&[0x65, 0x88]))];
+        let change = tracker.process(&idr, true).unwrap();
+        assert_eq!(change.sps.as_ref(), SPS_B);
+        assert_eq!(change.pps.as_ref(), PPS_A);
+        assert!(!tracker.has_pending_change());
+    }
+
+    #[test]
+    fn test_encoder_restart_switches_parameter_sets() {
+        let mut tracker = ParameterSetTracker::new();
+        let mut changes = Vec::new();
+        for (width, height) in [(1280, 720), (640, 360)] {
+            let config = VideoEncoderConfig {
+                width,
+                height,
+                fps: 30,
+                keyframe_interval_secs: 1,
+                ..Default::default()
+            };
+            let frame = vec![0u8; PixelFormat::Nv12.frame_size(width, height)];
+            let mut encoder = FakeH264Encoder::new(config).unwrap();
+            for packet in (0..45).flat_map(|i| encoder.encode(&frame, i).unwrap()) {
+                changes.extend(tracker.process(&parse_annex_b(&packet.data), packet.is_keyframe));
+            }
+        }
+
+        // Repeated parameter sets at the second keyframe of each encoder are
+        // not a change
+        assert_eq!(changes.len(), 2);
+        let record = AvcDecoderConfigurationRecord::parse(&changes[1].decoder_config).unwrap();
+        assert_eq!(record.sps[0], changes[1].sps);
+        assert_ne!(changes[0].sps, changes[1].sps);
+    }
+}
@@ -0,0 +1,312 @@
+//! RBSP (raw byte sequence payload) bit-level reading and writing.
+//!
+//! NAL unit payloads are escaped with emulation prevention bytes: whenever two
+//! zero bytes would be followed by a byte <= 0x03, an extra 0x03 is inserted
+//! so the payload can never contain a start code. Syntax elements inside the
+//! RBSP are bit-packed and many use exp-Golomb codes (`ue(v)` / `se(v)`).
+
+use crate::error::TransportError;
+use crate::TransportResult;
+
+/// Remove emulation prevention bytes (`00 00 03` -> `00 00`) from a NAL payload.
+pub fn remove_emulation_prevention(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut zeros = 0;
+
+    for &byte in data {
+        if zeros >= 2 && byte == 0x03 {
+            zeros = 0;
+            continue;
+        }
+
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        out.push(byte);
+    }
+
+    out
+}
+
+/// Insert emulation prevention bytes so the payload contains no start codes.
+pub fn add_emulation_prevention(rbsp: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(rbsp.len() + rbsp.len() / 64 + 1);
+    let mut zeros = 0;
+
+    for &byte in rbsp {
+        if zeros >= 2 && byte <= 0x03 {
+            out.push(0x03);
+            zeros = 0;
+        }
+
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        out.push(byte);
+    }
+
+    out
+}
+
+/// MSB-first bit reader over an RBSP.
+#[derive(Debug, Clone)]
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    /// Current position in bits.
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    /// Create a reader positioned at the first bit of `data`.
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    /// Number of unread bits.
+    pub fn bits_remaining(&self) -> usize {
+        (self.data.len() * 8).saturating_sub(self.pos)
+    }
+
+    /// Current position in bits from the start of the data.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Whether the reader is on a byte boundary.
+    pub fn is_byte_aligned(&self) -> bool {
+        self.pos.is_multiple_of(8)
+    }
+
+    /// Read a single bit.
+    pub fn read_bit(&mut self) -> TransportResult<u8> {
+        if self.pos >= self.data.len() * 8 {
+            return Err(TransportError::InvalidBitstream(
+                "Unexpected end of bitstream".to_string(),
+            ));
+        }
+
+        let byte = self.data[self.pos / 8];
+        let bit = (byte >> (7 - (self.pos % 8))) & 1;
+        self.pos += 1;
+        Ok(bit)
+    }
+
+    /// Read a single bit as a flag.
+    pub fn read_flag(&mut self) -> TransportResult<bool> {
+        Ok(self.read_bit()? == 1)
+    }
+
+    /// Read `count` bits (at most 32) as an unsigned integer, `u(n)`.
+    pub fn read_bits(&mut self, count: u32) -> TransportResult<u32> {
+        Ok(self.read_bits_u64(count)? as u32)
+    }
+
+    /// Read `count` bits (at most 64) as an unsigned integer.
+    pub fn read_bits_u64(&mut self, count: u32) -> TransportResult<u64> {
+        if count > 64 {
+            return Err(TransportError::InvalidBitstream(format!(
+                "Cannot read {} bits at once",
+                count
+            )));
+        }
+        if count as usize > self.bits_remaining() {
+            return Err(TransportError::InvalidBitstream(
+                "Unexpected end of bitstream".to_string(),
+            ));
+        }
+
+        let mut value = 0u64;
+        for _ in 0..count {
+            value = (value << 1) | self.read_bit()? as u64;
+        }
+        Ok(value)
+    }
+
+    /// Skip `count` bits.
+    pub fn skip_bits(&mut self, count: usize) -> TransportResult<()> {
+        if count > self.bits_remaining() {
+            return Err(TransportError::InvalidBitstream(
+                "Unexpected end of bitstream".to_string(),
+            ));
+        }
+        self.pos += count;
+        Ok(())
+    }
+
+    /// Read an unsigned exp-Golomb code, `ue(v)`.
+    pub fn read_ue(&mut self) -> TransportResult<u32> {
+        let mut leading_zeros = 0u32;
+        while self.read_bit()? == 0 {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                return Err(TransportError::InvalidBitstream(
+                    "Exp-Golomb code too long".to_string(),
+                ));
+            }
+        }
+
+        if leading_zeros == 0 {
+            return Ok(0);
+        }
+
+        let suffix = self.read_bits_u64(leading_zeros)?;
+        Ok(((1u64 << leading_zeros) - 1 + suffix) as u32)
+    }
+
+    /// Read a signed exp-Golomb code, `se(v)`.
+    pub fn read_se(&mut self) -> TransportResult<i32> {
+        let code = self.read_ue()? as i64;
+        let value = if code % 2 == 1 {
+            (code + 1) / 2
+        } else {
+            -(code / 2)
+        };
+        Ok(value as i32)
+    }
+
+    /// Whether more syntax elements follow before the RBSP trailing bits.
+    ///
+    /// Implements `more_rbsp_data()`: true if any `1` bit exists after the
+    /// current position other than the final stop bit.
+    pub fn more_rbsp_data(&self) -> bool {
+        let Some(last_byte_index) = self.data.iter().rposition(|&b| b != 0) else {
+            return false;
+        };
+
+        let last_byte = self.data[last_byte_index];
+        let stop_bit_pos = last_byte_index * 8 + 7 - last_byte.trailing_zeros() as usize;
+        self.pos < stop_bit_pos
+    }
+}
+
+/// MSB-first bit writer producing an RBSP.
+#[derive(Debug, Clone, Default)]
+pub struct BitWriter {
+    data: Vec<u8>,
+    /// Number of bits used in the last byte (0 means byte aligned).
+    bit_offset: u8,
+}
+
+impl BitWriter {
+    /// Create an empty writer.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Write a single bit.
+    pub fn write_bit(&mut self, bit: bool) {
+        if self.bit_offset == 0 {
+            self.data.push(0);
+        }
+
+        if bit {
+            let last = self.data.len() - 1;
+            self.data[last] |= 1 << (7 - self.bit_offset);
+        }
+
+        self.bit_offset = (self.bit_offset + 1) % 8;
+    }
+
+    /// Write the low `count` bits of `value`, MSB first, `u(n)`.
+    pub fn write_bits(&mut self, value: u64, count: u32) {
+        for i in (0..count).rev() {
+            self.write_bit((value >> i) & 1 == 1);
+        }
+    }
+
+    /// Write an unsigned exp-Golomb code, `ue(v)`.
+    pub fn write_ue(&mut self, value: u32) {
+        self.write_exp_golomb(value as u64);
+    }
+
+    /// Write a signed exp-Golomb code, `se(v)`.
+    ///
+    /// `i32::MIN` is outside the range of `se(v)`; its code is one past the
+    /// largest a reader accepts.
+    pub fn write_se(&mut self, value: i32) {
+        let magnitude = value.unsigned_abs() as u64;
+        let code = if value > 0 {
+            magnitude * 2 - 1
+        } else {
+            magnitude * 2
+        };
+        self.write_exp_golomb(code);
+    }
+
+    /// Write exp-Golomb code number `code`.
+    fn write_exp_golomb(&mut self, code: u64) {
+        let code = code + 1;
+        let bits = 64 - code.leading_zeros();
+        self.write_bits(0, bits - 1);
+        self.write_bits(code, bits);
+    }
+
+    /// Whether the writer is on a byte boundary.
+    pub fn is_byte_aligned(&self) -> bool {
+        self.bit_offset == 0
+    }
+
+    /// Append `rbsp_trailing_bits()`: a stop bit followed by zero alignment.
+    pub fn write_trailing_bits(&mut self) {
+        self.write_bit(true);
+        while !self.is_byte_aligned() {
+            self.write_bit(false);
+        }
+    }
+
+    /// Return the written bytes, zero-padding any partial final byte.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_emulation_prevention_round_trip() {
+        let rbsp = [0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x03, 0xFF];
+        let escaped = add_emulation_prevention(&rbsp);
+        assert_eq!(
+            escaped,
+            [0x00, 0x00, 0x03, 0x01, 0x00, 0x00, 0x03, 0x00, 0x00, 0x03, 0x03, 0xFF]
+        );
+        assert_eq!(remove_emulation_prevention(&escaped), rbsp);
+    }
+
+    #[test]
+    fn test_exp_golomb_round_trip() {
+        let mut writer = BitWriter::new();
+        for value in [0u32, 1, 2, 3, 7, 255, 65_535, u32::MAX - 1] {
+            writer.write_ue(value);
+        }
+        for value in [0i32, 1, -1, 2, -2, 100, -100, i32::MAX, -i32::MAX] {
+            writer.write_se(value);
+        }
+        writer.write_trailing_bits();
+        let bytes = writer.into_bytes();
+
+        let mut reader = BitReader::new(&bytes);
+        for value in [0u32, 1, 2, 3, 7, 255, 65_535, u32::MAX - 1] {
+            assert_eq!(reader.read_ue().unwrap(), value);
+        }
+        for value in [0i32, 1, -1, 2, -2, 100, -100, i32::MAX, -i32::MAX] {
+            assert_eq!(reader.read_se().unwrap(), value);
+        }
+        assert!(!reader.more_rbsp_data());
+    }
+
+    #[test]
+    fn test_exp_golomb_out_of_range() {
+        let mut writer = BitWriter::new();
+        writer.write_se(i32::MIN);
+        writer.write_trailing_bits();
+        let bytes = writer.into_bytes();
+
+        assert!(BitReader::new(&bytes).read_se().is_err());
+    }
+
+    #[test]
+    fn test_read_past_end_fails() {
+        let mut reader = BitReader::new(&[0xFF]);
+        assert_eq!(reader.read_bits(8).unwrap(), 0xFF);
+        assert!(reader.read_bit().is_err());
+    }
+}
@@ -0,0 +1,166 @@
+//! H.264 SEI (supplemental enhancement information) messages.
+//!
+//! An SEI NAL unit carries one or more messages, each prefixed with its
+//! payload type and size. Both are coded as a run of `0xFF` bytes followed by
+//! a final byte, so values of 255 and above take more than one byte. The
+//! RBSP ends with a stop bit and is escaped like any other NAL payload.
+
+use bytes::Bytes;
+
+use crate::error::TransportError;
+use crate::nal::rbsp::{add_emulation_prevention, remove_emulation_prevention};
+use crate::nal::{NalUnit, NalUnitType};
+use crate::TransportResult;
+
+/// SEI payload type of `user_data_registered_itu_t_t35`.
+pub const SEI_USER_DATA_REGISTERED: u32 = 4;
+
+/// SEI payload type of `user_data_unregistered`.
+pub const SEI_USER_DATA_UNREGISTERED: u32 = 5;
+
+/// NAL header of an SEI (nal_ref_idc 0, type 6).
+const SEI_NAL_HEADER: u8 = 0x06;
+
+/// A single SEI message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeiMessage {
+    pub payload_type: u32,
+    pub payload: Bytes,
+}
+
+impl SeiMessage {
+    /// Create a message of `payload_type`.
+    pub fn new(payload_type: u32, payload: impl Into<Bytes>) -> Self {
+        Self {
+            payload_type,
+            payload: payload.into(),
+        }
+    }
+}
+
+/// Build an SEI NAL unit carrying `messages`.
+pub fn build_sei_nal(messages: &[SeiMessage]) -> NalUnit {
+    let mut rbsp = Vec::new();
+    for message in messages {
+        put_sei_value(&mut rbsp, message.payload_type as usize);
+        put_sei_value(&mut rbsp, message.payload.len());
+        rbsp.extend_from_slice(&message.payload);
+    }
+    // rbsp_trailing_bits
+    rbsp.push(0x80);
+
+    let mut data = Vec::with_capacity(rbsp.len() + 8);
+    data.push(SEI_NAL_HEADER);
+    data.extend_from_slice(&add_emulation_prevention(&rbsp));
+    NalUnit::new(Bytes::from(data))
+}
+
+/// Parse the messages of an SEI NAL unit (including NAL header).
+pub fn parse_sei_nal(nal: &[u8]) -> TransportResult<Vec<SeiMessage>> {
+    match nal.first() {
+        Some(&header) if NalUnitType::from(header) == NalUnitType::Sei => {}
+        _ => {
+            return Err(TransportError::InvalidBitstream(
+                "NAL unit is not an SEI".to_string(),
+            ))
+        }
+    }
+
+    let rbsp = remove_emulation_prevention(&nal[1..]);
+    let mut messages = Vec::new();
+    let mut pos = 0;
+
+    // more_rbsp_data: stop when only the trailing bits remain
+    while pos < rbsp.len() && !(pos + 1 == rbsp.len() && rbsp[pos] == 0x80) {
+        let payload_type = read_sei_value(&rbsp, &mut pos)?;
+        let size = read_sei_value(&rbsp, &mut pos)?;
+        let end = pos + size;
+        if end > rbsp.len() {
+            return Err(TransportError::InvalidBitstream(format!(
+                "SEI payload of {} bytes exceeds NAL unit",
+                size
+            )));
+        }
+
+        messages.push(SeiMessage::new(
+            payload_type as u32,
+            Bytes::copy_from_slice(&rbsp[pos..end]),
+        ));
+        pos = end;
+    }
+
+    Ok(messages)
+}
+
+/// Insert an SEI NAL unit into an access unit.
+///
+/// SEI must precede the first slice, so it is placed after any access unit
+/// delimiter, parameter sets and existing SEI.
+pub fn insert_sei(nals: &mut Vec<NalUnit>, sei: NalUnit) {
+    let pos = nals
+        .iter()
+        .position(|nal| {
+            !matches!(
+                nal.nal_type,
+                NalUnitType::Aud | NalUnitType::Sps | NalUnitType::Pps | NalUnitType::Sei
+            )
+        })
+        .unwrap_or(nals.len());
+    nals.insert(pos, sei);
+}
+
+fn put_sei_value(buf: &mut Vec<u8>, mut value: usize) {
+    while value >= 0xFF {
+        buf.push(0xFF);
+        value -= 0xFF;
+    }
+    buf.push(value as u8);
+}
+
+fn read_sei_value(data: &[u8], pos: &mut usize) -> TransportResult<usize> {
+    let mut value = 0;
+    loop {
+        let byte = *data.get(*pos).ok_or_else(|| {
+            TransportError::InvalidBitstream("Truncated SEI message header".to_string())
+        })?;
+        *pos += 1;
+        value += byte as usize;
+        if byte != 0xFF {
+            return Ok(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sei_round_trip() {
+        let messages = vec![
+            SeiMessage::new(SEI_USER_DATA_REGISTERED, vec![0xB5, 0x00, 0x00, 0x01]),
+            SeiMessage::new(SEI_USER_DATA_UNREGISTERED, vec![0x42; 300]),
+        ];
+
+        let nal = build_sei_nal(&messages);
+        assert_eq!(nal.nal_type, NalUnitType::Sei);
+        // 00 00 01 in the first payload must be escaped
+        assert!(!nal.data.windows(3).any(|w| w == [0x00, 0x00, 0x01]));
+
+        assert_eq!(parse_sei_nal(&nal.data).unwrap(), messages);
+    }
+
+    #[test]
+    fn test_insert_sei_before_first_slice() {
+        let mut nals = vec![
+            NalUnit::new(Bytes::from_static(&[0x09, 0xF0])),
+            NalUnit::new(Bytes::from_static(&[0x67, 0x42])),
+            NalUnit::new(Bytes::from_static(&[0x68, 0xCE])),
+            NalUnit::new(Bytes::from_static(&[0x65, 0x88])),
+        ];
+
+        insert_sei(&mut nals, build_sei_nal(&[SeiMessage::new(5, vec![0])]));
+        assert_eq!(nals[3].nal_type, NalUnitType::Sei);
+        assert_eq!(nals[4].nal_type, NalUnitType::IdrSlice);
+    }
+}
@@ -0,0 +1,632 @@
+//! H.264 sequence and picture parameter set parsing (ITU-T H.264 7.3.2.1, 7.3.2.2).
+
+use crate::error::TransportError;
+use crate::nal::rbsp::{remove_emulation_prevention, BitReader};
+use crate::nal::NalUnitType;
+use crate::TransportResult;
+
+/// Profiles whose SPS carries chroma format, bit depth and scaling matrices.
+const HIGH_PROFILE_IDCS: [u8; 12] = [100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134];
+
+/// Frame cropping rectangle, in crop units.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameCropping {
+    pub left: u32,
+    pub right: u32,
+    pub top: u32,
+    pub bottom: u32,
+}
+
+/// HRD (hypothetical reference decoder) parameters from the VUI.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HrdParameters {
+    /// Bit rate in bits per second for each CPB specification.
+    pub bit_rates: Vec<u64>,
+    /// CPB size in bits for each CPB specification.
+    pub cpb_sizes: Vec<u64>,
+    /// Whether each CPB specification is constant bit rate.
+    pub cbr_flags: Vec<bool>,
+}
+
+/// VUI timing information.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimingInfo {
+    pub num_units_in_tick: u32,
+    pub time_scale: u32,
+    pub fixed_frame_rate: bool,
+}
+
+/// Video usability information (ITU-T H.264 Annex E).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VuiParameters {
+    /// Sample aspect ratio as (width, height), if signalled.
+    pub sample_aspect_ratio: Option<(u16, u16)>,
+    /// Whether the video uses full-range (0-255) samples.
+    pub video_full_range: bool,
+    /// Colour primaries, transfer characteristics and matrix coefficients.
+    pub colour_description: Option<(u8, u8, u8)>,
+    /// Timing information, if signalled.
+    pub timing_info: Option<TimingInfo>,
+    /// NAL HRD parameters, if signalled.
+    pub nal_hrd: Option<HrdParameters>,
+    /// VCL HRD parameters, if signalled.
+    pub vcl_hrd: Option<HrdParameters>,
+    /// Maximum number of frames that precede any frame in decoding order
+    /// and follow it in output order.
+    pub max_num_reorder_frames: Option<u32>,
+    /// Required DPB size in frames.
+    pub max_dec_frame_buffering: Option<u32>,
+}
+
+/// A parsed H.264 sequence parameter set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sps {
+    pub profile_idc: u8,
+    /// constraint_set0..5 flags plus the two reserved bits.
+    pub constraint_flags: u8,
+    pub level_idc: u8,
+    pub seq_parameter_set_id: u32,
+    /// 0 = monochrome, 1 = 4:2:0, 2 = 4:2:2, 3 = 4:4:4.
+    pub chroma_format_idc: u32,
+    pub separate_colour_plane: bool,
+    pub bit_depth_luma: u32,
+    pub bit_depth_chroma: u32,
+    pub log2_max_frame_num: u32,
+    pub pic_order_cnt_type: u32,
+    pub max_num_ref_frames: u32,
+    pub pic_width_in_mbs: u32,
+    pub pic_height_in_map_units: u32,
+    pub frame_mbs_only: bool,
+    pub frame_cropping: Option<FrameCropping>,
+    pub vui: Option<VuiParameters>,
+}
+
+impl Sps {
+    /// Parse an SPS NAL unit (including its one-byte NAL header).
+    pub fn parse(nal: &[u8]) -> TransportResult<Self> {
+        if nal.is_empty() || NalUnitType::from(nal[0]) != NalUnitType::Sps {
+            return Err(TransportError::InvalidBitstream(
+                "Not an SPS NAL unit".to_string(),
+            ));
+        }
+
+        let rbsp = remove_emulation_prevention(&nal[1..]);
+        let mut r = BitReader::new(&rbsp);
+
+        let profile_idc = r.read_bits(8)? as u8;
+        let constraint_flags = r.read_bits(8)? as u8;
+        let level_idc = r.read_bits(8)? as u8;
+        let seq_parameter_set_id = r.read_ue()?;
+
+        let mut chroma_format_idc = 1;
+        let mut separate_colour_plane = false;
+        let mut bit_depth_luma = 8;
+        let mut bit_depth_chroma = 8;
+
+        if HIGH_PROFILE_IDCS.contains(&profile_idc) {
+            chroma_format_idc = r.read_ue()?;
+            if chroma_format_idc == 3 {
+                separate_colour_plane = r.read_flag()?;
+            }
+            bit_depth_luma = read_ue_offset(&mut r, 8)?;
+            bit_depth_chroma = read_ue_offset(&mut r, 8)?;
+            let _qpprime_y_zero_transform_bypass = r.read_flag()?;
+
+            if r.read_flag()? {
+                // seq_scaling_matrix_present_flag
+                let list_count = if chroma_format_idc != 3 { 8 } else { 12 };
+                for i in 0..list_count {
+                    if r.read_flag()? {
+                        skip_scaling_list(&mut r, if i < 6 { 16 } else { 64 })?;
+                    }
+                }
+            }
+        }
+
+        let log2_max_frame_num = read_ue_offset(&mut r, 4)?;
+        let pic_order_cnt_type = r.read_ue()?;
+        match pic_order_cnt_type {
+            0 => {
+                let _log2_max_pic_order_cnt_lsb_minus4 = r.read_ue()?;
+            }
+            1 => {
+                let _delta_pic_order_always_zero = r.read_flag()?;
+                let _offset_for_non_ref_pic = r.read_se()?;
+                let _offset_for_top_to_bottom_field = r.read_se()?;
+                let cycle_len = r.read_ue()?;
+                for _ in 0..cycle_len {
+                    let _offset_for_ref_frame = r.read_se()?;
+                }
+            }
+            _ => {}
+        }
+
+        let max_num_ref_frames = r.read_ue()?;
+        let _gaps_in_frame_num_allowed = r.read_flag()?;
+        let pic_width_in_mbs = read_ue_offset(&mut r, 1)?;
+        let pic_height_in_map_units = read_ue_offset(&mut r, 1)?;
+        let frame_mbs_only = r.read_flag()?;
+        let field_factor = if frame_mbs_only { 1 } else { 2 };
+        if pic_width_in_mbs.checked_mul(16).is_none()
+            || pic_height_in_map_units
+                .checked_mul(field_factor * 16)
+                .is_none()
+        {
+            return Err(TransportError::InvalidBitstream(format!(
+                "Frame size of {}x{} macroblocks out of range",
+                pic_width_in_mbs, pic_height_in_map_units
+            )));
+        }
+        if !frame_mbs_only {
+            let _mb_adaptive_frame_field = r.read_flag()?;
+        }
+        let _direct_8x8_inference = r.read_flag()?;
+
+        let frame_cropping = if r.read_flag()? {
+            Some(FrameCropping {
+                left: r.read_ue()?,
+                right: r.read_ue()?,
+                top: r.read_ue()?,
+                bottom: r.read_ue()?,
+            })
+        } else {
+            None
+        };
+
+        let vui = if r.read_flag()? {
+            Some(parse_vui(&mut r)?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            profile_idc,
+            constraint_flags,
+            level_idc,
+            seq_parameter_set_id,
+            chroma_format_idc,
+            separate_colour_plane,
+            bit_depth_luma,
+            bit_depth_chroma,
+            log2_max_frame_num,
+            pic_order_cnt_type,
+            max_num_ref_frames,
+            pic_width_in_mbs,
+            pic_height_in_map_units,
+            frame_mbs_only,
+            frame_cropping,
+            vui,
+        })
+    }
+
+    /// ChromaArrayType as defined by the spec.
+    fn chroma_array_type(&self) -> u32 {
+        if self.separate_colour_plane {
+            0
+        } else {
+            self.chroma_format_idc
+        }
+    }
+
+    /// Horizontal and vertical crop unit sizes in luma samples.
+    fn crop_units(&self) -> (u32, u32) {
+        let field_factor = if self.frame_mbs_only { 1 } else { 2 };
+        match self.chroma_array_type() {
+            0 => (1, field_factor),
+            1 => (2, 2 * field_factor),
+            2 => (2, field_factor),
+            _ => (1, field_factor),
+        }
+    }
+
+    /// Displayed width in pixels, after frame cropping.
+    pub fn width(&self) -> u32 {
+        let full = self.pic_width_in_mbs.saturating_mul(16);
+        match self.frame_cropping {
+            Some(crop) => {
+                let (unit_x, _) = self.crop_units();
+                full.saturating_sub(unit_x.saturating_mul(crop.left.saturating_add(crop.right)))
+            }
+            None => full,
+        }
+    }
+
+    /// Displayed height in pixels, after frame cropping.
+    pub fn height(&self) -> u32 {
+        let field_factor = if self.frame_mbs_only { 1 } else { 2 };
+        let full = self
+            .pic_height_in_map_units
+            .saturating_mul(field_factor * 16);
+        match self.frame_cropping {
+            Some(crop) => {
+                let (_, unit_y) = self.crop_units();
+                full.saturating_sub(unit_y.saturating_mul(crop.top.saturating_add(crop.bottom)))
+            }
+            None => full,
+        }
+    }
+
+    /// Frame rate from the VUI timing information, if present.
+    pub fn frame_rate(&self) -> Option<f64> {
+        let timing = self.vui.as_ref()?.timing_info?;
+        if timing.num_units_in_tick == 0 {
+            return None;
+        }
+        // One frame spans two ticks (one per field)
+        Some(timing.time_scale as f64 / (2.0 * timing.num_units_in_tick as f64))
+    }
+}
+
+/// A parsed H.264 picture parameter set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pps {
+    pub pic_parameter_set_id: u32,
+    pub seq_parameter_set_id: u32,
+    /// true = CABAC, false = CAVLC.
+    pub entropy_coding_mode: bool,
+    pub bottom_field_pic_order_in_frame_present: bool,
+    pub num_slice_groups: u32,
+    pub num_ref_idx_l0_default_active: u32,
+    pub num_ref_idx_l1_default_active: u32,
+    pub weighted_pred: bool,
+    pub weighted_bipred_idc: u32,
+    pub pic_init_qp: i32,
+    pub pic_init_qs: i32,
+    pub chroma_qp_index_offset: i32,
+    pub deblocking_filter_control_present: bool,
+    pub constrained_intra_pred: bool,
+    pub redundant_pic_cnt_present: bool,
+    pub transform_8x8_mode: bool,
+    pub second_chroma_qp_index_offset: i32,
+}
+
+impl Pps {
+    /// Parse a PPS NAL unit (including its one-byte NAL header).
+    ///
+    /// The referenced SPS is needed to size the optional scaling lists; when
+    /// it is not available, 4:2:0 chroma is assumed.
+    pub fn parse(nal: &[u8], sps: Option<&Sps>) -> TransportResult<Self> {
+        if nal.is_empty() || NalUnitType::from(nal[0]) != NalUnitType::Pps {
+            return Err(TransportError::InvalidBitstream(
+                "Not a PPS NAL unit".to_string(),
+            ));
+        }
+
+        let rbsp = remove_emulation_prevention(&nal[1..]);
+        let mut r = BitReader::new(&rbsp);
+
+        let pic_parameter_set_id = r.read_ue()?;
+        let seq_parameter_set_id = r.read_ue()?;
+        let entropy_coding_mode = r.read_flag()?;
+        let bottom_field_pic_order_in_frame_present = r.read_flag()?;
+        let num_slice_groups = read_ue_offset(&mut r, 1)?;
+
+        if num_slice_groups > 1 {
+            let slice_group_map_type = r.read_ue()?;
+            match slice_group_map_type {
+                0 => {
+                    for _ in 0..num_slice_groups {
+                        let _run_length_minus1 = r.read_ue()?;
+                    }
+                }
+                2 => {
+                    for _ in 0..num_slice_groups - 1 {
+                        let _top_left = r.read_ue()?;
+                        let _bottom_right = r.read_ue()?;
+                    }
+                }
+                3..=5 => {
+                    let _change_direction = r.read_flag()?;
+                    let _change_rate_minus1 = r.read_ue()?;
+                }
+                6 => {
+                    let pic_size_in_map_units = read_ue_offset(&mut r, 1)?;
+                    let bits = 32 - (num_slice_groups - 1).leading_zeros();
+                    r.skip_bits(pic_size_in_map_units as usize * bits as usize)?;
+                }
+                _ => {}
+            }
+        }
+
+        let num_ref_idx_l0_default_active = read_ue_offset(&mut r, 1)?;
+        let num_ref_idx_l1_default_active = read_ue_offset(&mut r, 1)?;
+        let weighted_pred = r.read_flag()?;
+        let weighted_bipred_idc = r.read_bits(2)?;
+        let pic_init_qp = read_se_offset(&mut r, 26)?;
+        let pic_init_qs = read_se_offset(&mut r, 26)?;
+        let chroma_qp_index_offset = r.read_se()?;
+        let deblocking_filter_control_present = r.read_flag()?;
+        let constrained_intra_pred = r.read_flag()?;
+        let redundant_pic_cnt_present = r.read_flag()?;
+
+        let mut transform_8x8_mode = false;
+        let mut second_chroma_qp_index_offset = chroma_qp_index_offset;
+
+        if r.more_rbsp_data() {
+            transform_8x8_mode = r.read_flag()?;
+            if r.read_flag()? {
+                // pic_scaling_matrix_present_flag
+                let chroma_format_idc = sps.map_or(1, |s| s.chroma_format_idc);
+                let extra = if chroma_format_idc == 3 { 6 } else { 2 };
+                let list_count = 6 + if transform_8x8_mode { extra } else { 0 };
+                for i in 0..list_count {
+                    if r.read_flag()? {
+                        skip_scaling_list(&mut r, if i < 6 { 16 } else { 64 })?;
+                    }
+                }
+            }
+            second_chroma_qp_index_offset = r.read_se()?;
+        }
+
+        Ok(Self {
+            pic_parameter_set_id,
+            seq_parameter_set_id,
+            entropy_coding_mode,
+            bottom_field_pic_order_in_frame_present,
+            num_slice_groups,
+            num_ref_idx_l0_default_active,
+            num_ref_idx_l1_default_active,
+            weighted_pred,
+            weighted_bipred_idc,
+            pic_init_qp,
+            pic_init_qs,
+            chroma_qp_index_offset,
+            deblocking_filter_control_present,
+            constrained_intra_pred,
+            redundant_pic_cnt_present,
+            transform_8x8_mode,
+            second_chroma_qp_index_offset,
+        })
+    }
+}
+
+/// Read a `ue(v)` coded with `offset` subtracted, e.g. a `_minus1` element.
+fn read_ue_offset(r: &mut BitReader, offset: u32) -> TransportResult<u32> {
+    r.read_ue()?.checked_add(offset).ok_or_else(|| {
+        TransportError::InvalidBitstream("Exp-Golomb value out of range".to_string())
+    })
+}
+
+/// Read an `se(v)` coded with `offset` subtracted, e.g. `pic_init_qp_minus26`.
+fn read_se_offset(r: &mut BitReader, offset: i32) -> TransportResult<i32> {
+    r.read_se()?.checked_add(offset).ok_or_else(|| {
+        TransportError::InvalidBitstream("Exp-Golomb value out of range".to_string())
+    })
+}
+
+/// Skip a `scaling_list()` of the given size.
+fn skip_scaling_list(r: &mut BitReader, size: usize) -> TransportResult<()> {
+    let mut last_scale = 8i32;
+    let mut next_scale = 8i32;
+
+    for _ in 0..size {
+        if next_scale != 0 {
+            let delta_scale = r.read_se()?;
+            if !(-128..=127).contains(&delta_scale) {
+                return Err(TransportError::InvalidBitstream(format!(
+                    "Invalid delta_scale {}",
+                    delta_scale
+                )));
+            }
+            next_scale = (last_scale + delta_scale + 256) % 256;
+        }
+        if next_scale != 0 {
+            last_scale = next_scale;
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_vui(r: &mut BitReader) -> TransportResult<VuiParameters> {
+    let mut vui = VuiParameters::default();
+
+    if r.read_flag()? {
+        // aspect_ratio_info_present_flag
+        let aspect_ratio_idc = r.read_bits(8)? as u8;
+        vui.sample_aspect_ratio = if aspect_ratio_idc == 255 {
+            Some((r.read_bits(16)? as u16, r.read_bits(16)? as u16))
+        } else {
+            sample_aspect_ratio_from_idc(aspect_ratio_idc)
+        };
+    }
+
+    if r.read_flag()? {
+        // overscan_info_present_flag
+        let _overscan_appropriate = r.read_flag()?;
+    }
+
+    if r.read_flag()? {
+        // video_signal_type_present_flag
+        let _video_format = r.read_bits(3)?;
+        vui.video_full_range = r.read_flag()?;
+        if r.read_flag()? {
+            vui.colour_description = Some((
+                r.read_bits(8)? as u8,
+                r.read_bits(8)? as u8,
+                r.read_bits(8)? as u8,
+            ));
+        }
+    }
+
+    if r.read_flag()? {
+        // chroma_loc_info_present_flag
+        let _top_field = r.read_ue()?;
+        let _bottom_field = r.read_ue()?;
+    }
+
+    if r.read_flag()? {
+        vui.timing_info = Some(TimingInfo {
+            num_units_in_tick: r.read_bits(32)?,
+            time_scale: r.read_bits(32)?,
+            fixed_frame_rate: r.read_flag()?,
+        });
+    }
+
+    if r.read_flag()? {
+        vui.nal_hrd = Some(parse_hrd(r)?);
+    }
+    if r.read_flag()? {
+        vui.vcl_hrd = Some(parse_hrd(r)?);
+    }
+    if vui.nal_hrd.is_some() || vui.vcl_hrd.is_some() {
+        let _low_delay_hrd = r.read_flag()?;
+    }
+
+    let _pic_struct_present = r.read_flag()?;
+
+    if r.read_flag()? {
+        // bitstream_restriction_flag
+        let _motion_vectors_over_pic_boundaries = r.read_flag()?;
+        let _max_bytes_per_pic_denom = r.read_ue()?;
+        let _max_bits_per_mb_denom = r.read_ue()?;
+        let _log2_max_mv_length_horizontal = r.read_ue()?;
+        let _log2_max_mv_length_vertical = r.read_ue()?;
+        vui.max_num_reorder_frames = Some(r.read_ue()?);
+        vui.max_dec_frame_buffering = Some(r.read_ue()?);
+    }
+
+    Ok(vui)
+}
+
+fn parse_hrd(r: &mut BitReader) -> TransportResult<HrdParameters> {
+    let cpb_cnt = read_ue_offset(r, 1)?;
+    if cpb_cnt > 32 {
+        return Err(TransportError::InvalidBitstream(format!(
+            "Invalid cpb_cnt {}",
+            cpb_cnt
+        )));
+    }
+
+    let bit_rate_scale = r.read_bits(4)?;
+    let cpb_size_scale = r.read_bits(4)?;
+
+    let mut hrd = HrdParameters::default();
+    for _ in 0..cpb_cnt {
+        let bit_rate_value = r.read_ue()? as u64 + 1;
+        let cpb_size_value = r.read_ue()? as u64 + 1;
+        hrd.bit_rates.push(bit_rate_value << (6 + bit_rate_scale));
+        hrd.cpb_sizes.push(cpb_size_value << (4 + cpb_size_scale));
+        hrd.cbr_flags.push(r.read_flag()?);
+    }
+
+    // initial_cpb_removal_delay_length_minus1, cpb_removal_delay_length_minus1,
+    // dpb_output_delay_length_minus1, time_offset_length
+    r.skip_bits(5 + 5 + 5 + 5)?;
+
+    Ok(hrd)
+}
+
+/// Table E-1 sample aspect ratios.
+fn sample_aspect_ratio_from_idc(idc: u8) -> Option<(u16, u16)> {
+    const TABLE: [(u16, u16); 17] = [
+        (0, 0),
+        (1, 1),
+        (12, 11),
+        (10, 11),
+        (16, 11),
+        (40, 33),
+        (24, 11),
+        (20, 11),
+        (32, 11),
+        (80, 33),
+        (18, 11),
+        (15, 11),
+        (64, 33),
+        (160, 99),
+        (4, 3),
+        (3, 2),
+        (2, 1),
+    ];
+
+    TABLE
+        .get(idc as usize)
+        .copied()
+        .filter(|&(w, h)| w != 0 && h != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nal::fixtures::{X264_PPS, X264_SPS_1080P};
+    use crate::nal::rbsp::{add_emulation_prevention, BitWriter};
+
+    #[test]
+    fn test_parse_x264_sps() {
+        let sps = Sps::parse(&X264_SPS_1080P).unwrap();
+
+        assert_eq!(sps.profile_idc, 100);
+        assert_eq!(sps.level_idc, 40);
+        assert_eq!(sps.chroma_format_idc, 1);
+        assert_eq!(sps.bit_depth_luma, 8);
+        assert_eq!(sps.width(), 1920);
+        assert_eq!(sps.height(), 1080);
+        assert!(sps.frame_cropping.is_some());
+        assert_eq!(sps.frame_rate(), Some(30.0));
+    }
+
+    #[test]
+    fn test_parse_sps_rejects_other_nal_types() {
+        assert!(Sps::parse(&[0x68, 0xCE, 0x3C, 0x80]).is_err());
+        assert!(Sps::parse(&[]).is_err());
+    }
+
+    /// Baseline SPS with `log2_max_frame_num_minus4` and
+    /// `pic_width_in_mbs_minus1` as given and otherwise 1280x720.
+    fn baseline_sps(log2_max_frame_num_minus4: u32, pic_width_in_mbs_minus1: u32) -> Vec<u8> {
+        let mut w = BitWriter::new();
+        w.write_bits(66, 8); // profile_idc
+        w.write_bits(0, 8); // constraint flags
+        w.write_bits(31, 8); // level_idc
+        w.write_ue(0); // seq_parameter_set_id
+        w.write_ue(log2_max_frame_num_minus4);
+        w.write_ue(2); // pic_order_cnt_type
+        w.write_ue(1); // max_num_ref_frames
+        w.write_bit(false); // gaps_in_frame_num_value_allowed_flag
+        w.write_ue(pic_width_in_mbs_minus1);
+        w.write_ue(44); // pic_height_in_map_units_minus1
+        w.write_bit(true); // frame_mbs_only_flag
+        w.write_bit(true); // direct_8x8_inference_flag
+        w.write_bit(false); // frame_cropping_flag
+        w.write_bit(false); // vui_parameters_present_flag
+        w.write_trailing_bits();
+
+        let mut nal = vec![0x67];
+        nal.extend(add_emulation_prevention(&w.into_bytes()));
+        nal
+    }
+
+    #[test]
+    fn test_parse_sps_rejects_out_of_range_values() {
+        let sps = Sps::parse(&baseline_sps(0, 79)).unwrap();
+        assert_eq!((sps.width(), sps.height()), (1280, 720));
+
+        // u32::MAX - 1 is a ue(v) with 31 leading zeros, the longest there is
+        for sps in [
+            baseline_sps(u32::MAX - 1, 79),
+            baseline_sps(0, u32::MAX - 1),
+        ] {
+            assert!(matches!(
+                Sps::parse(&sps),
+                Err(TransportError::InvalidBitstream(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_parse_truncated_sps_fails() {
+        assert!(Sps::parse(&X264_SPS_1080P[..6]).is_err());
+    }
+
+    #[test]
+    fn test_parse_pps() {
+        let pps = Pps::parse(&X264_PPS, None).unwrap();
+
+        assert_eq!(pps.pic_parameter_set_id, 0);
+        assert_eq!(pps.seq_parameter_set_id, 0);
+        assert!(pps.entropy_coding_mode);
+        assert!(pps.transform_8x8_mode);
+        assert_eq!(pps.chroma_qp_index_offset, -2);
+        assert_eq!(pps.second_chroma_qp_index_offset, -2);
+    }
+}
@@ -0,0 +1,147 @@
+//! Incremental Annex B parsing for pipe and file input.
+//!
+//! Input arrives in arbitrary chunks, so a start code may be split across two
+//! pushes. The parser buffers the current NAL unit and only emits it once the
+//! next start code has been seen. Emitted NAL units are frozen slices of the
+//! internal buffer, so no per-NAL copy is made.
+
+use bytes::{Bytes, BytesMut};
+
+use super::find_start_code;
+
+/// Streaming Annex B parser.
+///
+/// The parser is codec-agnostic and returns raw NAL unit data (header
+/// included, start code and trailing zero bytes excluded). Use
+/// [`NalUnit::new`](super::NalUnit::new) to classify H.264 NAL units.
+#[derive(Debug, Default)]
+pub struct AnnexBStreamParser {
+    /// Unconsumed input. Starts with NAL data when `in_nal` is set.
+    buf: BytesMut,
+    /// Whether a start code has been seen, i.e. `buf` holds NAL data.
+    in_nal: bool,
+    /// Offset in `buf` from which to resume the start code scan.
+    scan_pos: usize,
+}
+
+impl AnnexBStreamParser {
+    /// Create an empty parser.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed a chunk of input and return every NAL unit it completes.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<Bytes> {
+        self.buf.extend_from_slice(chunk);
+
+        let mut nals = Vec::new();
+        while let Some(pos) = find_start_code(&self.buf, self.scan_pos) {
+            let data = self.buf.split_to(pos + 3).freeze();
+            if self.in_nal {
+                nals.extend(trim_trailing_zeros(data, pos));
+            }
+            self.in_nal = true;
+            self.scan_pos = 0;
+        }
+
+        // A start code may straddle the next chunk boundary
+        self.scan_pos = self.buf.len().saturating_sub(2);
+        if !self.in_nal {
+            // Data before the first start code is not part of any NAL unit
+            let _ = self.buf.split_to(self.scan_pos);
+            self.scan_pos = 0;
+        }
+
+        nals
+    }
+
+    /// Signal end of input and return the final NAL unit, if any.
+    ///
+    /// The parser is reset and can be reused for a new stream.
+    pub fn finish(&mut self) -> Option<Bytes> {
+        let data = std::mem::take(&mut self.buf).freeze();
+        let in_nal = std::mem::take(&mut self.in_nal);
+        self.scan_pos = 0;
+
+        if in_nal {
+            let len = data.len();
+            trim_trailing_zeros(data, len)
+        } else {
+            None
+        }
+    }
+
+    /// Number of buffered bytes not yet emitted.
+    pub fn buffered_len(&self) -> usize {
+        self.buf.len()
+    }
+}
+
+/// Slice `data[..end]` without trailing zero bytes, `None` if nothing is left.
+///
+/// Zero bytes before a start code are either the first byte of a 4-byte
+/// start code or `trailing_zero_8bits`, never NAL data.
+fn trim_trailing_zeros(data: Bytes, end: usize) -> Option<Bytes> {
+    let len = data[..end]
+        .iter()
+        .rposition(|&b| b != 0)
+        .map_or(0, |p| p + 1);
+    (len > 0).then(|| data.slice(..len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nal::parse_annex_b;
+
+    const STREAM: [u8; 24] = [
+        0x00, 0x00, 0x00, 0x01, 0x67, 0x42, 0x00, 0x1E, // SPS
+        0x00, 0x00, 0x01, 0x68, 0xCE, 0x3C, 0x80, // PPS
+        0x00, 0x00, 0x00, 0x01, 0x65, 0x88, 0x00, 0x03, 0x84, // IDR
+    ];
+
+    fn parse_in_chunks(data: &[u8], chunk_size: usize) -> Vec<Bytes> {
+        let mut parser = AnnexBStreamParser::new();
+        let mut nals = Vec::new();
+        for chunk in data.chunks(chunk_size) {
+            nals.extend(parser.push(chunk));
+        }
+        nals.extend(parser.finish());
+        nals
+    }
+
+    #[test]
+    fn test_matches_batch_parser_for_any_chunking() {
+        let expected: Vec<Bytes> = parse_annex_b(&STREAM).into_iter().map(|n| n.data).collect();
+
+        for chunk_size in 1..=STREAM.len() {
+            assert_eq!(
+                parse_in_chunks(&STREAM, chunk_size),
+                expected,
+                "chunk size {}",
+                chunk_size
+            );
+        }
+    }
+
+    #[test]
+    fn test_nal_emitted_only_after_next_start_code() {
+        let mut parser = AnnexBStreamParser::new();
+
+        assert!(parser.push(&STREAM[..10]).is_empty());
+        let nals = parser.push(&STREAM[10..11]);
+        assert_eq!(nals, vec![Bytes::from_static(&[0x67, 0x42, 0x00, 0x1E])]);
+    }
+
+    #[test]
+    fn test_leading_garbage_is_discarded() {
+        let mut data = vec![0xFF; 1000];
+        data.extend_from_slice(&STREAM);
+
+        let mut parser = AnnexBStreamParser::new();
+        parser.push(&data[..1000]);
+        assert!(parser.buffered_len() <= 2);
+
+        assert_eq!(parse_in_chunks(&data, 7).len(), 3);
+    }
+}
@@ -6,7 +6,7 @@ use std::time::Duration;
 
 use bytes::Bytes;
 use crossbeam_channel::{Receiver, Sender};
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use rml_rtmp::handshake::{Handshake, HandshakeProcessResult, PeerType};
 use rml_rtmp::sessions::{
     ClientSession, ClientSessionConfig, ClientSessionEvent, ClientSessionResult,
@@ -20,7 +20,9 @@ use tracing::{debug, error, info, instrument, trace, warn};
 use url::Url;
 
 use crate::connection::{ConnectionState, ReconnectPolicy};
+use crate::dump::PacketRecorder;
 use crate::error::TransportError;
+use crate::metadata::decode_metadata;
 use crate::{TransportResult, PACKET_CHANNEL_CAPACITY};
 
 /// A packet to send over RTMP.
@@ -41,6 +43,10 @@ pub struct RtmpPacket {
     /// Whether this is an AVC sequence header (decoder configuration).
     /// Sequence headers must be sent before any video frames.
     pub is_sequence_header: bool,
+
+    /// Whether this is onMetaData (`data` holds an AMF0 object built by
+    /// [`encode_metadata`](crate::encode_metadata)).
+    pub is_metadata: bool,
 }
 
 /// RTMP client for streaming.
@@ -55,6 +61,7 @@ pub struct RtmpClient {
     bytes_sent: AtomicU64,
     packets_sent: AtomicU64,
     packets_dropped: AtomicU64,
+    recorder: Option<Arc<Mutex<PacketRecorder>>>,
 }
 
 impl RtmpClient {
@@ -78,9 +85,17 @@ impl RtmpClient {
             bytes_sent: AtomicU64::new(0),
             packets_sent: AtomicU64::new(0),
             packets_dropped: AtomicU64::new(0),
+            recorder: None,
         })
     }
 
+    /// Record every packet sent on this connection to a dump.
+    ///
+    /// Must be called before [`connect`](Self::connect) to take effect.
+    pub fn set_recorder(&mut self, recorder: PacketRecorder) {
+        self.recorder = Some(Arc::new(Mutex::new(recorder)));
+    }
+
     /// Connect to the RTMP server.
     #[instrument(name = "rtmp_connect", skip(self))]
     pub fn connect(&mut self) -> TransportResult<Sender<RtmpPacket>> {
@@ -112,6 +127,7 @@ impl RtmpClient {
         let bytes_sent_clone = Arc::clone(&bytes_sent);
         let packets_sent_clone = Arc::clone(&packets_sent);
         let packets_dropped_clone = Arc::clone(&packets_dropped);
+        let recorder = self.recorder.clone();
 
         // Create channel to receive initial connection result
         let (init_tx, init_rx) = oneshot::channel::<Result<(), TransportError>>();
@@ -128,6 +144,7 @@ impl RtmpClient {
                 bytes_sent_clone,
                 packets_sent_clone,
                 packets_dropped_clone,
+                recorder,
                 Some(init_tx),
             )
             .await
@@ -179,6 +196,12 @@ impl RtmpClient {
             runtime.shutdown_timeout(Duration::from_secs(5));
         }
 
+        if let Some(ref recorder) = self.recorder {
+            if let Err(e) = recorder.lock().flush() {
+                warn!("Failed to flush packet dump: {}", e);
+            }
+        }
+
         *self.state.write() = ConnectionState::Disconnected;
 
         info!("Disconnected from RTMP server");
@@ -230,6 +253,7 @@ async fn run_rtmp_connection(
     bytes_sent: Arc<AtomicU64>,
     packets_sent: Arc<AtomicU64>,
     packets_dropped: Arc<AtomicU64>,
+    recorder: Option<Arc<Mutex<PacketRecorder>>>,
     init_signal: Option<oneshot::Sender<Result<(), TransportError>>>,
 ) -> TransportResult<()> {
     let mut attempt = 0u32;
@@ -272,6 +296,12 @@ async fn run_rtmp_connection(
                             }
                             bytes_sent.fetch_add(packet.data.len() as u64, Ordering::Relaxed);
                             packets_sent.fetch_add(1, Ordering::Relaxed);
+
+                            if let Some(ref recorder) = recorder {
+                                if let Err(e) = recorder.lock().record(&packet) {
+                                    warn!("Failed to record packet: {}", e);
+                                }
+                            }
                         }
                         Err(crossbeam_channel::RecvTimeoutError::Timeout) => continue,
                         Err(crossbeam_channel::RecvTimeoutError::Disconnected) => {
@@ -583,7 +613,10 @@ async fn send_packet(connection: &mut RtmpConnection, packet: &RtmpPacket) -> Tr
     let timestamp = RtmpTimestamp::new(packet.timestamp_ms);
 
     // Publish the packet through the session
-    let result = if packet.is_video {
+    let result = if packet.is_metadata {
+        let metadata = decode_metadata(&packet.data)?;
+        connection.session.publish_metadata(&metadata)
+    } else if packet.is_video {
         connection.session.publish_video_data(
             packet.data.clone(),
             timestamp,
@@ -0,0 +1,114 @@
+//! FLV tag timestamps from encoder PTS/DTS.
+//!
+//! FLV video tags are stamped with the decode timestamp and carry the
+//! presentation offset (PTS - DTS) as composition time. With B-frames the
+//! encoder's first decode timestamps are negative, which FLV cannot express,
+//! so the whole stream is shifted by the initial DTS delay. Audio is shifted by
+//! the same amount to stay in sync.
+
+/// Timestamps of one FLV video tag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VideoTagTiming {
+    /// Tag timestamp (DTS) in milliseconds.
+    pub timestamp_ms: u32,
+    /// Composition time offset (PTS - DTS) in milliseconds, never negative.
+    pub composition_time_ms: i32,
+}
+
+impl VideoTagTiming {
+    /// Presentation timestamp in milliseconds.
+    pub fn pts_ms(&self) -> u32 {
+        self.timestamp_ms + self.composition_time_ms as u32
+    }
+}
+
+/// Maps encoder timestamps to FLV tag timestamps for one stream.
+#[derive(Debug, Default)]
+pub struct FlvTimestamps {
+    /// Shift applied to all timestamps, fixed by the first video frame.
+    offset_ms: Option<i64>,
+    last_video_dts_ms: Option<i64>,
+}
+
+impl FlvTimestamps {
+    /// Create a mapper for a new stream.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Timestamps for a video frame with the given encoder PTS and DTS.
+    ///
+    /// DTS is kept monotonic and never exceeds the PTS.
+    pub fn video(&mut self, pts_ms: i64, dts_ms: i64) -> VideoTagTiming {
+        let offset = *self.offset_ms.get_or_insert((-dts_ms).max(0));
+
+        let mut dts = (dts_ms + offset).max(0);
+        if let Some(last) = self.last_video_dts_ms {
+            dts = dts.max(last);
+        }
+        self.last_video_dts_ms = Some(dts);
+
+        let pts = (pts_ms + offset).max(dts);
+
+        VideoTagTiming {
+            timestamp_ms: dts as u32,
+            composition_time_ms: (pts - dts) as i32,
+        }
+    }
+
+    /// Tag timestamp for audio presented at `pts_ms`.
+    pub fn audio(&self, pts_ms: i64) -> u32 {
+        (pts_ms + self.offset_ms()).max(0) as u32
+    }
+
+    /// The shift applied to all timestamps, 0 until the first video frame.
+    pub fn offset_ms(&self) -> i64 {
+        self.offset_ms.unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_bframes_passes_through() {
+        let mut timestamps = FlvTimestamps::new();
+        for ms in [0, 17, 33] {
+            let timing = timestamps.video(ms, ms);
+            assert_eq!(timing.timestamp_ms, ms as u32);
+            assert_eq!(timing.composition_time_ms, 0);
+        }
+        assert_eq!(timestamps.audio(21), 21);
+    }
+
+    #[test]
+    fn test_negative_dts_shifts_stream() {
+        let mut timestamps = FlvTimestamps::new();
+
+        // I P B B in decode order with a two-frame reorder delay at 100 fps
+        let frames = [(0, -20), (30, -10), (10, 0), (20, 10)];
+        let timings: Vec<_> = frames
+            .iter()
+            .map(|&(pts, dts)| timestamps.video(pts, dts))
+            .collect();
+
+        let dts: Vec<u32> = timings.iter().map(|t| t.timestamp_ms).collect();
+        assert_eq!(dts, [0, 10, 20, 30]);
+        let pts: Vec<u32> = timings.iter().map(|t| t.pts_ms()).collect();
+        assert_eq!(pts, [20, 50, 30, 40]);
+
+        assert_eq!(timestamps.offset_ms(), 20);
+        assert_eq!(timestamps.audio(0), 20);
+    }
+
+    #[test]
+    fn test_dts_kept_monotonic() {
+        let mut timestamps = FlvTimestamps::new();
+        timestamps.video(40, 40);
+
+        let timing = timestamps.video(35, 30);
+        assert_eq!(timing.timestamp_ms, 40);
+        assert_eq!(timing.composition_time_ms, 0);
+    }
+}
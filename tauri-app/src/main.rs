@@ -17,7 +17,9 @@ use tauri::State;
 use tracing::{info};
 
 use broadcaster_engine::Engine;
-use broadcaster_ipc::{EngineCommand, EngineEvent, EngineState, StreamConfig};
+use broadcaster_ipc::{
+    EncoderSettingsUpdate, EngineCommand, EngineEvent, EngineState, RegionOfInterest, StreamConfig,
+};
 
 /// Application state shared across Tauri commands.
 struct AppState {
@@ -71,6 +73,17 @@ fn get_audio_devices(state: State<AppState>) -> CommandResult<()> {
     Ok(())
 }
 
+/// List video encoder backends, optionally benchmarking them.
+/// This is a fire-and-forget command - encoders arrive via poll_events.
+#[tauri::command]
+fn get_encoders(state: State<AppState>, benchmark: bool) -> CommandResult<()> {
+    state
+        .command_tx
+        .send(EngineCommand::GetEncoders { benchmark })
+        .map_err(|e| CommandError::from(format!("Failed to send command: {}", e)))?;
+    Ok(())
+}
+
 /// Poll for events from the engine (non-blocking).
 #[tauri::command]
 fn poll_events(state: State<AppState>) -> Vec<EngineEvent> {
@@ -90,7 +103,9 @@ fn poll_events(state: State<AppState>) -> Vec<EngineEvent> {
 fn start_stream(state: State<AppState>, config: StreamConfig) -> CommandResult<()> {
     state
         .command_tx
-        .send(EngineCommand::Start { config })
+        .send(EngineCommand::Start {
+            config: Box::new(config),
+        })
         .map_err(|e| CommandError::from(format!("Failed to send start command: {}", e)))?;
     Ok(())
 }
@@ -147,6 +162,49 @@ fn set_system_muted(state: State<AppState>, muted: bool) -> CommandResult<()> {
     Ok(())
 }
 
+/// Change video encoder settings while live.
+#[tauri::command]
+fn update_encoder_settings(state: State<AppState>, settings: EncoderSettingsUpdate) -> CommandResult<()> {
+    state
+        .command_tx
+        .send(EngineCommand::UpdateEncoderSettings { settings })
+        .map_err(|e| CommandError::from(format!("Failed to send command: {}", e)))?;
+    Ok(())
+}
+
+/// Make the next video frame a keyframe.
+#[tauri::command]
+fn force_keyframe(state: State<AppState>) -> CommandResult<()> {
+    state
+        .command_tx
+        .send(EngineCommand::ForceKeyframe)
+        .map_err(|e| CommandError::from(format!("Failed to send command: {}", e)))?;
+    Ok(())
+}
+
+/// Replace the regions encoded at their own quality.
+#[tauri::command]
+fn set_regions_of_interest(
+    state: State<AppState>,
+    regions: Vec<RegionOfInterest>,
+) -> CommandResult<()> {
+    state
+        .command_tx
+        .send(EngineCommand::SetRegionsOfInterest { regions })
+        .map_err(|e| CommandError::from(format!("Failed to send command: {}", e)))?;
+    Ok(())
+}
+
+/// Send a line of closed caption text.
+#[tauri::command]
+fn send_caption(state: State<AppState>, text: String) -> CommandResult<()> {
+    state
+        .command_tx
+        .send(EngineCommand::SendCaption { text })
+        .map_err(|e| CommandError::from(format!("Failed to send command: {}", e)))?;
+    Ok(())
+}
+
 /// Get the current engine state.
 #[tauri::command]
 fn get_state(state: State<AppState>) -> CommandResult<EngineState> {
@@ -203,6 +261,7 @@ fn main() {
         .invoke_handler(tauri::generate_handler![
             get_capture_sources,
             get_audio_devices,
+            get_encoders,
             poll_events,
             start_stream,
             stop_stream,
@@ -210,6 +269,10 @@ fn main() {
             set_system_volume,
             set_mic_muted,
             set_system_muted,
+            update_encoder_settings,
+            force_keyframe,
+            set_regions_of_interest,
+            send_caption,
             get_state,
         ])
         .run(tauri::generate_context!())